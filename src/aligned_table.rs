@@ -0,0 +1,200 @@
+// This module implements a fixed-size table of entries in memory aligned
+// to the CPU cache line, for the transposition table.  An aligned table
+// never has its first entry split across cache lines, and the entries
+// fall on the same cache line boundaries on every run.  On Linux, tables
+// of at least a huge page (2 MiB) are aligned to huge pages, and the
+// kernel is advised to back them with transparent huge pages, which cuts
+// down on TLB misses when probing a large table at random.  The advice is
+// only a hint, so the table works the same if huge pages are unavailable.
+
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::slice;
+use std::thread;
+
+// Alignment of every table, which is the cache line size of most CPUs
+const CACHE_LINE_SIZE: usize = 64;
+
+// Size (and alignment) of a transparent huge page on Linux
+#[cfg(target_os = "linux")]
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+// Value of MADV_HUGEPAGE for madvise on Linux
+#[cfg(target_os = "linux")]
+const MADV_HUGEPAGE: i32 = 14;
+
+// Minimum number of entries filled by each thread, so that small tables are
+// filled without spawning threads
+const FILL_CHUNK_ENTRIES: usize = 1 << 20;
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn madvise(addr: *mut u8, len: usize, advice: i32) -> i32;
+}
+
+// A fixed-size table of entries in aligned memory, used like a slice.
+// Entries must be Copy, so they never need to be dropped and can be
+// overwritten in place.
+pub struct AlignedTable<T: Copy> {
+    ptr: NonNull<T>,
+    len: usize,
+    layout: Option<Layout>,
+}
+
+// SAFETY: the table owns its entries, like a Vec
+unsafe impl<T: Copy + Send> Send for AlignedTable<T> {}
+unsafe impl<T: Copy + Sync> Sync for AlignedTable<T> {}
+
+impl<T: Copy + Send + Sync> AlignedTable<T> {
+
+    // Construct an empty table, which does not allocate
+    pub fn empty() -> AlignedTable<T> {
+        AlignedTable {ptr: NonNull::dangling(), len: 0, layout: None}
+    }
+
+    // Construct a table of the given number of entries, each set to the
+    // given value
+    pub fn new(len: usize, value: T) -> AlignedTable<T> {
+        let size = len.checked_mul(std::mem::size_of::<T>()).expect("Table size overflow");
+        if size == 0 {
+            return AlignedTable::empty();
+        }
+        let layout = Layout::from_size_align(size, get_alignment::<T>(size)).expect("Invalid table layout");
+
+        // SAFETY: the layout has a nonzero size
+        let raw = unsafe { alloc::alloc(layout) };
+        let ptr = match NonNull::new(raw as *mut T) {
+            Some(p) => p,
+            None => alloc::handle_alloc_error(layout),
+        };
+        advise_huge_pages(raw, size);
+
+        // SAFETY: the allocation holds len entries, which are initialized
+        // here before the table is used as a slice
+        let entries = unsafe { slice::from_raw_parts_mut(ptr.as_ptr() as *mut MaybeUninit<T>, len) };
+        fill_parallel(entries, value);
+        AlignedTable {ptr, len, layout: Some(layout)}
+    }
+
+    // Set every entry to the given value.  Filling a multi-GB table in a
+    // single thread is a noticeable stall, so large tables are filled in
+    // parallel chunks.
+    pub fn fill(&mut self, value: T) {
+        // SAFETY: T is Copy, so overwriting entries without dropping them
+        // is fine, and MaybeUninit<T> has the same layout as T
+        let entries = unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr() as *mut MaybeUninit<T>, self.len) };
+        fill_parallel(entries, value);
+    }
+
+    // Returns whether the start of the table is aligned to the given number
+    // of bytes
+    #[cfg(test)]
+    fn is_aligned_to(&self, align: usize) -> bool {
+        (self.ptr.as_ptr() as usize).is_multiple_of(align)
+    }
+
+}
+
+impl<T: Copy> Drop for AlignedTable<T> {
+    fn drop(&mut self) {
+        if let Some(layout) = self.layout {
+            // SAFETY: the memory was allocated with this layout in new
+            unsafe { alloc::dealloc(self.ptr.as_ptr() as *mut u8, layout) };
+        }
+    }
+}
+
+impl<T: Copy> Deref for AlignedTable<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        // SAFETY: the table holds len initialized entries (or none, with a
+        // dangling but aligned pointer)
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for AlignedTable<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: as in deref, and the table is borrowed mutably
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+// Get the alignment of a table of the given size in bytes, which is a huge
+// page if the table is large enough to use them
+#[cfg(target_os = "linux")]
+fn get_alignment<T>(size: usize) -> usize {
+    let align = cmp::max(CACHE_LINE_SIZE, std::mem::align_of::<T>());
+    if size >= HUGE_PAGE_SIZE {cmp::max(align, HUGE_PAGE_SIZE)} else {align}
+}
+#[cfg(not(target_os = "linux"))]
+fn get_alignment<T>(_size: usize) -> usize {
+    cmp::max(CACHE_LINE_SIZE, std::mem::align_of::<T>())
+}
+
+// Advise the kernel to back a table with transparent huge pages, if it is
+// large enough to use them
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(ptr: *mut u8, size: usize) {
+    if size >= HUGE_PAGE_SIZE {
+        // SAFETY: the range is a single allocation aligned to a huge page.
+        // Failure (such as on a kernel without transparent huge pages) is
+        // ignored, as the advice is only a hint.
+        unsafe { madvise(ptr, size, MADV_HUGEPAGE) };
+    }
+}
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_ptr: *mut u8, _size: usize) {}
+
+// Write the value to every entry, in parallel chunks for large tables
+fn fill_parallel<T: Copy + Send + Sync>(entries: &mut [MaybeUninit<T>], value: T) {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = cmp::max(FILL_CHUNK_ENTRIES, entries.len().div_ceil(threads));
+    if entries.len() <= chunk_size {
+        entries.iter_mut().for_each(|e| { e.write(value); });
+    } else {
+        thread::scope(|s| {
+            for chunk in entries.chunks_mut(chunk_size) {
+                s.spawn(move || chunk.iter_mut().for_each(|e| { e.write(value); }));
+            }
+        });
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test allocation, alignment, and filling of tables
+    #[test]
+    fn test_aligned_table() {
+        let mut table = AlignedTable::new(1000, 7u64);
+        assert!(table.is_aligned_to(CACHE_LINE_SIZE));
+        assert_eq!(table.len(), 1000);
+        assert!(table.iter().all(|&e| e == 7));
+        table[999] = 3;
+        assert_eq!(table[999], 3);
+        table.fill(5);
+        assert!(table.iter().all(|&e| e == 5));
+
+        // Large tables are filled in parallel, and on Linux are aligned to
+        // huge pages
+        let table = AlignedTable::new(3 * FILL_CHUNK_ENTRIES, Some(1u32));
+        assert!(table.iter().all(|&e| e == Some(1)));
+        #[cfg(target_os = "linux")]
+        assert!(table.is_aligned_to(HUGE_PAGE_SIZE));
+
+        let table: AlignedTable<u64> = AlignedTable::empty();
+        assert!(table.is_empty());
+        assert!(AlignedTable::new(0, 1u8).is_empty());
+    }
+
+}
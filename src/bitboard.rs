@@ -60,6 +60,16 @@ pub const BB_FILES: [u64; 8] = [
     0x4040404040404040, // G file
     0x8080808080808080  // H file
 ];
+pub const BB_RANKS: [u64; 8] = [
+    0x00000000000000FF, // 1st rank
+    0x000000000000FF00, // 2nd rank
+    0x0000000000FF0000, // 3rd rank
+    0x00000000FF000000, // 4th rank
+    0x000000FF00000000, // 5th rank
+    0x0000FF0000000000, // 6th rank
+    0x00FF000000000000, // 7th rank
+    0xFF00000000000000  // 8th rank
+];
 pub const BB_4RANK: u64 = 0x00000000FF000000;
 pub const BB_5RANK: u64 = 0x000000FF00000000;
 pub const BB_NOT_AFILE: u64 = 0xfefefefefefefefe;
@@ -67,6 +77,10 @@ pub const BB_NOT_HFILE: u64 = 0x7f7f7f7f7f7f7f7f;
 pub const BB_MAIN_DIAGONAL: u64 = 0x8040201008040201;
 const BB_MAIN_ANTIDIAGONAL: u64 = 0x0102040810204080;
 
+// Bitboards of the light and dark squares (the color complexes)
+pub const BB_LIGHT_SQUARES: u64 = 0x55AA55AA55AA55AA;
+pub const BB_DARK_SQUARES: u64 = !BB_LIGHT_SQUARES;
+
 // Bitboards related to castling.  These set the squares between
 // the king and appropriate rook to 1.  We'll AND these squares
 // with occupancy and then check for a 0 bitboard to determine
@@ -133,9 +147,6 @@ pub const BB_ANTIDIAGONAL_MASK: [u64; 64] = compute_antidiagonal_masks();
 
 // First rank attack lookup table.  This is used by Kindergarten bitboards
 // to compute sliding piece attacks.
-const FULL_RAY: i32 = 0;
-const WEST_RAY: i32 = 1;
-const EAST_RAY: i32 = 2;
 const fn compute_first_rank_west_attacks_for_square(square: u8, occ: u8) -> u8 {
     let square_bb = 1u8.wrapping_shl(square as u32);
     let mut west_attacks = square_bb - 1;
@@ -158,19 +169,13 @@ const fn compute_first_rank_east_attacks_for_square(square: u8, occ: u8) -> u8 {
     }
     east_attacks
 }
-const fn compute_first_rank_attacks(ray: i32) -> [[u8; 256]; 8] {
+const fn compute_first_rank_attacks() -> [[u8; 256]; 8] {
     let mut attacks: [[u8; 256]; 8] = [[0; 256]; 8];
     let mut square: u8 = 0;
     let mut occ: u8 = 0;
     loop {
         loop {
-            if ray == FULL_RAY {
-                attacks[square as usize][occ as usize] = compute_first_rank_west_attacks_for_square(square, occ) ^ compute_first_rank_east_attacks_for_square(square, occ);
-            } else if ray == WEST_RAY {
-                attacks[square as usize][occ as usize] = compute_first_rank_west_attacks_for_square(square, occ);
-            } else if ray == EAST_RAY {
-                attacks[square as usize][occ as usize] = compute_first_rank_east_attacks_for_square(square, occ);
-            }
+            attacks[square as usize][occ as usize] = compute_first_rank_west_attacks_for_square(square, occ) ^ compute_first_rank_east_attacks_for_square(square, occ);
             if occ >= 255 {
                 break;
             }
@@ -184,12 +189,68 @@ const fn compute_first_rank_attacks(ray: i32) -> [[u8; 256]; 8] {
     }
     attacks
 }
-pub const BB_FIRST_RANK_ATTACKS: [[u8; 256]; 8] = compute_first_rank_attacks(FULL_RAY);
+pub const BB_FIRST_RANK_ATTACKS: [[u8; 256]; 8] = compute_first_rank_attacks();
 
-// For SEE capture scoring, it's easier for us to have "east" and "west"
-// first rank attack rays.  So, store these seperately.
-pub const BB_FIRST_RANK_WEST_ATTACKS: [[u8; 256]; 8] = compute_first_rank_attacks(WEST_RAY);
-pub const BB_FIRST_RANK_EAST_ATTACKS: [[u8; 256]; 8] = compute_first_rank_attacks(EAST_RAY);
+// Bitboards representing the squares between two squares, and the entire
+// line (rank, file, diagonal, or anti-diagonal) that passes through two
+// squares, indexed by [square][square].  These are empty if the squares
+// do not share a line.  The "between" bitboards exclude both squares,
+// while the "line" bitboards include both squares and extend from edge
+// to edge of the board.
+const fn compute_between_and_line(compute_line: bool) -> [[u64; 64]; 64] {
+    let mut table: [[u64; 64]; 64] = [[0; 64]; 64];
+    let mut from: i32 = 0;
+    loop {
+        let mut to: i32 = 0;
+        loop {
+            let rank_diff = to / 8 - from / 8;
+            let file_diff = to % 8 - from % 8;
+            let aligned = from != to && (rank_diff == 0 || file_diff == 0 || rank_diff == file_diff || rank_diff == -file_diff);
+            if aligned {
+                let rank_step = rank_diff.signum();
+                let file_step = file_diff.signum();
+                let mut bb: u64 = 0;
+                if compute_line {
+                    // Walk backwards to the edge of the board, and then
+                    // forwards to the other edge
+                    let mut rank = from / 8;
+                    let mut file = from % 8;
+                    while rank - rank_step >= 0 && rank - rank_step < 8 && file - file_step >= 0 && file - file_step < 8 {
+                        rank -= rank_step;
+                        file -= file_step;
+                    }
+                    while rank >= 0 && rank < 8 && file >= 0 && file < 8 {
+                        bb |= to_bb((rank * 8 + file) as usize);
+                        rank += rank_step;
+                        file += file_step;
+                    }
+                } else {
+                    let mut rank = from / 8 + rank_step;
+                    let mut file = from % 8 + file_step;
+                    while rank * 8 + file != to {
+                        bb |= to_bb((rank * 8 + file) as usize);
+                        rank += rank_step;
+                        file += file_step;
+                    }
+                }
+                table[from as usize][to as usize] = bb;
+            }
+            to += 1;
+            if to >= 64 {
+                break;
+            }
+        }
+        from += 1;
+        if from >= 64 {
+            break;
+        }
+    }
+    table
+}
+// These tables are 32KB each, so they are stored as statics rather than
+// constants to avoid copying them at each use.
+pub static BB_BETWEEN: [[u64; 64]; 64] = compute_between_and_line(false);
+pub static BB_LINE: [[u64; 64]; 64] = compute_between_and_line(true);
 
 // Compute single step bitboard functions
 pub const fn south_one(bb: u64) -> u64 {bb.wrapping_shr(8)}
@@ -356,6 +417,14 @@ const fn bit_scan_reverse(mut bb: u64) -> Option<usize> {
  }
 
 
+// Returns the distance between two squares in king moves (the Chebyshev
+// distance), which is the larger of the file and rank distances.
+pub fn square_distance(square1: usize, square2: usize) -> usize {
+    let file_distance = (square1 % 8).abs_diff(square2 % 8);
+    let rank_distance = (square1 / 8).abs_diff(square2 / 8);
+    file_distance.max(rank_distance)
+}
+
 // Given a bitboard, return a list of the locations of all 1's set.
 // These typically represent "occupied" squares in a bitboard.
 // Note the bitboard is copied here so we're not modifying the
@@ -15,6 +15,7 @@
 use crate::bitboard;
 use crate::zobrist;
 use crate::pieces;
+use crate::movegen;
 
 // FEN string for the starting position
 pub const STARTFEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
@@ -24,7 +25,37 @@ pub fn file_rank_to_square(file: usize, rank: usize) -> usize {
     rank * 8 + file
 }
 
-#[derive(Debug)]
+// Convert a square ID to its string representation (e.g., 3 -> "d1")
+pub fn square_to_str(square: usize) -> String {
+    format!("{}{}", (b'a' + (square % 8) as u8) as char, square / 8 + 1)
+}
+
+// Get the FEN string of the color-flipped mirror of a position: the board
+// is flipped vertically, the colors of all pieces are swapped, and the
+// other player is to move.  The mirror should evaluate the same as the
+// original position for the player to move.
+pub fn mirror_fen(fen: &str) -> String {
+    let swap_case = |c: char| if c.is_ascii_uppercase() {c.to_ascii_lowercase()} else {c.to_ascii_uppercase()};
+    let fields: Vec<&str> = fen.split_whitespace().collect();
+    let mut mirrored: Vec<String> = Vec::new();
+    let ranks: Vec<String> = fields[0].split('/').rev().map(|rank| rank.chars().map(swap_case).collect()).collect();
+    mirrored.push(ranks.join("/"));
+    if let Some(side) = fields.get(1) {
+        mirrored.push(String::from(if *side == "w" {"b"} else {"w"}));
+    }
+    if let Some(castling) = fields.get(2) {
+        let swapped: String = castling.chars().map(swap_case).collect();
+        let ordered: String = "KQkq".chars().filter(|c| swapped.contains(*c)).collect();
+        mirrored.push(if ordered.is_empty() {String::from("-")} else {ordered});
+    }
+    if let Some(en_passant) = fields.get(3) {
+        mirrored.push(en_passant.replace('3', "x").replace('6', "3").replace('x', "6"));
+    }
+    mirrored.extend(fields.iter().skip(4).map(|f| f.to_string()));
+    mirrored.join(" ")
+}
+
+#[derive(Debug, Clone)]
 struct MoveRecord {
 
     // Starting square from 0 to 63
@@ -54,17 +85,19 @@ struct MoveRecord {
     prior_black_ks_castling_rights: bool,
     prior_black_qs_castling_rights: bool,
     prior_en_passant_rights: Option<usize>,
+    prior_halfmove_clock: u16,
 }
 
+#[derive(Clone)]
 pub struct ChessBoard {
 
     // Bitboards representing occupied squares for a [color][piece]
     // for a total of 12
-    pub bb_pieces: Vec<Vec<u64>>,
+    pub bb_pieces: [[u64; 6]; 2],
 
     // Bitboards representing all occupied squares for a side (white
     // and black)
-    pub bb_side: Vec<u64>,
+    pub bb_side: [u64; 2],
 
     // Bitboard representing all occupied squares for the entire board
     pub bb_occupied_squares: u64,
@@ -93,9 +126,26 @@ pub struct ChessBoard {
     // move, if the current player can capture en passant to that square.
     pub en_passant_rights: Option<usize>,
 
+    // Number of halfmoves since the last capture or pawn move, used
+    // for the fifty-move rule
+    pub halfmove_clock: u16,
+
+    // Number of the full move, starting at 1 and incremented after
+    // black's move
+    pub fullmove_number: u16,
+
     // Zobrist hash of the current board state
     zobrist_hasher: zobrist::ZobristHasher,
     pub zobrist_hash: u64,
+
+    // Running totals of piece values and piece-square table (PST) values
+    // for a [color][phase], where phase 0 is the middle game and 1 is the
+    // end game, and the total phase contribution of the pieces on the
+    // board.  These are updated incrementally as moves are made so the
+    // evaluation does not have to loop over every piece.
+    pub material: [[i32; 2]; 2],
+    pub pst: [[i32; 2]; 2],
+    pub phase_material: i32,
 }
 
 impl ChessBoard {
@@ -103,8 +153,8 @@ impl ChessBoard {
     // Construct a new ChessBoard
     pub fn new() -> ChessBoard {
         ChessBoard {
-            bb_pieces: vec![vec![0; 6]; 2],
-            bb_side: vec![0; 2],
+            bb_pieces: [[0; 6]; 2],
+            bb_side: [0; 2],
             bb_occupied_squares: 0,
             bb_empty_squares: 0,
             move_history: Vec::new(),          
@@ -115,60 +165,88 @@ impl ChessBoard {
             black_ks_castling_rights: true,
             black_qs_castling_rights: true,
             en_passant_rights: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
             zobrist_hasher: zobrist::ZobristHasher::new(),
             zobrist_hash: 0,
+            material: [[0; 2]; 2],
+            pst: [[0; 2]; 2],
+            phase_material: 0,
         }
     }
 
     // Set / reset the game from the starting position
     pub fn new_game(&mut self) {
-        self.new_game_from_fen(STARTFEN);
+        self.new_game_from_fen(STARTFEN).expect("Invalid starting FEN string");
     }
 
     // Set / reset the game state to the point listed in the
-    // FEN string (see https://en.wikipedia.org/wiki/Forsyth–Edwards_Notation)
-    pub fn new_game_from_fen(&mut self, fen_str: &str) {
+    // FEN string (see https://en.wikipedia.org/wiki/Forsyth–Edwards_Notation).
+    // If the FEN string is malformed, an error is returned and the game
+    // state is left untouched.
+    pub fn new_game_from_fen(&mut self, fen_str: &str) -> Result<(), String> {
 
         // Get the 6 components of the FEN string
         let tokens: Vec<&str> = fen_str.split_whitespace().collect();
         if tokens.len() != 6 {
-            panic!("Invalid FEN string: {}", fen_str);
+            return Err(format!("Invalid FEN string (expected 6 fields): {}", fen_str));
         }
 
         // Component 1: Piece placement
         let piece_tokens: Vec<&str> = tokens[0].split('/').collect();
         if piece_tokens.len() != 8 {
-            panic!("Invalid FEN string: {}", fen_str);
-        }
-        for color in 0..2 {
-            for piece in 0..6 {
-                self.bb_pieces[color][piece] = 0;
-            }
+            return Err(format!("Invalid FEN string (expected 8 ranks): {}", fen_str));
         }
+        let mut bb_pieces = [[0; 6]; 2];
         for (i, file_str) in piece_tokens.iter().enumerate() {
-            let mut square = (7-i)*8;
+            let mut file = 0;
             for c in file_str.chars() {
-                if c.is_digit(10) {
-                    if let Some(d) = c.to_digit(10) {
-                        square += d as usize;
-                    } else {
-                        panic!("Invalid FEN string: {}", fen_str);
-                    }
-                } else if c.is_lowercase() {
-                    let piece = pieces::PIECE_ID_TO_CHAR[pieces::COLOR_BLACK].iter().position(|&r| r == c).unwrap();
-                    self.bb_pieces[pieces::COLOR_BLACK][piece] |= bitboard::to_bb(square);
-                    square += 1;
-                } else if c.is_uppercase() {
-                    let piece = pieces::PIECE_ID_TO_CHAR[pieces::COLOR_WHITE].iter().position(|&r| r == c).unwrap();
-                    self.bb_pieces[pieces::COLOR_WHITE][piece] |= bitboard::to_bb(square);
-                    square += 1;
+                if let Some(d) = c.to_digit(10) {
+                    file += d as usize;
                 } else {
-                    panic!("Invalid FEN string: {}", fen_str);
+                    let color = if c.is_lowercase() {pieces::COLOR_BLACK} else {pieces::COLOR_WHITE};
+                    let piece = match pieces::PIECE_ID_TO_CHAR[color].iter().position(|&r| r == c) {
+                        Some(p) => p,
+                        None => return Err(format!("Invalid FEN string (unknown piece '{}'): {}", c, fen_str)),
+                    };
+                    if file < 8 {
+                        bb_pieces[color][piece] |= bitboard::to_bb(file_rank_to_square(file, 7 - i));
+                    }
+                    file += 1;
                 }
             }
+            if file != 8 {
+                return Err(format!("Invalid FEN string (rank {} does not have 8 squares): {}", 8 - i, fen_str));
+            }
         }
+        for (color, bbs) in bb_pieces.iter().enumerate() {
+            if bitboard::pop_count(bbs[pieces::KING]) != 1 {
+                return Err(format!("Invalid FEN string ({} must have exactly one king): {}", if color == pieces::COLOR_WHITE {"white"} else {"black"}, fen_str));
+            }
+        }
+
+        // Component 2: Turn
+        let whites_turn = match tokens[1] {
+            "w" => true,
+            "b" => false,
+            _ => return Err(format!("Invalid FEN string (unknown side to move): {}", fen_str)),
+        };
+
+        // Component 5: Halfmove clock
+        let halfmove_clock = match tokens[4].parse::<u16>() {
+            Ok(h) => h,
+            Err(_) => return Err(format!("Invalid FEN string (bad halfmove clock): {}", fen_str)),
+        };
+
+        // Component 6: Fullmove number
+        let fullmove_number = match tokens[5].parse::<u16>() {
+            Ok(f) => f,
+            Err(_) => return Err(format!("Invalid FEN string (bad fullmove number): {}", fen_str)),
+        };
 
-        // Reset side and occupied bitboards
+        // The FEN string is valid, so set the piece placement and reset the
+        // side and occupied bitboards
+        self.bb_pieces = bb_pieces;
         for c in 0..2 {
             self.bb_side[c] = 0;
             for p in self.bb_pieces[c].iter() {
@@ -177,15 +255,9 @@ impl ChessBoard {
         }
         self.bb_occupied_squares = self.bb_side[pieces::COLOR_WHITE] | self.bb_side[pieces::COLOR_BLACK];
         self.bb_empty_squares = !self.bb_occupied_squares;
-
-        // Component 2: Turn
-        if tokens[1] == "w" {
-            self.whites_turn = true;
-        } else if tokens[1] == "b" {
-            self.whites_turn = false;
-        } else {
-            panic!("Invalid FEN string: {}", fen_str);
-        }
+        self.whites_turn = whites_turn;
+        self.halfmove_clock = halfmove_clock;
+        self.fullmove_number = fullmove_number;
 
         // Component 3: Castling rights
         self.white_ks_castling_rights = false;
@@ -206,25 +278,37 @@ impl ChessBoard {
         }
 
         // Component 4: En passant target square
-        // TODO - we represent en passant rights only if a legal en passant
-        // capture is possible.  However, the older (and more common) FEN
-        // notation adds the en passant target square if a pawn moves two
-        // spaces, regardless if an en passant capture is possible.  We
-        // have to reconcile this before we can use this information.
+        // We represent en passant rights only if an en passant capture is
+        // possible.  However, the older (and more common) FEN notation adds
+        // the en passant target square if a pawn moves two spaces,
+        // regardless if an en passant capture is possible.  So, only keep
+        // the target square if one of our pawns attacks it.
         self.en_passant_rights = None;
-
-        // Component 5: Halfmove clock
-        // TODO
-
-        // Component 6: Fullmove number
-        // TODO
+        let ep_chars: Vec<char> = tokens[3].chars().collect();
+        if ep_chars.len() == 2 {
+            if let (Some(file), Some(rank)) = ("abcdefgh".find(ep_chars[0]), ep_chars[1].to_digit(10)) {
+                if (1..=8).contains(&rank) {
+                    let ep_square = file_rank_to_square(file, (rank - 1) as usize);
+                    let my_color = if self.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+                    if bitboard::BB_PAWN_ATTACKS[1 - my_color][ep_square] & self.bb_pieces[my_color][pieces::PAWN] != 0 {
+                        self.en_passant_rights = Some(ep_square);
+                    }
+                }
+            }
+        }
 
         // Reset the rest of the state
         self.move_history.clear();
         self.zobrist_history.clear();
 
-        // Reset the Zobrist hash
+        // Reset the Zobrist hash and evaluation totals.  The starting
+        // position is part of the history so that returning to it counts as
+        // a repetition.
         self.zobrist_hash = self.zobrist_hasher.full_hash(self);
+        self.zobrist_history.push(self.zobrist_hash);
+        (self.material, self.pst, self.phase_material) = self.compute_eval_totals();
+
+        Ok(())
 
     }
 
@@ -289,9 +373,21 @@ impl ChessBoard {
             prior_black_ks_castling_rights: self.black_ks_castling_rights,
             prior_black_qs_castling_rights: self.black_qs_castling_rights,
             prior_en_passant_rights: self.en_passant_rights,
+            prior_halfmove_clock: self.halfmove_clock,
         };
         self.move_history.push(move_record);
 
+        // Update the move clocks.  The halfmove clock is reset by any
+        // capture or pawn move.
+        if piece == pieces::PAWN || captured_piece.is_some() {
+            self.halfmove_clock = 0;
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if !self.whites_turn {
+            self.fullmove_number += 1;
+        }
+
         // Check if we have to give our opponent en passant rights
         let mut give_en_passant_rights = false;
         if piece == pieces::PAWN && (start_rank == 1 && end_rank == 3 || start_rank == 6 && end_rank == 4) {
@@ -335,6 +431,8 @@ impl ChessBoard {
         // Hash - place the source on dest, and revert the source square
         self.zobrist_hash ^= self.zobrist_hasher.hash_piece[end_square][my_color][piece];
         self.zobrist_hash ^= self.zobrist_hasher.hash_piece[start_square][my_color][piece];
+        self.update_eval_totals(my_color, piece, start_square, -1);
+        self.update_eval_totals(my_color, piece, end_square, 1);
 
         // Handle potential captures
         if let Some(cp) = captured_piece {
@@ -350,6 +448,7 @@ impl ChessBoard {
                 self.bb_occupied_squares ^= captured_pawn_square_bb;
                 self.bb_empty_squares ^= captured_pawn_square_bb;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[captured_pawn_square][opp_color][cp];
+                self.update_eval_totals(opp_color, cp, captured_pawn_square, -1);
             } else {
                 // Remove captured piece from board
                 self.bb_pieces[opp_color][cp] ^= to_bb;
@@ -357,6 +456,7 @@ impl ChessBoard {
                 self.bb_occupied_squares ^= from_bb;
                 self.bb_empty_squares ^= from_bb;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[end_square][opp_color][cp];
+                self.update_eval_totals(opp_color, cp, end_square, -1);
             }
         } else {
             // There was no capture; this is a "quiet" move
@@ -377,6 +477,8 @@ impl ChessBoard {
             self.bb_pieces[my_color][pp] ^= to_bb;
             self.zobrist_hash ^= self.zobrist_hasher.hash_piece[end_square][my_color][pieces::PAWN];
             self.zobrist_hash ^= self.zobrist_hasher.hash_piece[end_square][my_color][pp];
+            self.update_eval_totals(my_color, pieces::PAWN, end_square, -1);
+            self.update_eval_totals(my_color, pp, end_square, 1);
         } 
 
         // If this was a castling move, we now have to take care to move
@@ -394,6 +496,8 @@ impl ChessBoard {
                 // Hash - apply rook to new square and revert it from old square
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[7][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[5][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 7, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 5, 1);
             } else if start_square == 4 && end_square == 2 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_WQS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_WQS_CASTLING_ROOKS_FROM_TO;
@@ -402,6 +506,8 @@ impl ChessBoard {
                 // Hash - apply rook to new square and revert it from old square
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[0][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[3][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 0, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 3, 1);
             } else if start_square == 60 && end_square == 62 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_BKS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_BKS_CASTLING_ROOKS_FROM_TO;
@@ -410,6 +516,8 @@ impl ChessBoard {
                 // Hash - apply rook to new square and revert it from old square
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[63][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[61][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 63, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 61, 1);
             } else if start_square == 60 && end_square == 58 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_BQS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_BQS_CASTLING_ROOKS_FROM_TO;
@@ -418,6 +526,8 @@ impl ChessBoard {
                 // Hash - apply rook to new square and revert it from old square
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[56][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[59][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 56, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 59, 1);
             }
         }
 
@@ -485,6 +595,9 @@ impl ChessBoard {
         // Store Zobrist hash in history
         self.zobrist_history.push(self.zobrist_hash);
 
+        #[cfg(debug_assertions)]
+        self.audit_state("make_move");
+
     }
 
     // Undo the last move.  This restores all state to the state prior
@@ -508,6 +621,12 @@ impl ChessBoard {
         self.zobrist_hash ^= self.zobrist_hasher.hash_blacks_turn;
         self.whites_turn = !self.whites_turn;
 
+        // Restore the move clocks
+        self.halfmove_clock = last_move.prior_halfmove_clock;
+        if !self.whites_turn {
+            self.fullmove_number -= 1;
+        }
+
         // Get rank (0-7) for important squares
         let end_rank = last_move.end_square / 8;
         let end_file = last_move.end_square % 8;
@@ -562,6 +681,8 @@ impl ChessBoard {
                 self.bb_empty_squares ^= bitboard::BB_WKS_CASTLING_ROOKS_FROM_TO;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[7][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[5][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 5, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 7, 1);
             } else if last_move.start_square == 4 && last_move.end_square == 2 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_WQS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_WQS_CASTLING_ROOKS_FROM_TO;
@@ -569,6 +690,8 @@ impl ChessBoard {
                 self.bb_empty_squares ^= bitboard::BB_WQS_CASTLING_ROOKS_FROM_TO;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[0][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[3][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 3, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 0, 1);
             } else if last_move.start_square == 60 && last_move.end_square == 62 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_BKS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_BKS_CASTLING_ROOKS_FROM_TO;
@@ -576,6 +699,8 @@ impl ChessBoard {
                 self.bb_empty_squares ^= bitboard::BB_BKS_CASTLING_ROOKS_FROM_TO;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[63][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[61][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 61, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 63, 1);
             } else if last_move.start_square == 60 && last_move.end_square == 58 {
                 self.bb_pieces[my_color][pieces::ROOK] ^= bitboard::BB_BQS_CASTLING_ROOKS_FROM_TO;
                 self.bb_side[my_color] ^= bitboard::BB_BQS_CASTLING_ROOKS_FROM_TO;
@@ -583,6 +708,8 @@ impl ChessBoard {
                 self.bb_empty_squares ^= bitboard::BB_BQS_CASTLING_ROOKS_FROM_TO;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[56][my_color][pieces::ROOK];
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[59][my_color][pieces::ROOK];
+                self.update_eval_totals(my_color, pieces::ROOK, 59, -1);
+                self.update_eval_totals(my_color, pieces::ROOK, 56, 1);
             }
         }
 
@@ -602,6 +729,8 @@ impl ChessBoard {
             self.bb_pieces[my_color][pp] ^= to_bb;
             self.zobrist_hash ^= self.zobrist_hasher.hash_piece[p][my_color][pieces::PAWN];
             self.zobrist_hash ^= self.zobrist_hasher.hash_piece[p][my_color][pp];
+            self.update_eval_totals(my_color, pp, p, -1);
+            self.update_eval_totals(my_color, pieces::PAWN, p, 1);
         }
 
         // Handle potential captures
@@ -618,6 +747,7 @@ impl ChessBoard {
                 self.bb_empty_squares ^= captured_pawn_square_bb;
                 self.bb_empty_squares ^= from_to_bb;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[captured_pawn_square][opp_color][cp];
+                self.update_eval_totals(opp_color, cp, captured_pawn_square, 1);
             } else {
                 // Add the captured piece back to the board
                 self.bb_pieces[opp_color][cp] ^= to_bb;
@@ -625,6 +755,7 @@ impl ChessBoard {
                 self.bb_occupied_squares ^= from_bb;
                 self.bb_empty_squares ^= from_bb;
                 self.zobrist_hash ^= self.zobrist_hasher.hash_piece[last_move.end_square][opp_color][cp];
+                self.update_eval_totals(opp_color, cp, last_move.end_square, 1);
             }
         } else {
             // There was no capture; this is a "quiet" move
@@ -637,9 +768,76 @@ impl ChessBoard {
         self.bb_side[my_color] ^= from_to_bb;
         self.zobrist_hash ^= self.zobrist_hasher.hash_piece[last_move.end_square][my_color][last_move.piece];
         self.zobrist_hash ^= self.zobrist_hasher.hash_piece[last_move.start_square][my_color][last_move.piece];
+        self.update_eval_totals(my_color, last_move.piece, last_move.end_square, -1);
+        self.update_eval_totals(my_color, last_move.piece, last_move.start_square, 1);
+
+        #[cfg(debug_assertions)]
+        self.audit_state("unmake_move");
 
     }
 
+    // Check that the incrementally updated state (Zobrist hash, side and
+    // occupancy bitboards, and evaluation totals) matches the state
+    // computed from scratch, panicking on a mismatch.  This is only built
+    // in debug builds, so that corruption from a new kind of move surfaces
+    // at the move that caused it rather than as a bad search much later.
+    #[cfg(debug_assertions)]
+    fn audit_state(&self, context: &str) {
+        let mut bb_side = [0u64; 2];
+        for color in 0..2 {
+            for piece in 0..6 {
+                assert_eq!(bb_side[0] & self.bb_pieces[color][piece], 0, "Overlapping pieces after {}", context);
+                assert_eq!(bb_side[1] & self.bb_pieces[color][piece], 0, "Overlapping pieces after {}", context);
+                bb_side[color] |= self.bb_pieces[color][piece];
+            }
+        }
+        assert_eq!(self.bb_side, bb_side, "Side bitboards mismatch after {}", context);
+        assert_eq!(self.bb_occupied_squares, bb_side[0] | bb_side[1], "Occupied squares mismatch after {}", context);
+        assert_eq!(self.bb_empty_squares, !self.bb_occupied_squares, "Empty squares mismatch after {}", context);
+        assert_eq!(self.zobrist_hash, self.zobrist_hasher.full_hash(self), "Zobrist hash mismatch after {}", context);
+        assert_eq!((self.material, self.pst, self.phase_material), self.compute_eval_totals(), "Evaluation totals mismatch after {}", context);
+    }
+
+    // Add (sign of 1) or remove (sign of -1) a piece's contribution to the
+    // running evaluation totals.  The PST's are from white's perspective,
+    // so the look up is flipped for black.  Performing a bitwise "xor 56"
+    // on the square will "flip" the square to the other side.
+    fn update_eval_totals(&mut self, color: usize, piece: usize, square: usize, sign: i32) {
+        let pst_square = if color == pieces::COLOR_WHITE {square} else {square ^ 56};
+        self.material[color][0] += sign * pieces::PIECE_VALUES_MG[piece];
+        self.material[color][1] += sign * pieces::PIECE_VALUES_EG[piece];
+        self.pst[color][0] += sign * pieces::PST_MIDDLE_GAME[piece][pst_square];
+        self.pst[color][1] += sign * pieces::PST_END_GAME[piece][pst_square];
+        self.phase_material += sign * pieces::PIECE_PHASE[piece];
+    }
+
+    // Compute the material, PST, and phase totals from scratch by looping
+    // over every piece on the board
+    pub fn compute_eval_totals(&self) -> ([[i32; 2]; 2], [[i32; 2]; 2], i32) {
+        let mut material = [[0; 2]; 2];
+        let mut pst = [[0; 2]; 2];
+        let mut phase_material = 0;
+        for color in 0..2 {
+            for (piece, bb) in self.bb_pieces[color].iter().enumerate() {
+                for square in bitboard::occupied_squares(*bb) {
+                    let pst_square = if color == pieces::COLOR_WHITE {square} else {square ^ 56};
+                    material[color][0] += pieces::PIECE_VALUES_MG[piece];
+                    material[color][1] += pieces::PIECE_VALUES_EG[piece];
+                    pst[color][0] += pieces::PST_MIDDLE_GAME[piece][pst_square];
+                    pst[color][1] += pieces::PST_END_GAME[piece][pst_square];
+                    phase_material += pieces::PIECE_PHASE[piece];
+                }
+            }
+        }
+        (material, pst, phase_material)
+    }
+
+    // Return the piece moved (before any promotion) and the end square of
+    // the last move made, or None if no move has been made.
+    pub fn get_last_move(&self) -> Option<(usize, usize)> {
+        self.move_history.last().map(|m| (m.piece, m.end_square))
+    }
+
     // Return a tuple representing the color and piece on a given square.
     // The will return None if the square is empty.
     pub fn get_color_and_piece_on_square(&self, square: usize) -> Option<(usize, usize)> {
@@ -657,9 +855,75 @@ impl ChessBoard {
 
     }
 
-    // Print the board
+    // Export the game state as a FEN string
+    // (see https://en.wikipedia.org/wiki/Forsyth–Edwards_Notation).
+    // Note that the en passant target square is only included if an
+    // en passant capture is actually possible.
+    pub fn to_fen(&self) -> String {
+
+        // Component 1: Piece placement
+        let mut fen = String::new();
+        for rank in (0..8).rev() {
+            let mut empty_squares = 0;
+            for file in 0..8 {
+                match self.get_color_and_piece_on_square(file_rank_to_square(file, rank)) {
+                    Some((color, piece)) => {
+                        if empty_squares > 0 {
+                            fen.push_str(&empty_squares.to_string());
+                            empty_squares = 0;
+                        }
+                        fen.push(pieces::PIECE_ID_TO_CHAR[color][piece]);
+                    },
+                    None => empty_squares += 1,
+                }
+            }
+            if empty_squares > 0 {
+                fen.push_str(&empty_squares.to_string());
+            }
+            if rank > 0 {
+                fen.push('/');
+            }
+        }
+
+        // Component 2: Turn
+        fen.push_str(if self.whites_turn {" w "} else {" b "});
+
+        // Component 3: Castling rights
+        let mut castling = String::new();
+        if self.white_ks_castling_rights {
+            castling.push('K');
+        }
+        if self.white_qs_castling_rights {
+            castling.push('Q');
+        }
+        if self.black_ks_castling_rights {
+            castling.push('k');
+        }
+        if self.black_qs_castling_rights {
+            castling.push('q');
+        }
+        if castling.is_empty() {
+            castling.push('-');
+        }
+        fen.push_str(&castling);
+
+        // Component 4: En passant target square
+        fen.push(' ');
+        match self.en_passant_rights {
+            Some(e) => fen.push_str(&square_to_str(e)),
+            None => fen.push('-'),
+        }
+
+        // Components 5 and 6: Halfmove clock and fullmove number
+        fen.push_str(&format!(" {} {}", self.halfmove_clock, self.fullmove_number));
+        fen
+
+    }
+
+    // Print the board.  If flipped, the board is shown from black's
+    // perspective (black at the bottom).
     #[allow(dead_code)]
-    pub fn print(&self, use_unicode: bool) {
+    pub fn print(&self, use_unicode: bool, flipped: bool) {
         let mut char_board = [['.'; 8]; 8];
         let mut index = 0;
         for (color, _) in self.bb_pieces.iter().enumerate() {
@@ -681,14 +945,46 @@ impl ChessBoard {
                 index = 0;
             }
         }
-        for (rank, cs) in char_board.iter().enumerate() {
-            print!("{}  ", 8 - rank);
-            for cell in cs {
-                print!("{} ", cell);
+        if flipped {
+            for (rank, cs) in char_board.iter().enumerate().rev() {
+                print!("{}  ", 8 - rank);
+                for cell in cs.iter().rev() {
+                    print!("{} ", cell);
+                }
+                println!();
+            }
+            println!("   h g f e d c b a");
+        } else {
+            for (rank, cs) in char_board.iter().enumerate() {
+                print!("{}  ", 8 - rank);
+                for cell in cs {
+                    print!("{} ", cell);
+                }
+                println!();
             }
-            println!();
+            println!("   a b c d e f g h");
         }
-        println!("   a b c d e f g h");
+    }
+
+    // Print the board along with the state needed to reproduce it: the
+    // FEN string, Zobrist key, castling and en passant state, and the
+    // squares of any pieces giving check
+    pub fn print_display(&self) {
+        let fen = self.to_fen();
+        let fields: Vec<&str> = fen.split(' ').collect();
+        let side = if self.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let checkers: Vec<String> = bitboard::occupied_squares(movegen::checkers(self, side)).into_iter().map(square_to_str).collect();
+        println!();
+        self.print(false, false);
+        println!();
+        println!("Fen: {}", fen);
+        println!("Key: {:016X}", self.zobrist_hash);
+        println!("Side to move: {}", if self.whites_turn {"white"} else {"black"});
+        println!("Castling rights: {}", fields[2]);
+        println!("En passant square: {}", fields[3]);
+        println!("Halfmove clock: {}", self.halfmove_clock);
+        println!("Fullmove number: {}", self.fullmove_number);
+        println!("Checkers: {}", checkers.join(" "));
     }
 
     // Print the game state, for debugging purposes
@@ -696,7 +992,7 @@ impl ChessBoard {
     pub fn print_debug(&self) {
         println!("----------------- DEBUG STATE -----------------");
         println!("BOARD STATE");
-        self.print(false);
+        self.print(false, false);
         println!("OTHER STATE");
         println!("   move_history: {:?}", self.move_history);
         println!("   zobrist_history: {:?}", self.zobrist_history);
@@ -706,12 +1002,21 @@ impl ChessBoard {
         println!("   black_ks_castling_rights: {}", self.black_ks_castling_rights);
         println!("   black_qs_castling_rights: {}", self.black_qs_castling_rights);
         println!("   en_passant_rights: {:?}", self.en_passant_rights);
+        println!("   halfmove_clock: {}", self.halfmove_clock);
+        println!("   fullmove_number: {}", self.fullmove_number);
+        println!("   fen: {}", self.to_fen());
         println!("   zobrist_hash: {}", self.zobrist_hash);
         println!("-------------- END DEBUG STATE ----------------");
     }
 
 }
 
+impl Default for ChessBoard {
+    fn default() -> ChessBoard {
+        ChessBoard::new()
+    }
+}
+
 // =====================================
 //             UNIT TESTS
 // =====================================
@@ -738,14 +1043,60 @@ mod tests {
                 board.make_move(start_square, end_square, None);
             }
             assert_eq!(board.zobrist_hash, board.zobrist_hasher.full_hash(&board));
+            assert_eq!((board.material, board.pst, board.phase_material), board.compute_eval_totals());
         }
         // Unmake moves, checking hashes
         while board.move_history.len() > 0 {
             board.unmake_move();
             assert_eq!(board.zobrist_hash, board.zobrist_hasher.full_hash(&board));
+            assert_eq!((board.material, board.pst, board.phase_material), board.compute_eval_totals());
         }
         // Ensure initial hash matches
         assert_eq!(initial_hash, board.zobrist_hash);
     }
+
+    #[test]
+    fn test_to_fen() {
+        let mut board = ChessBoard::new();
+        board.new_game();
+        assert_eq!(board.to_fen(), super::STARTFEN);
+        // 1. e4 c5 2. Nf3 d5 3. e5 f5 (en passant possible on f6)
+        for (start_square, end_square) in [(12, 28), (50, 34), (6, 21), (51, 35), (28, 36), (53, 37)] {
+            board.make_move(start_square, end_square, None);
+        }
+        let fen = "rnbqkbnr/pp2p1pp/8/2ppPp2/8/5N2/PPPP1PPP/RNBQKB1R w KQkq f6 0 4";
+        assert_eq!(board.to_fen(), fen);
+        board.make_move(4, 12, None); // Ke2
+        assert_eq!(board.to_fen(), "rnbqkbnr/pp2p1pp/8/2ppPp2/8/5N2/PPPPKPPP/RNBQ1B1R b kq - 1 4");
+        board.unmake_move();
+        assert_eq!(board.to_fen(), fen);
+        // Round trip through the FEN parser
+        let hash = board.zobrist_hash;
+        board.new_game_from_fen(fen).unwrap();
+        assert_eq!(board.to_fen(), fen);
+        assert_eq!(board.zobrist_hash, hash);
+    }
+
+    #[test]
+    fn test_mirror_fen() {
+        assert_eq!(super::mirror_fen(super::STARTFEN), "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1");
+        let fen = "rnbqkbnr/pp2p1pp/8/2ppPp2/8/5N2/PPPP1PPP/RNBQKB1R w Kq f6 0 4";
+        assert_eq!(super::mirror_fen(fen), "rnbqkb1r/pppp1ppp/5n2/8/2PPpP2/8/PP2P1PP/RNBQKBNR b Qk f3 0 4");
+        assert_eq!(super::mirror_fen(&super::mirror_fen(fen)), fen);
+    }
+
+    #[test]
+    fn test_invalid_fen() {
+        let mut board = ChessBoard::new();
+        board.new_game();
+        assert!(board.new_game_from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP w KQkq - 0 1").is_err());
+        assert!(board.new_game_from_fen("rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").is_err());
+        assert!(board.new_game_from_fen("rnbqkbnr/ppppxppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").is_err());
+        assert!(board.new_game_from_fen("rnbq1bnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1").is_err());
+        assert!(board.new_game_from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1").is_err());
+        assert!(board.new_game_from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - a 1").is_err());
+        // A failed parse leaves the board untouched
+        assert_eq!(board.to_fen(), super::STARTFEN);
+    }
     
 }
\ No newline at end of file
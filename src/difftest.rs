@@ -0,0 +1,323 @@
+// This module is a differential testing harness for move generation.  It
+// plays random games and, in every position, compares the legal moves
+// found by the engine's move generator (generate_all_psuedo_legal_moves
+// followed by is_legal_move) against a slow but simple reference move
+// generator that works square by square on a mailbox copy of the board.
+// Every legal move is also made and unmade to validate the incremental
+// board state and Zobrist hash.  This is meant to catch rare edge cases
+// (en passant, castling through check, promotions, and pins) that fixed
+// perft positions might not reach.
+//
+// The harness is only compiled for tests with the "difftest" feature, since
+// it is slow:  cargo test --release --features difftest
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::chess_board;
+use crate::movegen;
+use crate::pieces;
+
+// Number of random games played from each starting position, and the
+// maximum length of each game in plies
+const DIFFTEST_GAMES: usize = 50;
+const DIFFTEST_MAX_PLIES: usize = 300;
+
+// Seed of the random number generator, so failures are reproducible
+const DIFFTEST_SEED: u64 = 0x746f706173;
+
+// Starting positions for the random games, chosen to have castling, en
+// passant, promotions, and pins available
+const DIFFTEST_POSITIONS: [&str; 6] = [
+    chess_board::STARTFEN,
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    "8/8/8/2k5/3pP3/8/8/4K2R b K e3 0 1",
+];
+
+// A mailbox board, holding the (color, piece) on each square
+type Mailbox = [Option<(usize, usize)>; 64];
+
+// Knight and king move offsets as (file, rank) deltas
+const KNIGHT_DELTAS: [(i32, i32); 8] = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+const KING_DELTAS: [(i32, i32); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+
+// Sliding directions as (file, rank) deltas
+const BISHOP_DIRECTIONS: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+const ROOK_DIRECTIONS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+// Get the square offset from a square by a (file, rank) delta, if it is
+// on the board
+fn offset(square: usize, (df, dr): (i32, i32)) -> Option<usize> {
+    let file = (square % 8) as i32 + df;
+    let rank = (square / 8) as i32 + dr;
+    if (0..8).contains(&file) && (0..8).contains(&rank) {
+        Some((rank * 8 + file) as usize)
+    } else {
+        None
+    }
+}
+
+// Check if a square is attacked by the given color, by looking outward
+// from the square for each type of attacker
+fn is_attacked(mailbox: &Mailbox, square: usize, by_color: usize) -> bool {
+    let pawn_rank_delta = if by_color == pieces::COLOR_WHITE {-1} else {1};
+    for df in [-1, 1] {
+        if let Some(s) = offset(square, (df, pawn_rank_delta)) {
+            if mailbox[s] == Some((by_color, pieces::PAWN)) {
+                return true;
+            }
+        }
+    }
+    for (deltas, piece) in [(KNIGHT_DELTAS, pieces::KNIGHT), (KING_DELTAS, pieces::KING)] {
+        if deltas.iter().filter_map(|d| offset(square, *d)).any(|s| mailbox[s] == Some((by_color, piece))) {
+            return true;
+        }
+    }
+    for (directions, piece) in [(BISHOP_DIRECTIONS, pieces::BISHOP), (ROOK_DIRECTIONS, pieces::ROOK)] {
+        for direction in directions {
+            let mut s = square;
+            while let Some(next) = offset(s, direction) {
+                s = next;
+                if let Some((color, p)) = mailbox[s] {
+                    if color == by_color && (p == piece || p == pieces::QUEEN) {
+                        return true;
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    false
+}
+
+// Make a move on a mailbox, including the rook move of a castle and the
+// pawn removed by an en passant capture
+fn make_mailbox_move(mailbox: &Mailbox, (start, end, promotion): (usize, usize, Option<usize>)) -> Mailbox {
+    let mut result = *mailbox;
+    let (color, piece) = mailbox[start].expect("No piece on the start square");
+    if piece == pieces::PAWN && start % 8 != end % 8 && mailbox[end].is_none() {
+        result[start / 8 * 8 + end % 8] = None;
+    }
+    if piece == pieces::KING && start.abs_diff(end) == 2 {
+        let (rook_start, rook_end) = if end > start {(end + 1, end - 1)} else {(end - 2, end + 1)};
+        result[rook_end] = result[rook_start].take();
+    }
+    result[start] = None;
+    result[end] = Some((color, promotion.unwrap_or(piece)));
+    result
+}
+
+// Generate the legal moves of a position as (start square, end square,
+// promotion piece), one square at a time
+fn reference_legal_moves(board: &chess_board::ChessBoard) -> Vec<(usize, usize, Option<usize>)> {
+    let mut mailbox: Mailbox = [None; 64];
+    for (square, cell) in mailbox.iter_mut().enumerate() {
+        *cell = board.get_color_and_piece_on_square(square);
+    }
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let opp_color = 1 - my_color;
+    let forward = if my_color == pieces::COLOR_WHITE {1} else {-1};
+    let home_rank = if my_color == pieces::COLOR_WHITE {0} else {7};
+    let mut candidates = Vec::new();
+    for square in 0..64 {
+        let piece = match mailbox[square] {
+            Some((color, piece)) if color == my_color => piece,
+            _ => continue,
+        };
+        let mut targets = Vec::new();
+        match piece {
+            pieces::PAWN => {
+                if let Some(s) = offset(square, (0, forward)) {
+                    if mailbox[s].is_none() {
+                        targets.push(s);
+                        let start_rank = if my_color == pieces::COLOR_WHITE {1} else {6};
+                        if square / 8 == start_rank {
+                            if let Some(s2) = offset(s, (0, forward)) {
+                                if mailbox[s2].is_none() {
+                                    targets.push(s2);
+                                }
+                            }
+                        }
+                    }
+                }
+                for df in [-1, 1] {
+                    if let Some(s) = offset(square, (df, forward)) {
+                        let is_capture = matches!(mailbox[s], Some((color, _)) if color == opp_color);
+                        if is_capture || board.en_passant_rights == Some(s) {
+                            targets.push(s);
+                        }
+                    }
+                }
+            },
+            pieces::KNIGHT | pieces::KING => {
+                let deltas = if piece == pieces::KNIGHT {KNIGHT_DELTAS} else {KING_DELTAS};
+                for s in deltas.iter().filter_map(|d| offset(square, *d)) {
+                    if !matches!(mailbox[s], Some((color, _)) if color == my_color) {
+                        targets.push(s);
+                    }
+                }
+            },
+            _ => {
+                let directions: Vec<(i32, i32)> = match piece {
+                    pieces::BISHOP => BISHOP_DIRECTIONS.to_vec(),
+                    pieces::ROOK => ROOK_DIRECTIONS.to_vec(),
+                    _ => BISHOP_DIRECTIONS.iter().chain(ROOK_DIRECTIONS.iter()).copied().collect(),
+                };
+                for direction in directions {
+                    let mut s = square;
+                    while let Some(next) = offset(s, direction) {
+                        s = next;
+                        match mailbox[s] {
+                            None => targets.push(s),
+                            Some((color, _)) => {
+                                if color == opp_color {
+                                    targets.push(s);
+                                }
+                                break;
+                            },
+                        }
+                    }
+                }
+            },
+        }
+        for end in targets {
+            if piece == pieces::PAWN && (end / 8 == 0 || end / 8 == 7) {
+                for promotion in [pieces::KNIGHT, pieces::BISHOP, pieces::ROOK, pieces::QUEEN] {
+                    candidates.push((square, end, Some(promotion)));
+                }
+            } else {
+                candidates.push((square, end, None));
+            }
+        }
+    }
+
+    // Castling requires the rights, empty squares between the king and
+    // rook, and the king not to be in, pass through, or land in check
+    let king_square = home_rank * 8 + 4;
+    let (ks_rights, qs_rights) = if my_color == pieces::COLOR_WHITE {
+        (board.white_ks_castling_rights, board.white_qs_castling_rights)
+    } else {
+        (board.black_ks_castling_rights, board.black_qs_castling_rights)
+    };
+    if mailbox[king_square] == Some((my_color, pieces::KING)) && !is_attacked(&mailbox, king_square, opp_color) {
+        if ks_rights && mailbox[king_square + 1].is_none() && mailbox[king_square + 2].is_none()
+            && !is_attacked(&mailbox, king_square + 1, opp_color) {
+            candidates.push((king_square, king_square + 2, None));
+        }
+        if qs_rights && mailbox[king_square - 1].is_none() && mailbox[king_square - 2].is_none() && mailbox[king_square - 3].is_none()
+            && !is_attacked(&mailbox, king_square - 1, opp_color) {
+            candidates.push((king_square, king_square - 2, None));
+        }
+    }
+
+    // Keep the moves that don't leave the king in check
+    candidates.retain(|m| {
+        let after = make_mailbox_move(&mailbox, *m);
+        let king = (0..64).find(|s| after[*s] == Some((my_color, pieces::KING))).expect("No king on the board");
+        !is_attacked(&after, king, opp_color)
+    });
+    candidates.sort();
+    candidates
+}
+
+// Get the legal moves of a position from the engine's move generator, in
+// the same form as the reference move generator
+fn engine_legal_moves(board: &mut chess_board::ChessBoard) -> Vec<(usize, usize, Option<usize>)> {
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let mut moves = movegen::MoveList::new();
+    movegen::generate_all_psuedo_legal_moves(board, my_color, false, &mut moves);
+    let mut legal: Vec<(usize, usize, Option<usize>)> = moves.iter()
+        .filter(|m| movegen::is_legal_move(board, m))
+        .map(|m| (m.start_square, m.end_square, m.promotion_piece))
+        .collect();
+    legal.sort();
+    legal
+}
+
+// Check that a board's incrementally updated state matches the state
+// computed from scratch, by loading its FEN into a new board
+fn check_board_state(board: &chess_board::ChessBoard, context: &str) {
+    let fen = board.to_fen();
+    let mut fresh = chess_board::ChessBoard::new();
+    fresh.new_game_from_fen(&fen).unwrap();
+    assert_eq!(board.zobrist_hash, fresh.zobrist_hash, "Zobrist hash mismatch {}: {}", context, fen);
+    assert_eq!(board.bb_side, fresh.bb_side, "Side bitboard mismatch {}: {}", context, fen);
+    assert_eq!(board.bb_occupied_squares, fresh.bb_occupied_squares, "Occupancy mismatch {}: {}", context, fen);
+    assert_eq!(board.bb_empty_squares, !fresh.bb_occupied_squares, "Empty squares mismatch {}: {}", context, fen);
+    assert_eq!((board.material, board.pst, board.phase_material), board.compute_eval_totals(), "Eval totals mismatch {}: {}", context, fen);
+}
+
+// Play random games from a position, comparing the legal moves against the
+// reference move generator and making and unmaking every legal move
+fn run_playouts(fen: &str, rng: &mut StdRng) {
+    for _ in 0..DIFFTEST_GAMES {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen(fen).unwrap();
+        for _ in 0..DIFFTEST_MAX_PLIES {
+            let fen_before = board.to_fen();
+            let hash_before = board.zobrist_hash;
+            let expected = reference_legal_moves(&board);
+            let found = engine_legal_moves(&mut board);
+            assert_eq!(found, expected, "Legal move mismatch in position {}", fen_before);
+            if found.is_empty() {
+                break;
+            }
+
+            // Validate make and unmake of every legal move, and that
+            // gives_check agrees with the position after the move
+            let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+            for &(start, end, promotion) in found.iter() {
+                let m = movegen::get_pseudo_legal_move(&board, start, end, promotion).unwrap();
+                let gives_check = movegen::gives_check(&board, &m);
+                board.make_move(start, end, promotion);
+                let context = format!("after {} in {}", movegen::convert_move_list_to_lan(&vec![(start as u8, end as u8, promotion)]).trim(), fen_before);
+                check_board_state(&board, &context);
+                assert_eq!(gives_check, movegen::is_king_in_check(&board, 1 - my_color), "gives_check mismatch {}", context);
+                board.unmake_move();
+                assert_eq!(board.to_fen(), fen_before, "Unmake did not restore the position {}", context);
+                assert_eq!(board.zobrist_hash, hash_before, "Unmake did not restore the hash {}", context);
+            }
+
+            // Play a random legal move, stopping at the fifty-move rule
+            let (start, end, promotion) = found[rng.gen_range(0..found.len())];
+            board.make_move(start, end, promotion);
+            if board.halfmove_clock >= 100 {
+                break;
+            }
+        }
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test the reference move generator against known perft(1) counts
+    #[test]
+    fn test_reference_legal_moves() {
+        let counts = [20, 48, 14, 6, 44, 8];
+        for (fen, count) in DIFFTEST_POSITIONS.iter().zip(counts) {
+            let mut board = chess_board::ChessBoard::new();
+            board.new_game_from_fen(fen).unwrap();
+            assert_eq!(reference_legal_moves(&board).len(), count, "Wrong move count in {}", fen);
+        }
+    }
+
+    // Compare the engine's move generator against the reference move
+    // generator in randomized games
+    #[test]
+    fn test_random_playouts() {
+        let mut rng = StdRng::seed_from_u64(DIFFTEST_SEED);
+        for fen in DIFFTEST_POSITIONS {
+            run_playouts(fen, &mut rng);
+        }
+    }
+
+}
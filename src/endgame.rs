@@ -0,0 +1,129 @@
+// This module implements endgame recognizers, which identify material
+// configurations that are known draws even though there is enough material
+// on the board to continue playing.  The static evaluation of such positions
+// would otherwise favor the side with more material, leading the engine to
+// trade into (or avoid) endgames it cannot win (or lose).
+
+use crate::chess_board;
+use crate::bitboard;
+use crate::pieces;
+
+// Returns the name of the recognized known-draw endgame, if the position
+// is one.  Note that the position may still contain tactics (such as a
+// skewer in KR vs KR), so this is meant to be used for static evaluation,
+// where quiescence search will still resolve any captures.
+pub fn recognize_draw(board: &chess_board::ChessBoard) -> Option<&'static str> {
+
+    // All recognized endgames have at most five pieces, so skip the more
+    // detailed checks for anything larger
+    if bitboard::pop_count(board.bb_occupied_squares) > 5 {
+        return None;
+    }
+    let has_pawns = board.bb_pieces[pieces::COLOR_WHITE][pieces::PAWN] | board.bb_pieces[pieces::COLOR_BLACK][pieces::PAWN] != 0;
+    for strong in 0..2 {
+        let weak = 1 - strong;
+        let strong_pieces = board.bb_side[strong] & !board.bb_pieces[strong][pieces::KING];
+        let weak_pieces = board.bb_side[weak] & !board.bb_pieces[weak][pieces::KING];
+
+        // KNN vs K, where mate cannot be forced
+        if weak_pieces == 0 && strong_pieces == board.bb_pieces[strong][pieces::KNIGHT] && bitboard::pop_count(strong_pieces) == 2 {
+            return Some("KNN vs K");
+        }
+
+        // KB and rook pawns vs K, where the bishop does not control the
+        // promotion square and the defending king reaches the corner
+        if weak_pieces == 0 && is_wrong_bishop_draw(board, strong) {
+            return Some("KBP vs K (wrong bishop)");
+        }
+    }
+    if has_pawns {
+        return None;
+    }
+
+    // Minor piece vs minor piece, or rook vs rook, with no pawns
+    let white_pieces = board.bb_side[pieces::COLOR_WHITE] & !board.bb_pieces[pieces::COLOR_WHITE][pieces::KING];
+    let black_pieces = board.bb_side[pieces::COLOR_BLACK] & !board.bb_pieces[pieces::COLOR_BLACK][pieces::KING];
+    if bitboard::pop_count(white_pieces) == 1 && bitboard::pop_count(black_pieces) == 1 {
+        let minors = [pieces::COLOR_WHITE, pieces::COLOR_BLACK].map(|color| {
+            board.bb_pieces[color][pieces::KNIGHT] | board.bb_pieces[color][pieces::BISHOP]
+        });
+        if white_pieces == minors[pieces::COLOR_WHITE] && black_pieces == minors[pieces::COLOR_BLACK] {
+            return Some("Km vs Km");
+        }
+        if white_pieces == board.bb_pieces[pieces::COLOR_WHITE][pieces::ROOK] && black_pieces == board.bb_pieces[pieces::COLOR_BLACK][pieces::ROOK] {
+            return Some("KR vs KR");
+        }
+    }
+    None
+}
+
+// Check if the strong side has only a bishop and pawns on a single rook
+// file, the bishop is of the opposite color to the promotion square, and
+// the weak side's king controls the promotion square.
+fn is_wrong_bishop_draw(board: &chess_board::ChessBoard, strong: usize) -> bool {
+    let pawns = board.bb_pieces[strong][pieces::PAWN];
+    let bishops = board.bb_pieces[strong][pieces::BISHOP];
+    if pawns == 0 || bitboard::pop_count(bishops) != 1 {
+        return false;
+    }
+    if board.bb_side[strong] != pawns | bishops | board.bb_pieces[strong][pieces::KING] {
+        return false;
+    }
+    let file = if pawns & !bitboard::BB_FILES[0] == 0 {
+        0
+    } else if pawns & !bitboard::BB_FILES[7] == 0 {
+        7
+    } else {
+        return false;
+    };
+    let promotion_square = if strong == pieces::COLOR_WHITE {56 + file} else {file};
+    let promotion_square_is_light = bitboard::to_bb(promotion_square) & bitboard::BB_LIGHT_SQUARES != 0;
+    let bishop_is_light = bishops & bitboard::BB_LIGHT_SQUARES != 0;
+    if promotion_square_is_light == bishop_is_light {
+        return false;
+    }
+    match bitboard::bit_scan_forward(board.bb_pieces[1-strong][pieces::KING]) {
+        Some(king_square) => bitboard::square_distance(king_square, promotion_square) <= 1,
+        None => false,
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test recognition of known draws
+    #[test]
+    fn test_recognize_draw() {
+        let draws = [
+            "8/8/4k3/8/8/3NN3/8/4K3 w - - 0 1",
+            "8/8/4k3/8/8/3nn3/8/4K3 b - - 0 1",
+            "7k/8/8/7P/8/8/4B3/4K3 w - - 0 1",
+            "8/8/8/8/p7/8/4b3/K3k3 b - - 0 1",
+            "8/8/4k3/3b4/8/3N4/8/4K3 w - - 0 1",
+            "8/8/4k3/3r4/8/3R4/8/4K3 w - - 0 1",
+        ];
+        let not_draws = [
+            "8/8/4k3/8/8/3NB3/8/4K3 w - - 0 1",
+            "7k/8/8/7P/8/8/3B4/4K3 w - - 0 1",
+            "8/8/4k3/7P/8/8/4B3/4K3 w - - 0 1",
+            "8/8/4k3/3q4/8/3R4/8/4K3 w - - 0 1",
+            "8/8/4k3/3r4/8/3R4/P7/4K3 w - - 0 1",
+        ];
+        let mut board = chess_board::ChessBoard::new();
+        for fen in draws.iter() {
+            board.new_game_from_fen(fen).unwrap();
+            assert!(recognize_draw(&board).is_some(), "{}", fen);
+        }
+        for fen in not_draws.iter() {
+            board.new_game_from_fen(fen).unwrap();
+            assert!(recognize_draw(&board).is_none(), "{}", fen);
+        }
+    }
+
+}
@@ -7,150 +7,327 @@
 use crate::chess_board;
 use crate::bitboard;
 use crate::pieces;
+use crate::endgame;
 
 // Bonuses and penalities, in centipawns, for various situations
-const TEMPO_BONUS: i32 = 28;
 const BISHOP_PAIR_BONUS: i32 = 25;
 const ISOLATED_PAWN_PENALTY: i32 = 25;
 const DOUBLE_PAWN_PENALTY: i32 = 30;
+const BACKWARD_PAWN_PENALTY: i32 = 15;
+const WEAK_PAWN_ON_HALF_OPEN_FILE_PENALTY: i32 = 10;
+const ROOK_ON_SEVENTH_BONUS: i32 = 20;
+const DOUBLED_ROOKS_ON_SEVENTH_BONUS: i32 = 20;
+const BAD_BISHOP_PENALTY: i32 = 4;
 
-// Check if the current Zobrist hash has been repeated twice before.
+// Bonus for the side to move, indexed by [middle game, end game]
+pub const TEMPO_BONUS: [i32; 2] = [28, 0];
+
+// Passed pawn bonuses and penalties, indexed by [middle game, end game]
+const BLOCKED_PASSED_PAWN_PENALTY: [i32; 2] = [4, 8];
+const ROOK_BEHIND_PASSED_PAWN_BONUS: [i32; 2] = [10, 25];
+const UNSTOPPABLE_PASSED_PAWN_BONUS: [i32; 2] = [0, 200];
+const CANDIDATE_PASSED_PAWN_BONUS: usize = 6;
+const BLOCKED_BISHOP_PENALTY: i32 = 12;
+
+// Count the appearances of the current Zobrist hash in the history, which
+// includes both the game history and the moves made during a search,
+// stopping once the count reaches the limit.
 // Note the hash will only be the same if its the same player's turn,
-// so we can skip every other element in the list
-pub fn is_draw_by_threefold_repitition(board: &chess_board::ChessBoard) -> bool {
+// so we can skip every other element in the list.  Positions before the
+// last capture or pawn move (which reset the halfmove clock) can never
+// be repeated, so only the last halfmove clock + 1 positions are scanned.
+fn count_appearances(board: &chess_board::ChessBoard, limit: usize) -> usize {
     let hash = board.zobrist_hash;
     let mut appearances = 0;
     let mut check = true;
-    for h in board.zobrist_history.iter().rev() {
+    for h in board.zobrist_history.iter().rev().take(board.halfmove_clock as usize + 1) {
         if check && hash == *h {
             // Note that the first iteration will always be an "appearance"
             appearances += 1;
         }
-        if appearances == 3 {
-            return true;
+        if appearances == limit {
+            break;
         }
         check = !check;
     }
-    false
+    appearances
 }
 
-// Check if there is not enough material to play on.
+// Check if the current Zobrist hash has been repeated twice before
+pub fn is_draw_by_threefold_repitition(board: &chess_board::ChessBoard) -> bool {
+    count_appearances(board, 3) == 3
+}
+
+// Check if the current Zobrist hash has been repeated at least once
+// before.  The search scores this as a draw: if repeating a position is
+// good for a player, they can repeat it again, so the side that is worse
+// off must avoid it anyway.
+pub fn is_repetition(board: &chess_board::ChessBoard) -> bool {
+    count_appearances(board, 2) == 2
+}
+
+// Check if there is not enough material for either side to checkmate by
+// any sequence of legal moves (a "dead position" under the FIDE rules).
+// This is the case for K vs K, K + minor piece vs K, and any number of
+// bishops on both sides that are all on the same color of square.  Other
+// positions, such as K + 2N vs K or KN vs KN, cannot be won by force but
+// a checkmate is still possible, so they are not claimed as a draw.
 pub fn is_draw_by_insufficient_material(board: &chess_board::ChessBoard) -> bool {
 
     // If there are any pawns, rooks, or queens, it is not a draw
-    if bitboard::pop_count(board.bb_pieces[pieces::COLOR_WHITE][pieces::PAWN] | board.bb_pieces[pieces::COLOR_BLACK][pieces::PAWN]) > 0 {
-        return false;
-    }
-    if bitboard::pop_count(board.bb_pieces[pieces::COLOR_WHITE][pieces::ROOK] | board.bb_pieces[pieces::COLOR_BLACK][pieces::ROOK]) > 0 {
-        return false;
-    }
-    if bitboard::pop_count(board.bb_pieces[pieces::COLOR_WHITE][pieces::QUEEN] | board.bb_pieces[pieces::COLOR_BLACK][pieces::QUEEN]) > 0 {
-        return false;
+    for color in 0..2 {
+        if board.bb_pieces[color][pieces::PAWN] | board.bb_pieces[color][pieces::ROOK] | board.bb_pieces[color][pieces::QUEEN] != 0 {
+            return false;
+        }
     }
 
-    // If the total of white's knights + bishops count is more than 1, it's not a draw
-    if bitboard::pop_count(board.bb_pieces[pieces::COLOR_WHITE][pieces::KNIGHT] | board.bb_pieces[pieces::COLOR_WHITE][pieces::BISHOP]) > 1 {
-        return false;
+    // A single minor piece cannot checkmate
+    let knights = board.bb_pieces[pieces::COLOR_WHITE][pieces::KNIGHT] | board.bb_pieces[pieces::COLOR_BLACK][pieces::KNIGHT];
+    let bishops = board.bb_pieces[pieces::COLOR_WHITE][pieces::BISHOP] | board.bb_pieces[pieces::COLOR_BLACK][pieces::BISHOP];
+    if bitboard::pop_count(knights | bishops) <= 1 {
+        return true;
     }
 
-    // If the total of black's knights + bishops count is more than 1, it's not a draw
-    if bitboard::pop_count(board.bb_pieces[pieces::COLOR_BLACK][pieces::KNIGHT] | board.bb_pieces[pieces::COLOR_BLACK][pieces::BISHOP]) > 1 {
-        return false;
+    // Bishops that are all on the same color of square cannot checkmate
+    knights == 0 && (bishops & bitboard::BB_LIGHT_SQUARES == 0 || bishops & bitboard::BB_DARK_SQUARES == 0)
+
+}
+
+// Returns a bitboard of the files adjacent to the given file
+fn get_neighbor_files(file: usize) -> u64 {
+    let mut neighbor_files_bb: u64 = 0;
+    if file > 0 {
+        neighbor_files_bb |= bitboard::BB_FILES[file-1];
+    }
+    if file < 7 {
+        neighbor_files_bb |= bitboard::BB_FILES[file+1];
     }
+    neighbor_files_bb
+}
+
+// Evaluation terms, used to index an evaluation trace
+pub const TERM_TEMPO: usize = 0;
+pub const TERM_MATERIAL: usize = 1;
+pub const TERM_PST: usize = 2;
+pub const TERM_BISHOP_PAIR: usize = 3;
+pub const TERM_PAWN_STRUCTURE: usize = 4;
+pub const TERM_PASSED_PAWNS: usize = 5;
+pub const TERM_ROOKS: usize = 6;
+pub const TERM_BAD_BISHOPS: usize = 7;
+pub const NUM_EVAL_TERMS: usize = 8;
+pub const EVAL_TERM_NAMES: [&str; NUM_EVAL_TERMS] = ["Tempo", "Material", "PST", "Bishop pair", "Pawn structure", "Passed pawns", "Rooks", "Bad bishops"];
 
-    // Every other case is a draw
-    true
+// Which evaluation terms are enabled, indexed by term.  Terms can be turned
+// off at runtime to measure their impact without recompiling.
+pub type EvalTerms = [bool; NUM_EVAL_TERMS];
+pub const ALL_EVAL_TERMS: EvalTerms = [true; NUM_EVAL_TERMS];
 
+// A breakdown of a static evaluation into its terms.  Each term is
+// indexed by [term][color] and is from that color's point of view.
+pub struct EvalTrace {
+    pub terms: [[i32; 2]; NUM_EVAL_TERMS],
 }
 
 // Returns the game board evaluation, specific to whether this is an end
 // game or not, from the point of view of the
 // player whose turn it is.  Returned value is in centipawns.
 // Note that this assumes that the game is not over.
-pub fn static_evaluation_phase(board: &chess_board::ChessBoard, is_end_game: bool) -> i32 {
-    
-    // Running totals of white and black evaluation
-    let mut totals: [i32; 2] = [0; 2];
+// Only the enabled terms are added to the evaluation.
+// If a trace is passed in, it will be filled in with the contribution of
+// each evaluation term (whether or not it is enabled).
+pub fn static_evaluation_phase(board: &chess_board::ChessBoard, is_end_game: bool, enabled: &EvalTerms, trace: Option<&mut EvalTrace>) -> i32 {
 
-    // Add a tempo bonus for current player if not in the end game
-    if !is_end_game {
-        if board.whites_turn {
-            totals[pieces::COLOR_WHITE] += TEMPO_BONUS;
-        } else {
-            totals[pieces::COLOR_BLACK] += TEMPO_BONUS;
-        }
+    // Running totals of white and black evaluation, per term
+    let mut terms: [[i32; 2]; NUM_EVAL_TERMS] = [[0; 2]; NUM_EVAL_TERMS];
+
+    // Add a tempo bonus for the current player
+    let phase_index = if is_end_game {1} else {0};
+    if board.whites_turn {
+        terms[TERM_TEMPO][pieces::COLOR_WHITE] += TEMPO_BONUS[phase_index];
+    } else {
+        terms[TERM_TEMPO][pieces::COLOR_BLACK] += TEMPO_BONUS[phase_index];
     }
 
-    // Material evaluation, which is the sum of the piece value and its PST
+    // Material evaluation, which is the sum of the piece value and its PST.
+    // These are tracked incrementally by the board.
     for color in 0..2 {
-        for (piece, bb) in board.bb_pieces[color].iter().enumerate() {
-            for square in bitboard::occupied_squares(*bb) {
-                
-                // The PST's are from white's perspective, so we have to flip
-                // the look up for black.  Performing a bitwise "xor 56" on
-                // the square will "flip" the square to the other side.
-                if is_end_game {
-                    if color == pieces::COLOR_WHITE {
-                        totals[color] += pieces::PIECE_VALUES_EG[piece] + pieces::PST_END_GAME[piece][square];
-                    } else {
-                        totals[color] += pieces::PIECE_VALUES_EG[piece] + pieces::PST_END_GAME[piece][square ^ 56];
-                    }
-                } else {
-                    if color == pieces::COLOR_WHITE {
-                        totals[color] += pieces::PIECE_VALUES_MG[piece] + pieces::PST_MIDDLE_GAME[piece][square];
-                    } else {
-                        totals[color] += pieces::PIECE_VALUES_MG[piece] + pieces::PST_MIDDLE_GAME[piece][square ^ 56];
-                    }
-                }
-            }
-        }
+        terms[TERM_MATERIAL][color] = board.material[color][phase_index];
+        terms[TERM_PST][color] = board.pst[color][phase_index];
     }
 
     // Bishop pair bonus
     if bitboard::pop_count(board.bb_pieces[pieces::COLOR_WHITE][pieces::BISHOP]) >= 2 {
-        totals[pieces::COLOR_WHITE] += BISHOP_PAIR_BONUS;
+        terms[TERM_BISHOP_PAIR][pieces::COLOR_WHITE] += BISHOP_PAIR_BONUS;
     }
     if bitboard::pop_count(board.bb_pieces[pieces::COLOR_BLACK][pieces::BISHOP]) >= 2 {
-        totals[pieces::COLOR_BLACK] += BISHOP_PAIR_BONUS;
+        terms[TERM_BISHOP_PAIR][pieces::COLOR_BLACK] += BISHOP_PAIR_BONUS;
     }
 
     // Pawn structure penalties and bonuses
     for color in 0..2 {
         for file in 0..7 {
             let pawns_in_file = bitboard::pop_count(board.bb_pieces[color][pieces::PAWN] & bitboard::BB_FILES[file]) as i32;
-            let mut neighbor_files_bb: u64 = 0;
-            if file > 0 {
-                neighbor_files_bb |= bitboard::BB_FILES[file-1];
-            }
-            if file < 7 {
-                neighbor_files_bb |= bitboard::BB_FILES[file+1];
-            }
+            let neighbor_files_bb = get_neighbor_files(file);
 
             // Isolated pawn penalty
             if neighbor_files_bb & board.bb_pieces[color][pieces::PAWN] == 0 {
-                totals[color] -= ISOLATED_PAWN_PENALTY * pawns_in_file;
+                terms[TERM_PAWN_STRUCTURE][color] -= ISOLATED_PAWN_PENALTY * pawns_in_file;
             }
 
             // Double pawn penalty
             if pawns_in_file > 1 {
-                totals[color] -= DOUBLE_PAWN_PENALTY * (pawns_in_file - 1);
+                terms[TERM_PAWN_STRUCTURE][color] -= DOUBLE_PAWN_PENALTY * (pawns_in_file - 1);
             }
+        }
+    }
 
+    // Backward pawn penalty for pawns that cannot be defended by pawns (no
+    // friendly pawns on adjacent files beside or behind them) and whose stop
+    // square is attacked by an enemy pawn.  Backward and isolated pawns are
+    // further penalized if they are on a half-open file (no enemy pawns on
+    // the file), where they are exposed to attack by enemy rooks and queens.
+    for (color, pawn_term) in terms[TERM_PAWN_STRUCTURE].iter_mut().enumerate() {
+        let pawns = board.bb_pieces[color][pieces::PAWN];
+        let enemy_pawns = board.bb_pieces[1-color][pieces::PAWN];
+        for square in bitboard::occupied_squares(pawns) {
+            let file = square % 8;
+            let neighbor_files_bb = get_neighbor_files(file);
+            let (stop_square, support_squares) = if color == pieces::COLOR_WHITE {
+                (square + 8, bitboard::BB_PAWN_FRONT_SPAN[pieces::COLOR_BLACK][square + 8] & neighbor_files_bb)
+            } else {
+                (square - 8, bitboard::BB_PAWN_FRONT_SPAN[pieces::COLOR_WHITE][square - 8] & neighbor_files_bb)
+            };
+            let is_isolated = neighbor_files_bb & pawns == 0;
+            let is_backward = !is_isolated && support_squares & pawns == 0 &&
+                bitboard::BB_PAWN_ATTACKS[color][stop_square] & enemy_pawns != 0;
+            if is_backward {
+                *pawn_term -= BACKWARD_PAWN_PENALTY;
+            }
+            if (is_backward || is_isolated) && bitboard::BB_FILES[file] & enemy_pawns == 0 {
+                *pawn_term -= WEAK_PAWN_ON_HALF_OPEN_FILE_PENALTY;
+            }
         }
     }
 
     // Passed pawn bonuses based on rank.  Bonuses are 16*row where row is 1
-    // for the starting position (regardless of color).
-    for color in 0..2 {
+    // for the starting position (regardless of color).  The bonus is
+    // adjusted if the pawn is blocked, if it is supported by a rook behind
+    // it, and (in the end game) if the enemy king cannot catch it.
+    for (color, passed_term) in terms[TERM_PASSED_PAWNS].iter_mut().enumerate() {
+        let enemy_king_square = bitboard::bit_scan_forward(board.bb_pieces[1-color][pieces::KING]);
+        let enemy_has_only_pawns = board.bb_side[1-color] == board.bb_pieces[1-color][pieces::PAWN] | board.bb_pieces[1-color][pieces::KING];
         for square in bitboard::occupied_squares(board.bb_pieces[color][pieces::PAWN]) {
-            if bitboard::BB_PAWN_FRONT_SPAN[color][square] & board.bb_pieces[1-color][pieces::PAWN] == 0 {
-                // This is a passed pawn
-                let row = if color == pieces::COLOR_WHITE {square / 8} else {7 - (square / 8)};
-                totals[color] += (16 * row) as i32;
+            let row = if color == pieces::COLOR_WHITE {square / 8} else {7 - (square / 8)};
+            let file_bb = bitboard::BB_FILES[square % 8];
+            let path = bitboard::BB_PAWN_FRONT_SPAN[color][square] & file_bb;
+            let stop_square = if color == pieces::COLOR_WHITE {square + 8} else {square - 8};
+            let stoppers = bitboard::BB_PAWN_FRONT_SPAN[color][square] & board.bb_pieces[1-color][pieces::PAWN];
+            if stoppers != 0 {
+                // This is not a passed pawn, but it is a candidate passed
+                // pawn if no enemy pawn is directly in front of it and it
+                // has at least as many friendly pawns beside or behind it on
+                // adjacent files (supporters) as enemy pawns in front of it
+                // on adjacent files (stoppers).  Such a pawn can become
+                // passed by advancing and exchanging, so it gets a reduced
+                // bonus.
+                if path & stoppers == 0 {
+                    let supporters = bitboard::BB_PAWN_FRONT_SPAN[1-color][stop_square] & get_neighbor_files(square % 8) & board.bb_pieces[color][pieces::PAWN];
+                    if bitboard::pop_count(supporters) >= bitboard::pop_count(stoppers) {
+                        *passed_term += (CANDIDATE_PASSED_PAWN_BONUS * row) as i32;
+                    }
+                }
+                continue;
+            }
+
+            // This is a passed pawn
+            *passed_term += (16 * row) as i32;
+
+            // Blocked passed pawn penalty
+            if bitboard::to_bb(stop_square) & board.bb_occupied_squares != 0 {
+                *passed_term -= BLOCKED_PASSED_PAWN_PENALTY[phase_index] * row as i32;
+            }
+
+            // Rook behind the passed pawn bonus
+            let behind = bitboard::BB_PAWN_FRONT_SPAN[1-color][square] & file_bb;
+            for rook_square in bitboard::occupied_squares(board.bb_pieces[color][pieces::ROOK] & behind) {
+                if bitboard::BB_BETWEEN[rook_square][square] & board.bb_occupied_squares == 0 {
+                    *passed_term += ROOK_BEHIND_PASSED_PAWN_BONUS[phase_index];
+                }
+            }
+
+            // Unstoppable passed pawn bonus, using the "rule of the square".
+            // If the enemy has only pawns left and its king cannot reach the
+            // promotion square before the pawn (accounting for the pawn's
+            // double move and whose turn it is), the pawn will promote.
+            if let Some(king_square) = enemy_king_square {
+                if enemy_has_only_pawns && path & board.bb_occupied_squares == 0 {
+                    let promotion_square = if color == pieces::COLOR_WHITE {56 + square % 8} else {square % 8};
+                    let pawn_moves = (7 - row).min(5);
+                    let enemy_to_move = board.whites_turn == (color == pieces::COLOR_BLACK);
+                    let king_moves = bitboard::square_distance(king_square, promotion_square) - if enemy_to_move {1} else {0};
+                    if king_moves > pawn_moves {
+                        *passed_term += UNSTOPPABLE_PASSED_PAWN_BONUS[phase_index];
+                    }
+                }
             }
         }
     }
 
+    // Rook on the seventh rank bonus, when the enemy king is confined to
+    // its back rank or there are enemy pawns to attack on the seventh
+    // rank.  Rooks doubled on the seventh rank get an additional bonus.
+    for (color, rook_term) in terms[TERM_ROOKS].iter_mut().enumerate() {
+        let (seventh_rank, eighth_rank) = if color == pieces::COLOR_WHITE {
+            (bitboard::BB_RANKS[6], bitboard::BB_RANKS[7])
+        } else {
+            (bitboard::BB_RANKS[1], bitboard::BB_RANKS[0])
+        };
+        if board.bb_pieces[1-color][pieces::KING] & eighth_rank != 0 || board.bb_pieces[1-color][pieces::PAWN] & seventh_rank != 0 {
+            let rooks_on_seventh = bitboard::pop_count(board.bb_pieces[color][pieces::ROOK] & seventh_rank) as i32;
+            *rook_term += ROOK_ON_SEVENTH_BONUS * rooks_on_seventh;
+            if rooks_on_seventh > 1 {
+                *rook_term += DOUBLED_ROOKS_ON_SEVENTH_BONUS;
+            }
+        }
+    }
+
+    // Bad bishop penalty for each of a bishop's own pawns on its color
+    // complex, since those pawns restrict the bishop's mobility.  Central
+    // pawns that are fixed (blocked by an enemy pawn) on the bishop's color
+    // complex cannot easily move out of the way, so the bishop is further
+    // penalized as blocked.
+    let central_files = bitboard::BB_FILES[3] | bitboard::BB_FILES[4];
+    for (color, bishop_term) in terms[TERM_BAD_BISHOPS].iter_mut().enumerate() {
+        let pawns = board.bb_pieces[color][pieces::PAWN];
+        let enemy_pawns = board.bb_pieces[1-color][pieces::PAWN];
+        let blocked_pawns = if color == pieces::COLOR_WHITE {
+            bitboard::south_one(enemy_pawns) & pawns
+        } else {
+            bitboard::north_one(enemy_pawns) & pawns
+        };
+        let fixed_central_pawns = blocked_pawns & central_files;
+        for square in bitboard::occupied_squares(board.bb_pieces[color][pieces::BISHOP]) {
+            let color_complex = if bitboard::to_bb(square) & bitboard::BB_LIGHT_SQUARES != 0 {
+                bitboard::BB_LIGHT_SQUARES
+            } else {
+                bitboard::BB_DARK_SQUARES
+            };
+            *bishop_term -= BAD_BISHOP_PENALTY * bitboard::pop_count(pawns & color_complex) as i32;
+            *bishop_term -= BLOCKED_BISHOP_PENALTY * bitboard::pop_count(fixed_central_pawns & color_complex) as i32;
+        }
+    }
+
+    // Sum up the enabled terms, storing all of them in the trace if
+    // requested
+    let mut totals: [i32; 2] = [0; 2];
+    for (term, _) in terms.iter().zip(enabled).filter(|(_, &e)| e) {
+        totals[pieces::COLOR_WHITE] += term[pieces::COLOR_WHITE];
+        totals[pieces::COLOR_BLACK] += term[pieces::COLOR_BLACK];
+    }
+    if let Some(t) = trace {
+        t.terms = terms;
+    }
+
     // Return evaluation from the current player's perspective
     if board.whites_turn {
         totals[pieces::COLOR_WHITE] - totals[pieces::COLOR_BLACK]
@@ -161,11 +338,103 @@ pub fn static_evaluation_phase(board: &chess_board::ChessBoard, is_end_game: boo
 
 // Returns the phased game board evaluation from the point of view of the
 // player whose turn it is.  Returned value is in centipawns.
-pub fn static_evaluation(board: &chess_board::ChessBoard) -> i32 {
-    let middle_game_eval = static_evaluation_phase(board, false);
-    let end_game_eval = static_evaluation_phase(board, true);
+// Known draws (recognized by the endgame module) are evaluated as a draw.
+// Only the enabled terms are evaluated.
+// When built with the "evalcheck" feature, every evaluation is checked
+// against the evaluation of the color-flipped mirror of the position.
+pub fn static_evaluation(board: &chess_board::ChessBoard, enabled: &EvalTerms) -> i32 {
+    let eval = static_evaluation_unchecked(board, enabled);
+    #[cfg(feature = "evalcheck")]
+    if let Err(e) = check_eval_symmetry(board) {
+        panic!("{}", e);
+    }
+    eval
+}
+
+// Returns the evaluation without the symmetry check
+fn static_evaluation_unchecked(board: &chess_board::ChessBoard, enabled: &EvalTerms) -> i32 {
+    if endgame::recognize_draw(board).is_some() {
+        return 0;
+    }
+    let middle_game_eval = static_evaluation_phase(board, false, enabled, None);
+    let end_game_eval = static_evaluation_phase(board, true, enabled, None);
+    get_tapered_value(board, middle_game_eval, end_game_eval)
+}
+
+// Check that the position evaluates the same as its color-flipped mirror,
+// returning the evaluation, or an error describing the asymmetry.  An
+// evaluation term that treats the colors differently will show up here.
+pub fn check_eval_symmetry(board: &chess_board::ChessBoard) -> Result<i32, String> {
+    let fen = board.to_fen();
+    let mirror = chess_board::mirror_fen(&fen);
+    let mut mirror_board = chess_board::ChessBoard::new();
+    mirror_board.new_game_from_fen(&mirror)?;
+    let eval = static_evaluation_unchecked(board, &ALL_EVAL_TERMS);
+    let mirror_eval = static_evaluation_unchecked(&mirror_board, &ALL_EVAL_TERMS);
+    if eval != mirror_eval {
+        return Err(format!("Asymmetric evaluation: {} for {} but {} for its mirror {}", eval, fen, mirror_eval, mirror));
+    }
+    Ok(eval)
+}
+
+// Blends a middle game and end game value based on the game phase, in the
+// same way as the static evaluation
+pub fn get_tapered_value(board: &chess_board::ChessBoard, middle_game_value: i32, end_game_value: i32) -> i32 {
     let phase = get_phase(board);
-    ((middle_game_eval * (256 - phase)) + (end_game_eval * phase)) / 256
+    ((middle_game_value * (256 - phase)) + (end_game_value * phase)) / 256
+}
+
+// Prints the static evaluation broken down by term, for both colors and
+// both game phases, followed by the phased evaluation.
+pub fn print_eval_trace(board: &chess_board::ChessBoard) {
+    let mut mg_trace = EvalTrace {terms: [[0; 2]; NUM_EVAL_TERMS]};
+    let mut eg_trace = EvalTrace {terms: [[0; 2]; NUM_EVAL_TERMS]};
+    static_evaluation_phase(board, false, &ALL_EVAL_TERMS, Some(&mut mg_trace));
+    static_evaluation_phase(board, true, &ALL_EVAL_TERMS, Some(&mut eg_trace));
+    let phase = get_phase(board);
+    println!("      Term      |     White     |     Black     |     Total");
+    println!("                |   MG     EG   |   MG     EG   |   MG     EG");
+    println!("----------------+---------------+---------------+--------------");
+    let mut totals: [[i32; 2]; 3] = [[0; 2]; 3];
+    for (term, name) in EVAL_TERM_NAMES.iter().enumerate() {
+        let mg = mg_trace.terms[term];
+        let eg = eg_trace.terms[term];
+        let row = [[mg[pieces::COLOR_WHITE], eg[pieces::COLOR_WHITE]],
+                   [mg[pieces::COLOR_BLACK], eg[pieces::COLOR_BLACK]],
+                   [mg[pieces::COLOR_WHITE] - mg[pieces::COLOR_BLACK], eg[pieces::COLOR_WHITE] - eg[pieces::COLOR_BLACK]]];
+        println!(" {:<14} | {:>5}  {:>5}  | {:>5}  {:>5}  | {:>5}  {:>5}", name, row[0][0], row[0][1], row[1][0], row[1][1], row[2][0], row[2][1]);
+        for (total, r) in totals.iter_mut().zip(row.iter()) {
+            total[0] += r[0];
+            total[1] += r[1];
+        }
+    }
+    println!("----------------+---------------+---------------+--------------");
+    println!(" {:<14} | {:>5}  {:>5}  | {:>5}  {:>5}  | {:>5}  {:>5}", "Total", totals[0][0], totals[0][1], totals[1][0], totals[1][1], totals[2][0], totals[2][1]);
+    println!();
+    println!("Phase: {} (0 is middle game, 256 is end game), from phase material {} of {}", phase, board.phase_material, pieces::TOTAL_PHASE);
+    let npm = [pieces::COLOR_WHITE, pieces::COLOR_BLACK].map(|color| get_non_pawn_material(board, color));
+    println!("Non-pawn material (MG/EG): white {}/{}, black {}/{}", npm[0][0], npm[0][1], npm[1][0], npm[1][1]);
+    let recognized_draw = endgame::recognize_draw(board);
+    println!("Endgame recognizer: {}", recognized_draw.unwrap_or("none"));
+    match recognized_draw {
+        Some(name) => println!("Evaluation: 0 (known draw: {})", name),
+        None => {
+            let eval = get_tapered_value(board, totals[2][0], totals[2][1]);
+            println!("Evaluation: {} (white side)", eval);
+        },
+    }
+}
+
+// Returns the value of a color's pieces other than pawns and the king, in
+// the middle game and end game
+fn get_non_pawn_material(board: &chess_board::ChessBoard, color: usize) -> [i32; 2] {
+    let mut material = [0; 2];
+    for piece in [pieces::KNIGHT, pieces::BISHOP, pieces::ROOK, pieces::QUEEN] {
+        let count = bitboard::pop_count(board.bb_pieces[color][piece]) as i32;
+        material[0] += count * pieces::PIECE_VALUES_MG[piece];
+        material[1] += count * pieces::PIECE_VALUES_EG[piece];
+    }
+    material
 }
 
 // Returns the game phase for tapered evaluation.  This blends the middle game
@@ -173,17 +442,107 @@ pub fn static_evaluation(board: &chess_board::ChessBoard) -> i32 {
 // in evaluation between the middle and end game.
 // See https://www.chessprogramming.org/Tapered_Eval
 fn get_phase(board: &chess_board::ChessBoard) -> i32 {
-    let knight_phase = 1;
-    let bishop_phase = 1;
-    let rook_phase = 2;
-    let queen_phase = 4;
-    let total_phase = 24; // 4*knight_phase + 4*bishop_phase + 4*rook_phase + 2*queen_phase
-    let mut phase: i32 = total_phase;
-    for color in 0..2 {
-        phase -= (bitboard::pop_count(board.bb_pieces[color][pieces::KNIGHT]) * knight_phase) as i32;
-        phase -= (bitboard::pop_count(board.bb_pieces[color][pieces::BISHOP]) * bishop_phase) as i32;
-        phase -= (bitboard::pop_count(board.bb_pieces[color][pieces::ROOK]) * rook_phase) as i32;
-        phase -= (bitboard::pop_count(board.bb_pieces[color][pieces::QUEEN]) * queen_phase) as i32;
+    let phase = (pieces::TOTAL_PHASE - board.phase_material).max(0);
+    (phase * 256 + (pieces::TOTAL_PHASE / 2)) / pieces::TOTAL_PHASE
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test that positions evaluate the same as their mirrors
+    #[test]
+    fn test_eval_symmetry() {
+        let fens = [
+            chess_board::STARTFEN,
+            "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "4k3/8/8/8/8/8/4P3/4K3 b - - 0 1",
+        ];
+        let mut board = chess_board::ChessBoard::new();
+        for fen in fens.iter() {
+            board.new_game_from_fen(fen).unwrap();
+            assert!(check_eval_symmetry(&board).is_ok(), "{}", fen);
+        }
+    }
+
+    // Test that disabled terms are left out of the evaluation
+    #[test]
+    fn test_disabled_eval_terms() {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen("4k3/pppp4/8/8/8/8/PPPPPPPP/2B1KB2 w - - 0 1").unwrap();
+        let eval = static_evaluation(&board, &ALL_EVAL_TERMS);
+        let mut enabled = ALL_EVAL_TERMS;
+        enabled[TERM_BISHOP_PAIR] = false;
+        assert_eq!(static_evaluation(&board, &enabled), eval - BISHOP_PAIR_BONUS);
+        let mut enabled = [false; NUM_EVAL_TERMS];
+        assert_eq!(static_evaluation(&board, &enabled), 0);
+        enabled[TERM_TEMPO] = true;
+        assert!(static_evaluation(&board, &enabled) > 0);
+    }
+
+    // Test the non-pawn material of each side
+    #[test]
+    fn test_get_non_pawn_material() {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen("4k3/pppp4/2n5/8/8/8/PPPPPPPP/R2QK3 w - - 0 1").unwrap();
+        let white = pieces::PIECE_VALUES_MG[pieces::ROOK] + pieces::PIECE_VALUES_MG[pieces::QUEEN];
+        assert_eq!(get_non_pawn_material(&board, pieces::COLOR_WHITE)[0], white);
+        assert_eq!(get_non_pawn_material(&board, pieces::COLOR_BLACK), [pieces::PIECE_VALUES_MG[pieces::KNIGHT], pieces::PIECE_VALUES_EG[pieces::KNIGHT]]);
+        board.new_game_from_fen("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").unwrap();
+        assert_eq!(get_non_pawn_material(&board, pieces::COLOR_WHITE), [0, 0]);
+    }
+
+    // Test detection of repetitions, including of the starting position
+    #[test]
+    fn test_repetition() {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game();
+        assert!(!is_repetition(&board));
+        for i in 0..2 {
+            board.make_move(6, 21, None);
+            board.make_move(62, 45, None);
+            assert_eq!(is_repetition(&board), i == 1);
+            board.make_move(21, 6, None);
+            board.make_move(45, 62, None);
+            assert!(is_repetition(&board));
+            assert_eq!(is_draw_by_threefold_repitition(&board), i == 1);
+        }
     }
-    (phase * 256 + (total_phase / 2)) / total_phase
-}
\ No newline at end of file
+
+    // Test detection of draws by insufficient material
+    #[test]
+    fn test_insufficient_material() {
+        let draws = [
+            "8/8/4k3/8/8/8/8/4K3 w - - 0 1",
+            "8/8/4k3/8/8/3N4/8/4K3 w - - 0 1",
+            "8/8/4k3/8/8/8/3b4/4K3 w - - 0 1",
+            "8/8/4k3/4b3/8/8/3B4/4K3 w - - 0 1",
+            "8/2b5/4k3/8/8/8/3B1B2/4K3 w - - 0 1",
+        ];
+        let not_draws = [
+            "8/8/4k3/8/8/3NN3/8/4K3 w - - 0 1",
+            "8/8/4k3/3n4/8/3N4/8/4K3 w - - 0 1",
+            "8/8/4k3/3b4/8/3N4/8/4K3 w - - 0 1",
+            "8/8/4k3/3b4/8/8/3B4/4K3 w - - 0 1",
+            "8/8/4k3/8/8/8/3P4/4K3 w - - 0 1",
+        ];
+        let mut board = chess_board::ChessBoard::new();
+        for fen in draws.iter() {
+            board.new_game_from_fen(fen).unwrap();
+            assert!(is_draw_by_insufficient_material(&board), "{}", fen);
+        }
+        for fen in not_draws.iter() {
+            board.new_game_from_fen(fen).unwrap();
+            assert!(!is_draw_by_insufficient_material(&board), "{}", fen);
+        }
+    }
+
+}
@@ -0,0 +1,121 @@
+// This module implements a persistent learning file, which gives the
+// engine memory across games.  After each search, the root position's
+// Zobrist key is recorded along with the depth searched, the score, and
+// the best move.  When the same position is searched again to a shallower
+// depth, the deeper learned result is used instead.
+//
+// The file is plain text with one position per line, in the form
+// "<key in hex> <depth> <score> <move>", and new results are appended.
+// Since keys come from the fixed Zobrist seed, the file is only valid for
+// builds that use the same seed.
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use crate::movegen;
+
+// The result of an earlier search of a position
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LearnedPosition {
+
+    // Depth the position was searched to
+    pub depth: u8,
+
+    // Score from the perspective of the player to move
+    pub value: i32,
+
+    // The best move represented as (start square, end square, promotion piece)
+    pub best_move: (u8, u8, Option<usize>),
+
+}
+
+// The learned positions, along with the file they are stored in
+pub struct LearningTable {
+    path: String,
+    positions: HashMap<u64, LearnedPosition>,
+}
+
+// Parse a line of the learning file
+fn parse_line(line: &str) -> Result<(u64, LearnedPosition), String> {
+    let err = || format!("Invalid learning file line: {}", line);
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.len() != 4 {
+        return Err(err());
+    }
+    let key = u64::from_str_radix(tokens[0], 16).map_err(|_| err())?;
+    let depth = tokens[1].parse::<u8>().map_err(|_| err())?;
+    let value = tokens[2].parse::<i32>().map_err(|_| err())?;
+    let (start_square, end_square, promotion_piece) = movegen::convert_moves_str_into_list(tokens[3]).map_err(|_| err())?[0];
+    Ok((key, LearnedPosition {depth, value, best_move: (start_square as u8, end_square as u8, promotion_piece)}))
+}
+
+impl LearningTable {
+
+    // Load the learning file at the given path.  A file that does not
+    // exist yet is treated as empty, and is created on the first record.
+    pub fn load(path: &str) -> Result<LearningTable, String> {
+        let mut positions: HashMap<u64, LearnedPosition> = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(path) {
+            for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                let (key, learned) = parse_line(line)?;
+                if positions.get(&key).is_none_or(|p| learned.depth >= p.depth) {
+                    positions.insert(key, learned);
+                }
+            }
+        }
+        Ok(LearningTable {path: path.to_string(), positions})
+    }
+
+    // Get the learned result for a position, if any
+    pub fn probe(&self, key: u64) -> Option<LearnedPosition> {
+        self.positions.get(&key).copied()
+    }
+
+    // Record the result of a search of a position, unless it was already
+    // searched deeper, and append it to the learning file
+    pub fn record(&mut self, key: u64, learned: LearnedPosition) -> Result<(), String> {
+        if self.positions.get(&key).is_some_and(|p| p.depth > learned.depth) {
+            return Ok(());
+        }
+        self.positions.insert(key, learned);
+        let lan = movegen::convert_move_list_to_lan(&vec![learned.best_move]);
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| format!("Cannot open learning file {}: {}", self.path, e))?;
+        writeln!(file, "{:016x} {} {} {}", key, learned.depth, learned.value, lan.trim())
+            .map_err(|e| format!("Cannot write to learning file {}: {}", self.path, e))
+    }
+
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test recording positions and loading them back from the file
+    #[test]
+    fn test_learning_table() {
+        let path = std::env::temp_dir().join(format!("topas_learning_test_{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let mut table = LearningTable::load(path).unwrap();
+        assert_eq!(table.probe(42), None);
+        let deep = LearnedPosition {depth: 12, value: 35, best_move: (12, 28, None)};
+        let shallow = LearnedPosition {depth: 8, value: -20, best_move: (52, 60, Some(4))};
+        table.record(42, deep).unwrap();
+        table.record(42, shallow).unwrap();
+        table.record(7, shallow).unwrap();
+        assert_eq!(table.probe(42), Some(deep));
+        let table = LearningTable::load(path).unwrap();
+        assert_eq!(table.probe(42), Some(deep));
+        assert_eq!(table.probe(7), Some(shallow));
+        fs::remove_file(path).unwrap();
+        assert!(parse_line("zz 1 2 e2e4").is_err());
+    }
+
+}
@@ -0,0 +1,28 @@
+//! The Topas engine core as a library, for driving the engine from other
+//! programs (such as a GUI, a web back end, or a test harness) without going
+//! through the UCI loop on standard input and output.
+//!
+//! The main entry point is search::SearchEngine.  An engine is constructed
+//! with SearchEngine::with_platform, which takes the clock and output sink
+//! to use (see the platform module), and a position is analyzed with
+//! SearchEngine::analyze, which returns the search result to the caller.
+//! The command line program (UCI loop, analysis server, and tools such as
+//! bench and match) is built on top of this library.
+
+pub mod chess_board;
+pub mod zobrist;
+pub mod pieces;
+mod bitboard;
+pub mod movegen;
+#[cfg(all(test, feature = "difftest"))]
+mod difftest;
+#[cfg(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2"))]
+mod pext;
+pub mod evaluate;
+mod endgame;
+mod aligned_table;
+pub mod search;
+mod learning;
+pub mod strength;
+pub mod pgn;
+pub mod platform;
@@ -1,20 +1,167 @@
 //! Welcome to Topas by Sam Nelson!
-//! 
-//! This is the entry point into the Topas engine.  Control is
-//! immediately passed to the Universal Chess Interface (UCI)
-//! handling loop.
-
-mod chess_board;
-mod zobrist;
-mod pieces;
-mod bitboard;
-mod movegen;
-mod evaluate;
-mod search;
+//!
+//! This is the entry point into the Topas engine.  Unless a subcommand
+//! is given on the command line, control is immediately passed to the
+//! Universal Chess Interface (UCI) handling loop.  The engine core itself
+//! is in the topas library (see lib.rs).
+
+mod bench;
+mod tactics;
+mod selfplay;
+mod sprt;
+mod tournament;
+mod server;
 mod uci;
 
+use std::env;
+use std::collections::HashMap;
+use topas::chess_board;
+
+// Command line usage
+const USAGE: &str = "Usage: topas [--hash <MB>] [--threads <N>] [command]
+Commands:
+  (none)                                            Run the UCI loop
+  bench [depth | nodes <N>]                         Run the bench
+  perft <depth> [fen]                               Run perft on a position
+  analyze [--fen <fen>] [--depth N | --movetime ms] Analyze a position
+  match <rounds> <tc> <engine> <engine> [engine...] Run a round-robin match
+  serve [port]                                      Run the analysis server";
+
+// Flags that take a value
+const FLAGS: [&str; 5] = ["hash", "threads", "fen", "depth", "movetime"];
+
+// Split the command line arguments into positional arguments and flags of
+// the form "--<name> <value>"
+fn parse_args(args: &[String]) -> Result<(Vec<String>, HashMap<String, String>), String> {
+    let mut positional = Vec::new();
+    let mut flags = HashMap::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(name) = arg.strip_prefix("--") {
+            if !FLAGS.contains(&name) {
+                return Err(format!("Unknown flag: {}", arg));
+            }
+            match iter.next() {
+                Some(value) => flags.insert(name.to_string(), value.clone()),
+                None => return Err(format!("Missing value for {}", arg)),
+            };
+        } else {
+            positional.push(arg.clone());
+        }
+    }
+    Ok((positional, flags))
+}
+
+// Build the engine commands to run for a command line subcommand, which
+// are run in the engine thread as if they were sent over UCI
+fn get_commands(positional: &[String], flags: &HashMap<String, String>) -> Result<Vec<String>, String> {
+    let mut commands = Vec::new();
+    if let Some(hash) = flags.get("hash") {
+        commands.push(format!("setoption name Hash value {}", hash));
+    }
+    match positional[0].as_str() {
+        "bench" => {
+            commands.push(format!("bench {}", positional[1..].join(" ")));
+        },
+        "perft" => {
+            let depth = positional.get(1).ok_or("Missing perft depth")?;
+            let fen = positional[2..].join(" ");
+            if fen.is_empty() {
+                commands.push(String::from("position startpos"));
+            } else {
+                chess_board::ChessBoard::new().new_game_from_fen(&fen)?;
+                commands.push(format!("position fen {}", fen));
+            }
+            match flags.get("hash") {
+                Some(hash) => commands.push(format!("perft {} hash {}", depth, hash)),
+                None => commands.push(format!("perft {}", depth)),
+            }
+        },
+        "analyze" => {
+            match flags.get("fen") {
+                Some(fen) => {
+                    chess_board::ChessBoard::new().new_game_from_fen(fen)?;
+                    commands.push(format!("position fen {}", fen));
+                },
+                None => commands.push(String::from("position startpos")),
+            }
+            match (flags.get("depth"), flags.get("movetime")) {
+                (Some(depth), None) => commands.push(format!("go depth {}", depth)),
+                (None, Some(movetime)) => commands.push(format!("go movetime {}", movetime)),
+                _ => return Err(String::from("Analyze requires one of --depth or --movetime")),
+            }
+        },
+        "match" => {
+            commands.push(positional.join(" "));
+        },
+        e => return Err(format!("Unknown command: {}", e)),
+    }
+    Ok(commands)
+}
+
 fn main() {
     println!("Topas {} by Sam Nelson", env!("CARGO_PKG_VERSION"));
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (positional, flags) = match parse_args(&args) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", USAGE);
+            return;
+        },
+    };
+
+    // Topas searches with a single thread
+    if let Some(threads) = flags.get("threads") {
+        match threads.parse::<u32>() {
+            Ok(1) => {},
+            Ok(n) if n > 1 => println!("info string Topas searches with a single thread; ignoring --threads {}", n),
+            _ => {
+                println!("Invalid number of threads");
+                return;
+            },
+        }
+    }
+
+    // "topas serve [port]" runs the analysis server
+    if positional.first().map(|x| x.as_str()) == Some("serve") {
+        let hash_mb = match flags.get("hash").map(|x| x.parse::<u64>()) {
+            None => None,
+            Some(Ok(d)) if (1..=131072).contains(&d) => Some(d),
+            _ => {
+                println!("Invalid hash size");
+                return;
+            },
+        };
+        match positional.get(1).map(|x| x.parse::<u16>()) {
+            None => server::run_server(server::DEFAULT_SERVER_PORT, hash_mb),
+            Some(Ok(p)) => server::run_server(p, hash_mb),
+            Some(Err(_)) => println!("Invalid port"),
+        }
+        return;
+    }
+
+    // With no command, run the UCI loop
     let mut uci_main = uci::UCI::new();
-    uci_main.main_loop();
+    if positional.is_empty() {
+        if let Some(hash) = flags.get("hash") {
+            uci_main.send_command(&format!("setoption name Hash value {}", hash));
+        }
+        uci_main.main_loop();
+        return;
+    }
+
+    // Otherwise, run the command in the engine thread and exit
+    match get_commands(&positional, &flags) {
+        Ok(commands) => {
+            for command in commands.iter() {
+                uci_main.send_command(command);
+            }
+        },
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", USAGE);
+        },
+    }
+    uci_main.finish();
 }
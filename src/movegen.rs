@@ -1,11 +1,21 @@
 //! This module contains functions related to piece movement and
 //! move legality checking.
 
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::slice;
 use crate::bitboard;
 use crate::chess_board;
 use crate::pieces;
+#[cfg(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2"))]
+use crate::pext;
 
-#[derive(Debug, PartialEq, PartialOrd)]
+// The maximum number of moves that can be stored in a move list.  The
+// most legal moves known in any position is 218, so this leaves plenty
+// of room for psuedo-legal moves.
+pub const MAX_MOVES: usize = 256;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Default)]
 pub struct ChessMove {
     
     // Starting square of the piece being moved
@@ -26,6 +36,68 @@ pub struct ChessMove {
     // Whether or not this is an en passant capture
     pub is_en_passant: bool,
 
+    // The piece a pawn is promoted to, or None if this is not a promotion
+    pub promotion_piece: Option<usize>,
+
+}
+
+// Pieces a pawn may be promoted to, in the order they are generated
+const PROMOTION_PIECES: [usize; 4] = [pieces::QUEEN, pieces::ROOK, pieces::BISHOP, pieces::KNIGHT];
+
+// A fixed-size list of moves.  This is stack-allocated by the caller so
+// that move generation at every node of a search does not have to
+// allocate on the heap.  It dereferences to a slice of the moves that
+// have been added.  The moves are left uninitialized until they are added,
+// as a list is created at every node and only a few of its entries are
+// ever used.
+pub struct MoveList {
+    moves: [MaybeUninit<ChessMove>; MAX_MOVES],
+    len: usize,
+}
+
+impl MoveList {
+
+    // Construct a new, empty MoveList
+    pub fn new() -> MoveList {
+        MoveList {
+            moves: [MaybeUninit::uninit(); MAX_MOVES],
+            len: 0,
+        }
+    }
+
+    // Add a move to the end of the list
+    pub fn push(&mut self, m: ChessMove) {
+        self.moves[self.len].write(m);
+        self.len += 1;
+    }
+
+    // Remove all moves from the list
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+}
+
+impl Default for MoveList {
+    fn default() -> MoveList {
+        MoveList::new()
+    }
+}
+
+impl Deref for MoveList {
+    type Target = [ChessMove];
+    fn deref(&self) -> &[ChessMove] {
+        // SAFETY: the first len moves have been written, and
+        // MaybeUninit<ChessMove> has the same layout as ChessMove
+        unsafe { slice::from_raw_parts(self.moves.as_ptr() as *const ChessMove, self.len) }
+    }
+}
+
+impl DerefMut for MoveList {
+    fn deref_mut(&mut self) -> &mut [ChessMove] {
+        // SAFETY: as in deref, and the list is borrowed mutably
+        unsafe { slice::from_raw_parts_mut(self.moves.as_mut_ptr() as *mut ChessMove, self.len) }
+    }
 }
 
 // Converts a list of moves to long algebraic notation.  This notation is
@@ -52,110 +124,95 @@ pub fn convert_move_list_to_lan(moves: &Vec<(u8, u8, Option<usize>)>) -> String
 
 // Converts a UCI-style move list (long algebraic notation without
 // piece names) into a vector of (start square, end square, optional promotion
-// piece) tuples.
-pub fn convert_moves_str_into_list(move_str: &str) -> Vec<(usize, usize, Option<usize>)> {
+// piece) tuples.  An error is returned if any move is malformed.
+pub fn convert_moves_str_into_list(move_str: &str) -> Result<Vec<(usize, usize, Option<usize>)>, String> {
     let mut moves = Vec::new();
     for m in move_str.split_whitespace() {
-        let start_square = convert_square_str_into_id(&m[0..2]);
-        let end_square = convert_square_str_into_id(&m[2..4]);
+        if !m.is_ascii() || m.len() < 4 || m.len() > 5 {
+            return Err(format!("Invalid move string - {}", m));
+        }
+        let start_square = convert_square_str_into_id(&m[0..2])?;
+        let end_square = convert_square_str_into_id(&m[2..4])?;
         let mut promotion = None;
         // Check for a 5th character which indicates the promotion piece
-        if m.len() >= 5 {
-            let c = m.chars().nth(4).unwrap();
+        if let Some(c) = m.chars().nth(4) {
             // Note that UCI promotions are always lower case
-            let piece = pieces::PIECE_ID_TO_CHAR[pieces::COLOR_BLACK].iter().position(|&r| r == c).unwrap();
-            promotion = Some(piece);
+            match pieces::PIECE_ID_TO_CHAR[pieces::COLOR_BLACK].iter().position(|&r| r == c) {
+                Some(piece) if piece != pieces::PAWN && piece != pieces::KING => promotion = Some(piece),
+                _ => return Err(format!("Invalid move string - promotion - {}", m)),
+            }
         }
         moves.push((start_square, end_square, promotion));
     }
-    moves
+    Ok(moves)
 }
 
 // Get all diagonal attacks (bottom left to top right) from a starting
 // location.  This is used for bishop and queen movement.
-// Portion 0 is the entire ray
-// Portion 1 is the southern part of the ray (mapping west in first rank)
-// Portion 2 is the northern part of the ray (mapping east in first rank)
-pub fn get_diagonal_attacks_bb(occ: u64, square: usize, portion: i32) -> u64 {
+pub fn get_diagonal_attacks_bb(occ: u64, square: usize) -> u64 {
     let tmp_occ = (bitboard::BB_DIAGONAL_MASK[square] & occ).wrapping_mul(bitboard::BB_FILES[0]).wrapping_shr(56);
-    let first_rank_bb;
-    if portion == 1 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_WEST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else if portion == 2 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_EAST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else {
-        first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64
-    }
+    let first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64;
     bitboard::BB_DIAGONAL_MASK[square] & bitboard::BB_FILES[0].wrapping_mul(first_rank_bb)
 }
 
 // Get all anti-diagonal attacks (top left to bottom right) from a starting
 // location.  This is used for bishop and queen movement.
-// Portion 0 is the entire ray
-// Portion 1 is the northern part of the ray (mapping west in first rank)
-// Portion 2 is the southern part of the ray (mapping east in first rank)
-pub fn get_antidiagonal_attacks_bb(occ: u64, square: usize, portion: i32) -> u64 {
+pub fn get_antidiagonal_attacks_bb(occ: u64, square: usize) -> u64 {
     let tmp_occ = (bitboard::BB_ANTIDIAGONAL_MASK[square] & occ).wrapping_mul(bitboard::BB_FILES[0]).wrapping_shr(56);
-    let first_rank_bb;
-    if portion == 1 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_WEST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else if portion == 2 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_EAST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else {
-        first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64
-    }
+    let first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64;
     bitboard::BB_ANTIDIAGONAL_MASK[square] & bitboard::BB_FILES[0].wrapping_mul(first_rank_bb)
 }
 
 // Get all rank attacks from a starting location.  This is used for
 // rook and queen movement.
-// Portion 0 is the entire ray
-// Portion 1 is the western part of the ray (mapping west in first rank)
-// Portion 2 is the eastern part of the ray (mapping east in first rank)
-pub fn get_rank_attacks_bb(occ: u64, square: usize, portion: i32) -> u64 {
+pub fn get_rank_attacks_bb(occ: u64, square: usize) -> u64 {
     let tmp_occ = (bitboard::BB_RANK_MASK[square] & occ).wrapping_mul(bitboard::BB_FILES[0]).wrapping_shr(56);
-    let first_rank_bb;
-    if portion == 1 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_WEST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else if portion == 2 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_EAST_ATTACKS[square & 7][tmp_occ as usize] as u64
-    } else {
-        first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64
-    }
+    let first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[square & 7][tmp_occ as usize] as u64;
     bitboard::BB_RANK_MASK[square] & bitboard::BB_FILES[0].wrapping_mul(first_rank_bb)
 }
 
 // Get all file attacks from a starting location.  This is used for rook
 // and queen movement.
-// Portion 0 is the entire ray
-// Portion 1 is the northern part of the ray (mapping west in first rank)
-// Portion 2 is the southern part of the ray (mapping east in first rank)
-pub fn get_file_attacks_bb(occ: u64, square: usize, portion: i32) -> u64 {
+pub fn get_file_attacks_bb(occ: u64, square: usize) -> u64 {
     let tmp_square = square & 7;
     let mut tmp_occ = bitboard::BB_FILES[0] & occ.wrapping_shr(tmp_square as u32);
     tmp_occ = bitboard::BB_MAIN_DIAGONAL.wrapping_mul(tmp_occ).wrapping_shr(56);
     let index = (square ^ 56).wrapping_shr(3);
-    let first_rank_bb;
-    if portion == 1 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_WEST_ATTACKS[index][tmp_occ as usize] as u64
-    } else if portion == 2 {
-        first_rank_bb = bitboard::BB_FIRST_RANK_EAST_ATTACKS[index][tmp_occ as usize] as u64
-    } else {
-        first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[index][tmp_occ as usize] as u64
-    }
+    let first_rank_bb = bitboard::BB_FIRST_RANK_ATTACKS[index][tmp_occ as usize] as u64;
     tmp_occ = bitboard::BB_MAIN_DIAGONAL.wrapping_mul(first_rank_bb);
     (bitboard::BB_FILES[7] & tmp_occ).wrapping_shr((tmp_square ^ 7) as u32)
 }
 
-// Generate all psuedo-legal moves for a given color.
+// Get all bishop attacks from a starting location.  This uses PEXT
+// lookups if available, and Kindergarten bitboards otherwise.
+#[cfg(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2"))]
+pub fn get_bishop_attacks_bb(occ: u64, square: usize) -> u64 {
+    pext::get_bishop_attacks_bb(occ, square)
+}
+#[cfg(not(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2")))]
+pub fn get_bishop_attacks_bb(occ: u64, square: usize) -> u64 {
+    get_diagonal_attacks_bb(occ, square) | get_antidiagonal_attacks_bb(occ, square)
+}
+
+// Get all rook attacks from a starting location.  This uses PEXT
+// lookups if available, and Kindergarten bitboards otherwise.
+#[cfg(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2"))]
+pub fn get_rook_attacks_bb(occ: u64, square: usize) -> u64 {
+    pext::get_rook_attacks_bb(occ, square)
+}
+#[cfg(not(all(feature = "pext", target_arch = "x86_64", target_feature = "bmi2")))]
+pub fn get_rook_attacks_bb(occ: u64, square: usize) -> u64 {
+    get_rank_attacks_bb(occ, square) | get_file_attacks_bb(occ, square)
+}
+
+// Generate all psuedo-legal moves for a given color into the passed in
+// move list, which is cleared first.
 // A psuedo-legal move is an otherwise legal move that has not yet been
 // checked to determine if it leaves the player's king in check.
 // If "captures_only" is true, then we'll only generate capture moves.
-pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color: usize, captures_only: bool) -> Vec<ChessMove> {
-    
-    // Keep track of capture moves and quiet moves seperately
-    let mut capture_moves = Vec::new();
-    let mut quiet_moves = Vec::new();
+pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color: usize, captures_only: bool, moves: &mut MoveList) {
+
+    moves.clear();
 
     // Get color of opponent
     let opp_color = 1 - my_color;
@@ -171,9 +228,6 @@ pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color
     for (piece, bb) in board.bb_pieces[my_color].iter().enumerate() {
         for square in bitboard::occupied_squares(*bb) {
             
-            // Store state regarding an en passant capture
-            let mut is_en_passant = false;
-
             // Get quiet (i.e., non-capture) and capture move bitboards for the piece
             let mut quite_move_bb = 0;
             let capture_move_bb;
@@ -181,9 +235,6 @@ pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color
                 if !captures_only {
                     quite_move_bb = get_pawn_push_targets_bb(my_color, board.bb_empty_squares, square);
                 }
-                if bitboard::BB_PAWN_ATTACKS[my_color][square] & en_passant_bb != 0 {
-                    is_en_passant = true;
-                }
                 capture_move_bb = bitboard::BB_PAWN_ATTACKS[my_color][square] & (board.bb_side[opp_color] | en_passant_bb);
             } else if piece == pieces::KNIGHT {
                 if !captures_only {
@@ -191,20 +242,20 @@ pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color
                 }
                 capture_move_bb = bitboard::BB_KNIGHT_ATTACKS[square] & board.bb_side[opp_color];
             } else if piece == pieces::BISHOP {
-                let bishop_attacks = get_diagonal_attacks_bb(board.bb_occupied_squares, square, 0) | get_antidiagonal_attacks_bb(board.bb_occupied_squares, square, 0);
+                let bishop_attacks = get_bishop_attacks_bb(board.bb_occupied_squares, square);
                 if !captures_only {
                     quite_move_bb = bishop_attacks & board.bb_empty_squares;
                 }
                 capture_move_bb = bishop_attacks & board.bb_side[opp_color];
             } else if piece == pieces::ROOK {
-                let rook_attacks = get_rank_attacks_bb(board.bb_occupied_squares, square, 0) | get_file_attacks_bb(board.bb_occupied_squares, square, 0);
+                let rook_attacks = get_rook_attacks_bb(board.bb_occupied_squares, square);
                 if !captures_only {
                     quite_move_bb = rook_attacks & board.bb_empty_squares;
                 }
                 capture_move_bb = rook_attacks & board.bb_side[opp_color];
             } else if piece == pieces::QUEEN {
-                let bishop_attacks = get_diagonal_attacks_bb(board.bb_occupied_squares, square, 0) | get_antidiagonal_attacks_bb(board.bb_occupied_squares, square, 0);
-                let rook_attacks = get_rank_attacks_bb(board.bb_occupied_squares, square, 0) | get_file_attacks_bb(board.bb_occupied_squares, square, 0);
+                let bishop_attacks = get_bishop_attacks_bb(board.bb_occupied_squares, square);
+                let rook_attacks = get_rook_attacks_bb(board.bb_occupied_squares, square);
                 let queen_attacks = bishop_attacks | rook_attacks;
                 if !captures_only {
                     quite_move_bb = queen_attacks & board.bb_empty_squares;
@@ -223,52 +274,140 @@ pub fn generate_all_psuedo_legal_moves(board: &chess_board::ChessBoard, my_color
             // First get non-capture moves
             if !captures_only {
                 for m in bitboard::occupied_squares(quite_move_bb) {
-                    let cmove = ChessMove {
-                        start_square: square,
-                        end_square: m,
-                        piece,
-                        captured_piece: None,
-                        priority: 0,
-                        is_en_passant: false,
-                    };
-                    quiet_moves.push(cmove);
+                    push_moves(moves, square, m, piece, None, false);
                 }
             }
 
             // Next get capture moves
             for m in bitboard::occupied_squares(capture_move_bb) {
                 // Figure out the piece that is being captured
+                let is_en_passant = piece == pieces::PAWN && board.en_passant_rights == Some(m);
                 let cap = get_opponents_captured_piece(&board.bb_pieces[opp_color], m, is_en_passant);
-                let cmove = ChessMove {
-                    start_square: square,
-                    end_square: m,
-                    piece,
-                    captured_piece: Some(cap),
-                    priority: 0,
-                    is_en_passant,
-                };
-                capture_moves.push(cmove);
+                push_moves(moves, square, m, piece, Some(cap), is_en_passant);
             }
 
         }
     }
 
-    // Order capture moves first (by appending quiet moves to the end)
-    // This will get re-sorted anyway, but may make the re-sort faster.
-    if !captures_only {
-        capture_moves.append(&mut quiet_moves);
+}
+
+// Get the full move for a move given as (start square, end square, promotion
+// piece), if it is pseudo-legal for the side to move.  This is used to
+// validate moves that did not come from move generation, such as moves
+// stored in the transposition table, which may belong to a different
+// position after a hash collision.
+pub fn get_pseudo_legal_move(board: &chess_board::ChessBoard, start_square: usize, end_square: usize, promotion_piece: Option<usize>) -> Option<ChessMove> {
+    if start_square >= 64 || end_square >= 64 {
+        return None;
+    }
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let opp_color = 1 - my_color;
+    let piece = (0..6).find(|p| board.bb_pieces[my_color][*p] & bitboard::to_bb(start_square) != 0)?;
+
+    // Get the squares the piece can move to, as in move generation
+    let en_passant_bb = board.en_passant_rights.map_or(0, bitboard::to_bb);
+    let not_own = !board.bb_side[my_color];
+    let target_bb = match piece {
+        pieces::PAWN => get_pawn_push_targets_bb(my_color, board.bb_empty_squares, start_square) | (bitboard::BB_PAWN_ATTACKS[my_color][start_square] & (board.bb_side[opp_color] | en_passant_bb)),
+        pieces::KNIGHT => bitboard::BB_KNIGHT_ATTACKS[start_square] & not_own,
+        pieces::BISHOP => get_bishop_attacks_bb(board.bb_occupied_squares, start_square) & not_own,
+        pieces::ROOK => get_rook_attacks_bb(board.bb_occupied_squares, start_square) & not_own,
+        pieces::QUEEN => (get_bishop_attacks_bb(board.bb_occupied_squares, start_square) | get_rook_attacks_bb(board.bb_occupied_squares, start_square)) & not_own,
+        _ => (bitboard::BB_KING_ATTACKS[start_square] & not_own) | get_castling_king_targets_bb(board, my_color, board.bb_occupied_squares),
+    };
+    if target_bb & bitboard::to_bb(end_square) == 0 {
+        return None;
     }
-    capture_moves
 
+    // A promotion piece must be given exactly when a pawn reaches the last rank
+    let is_promotion = piece == pieces::PAWN && (end_square / 8 == 0 || end_square / 8 == 7);
+    match promotion_piece {
+        Some(p) if !is_promotion || !PROMOTION_PIECES.contains(&p) => return None,
+        None if is_promotion => return None,
+        _ => {},
+    }
+    let is_en_passant = piece == pieces::PAWN && board.en_passant_rights == Some(end_square);
+    let mut captured_piece = None;
+    if is_en_passant || board.bb_side[opp_color] & bitboard::to_bb(end_square) != 0 {
+        captured_piece = Some(get_opponents_captured_piece(&board.bb_pieces[opp_color], end_square, is_en_passant));
+    }
+    Some(ChessMove {
+        start_square,
+        end_square,
+        piece,
+        captured_piece,
+        priority: 0,
+        is_en_passant,
+        promotion_piece,
+    })
 }
 
-// Check whether or not the king of the passed in color is in check
-pub fn is_king_in_check(board: &chess_board::ChessBoard, king_color: usize) -> bool {
-    let king_square = match bitboard::bit_scan_forward(board.bb_pieces[king_color][pieces::KING]) {
+// Add a move to the move list.  If this is a pawn move to the last rank,
+// one move is added for each possible promotion piece.
+fn push_moves(moves: &mut MoveList, start_square: usize, end_square: usize, piece: usize, captured_piece: Option<usize>, is_en_passant: bool) {
+    if piece == pieces::PAWN && (end_square / 8 == 0 || end_square / 8 == 7) {
+        for promotion_piece in PROMOTION_PIECES {
+            moves.push(ChessMove {
+                start_square,
+                end_square,
+                piece,
+                captured_piece,
+                priority: 0,
+                is_en_passant,
+                promotion_piece: Some(promotion_piece),
+            });
+        }
+    } else {
+        moves.push(ChessMove {
+            start_square,
+            end_square,
+            piece,
+            captured_piece,
+            priority: 0,
+            is_en_passant,
+            promotion_piece: None,
+        });
+    }
+}
+
+// Get the square of the king of the passed in color
+fn get_king_square(board: &chess_board::ChessBoard, king_color: usize) -> usize {
+    match bitboard::bit_scan_forward(board.bb_pieces[king_color][pieces::KING]) {
         Some(e) => e,
         None => panic!("Cannot find king on bitboard"),
-    };
-    is_square_attacked_by_side(&board, king_square, 1 - king_color)
+    }
+}
+
+// Get a bitboard of the opponent pieces giving check to the king of the
+// passed in color
+pub fn checkers(board: &chess_board::ChessBoard, king_color: usize) -> u64 {
+    let king_square = get_king_square(board, king_color);
+    attackers_to(board, king_square, board.bb_occupied_squares) & board.bb_side[1 - king_color]
+}
+
+// Get a bitboard of the pieces of the passed in color that are pinned to
+// their king.  Opponent sliders that would attack the king if only the
+// opponent pieces were on the board (x-rays) are potential pinners; a piece
+// is pinned if it is the only piece between such a slider and the king.
+pub fn pinned_pieces(board: &chess_board::ChessBoard, king_color: usize) -> u64 {
+    let king_square = get_king_square(board, king_color);
+    let opp = &board.bb_pieces[1 - king_color];
+    let opp_occ = board.bb_side[1 - king_color];
+    let snipers = (get_bishop_attacks_bb(opp_occ, king_square) & (opp[pieces::BISHOP] | opp[pieces::QUEEN])) |
+                  (get_rook_attacks_bb(opp_occ, king_square) & (opp[pieces::ROOK] | opp[pieces::QUEEN]));
+    let mut pinned = 0;
+    for sniper_square in bitboard::occupied_squares(snipers) {
+        let between = bitboard::BB_BETWEEN[sniper_square][king_square] & board.bb_occupied_squares;
+        if between.count_ones() == 1 {
+            pinned |= between & board.bb_side[king_color];
+        }
+    }
+    pinned
+}
+
+// Check whether or not the king of the passed in color is in check
+pub fn is_king_in_check(board: &chess_board::ChessBoard, king_color: usize) -> bool {
+    checkers(board, king_color) != 0
 }
 
 // Checks if a psuedo-legal move is legal.  It is legal if the king is
@@ -285,13 +424,91 @@ pub fn is_legal_move(board: &mut chess_board::ChessBoard, m: &ChessMove) -> bool
             return false;
         }
     }
+    // En passant captures remove two pawns from the board, so they are
+    // checked separately rather than with the pin test below
+    if m.is_en_passant {
+        return is_legal_en_passant(board, m);
+    }
+    // If we are not in check, a non-king, non-en passant move is legal as
+    // long as the piece is not pinned, or it stays on the line of the pin.
+    if m.piece != pieces::KING && !m.is_en_passant && !is_king_in_check(board, my_color) {
+        let pinned = pinned_pieces(board, my_color);
+        if pinned & bitboard::to_bb(m.start_square) == 0 {
+            return true;
+        }
+        let king_square = get_king_square(board, my_color);
+        return bitboard::BB_LINE[m.start_square][king_square] & bitboard::to_bb(m.end_square) != 0;
+    }
     // Ensure the king is not in check after the move is made
-    board.make_move(m.start_square, m.end_square, None);
+    board.make_move(m.start_square, m.end_square, m.promotion_piece);
     let keepit = !is_king_in_check(board, my_color);
     board.unmake_move();
     keepit
 }
 
+// Checks if a psuedo-legal en passant capture is legal, without making the
+// move.  Both the capturing and the captured pawn leave their squares, so
+// the capture can expose the king along the rank the pawns shared (e.g.,
+// 8/8/8/KPp4r/8/8/8/7k w - c6), which the single-piece pin test misses.
+// The capture is legal if no opponent piece other than the captured pawn
+// attacks the king given the occupancy after the capture.
+fn is_legal_en_passant(board: &chess_board::ChessBoard, m: &ChessMove) -> bool {
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let king_square = get_king_square(board, my_color);
+    let captured_bb = bitboard::to_bb(m.start_square / 8 * 8 + m.end_square % 8);
+    let occ = (board.bb_occupied_squares ^ bitboard::to_bb(m.start_square) ^ captured_bb) | bitboard::to_bb(m.end_square);
+    attackers_to(board, king_square, occ) & board.bb_side[1 - my_color] & !captured_bb == 0
+}
+
+// Checks if a psuedo-legal move gives check to the opponent king, without
+// making the move.  This looks for direct checks by the moved piece (or the
+// rook, if castling) and discovered checks by sliders uncovered by the move.
+pub fn gives_check(board: &chess_board::ChessBoard, m: &ChessMove) -> bool {
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let king_square = get_king_square(board, 1 - my_color);
+    let start_bb = bitboard::to_bb(m.start_square);
+    let end_bb = bitboard::to_bb(m.end_square);
+
+    // Compute the occupancy after the move, along with where our sliders
+    // will be located
+    let mine = &board.bb_pieces[my_color];
+    let mut bishops_queens = (mine[pieces::BISHOP] | mine[pieces::QUEEN]) & !start_bb;
+    let mut rooks_queens = (mine[pieces::ROOK] | mine[pieces::QUEEN]) & !start_bb;
+    let mut occ = (board.bb_occupied_squares & !start_bb) | end_bb;
+    let piece = m.promotion_piece.unwrap_or(m.piece);
+    match piece {
+        pieces::BISHOP => bishops_queens |= end_bb,
+        pieces::ROOK => rooks_queens |= end_bb,
+        pieces::QUEEN => {
+            bishops_queens |= end_bb;
+            rooks_queens |= end_bb;
+        },
+        _ => (),
+    }
+    if m.is_en_passant {
+        let captured_square = if m.start_square < m.end_square {m.end_square - 8} else {m.end_square + 8};
+        occ &= !bitboard::to_bb(captured_square);
+    }
+    if m.piece == pieces::KING && m.start_square.abs_diff(m.end_square) == 2 {
+        let (rook_start, rook_end) = if m.end_square > m.start_square {(m.start_square + 3, m.start_square + 1)} else {(m.start_square - 4, m.start_square - 1)};
+        occ = (occ & !bitboard::to_bb(rook_start)) | bitboard::to_bb(rook_end);
+        rooks_queens = (rooks_queens & !bitboard::to_bb(rook_start)) | bitboard::to_bb(rook_end);
+    }
+
+    // Direct checks by non-sliding pieces
+    let direct = match piece {
+        pieces::PAWN => bitboard::BB_PAWN_ATTACKS[my_color][m.end_square],
+        pieces::KNIGHT => bitboard::BB_KNIGHT_ATTACKS[m.end_square],
+        _ => 0,
+    };
+    if direct & bitboard::to_bb(king_square) != 0 {
+        return true;
+    }
+
+    // Direct and discovered checks by sliding pieces
+    (get_bishop_attacks_bb(occ, king_square) & bishops_queens) | (get_rook_attacks_bb(occ, king_square) & rooks_queens) != 0
+}
+
 // Get any pawn push moves for a color from a starting location
 fn get_pawn_push_targets_bb(color: usize, empty: u64, square: usize) -> u64 {
     let pawn_bb = bitboard::to_bb(square);
@@ -338,7 +555,7 @@ fn get_castling_king_targets_bb(board: &chess_board::ChessBoard, color: usize, o
 }
 
 // Determine the opponent's piece that is being captured
-fn get_opponents_captured_piece(opp_bbs: &Vec<u64>, capture_square: usize, is_en_passant: bool) -> usize {
+fn get_opponents_captured_piece(opp_bbs: &[u64; 6], capture_square: usize, is_en_passant: bool) -> usize {
     if is_en_passant {
         return pieces::PAWN;
     }
@@ -350,39 +567,112 @@ fn get_opponents_captured_piece(opp_bbs: &Vec<u64>, capture_square: usize, is_en
     panic!("Invalid bitboard; cannot find opponents captured piece");
 }
 
+// Get a bitboard of all pieces, of both colors, attacking a square given
+// a board occupancy.  The occupancy may differ from the board's (e.g., to
+// uncover x-ray attackers during static exchange evaluation).
+pub fn attackers_to(board: &chess_board::ChessBoard, square: usize, occ: u64) -> u64 {
+    let bb = &board.bb_pieces;
+    let bishops_queens = bb[pieces::COLOR_WHITE][pieces::BISHOP] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::BISHOP] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
+    let rooks_queens = bb[pieces::COLOR_WHITE][pieces::ROOK] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::ROOK] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
+    (bitboard::BB_PAWN_ATTACKS[pieces::COLOR_BLACK][square] & bb[pieces::COLOR_WHITE][pieces::PAWN]) |
+    (bitboard::BB_PAWN_ATTACKS[pieces::COLOR_WHITE][square] & bb[pieces::COLOR_BLACK][pieces::PAWN]) |
+    (bitboard::BB_KNIGHT_ATTACKS[square] & (bb[pieces::COLOR_WHITE][pieces::KNIGHT] | bb[pieces::COLOR_BLACK][pieces::KNIGHT])) |
+    (bitboard::BB_KING_ATTACKS[square] & (bb[pieces::COLOR_WHITE][pieces::KING] | bb[pieces::COLOR_BLACK][pieces::KING])) |
+    (get_bishop_attacks_bb(occ, square) & bishops_queens) |
+    (get_rook_attacks_bb(occ, square) & rooks_queens)
+}
+
 // Determine if a square is attacked by a given side
 fn is_square_attacked_by_side(board: &chess_board::ChessBoard, square: usize, by_side_color: usize) -> bool {
-    let pawns = board.bb_pieces[by_side_color][pieces::PAWN];
-    if bitboard::BB_PAWN_ATTACKS[1 - by_side_color][square] & pawns != 0 {
-        return true;
-    }
-    let knights = board.bb_pieces[by_side_color][pieces::KNIGHT];
-    if bitboard::BB_KNIGHT_ATTACKS[square] & knights != 0 {
-        return true;
+    attackers_to(board, square, board.bb_occupied_squares) & board.bb_side[by_side_color] != 0
+}
+
+// Converts a standard square position string into a square ID.
+// For instance, "a3" -> 3
+fn convert_square_str_into_id(move_str: &str) -> Result<usize, String> {
+    let file = if let Some(e) = move_str.chars().next() {e} else {return Err(format!("Invalid move string - file - {}", move_str))};
+    let file = if let Some(e) = "abcdefgh".find(file) {e} else {return Err(format!("Invalid move string - file - {}", move_str))};
+    let rank = if let Some(e) = move_str.chars().nth(1) {e} else {return Err(format!("Invalid move string - rank - {}", move_str))};
+    let rank = match rank.to_digit(10) {
+        Some(e) if (1..=8).contains(&e) => (e-1) as usize,
+        _ => return Err(format!("Invalid move string - rank - {}", move_str)),
+    };
+    Ok(rank * 8 + file)
+}
+
+// An entry in the perft hash table
+#[derive(Clone, Copy)]
+struct PerftEntry {
+    zobrist_hash: u64,
+    depth: usize,
+    count: u64,
+}
+
+// Hash table of perft results, keyed by zobrist hash and depth.  This
+// allows transpositions to be counted once, speeding up deep perft.
+pub struct PerftTable {
+    entries: Vec<Option<PerftEntry>>,
+}
+
+impl PerftTable {
+
+    // Construct a new perft table of (approximately) the given size
+    pub fn new(size_mb: u64) -> PerftTable {
+        let num_entries = (size_mb * 1000000 / std::mem::size_of::<Option<PerftEntry>>() as u64).max(1) as usize;
+        PerftTable {
+            entries: vec![None; num_entries],
+        }
     }
-    let king = board.bb_pieces[by_side_color][pieces::KING];
-    if bitboard::BB_KING_ATTACKS[square] & king != 0 {
-        return true;
+
+}
+
+// Count the number of leaf nodes of the legal move tree to a given depth
+// (perft).  This is used to validate move generation.  Leaf nodes are
+// bulk counted at depth 1 (the number of legal moves) rather than made,
+// and, if a perft table is passed in, subtree counts are cached in it.
+// See https://www.chessprogramming.org/Perft
+pub fn perft(board: &mut chess_board::ChessBoard, depth: usize, mut table: Option<&mut PerftTable>) -> u64 {
+    if depth == 0 {
+        return 1;
     }
-    let bishops_queens = board.bb_pieces[by_side_color][pieces::BISHOP] | board.bb_pieces[by_side_color][pieces::QUEEN];
-    if (get_diagonal_attacks_bb(board.bb_occupied_squares, square, 0) | get_antidiagonal_attacks_bb(board.bb_occupied_squares, square, 0)) & bishops_queens != 0 {
-        return true;
+
+    // Check the perft table
+    if let Some(t) = table.as_deref() {
+        let key = (board.zobrist_hash % t.entries.len() as u64) as usize;
+        if let Some(e) = t.entries[key] {
+            if e.zobrist_hash == board.zobrist_hash && e.depth == depth {
+                return e.count;
+            }
+        }
     }
-    let rooks_queens = board.bb_pieces[by_side_color][pieces::ROOK] | board.bb_pieces[by_side_color][pieces::QUEEN];
-    if (get_rank_attacks_bb(board.bb_occupied_squares, square, 0) | get_file_attacks_bb(board.bb_occupied_squares, square, 0)) & rooks_queens != 0 {
-        return true;
+
+    let mut move_count = 0;
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let mut moves = MoveList::new();
+    generate_all_psuedo_legal_moves(board, my_color, false, &mut moves);
+    for m in moves.iter() {
+        if !is_legal_move(board, m) {
+            continue;
+        }
+        if depth == 1 {
+            move_count += 1;
+            continue;
+        }
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        move_count += perft(board, depth - 1, table.as_deref_mut());
+        board.unmake_move();
     }
-    false
-}
 
-// Converts a standard square position string into a square ID.
-// For instance, "a3" -> 3
-fn convert_square_str_into_id(move_str: &str) -> usize {
-    let file = if let Some(e) = move_str.chars().nth(0) {e} else {panic!("Invalid move string - file - {}", move_str)};
-    let file = if let Some(e) = "abcdefgh".find(file) {e as usize} else {panic!("Invalid move string - file - {}", move_str)};
-    let rank = if let Some(e) = move_str.chars().nth(1) {e} else {panic!("Invalid move string - rank - {}", move_str)};
-    let rank = if let Some(e) = rank.to_digit(10) {(e-1) as usize} else {panic!("Invalid move string - rank - {}", move_str)};
-    rank * 8 + file
+    // Store the result in the perft table
+    if let Some(t) = table {
+        let key = (board.zobrist_hash % t.entries.len() as u64) as usize;
+        t.entries[key] = Some(PerftEntry {
+            zobrist_hash: board.zobrist_hash,
+            depth,
+            count: move_count,
+        });
+    }
+    move_count
 }
 
 // =====================================
@@ -395,24 +685,6 @@ mod tests {
     use crate::chess_board::ChessBoard;
     use super::*;
 
-    fn get_number_of_valid_moves(board: &mut chess_board::ChessBoard, depth: usize) -> u64 {
-        if depth == 0 {
-            return 1;
-        }
-        let mut move_count = 0;
-        let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
-        let moves = generate_all_psuedo_legal_moves(&board, my_color, false);
-        for m in moves.iter() {
-            if !is_legal_move(board, m) {
-                continue;
-            }
-            board.make_move(m.start_square, m.end_square, None);
-            move_count += get_number_of_valid_moves(board, depth - 1);
-            board.unmake_move();
-        }
-        move_count
-    }
-
     // Test the number of valid moves
     #[test]
     fn test_perft() {
@@ -420,12 +692,25 @@ mod tests {
         let mut board = ChessBoard::new();
         board.new_game();
         for i in 0..results.len() {
-            let moves = get_number_of_valid_moves(&mut board, i);
+            let moves = perft(&mut board, i, None);
             assert_eq!(moves, results[i]);
             println!("{} moves at depth {}", moves, i);
         }
     }
 
+    // Test the number of valid moves using a perft table, reusing the
+    // table across depths
+    #[test]
+    fn test_perft_hashed() {
+        let results = [1, 48, 2039, 97862, 4085603];
+        let mut board = ChessBoard::new();
+        board.new_game_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+        let mut table = PerftTable::new(16);
+        for (depth, expected) in results.iter().enumerate() {
+            assert_eq!(perft(&mut board, depth, Some(&mut table)), *expected);
+        }
+    }
+
     // Test a capture
     #[test]
     fn test_capture() {
@@ -433,7 +718,8 @@ mod tests {
         board.new_game();
         board.make_move(12, 28, None); // e4
         board.make_move(51, 35, None); // d5
-        let moves = generate_all_psuedo_legal_moves(&board, pieces::COLOR_WHITE, false);
+        let mut moves = MoveList::new();
+        generate_all_psuedo_legal_moves(&board, pieces::COLOR_WHITE, false, &mut moves);
         let mut captures = 0;
         for m in moves.iter() {
             if !is_legal_move(&mut board, m) {
@@ -446,4 +732,105 @@ mod tests {
         assert_eq!(captures, 1);
     }
 
-}
\ No newline at end of file
+    // Test the number of valid moves in a position with many promotions
+    // and underpromotions
+    #[test]
+    fn test_perft_promotions() {
+        let results = [1, 24, 496, 9483, 182838];
+        let mut board = ChessBoard::new();
+        board.new_game_from_fen("n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1").unwrap();
+        for (depth, expected) in results.iter().enumerate() {
+            assert_eq!(perft(&mut board, depth, None), *expected);
+        }
+    }
+
+    // Compare gives_check against making the move, for all legal moves
+    // down to a given depth
+    fn verify_gives_check(board: &mut ChessBoard, depth: usize) {
+        if depth == 0 {
+            return;
+        }
+        let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let mut moves = MoveList::new();
+        generate_all_psuedo_legal_moves(board, my_color, false, &mut moves);
+        for m in moves.iter() {
+            if is_legal_move(board, m) {
+                let expected = gives_check(board, m);
+                board.make_move(m.start_square, m.end_square, m.promotion_piece);
+                assert_eq!(is_king_in_check(board, 1 - my_color), expected);
+                verify_gives_check(board, depth - 1);
+                board.unmake_move();
+            }
+        }
+    }
+
+    // Test move check detection
+    #[test]
+    fn test_gives_check() {
+        let mut board = ChessBoard::new();
+        board.new_game_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+        verify_gives_check(&mut board, 3);
+        board.new_game_from_fen("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1").unwrap();
+        verify_gives_check(&mut board, 4);
+        board.new_game_from_fen("n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1").unwrap();
+        verify_gives_check(&mut board, 3);
+    }
+
+    // Test pinned pieces and checkers
+    #[test]
+    fn test_pins_and_checkers() {
+        let mut board = ChessBoard::new();
+        board.new_game_from_fen("4r1k1/8/8/8/1b6/5n2/3BN3/4K3 w - - 0 1").unwrap();
+        assert_eq!(pinned_pieces(&board, pieces::COLOR_WHITE), bitboard::to_bb(11) | bitboard::to_bb(12));
+        assert_eq!(checkers(&board, pieces::COLOR_WHITE), bitboard::to_bb(21));
+        assert_eq!(pinned_pieces(&board, pieces::COLOR_BLACK), 0);
+        assert_eq!(checkers(&board, pieces::COLOR_BLACK), 0);
+    }
+
+    // Test en passant legality, including captures that expose the king
+    // along the rank and captures that remove a checking pawn
+    #[test]
+    fn test_en_passant_legality() {
+        let cases = [
+            ("8/8/8/KPp4r/8/8/8/7k w - c6 0 1", false),
+            ("8/8/8/KPp5/8/8/8/7k w - c6 0 1", true),
+            ("8/8/8/3k4/3pP3/8/8/4K3 b - e3 0 1", true),
+            ("8/8/8/8/1k1pP2R/8/8/4K3 b - e3 0 1", false),
+            ("8/8/6B1/8/3pP3/8/2k5/4K3 b - e3 0 1", false),
+        ];
+        for (fen, expected) in cases {
+            let mut board = chess_board::ChessBoard::new();
+            board.new_game_from_fen(fen).unwrap();
+            let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+            let mut moves = MoveList::new();
+            generate_all_psuedo_legal_moves(&board, my_color, false, &mut moves);
+            let m = moves.iter().find(|m| m.is_en_passant).expect("No en passant capture generated");
+            assert_eq!(is_legal_move(&mut board, m), expected, "Wrong en passant legality in {}", fen);
+            board.make_move(m.start_square, m.end_square, m.promotion_piece);
+            assert_eq!(!is_king_in_check(&board, my_color), expected, "Wrong en passant legality in {}", fen);
+        }
+    }
+
+    // Test validation of moves that did not come from move generation
+    #[test]
+    fn test_get_pseudo_legal_move() {
+        let mut board = ChessBoard::new();
+        board.new_game_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+        let mut moves = MoveList::new();
+        generate_all_psuedo_legal_moves(&board, pieces::COLOR_WHITE, false, &mut moves);
+        let mut count = 0;
+        for start_square in 0..64 {
+            for end_square in 0..64 {
+                for promotion_piece in [None, Some(pieces::QUEEN), Some(pieces::KNIGHT)] {
+                    if let Some(m) = get_pseudo_legal_move(&board, start_square, end_square, promotion_piece) {
+                        assert!(moves.iter().any(|g| g.start_square == m.start_square && g.end_square == m.end_square
+                            && g.promotion_piece == m.promotion_piece && g.captured_piece == m.captured_piece && g.piece == m.piece));
+                        count += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(count, moves.len());
+    }
+
+}
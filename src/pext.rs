@@ -0,0 +1,122 @@
+//! This module contains sliding piece attack lookups based on the BMI2
+//! PEXT instruction (parallel bits extract).  The relevant occupancy bits
+//! for a rook or bishop on a square are "extracted" into a dense index,
+//! which is used to look up a precomputed attack bitboard.
+//! See https://www.chessprogramming.org/BMI2#PEXT_Bitboards
+//!
+//! This module is only compiled when the "pext" feature is enabled and
+//! the target CPU supports BMI2 (for instance, when building with
+//! RUSTFLAGS="-C target-cpu=native").  Otherwise, the Kindergarten
+//! bitboard approach in the movegen module is used.
+
+use std::arch::x86_64::_pext_u64;
+use std::sync::OnceLock;
+use crate::bitboard;
+use crate::movegen;
+
+// Attack tables for all relevant occupancies of rooks and bishops.  The
+// rook table has 102400 entries and the bishop table has 5248 entries,
+// for a total of ~840KB.  These are computed once on first use.
+struct PextTables {
+
+    // Relevant occupancy mask for each square.  Edge squares are not
+    // relevant since a slider always attacks them if it reaches them.
+    rook_masks: [u64; 64],
+    bishop_masks: [u64; 64],
+
+    // Offset of each square's entries within the attack tables
+    rook_offsets: [usize; 64],
+    bishop_offsets: [usize; 64],
+
+    // Attack bitboards, indexed by offset + extracted occupancy
+    rook_attacks: Vec<u64>,
+    bishop_attacks: Vec<u64>,
+
+}
+
+static PEXT_TABLES: OnceLock<PextTables> = OnceLock::new();
+
+// Compute all attack tables, using the Kindergarten bitboard functions to
+// find the attacks for each relevant occupancy.
+fn compute_tables() -> PextTables {
+    let edge_files = bitboard::BB_FILES[0] | bitboard::BB_FILES[7];
+    let edge_ranks = bitboard::BB_RANK_MASK[0] | bitboard::BB_RANK_MASK[63];
+    let mut tables = PextTables {
+        rook_masks: [0; 64],
+        bishop_masks: [0; 64],
+        rook_offsets: [0; 64],
+        bishop_offsets: [0; 64],
+        rook_attacks: Vec::new(),
+        bishop_attacks: Vec::new(),
+    };
+    for square in 0..64 {
+        let square_bb = bitboard::to_bb(square);
+        let rook_mask = ((bitboard::BB_RANK_MASK[square] & !edge_files) | (bitboard::BB_FILES[square % 8] & !edge_ranks)) & !square_bb;
+        let bishop_mask = (bitboard::BB_DIAGONAL_MASK[square] | bitboard::BB_ANTIDIAGONAL_MASK[square]) & !(edge_files | edge_ranks) & !square_bb;
+        tables.rook_masks[square] = rook_mask;
+        tables.bishop_masks[square] = bishop_mask;
+        tables.rook_offsets[square] = tables.rook_attacks.len();
+        tables.bishop_offsets[square] = tables.bishop_attacks.len();
+
+        // Enumerate all subsets of the masks using the Carry-Rippler trick
+        // See https://www.chessprogramming.org/Traversing_Subsets_of_a_Set
+        let mut occ: u64 = 0;
+        loop {
+            tables.rook_attacks.push(movegen::get_rank_attacks_bb(occ, square) | movegen::get_file_attacks_bb(occ, square));
+            occ = occ.wrapping_sub(rook_mask) & rook_mask;
+            if occ == 0 {
+                break;
+            }
+        }
+        loop {
+            tables.bishop_attacks.push(movegen::get_diagonal_attacks_bb(occ, square) | movegen::get_antidiagonal_attacks_bb(occ, square));
+            occ = occ.wrapping_sub(bishop_mask) & bishop_mask;
+            if occ == 0 {
+                break;
+            }
+        }
+    }
+    tables
+}
+
+// Get all rook attacks from a square given the board occupancy
+#[inline]
+pub fn get_rook_attacks_bb(occ: u64, square: usize) -> u64 {
+    let tables = PEXT_TABLES.get_or_init(compute_tables);
+    // Safety: this module is only compiled when BMI2 is available
+    let index = unsafe { _pext_u64(occ, tables.rook_masks[square]) } as usize;
+    tables.rook_attacks[tables.rook_offsets[square] + index]
+}
+
+// Get all bishop attacks from a square given the board occupancy
+#[inline]
+pub fn get_bishop_attacks_bb(occ: u64, square: usize) -> u64 {
+    let tables = PEXT_TABLES.get_or_init(compute_tables);
+    // Safety: this module is only compiled when BMI2 is available
+    let index = unsafe { _pext_u64(occ, tables.bishop_masks[square]) } as usize;
+    tables.bishop_attacks[tables.bishop_offsets[square] + index]
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use rand::Rng;
+    use super::*;
+
+    // Compare PEXT attacks to Kindergarten attacks for random occupancies
+    #[test]
+    fn test_pext_attacks() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10000 {
+            let occ = rng.gen::<u64>() & rng.gen::<u64>();
+            let square = rng.gen_range(0..64);
+            assert_eq!(get_rook_attacks_bb(occ, square), movegen::get_rank_attacks_bb(occ, square) | movegen::get_file_attacks_bb(occ, square));
+            assert_eq!(get_bishop_attacks_bb(occ, square), movegen::get_diagonal_attacks_bb(occ, square) | movegen::get_antidiagonal_attacks_bb(occ, square));
+        }
+    }
+
+}
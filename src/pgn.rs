@@ -0,0 +1,353 @@
+// This module reads and writes games in Portable Game Notation (PGN).
+// Moves in a PGN file are written in Standard Algebraic Notation (SAN),
+// so this module also converts between SAN and the engine's moves.  A
+// parsed game is turned into a FEN string and a UCI-style move list, which
+// is what the search engine's "set_board_state" expects.
+// See https://en.wikipedia.org/wiki/Portable_Game_Notation
+
+use crate::chess_board;
+use crate::movegen;
+use crate::pieces;
+
+// Maximum line length of the move text when exporting
+const PGN_LINE_LENGTH: usize = 80;
+
+// Tokens that terminate the move text of a game
+const PGN_RESULTS: [&str; 4] = ["1-0", "0-1", "1/2-1/2", "*"];
+
+// A game parsed from PGN
+pub struct PgnGame {
+
+    // Tag pairs (e.g., ("White", "Topas")), in the order they appeared
+    pub headers: Vec<(String, String)>,
+
+    // FEN of the starting position
+    pub fen: String,
+
+    // Moves of the game as a UCI-style move list (e.g., "e2e4 e7e5 ")
+    pub moves: String,
+
+    // Game result ("1-0", "0-1", "1/2-1/2", or "*")
+    pub result: String,
+
+}
+
+// Get all legal moves for the side to move
+pub fn get_legal_moves(board: &mut chess_board::ChessBoard) -> Vec<movegen::ChessMove> {
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let mut moves = movegen::MoveList::new();
+    movegen::generate_all_psuedo_legal_moves(board, my_color, false, &mut moves);
+    moves.iter().filter(|m| movegen::is_legal_move(board, m)).copied().collect()
+}
+
+// Convert a legal move into SAN (e.g., "Nbd7", "exd5", "e8=Q+", "O-O").
+// The board must be in the position before the move is made.
+pub fn move_to_san(board: &mut chess_board::ChessBoard, m: &movegen::ChessMove) -> String {
+    let mut san = String::new();
+    if m.piece == pieces::KING && m.start_square.abs_diff(m.end_square) == 2 {
+        san.push_str(if m.end_square > m.start_square {"O-O"} else {"O-O-O"});
+    } else if m.piece == pieces::PAWN {
+        if m.captured_piece.is_some() {
+            san.push_str(&chess_board::square_to_str(m.start_square)[..1]);
+            san.push('x');
+        }
+        san.push_str(&chess_board::square_to_str(m.end_square));
+        if let Some(p) = m.promotion_piece {
+            san.push('=');
+            san.push(pieces::PIECE_ID_TO_CHAR[pieces::COLOR_WHITE][p]);
+        }
+    } else {
+        san.push(pieces::PIECE_ID_TO_CHAR[pieces::COLOR_WHITE][m.piece]);
+
+        // Disambiguate if another piece of the same type can move to the
+        // same square, preferring the file, then the rank, then both
+        let others: Vec<movegen::ChessMove> = get_legal_moves(board).into_iter().filter(|x| {
+            x.piece == m.piece && x.end_square == m.end_square && x.start_square != m.start_square
+        }).collect();
+        if !others.is_empty() {
+            let start = chess_board::square_to_str(m.start_square);
+            if others.iter().all(|x| x.start_square % 8 != m.start_square % 8) {
+                san.push_str(&start[..1]);
+            } else if others.iter().all(|x| x.start_square / 8 != m.start_square / 8) {
+                san.push_str(&start[1..]);
+            } else {
+                san.push_str(&start);
+            }
+        }
+        if m.captured_piece.is_some() {
+            san.push('x');
+        }
+        san.push_str(&chess_board::square_to_str(m.end_square));
+    }
+
+    // Add a check or checkmate indicator
+    if movegen::gives_check(board, m) {
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        san.push(if get_legal_moves(board).is_empty() {'#'} else {'+'});
+        board.unmake_move();
+    }
+    san
+}
+
+// Converts a list of moves from the given position to SAN, in the same
+// space-separated form as movegen::convert_move_list_to_lan.  If a move is
+// not legal, it and the rest of the moves are left in long algebraic
+// notation.
+pub fn convert_move_list_to_san(board: &chess_board::ChessBoard, moves: &[(u8, u8, Option<usize>)]) -> String {
+    let mut board = board.clone();
+    let mut san_str = String::new();
+    for (i, m) in moves.iter().enumerate() {
+        let legal_move = movegen::get_pseudo_legal_move(&board, m.0 as usize, m.1 as usize, m.2)
+            .filter(|cm| movegen::is_legal_move(&mut board, cm));
+        match legal_move {
+            Some(cm) => {
+                san_str.push_str(&move_to_san(&mut board, &cm));
+                san_str.push(' ');
+                board.make_move(cm.start_square, cm.end_square, cm.promotion_piece);
+            },
+            None => {
+                san_str.push_str(&movegen::convert_move_list_to_lan(&moves[i..].to_vec()));
+                break;
+            },
+        }
+    }
+    san_str
+}
+
+// Strip the parts of a SAN string that are optional or inconsistently
+// written (check indicators, annotations, and the promotion "=") so two
+// SAN strings for the same move compare equal.
+fn normalize_san(san: &str) -> String {
+    san.replace("0-0-0", "O-O-O").replace("0-0", "O-O").chars().filter(|c| !"+#!?=".contains(*c)).collect()
+}
+
+// Convert a SAN string into a legal move on the current board
+pub fn san_to_move(board: &mut chess_board::ChessBoard, san: &str) -> Result<movegen::ChessMove, String> {
+    let target = normalize_san(san);
+    for m in get_legal_moves(board) {
+        if normalize_san(&move_to_san(board, &m)) == target {
+            return Ok(m);
+        }
+    }
+    Err(format!("Illegal or invalid SAN move: {}", san))
+}
+
+// Convert a UCI-style move (e.g., "e7e8q") into a legal move on the
+// current board
+pub fn lan_to_move(board: &mut chess_board::ChessBoard, lan: &str) -> Result<movegen::ChessMove, String> {
+    let (start_square, end_square, promotion_piece) = movegen::convert_moves_str_into_list(lan)?[0];
+    let m = get_legal_moves(board).into_iter().find(|m| {
+        m.start_square == start_square && m.end_square == end_square && m.promotion_piece == promotion_piece
+    });
+    match m {
+        Some(e) => Ok(e),
+        None => Err(format!("Illegal move in move list: {}", lan)),
+    }
+}
+
+// Parse a tag pair line such as [White "Topas"]
+fn parse_header(line: &str) -> Result<(String, String), String> {
+    let inner = line.trim().trim_start_matches('[').trim_end_matches(']');
+    let (name, value) = match inner.split_once(' ') {
+        Some(e) => e,
+        None => return Err(format!("Invalid PGN tag pair: {}", line)),
+    };
+    let value = value.trim();
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Err(format!("Invalid PGN tag pair: {}", line));
+    }
+    let value = value[1..value.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\");
+    Ok((name.to_string(), value))
+}
+
+// Remove comments ({...} and ; to end of line) and recursive variations
+// ((...)) from the move text
+fn strip_comments_and_variations(movetext: &str) -> String {
+    let mut stripped = String::new();
+    let mut in_brace_comment = false;
+    let mut in_line_comment = false;
+    let mut variation_depth = 0;
+    for c in movetext.chars() {
+        if in_brace_comment {
+            in_brace_comment = c != '}';
+        } else if in_line_comment {
+            in_line_comment = c != '\n';
+        } else if c == '{' {
+            in_brace_comment = true;
+        } else if c == ';' {
+            in_line_comment = true;
+        } else if c == '(' {
+            variation_depth += 1;
+        } else if c == ')' {
+            if variation_depth > 0 {
+                variation_depth -= 1;
+            }
+        } else if variation_depth == 0 {
+            stripped.push(c);
+        }
+
+        // Comments and variations separate tokens
+        if !in_brace_comment && !in_line_comment && variation_depth == 0 && "{};()".contains(c) {
+            stripped.push(' ');
+        }
+    }
+    stripped
+}
+
+// Parse the first game of a PGN string
+pub fn parse_pgn(pgn_str: &str) -> Result<PgnGame, String> {
+
+    // Split the tag pairs from the move text
+    let mut headers = Vec::new();
+    let mut movetext = String::new();
+    for line in pgn_str.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && movetext.trim().is_empty() {
+            headers.push(parse_header(trimmed)?);
+        } else if trimmed.starts_with('[') {
+            // The next game has started
+            break;
+        } else if !trimmed.starts_with('%') {
+            movetext.push_str(line);
+            movetext.push('\n');
+        }
+    }
+
+    // Set up the starting position
+    let fen = match headers.iter().find(|(name, _)| name == "FEN") {
+        Some((_, value)) => value.clone(),
+        None => String::from(chess_board::STARTFEN),
+    };
+    let mut board = chess_board::ChessBoard::new();
+    board.new_game_from_fen(&fen)?;
+
+    // Play through the moves, skipping move numbers and annotation glyphs
+    let mut moves = Vec::new();
+    let mut result = String::from("*");
+    for token in strip_comments_and_variations(&movetext).split_whitespace() {
+        if PGN_RESULTS.contains(&token) {
+            result = token.to_string();
+            break;
+        }
+        let san = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+        if san.is_empty() || san.starts_with('$') {
+            continue;
+        }
+        let m = san_to_move(&mut board, san)?;
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        moves.push((m.start_square as u8, m.end_square as u8, m.promotion_piece));
+    }
+
+    Ok(PgnGame {
+        headers,
+        fen,
+        moves: movegen::convert_move_list_to_lan(&moves),
+        result,
+    })
+
+}
+
+// Export a game to PGN.  The game is described by its starting FEN and a
+// UCI-style move list.  The passed in headers are written first, followed
+// by the result and, if the game did not start from the standard starting
+// position, the SetUp and FEN tags.
+pub fn export_pgn(headers: &[(String, String)], fen: &str, move_str: &str, result: &str) -> Result<String, String> {
+    let mut pgn = String::new();
+    for (name, value) in headers.iter() {
+        pgn.push_str(&format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    pgn.push_str(&format!("[Result \"{}\"]\n", result));
+    if fen != chess_board::STARTFEN {
+        pgn.push_str("[SetUp \"1\"]\n");
+        pgn.push_str(&format!("[FEN \"{}\"]\n", fen));
+    }
+    pgn.push('\n');
+
+    // Convert the moves to SAN, with move numbers
+    let mut board = chess_board::ChessBoard::new();
+    board.new_game_from_fen(fen)?;
+    let mut tokens = Vec::new();
+    for lan in move_str.split_whitespace() {
+        let m = lan_to_move(&mut board, lan)?;
+        if board.whites_turn {
+            tokens.push(format!("{}.", board.fullmove_number));
+        } else if tokens.is_empty() {
+            tokens.push(format!("{}...", board.fullmove_number));
+        }
+        tokens.push(move_to_san(&mut board, &m));
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+    }
+    tokens.push(result.to_string());
+
+    // Wrap the move text
+    let mut line_length = 0;
+    for token in tokens.iter() {
+        if line_length > 0 && line_length + 1 + token.len() > PGN_LINE_LENGTH {
+            pgn.push('\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            pgn.push(' ');
+            line_length += 1;
+        }
+        pgn.push_str(token);
+        line_length += token.len();
+    }
+    pgn.push('\n');
+    Ok(pgn)
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test converting moves to and from SAN
+    #[test]
+    fn test_san() {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1").unwrap();
+        for (san, lan) in [("O-O-O", "e1c1"), ("Nxd7", "e5d7"), ("dxe6", "d5e6"), ("Qxf6", "f3f6"), ("Rb1", "a1b1"), ("Nb1", "c3b1")] {
+            let m = san_to_move(&mut board, san).unwrap();
+            assert_eq!(movegen::convert_move_list_to_lan(&vec![(m.start_square as u8, m.end_square as u8, m.promotion_piece)]).trim(), lan);
+            assert_eq!(move_to_san(&mut board, &m), san);
+        }
+        board.new_game_from_fen("n1n5/PPPk4/8/8/8/8/4Kppp/5N1N w - - 0 1").unwrap();
+        let m = san_to_move(&mut board, "bxa8=N").unwrap();
+        assert_eq!(m.promotion_piece, Some(pieces::KNIGHT));
+        let m = san_to_move(&mut board, "b8Q").unwrap();
+        assert_eq!(move_to_san(&mut board, &m), "b8=Q");
+        assert!(san_to_move(&mut board, "Ke4").is_err());
+    }
+
+    // Test converting a line of moves to SAN, leaving illegal moves in
+    // long algebraic notation
+    #[test]
+    fn test_convert_move_list_to_san() {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen(chess_board::STARTFEN).unwrap();
+        let moves = movegen::convert_moves_str_into_list("e2e4 e7e5 g1f3 b8c6 f1b5 a7a6 e1g1").unwrap();
+        let moves: Vec<(u8, u8, Option<usize>)> = moves.iter().map(|(s, e, p)| (*s as u8, *e as u8, *p)).collect();
+        assert_eq!(convert_move_list_to_san(&board, &moves), "e4 e5 Nf3 Nc6 Bb5 a6 O-O ");
+        assert_eq!(board.to_fen(), chess_board::STARTFEN);
+        assert_eq!(convert_move_list_to_san(&board, &[(12, 28, None), (12, 28, None)]), "e4 e2e4 ");
+    }
+
+    // Test round tripping a game through PGN
+    #[test]
+    fn test_parse_and_export_pgn() {
+        let pgn_str = "[Event \"Test\"]\n[White \"A\"]\n[Black \"B\"]\n[Result \"1-0\"]\n\n\
+                       1. e4 {best by test} e5 2. Bc4 Nc6 (2... Nf6 3. d3) 3. Qh5 $2 Nf6?? 4. Qxf7# 1-0\n";
+        let game = parse_pgn(pgn_str).unwrap();
+        assert_eq!(game.moves, "e2e4 e7e5 f1c4 b8c6 d1h5 g8f6 h5f7 ");
+        assert_eq!(game.result, "1-0");
+        assert_eq!(game.headers[0], (String::from("Event"), String::from("Test")));
+        let headers = vec![(String::from("White"), String::from("A"))];
+        let exported = export_pgn(&headers, &game.fen, &game.moves, &game.result).unwrap();
+        assert_eq!(exported, "[White \"A\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7# 1-0\n");
+    }
+
+}
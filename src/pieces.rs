@@ -19,22 +19,35 @@ pub const PIECE_ID_TO_CHAR: [[char; 6]; 2] = [['P', 'N', 'B', 'R', 'Q', 'K'],
 pub const PIECE_ID_TO_CHAR_UNICODE: [[char; 6]; 2] = [['\u{2659}', '\u{2658}', '\u{2657}', '\u{2656}', '\u{2655}', '\u{2654}'],
                                                       ['\u{265F}', '\u{265E}', '\u{265D}', '\u{265C}', '\u{265B}', '\u{265A}']];
 
+// Piece IDs to names
+pub const PIECE_ID_TO_NAME: [&str; 6] = ["Pawn", "Knight", "Bishop", "Rook", "Queen", "King"];
+
 // Constants for centipawn value of pieces (indexes using
 // the piece constants listed above)
 // See https://www.chessprogramming.org/PeSTO%27s_Evaluation_Function
 pub const PIECE_VALUES_MG: [i32; 6] = [82, 337, 365, 477, 1025, 20000];
 pub const PIECE_VALUES_EG: [i32; 6] = [94, 281, 297, 512, 936, 20000];
 
+// Contribution of each piece to the game phase used for tapered
+// evaluation, and the total for all pieces in the starting position
+// (4*knight + 4*bishop + 4*rook + 2*queen).
+// See https://www.chessprogramming.org/Tapered_Eval
+pub const PIECE_PHASE: [i32; 6] = [0, 1, 1, 2, 4, 0];
+pub const TOTAL_PHASE: i32 = 24;
+
 // Most valuable victom / least valuable attacker (MVV-LVA).  This is used
 // for ordering capture moves.  Higher numbers result in higher
-// priority for move ordering.
+// priority for move ordering.  A king is never captured in a legal move,
+// but a king victim is included so that a king capture from pseudo-legal
+// move generation can still be ordered.
 // See https://www.chessprogramming.org/MVV-LVA
-pub const MVV_LVA: [[i32; 6]; 5] = [
+pub const MVV_LVA: [[i32; 6]; 6] = [
     [5, 4, 3, 2, 1, 0],  // Pawn Victim -> PNBRQK Attackers
     [11, 10, 9, 8, 7, 6], // Knight Victim -> PNBRQK Attackers
     [17, 16, 15, 14, 13, 12], // Bishop Victim -> PNBRQK Attackers
     [23, 22, 21, 20, 19, 18], // Room Victim -> PNBRQK Attackers
     [29, 28, 27, 26, 25, 24], // Queen Victim -> PNBRQK Attackers
+    [35, 34, 33, 32, 31, 30], // King Victim -> PNBRQK Attackers
 ];
 
 // Piece square tables (PST) for augmenting piece values
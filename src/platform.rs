@@ -0,0 +1,84 @@
+// This module abstracts the platform services used by the engine core: a
+// clock for time management and a sink for output (such as UCI "info" and
+// "bestmove" lines).  The default implementations use std::time and
+// standard output.  Platforms without these, such as a browser running
+// the topas library compiled to wasm32-unknown-unknown (where
+// std::time::Instant panics and there is no standard output), can inject
+// their own, for instance a clock backed by performance.now() and an output
+// callback that posts each line to the page.
+
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time;
+
+// A monotonic clock, in milliseconds from an arbitrary starting point.
+// Pausing defaults to waiting on the clock, for platforms that cannot
+// put a thread to sleep.
+pub trait Clock {
+    fn now_ms(&self) -> u128;
+    fn sleep_ms(&self, ms: u64) {
+        let end = self.now_ms() + ms as u128;
+        while self.now_ms() < end {}
+    }
+}
+
+// A sink for lines of engine output
+pub trait Output {
+    fn write_line(&self, line: &str);
+}
+
+// Any function taking a line can be used as an output callback
+impl<F: Fn(&str)> Output for F {
+    fn write_line(&self, line: &str) {
+        self(line)
+    }
+}
+
+// Clock backed by std::time::Instant
+pub struct StdClock {
+    origin: time::Instant,
+}
+
+impl StdClock {
+    pub fn new() -> StdClock {
+        StdClock {
+            origin: time::Instant::now(),
+        }
+    }
+}
+
+impl Default for StdClock {
+    fn default() -> StdClock {
+        StdClock::new()
+    }
+}
+
+impl Clock for StdClock {
+    fn now_ms(&self) -> u128 {
+        self.origin.elapsed().as_millis()
+    }
+    fn sleep_ms(&self, ms: u64) {
+        thread::sleep(time::Duration::from_millis(ms));
+    }
+}
+
+// Output to standard out
+pub struct StdOutput;
+
+impl Output for StdOutput {
+    fn write_line(&self, line: &str) {
+        write_stdout(&[line]);
+    }
+}
+
+// Write lines to standard out while holding its lock, so that lines from the
+// main thread and the engine thread are never interleaved, and flush them
+// immediately so a GUI waiting on a reply (such as "bestmove") is not stalled
+pub fn write_stdout(lines: &[&str]) {
+    let stdout = io::stdout();
+    let mut writer = BufWriter::new(stdout.lock());
+    for line in lines {
+        let _ = writeln!(writer, "{}", line);
+    }
+    let _ = writer.flush();
+}
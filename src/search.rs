@@ -3,59 +3,202 @@
 //! implementation with alpha-beta pruning.
 //! 
 //! A transposition table (TT) is used to store results of previously
-//! searched nodes.  The TT size is configurable.  The TT is stored
-//! on the heap (in a Vec) and so should be sized with respect to the
-//! available memory on the system.
+//! searched nodes.  The TT size is configurable, and should be sized with
+//! respect to the available memory on the system.  The TT is an
+//! AlignedTable, allocated on the heap and aligned to the cache line size,
+//! or on Linux to huge pages for tables of 2 MiB or more, with the kernel
+//! advised to back it with transparent huge pages.  The TT is kept from
+//! one search to the next, with entries from earlier searches replaced
+//! first, and is only cleared for a new game or a change to an option
+//! that affects scores.
 //! 
 //! This module uses iterative deepening to progressively search higher
 //! depths, storing the principal variation (PV) -- the best line
 //! computed so far -- with the first move of the PV being the best
 //! candidate move for the next depth.
 
-use std::time;
 use std::cmp;
+use std::fmt;
 use std::mem;
 use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use crate::evaluate;
 use crate::chess_board;
 use crate::movegen;
 use crate::pieces;
 use crate::bitboard;
+use crate::platform;
+use crate::learning;
+use crate::pgn;
+use crate::strength;
+use crate::aligned_table::AlignedTable;
 
 // Default size of transposition table in MB
 pub const DEFAULT_TT_SIZE_MB: u64 = 16;
 
 // Scores for terminal states and infinity
 const CHECKMATE_VALUE: i32 = 50000;
-const DRAW_VALUE: i32 = 0;
 const INF: i32 = 100000000;
 
-// Initial aspiration window size (half)
-const ASPIRATION_WINDOW_HALF_SIZE: i32 = 30;
+// Default contempt in centipawns, which is how much worse than equal the
+// engine considers a draw to be for itself with all pieces on the board
+pub const DEFAULT_CONTEMPT: i32 = 20;
+
+// Search parameters, which can be changed at runtime (for instance, by an
+// SPSA tuner) as UCI options when built with the "tune" feature.  These
+// index the parameter values, and SEARCH_PARAMS gives the name (as a UCI
+// option), default, minimum, and maximum of each:
+//  - AspirationWindow: initial aspiration window size (half)
+//  - LmrMinMoves, LmrMinDepth: late move reductions apply to moves after
+//    this many have been searched, at this depth or more
+//  - LmrLateMoves, LmrLateDepth: very late moves (after this many have
+//    been searched, at this depth or more) may be reduced by another ply
+//  - SeePruningMaxDepth, SeePruningMargin: captures at non-PV nodes within
+//    this depth of the horizon are pruned if static exchange evaluation
+//    expects them to lose more than this margin per ply of depth
+//  - DeltaPruningMargin: margin for delta pruning in quiescence search
+pub const PARAM_ASPIRATION_WINDOW: usize = 0;
+pub const PARAM_LMR_MIN_MOVES: usize = 1;
+pub const PARAM_LMR_MIN_DEPTH: usize = 2;
+pub const PARAM_LMR_LATE_MOVES: usize = 3;
+pub const PARAM_LMR_LATE_DEPTH: usize = 4;
+pub const PARAM_SEE_PRUNING_MAX_DEPTH: usize = 5;
+pub const PARAM_SEE_PRUNING_MARGIN: usize = 6;
+pub const PARAM_DELTA_PRUNING_MARGIN: usize = 7;
+pub const NUM_SEARCH_PARAMS: usize = 8;
+pub const SEARCH_PARAMS: [(&str, i32, i32, i32); NUM_SEARCH_PARAMS] = [
+    ("AspirationWindow", 30, 5, 200),
+    ("LmrMinMoves", 4, 1, 20),
+    ("LmrMinDepth", 3, 2, 10),
+    ("LmrLateMoves", 7, 2, 40),
+    ("LmrLateDepth", 4, 3, 10),
+    ("SeePruningMaxDepth", 4, 0, 10),
+    ("SeePruningMargin", 100, 0, 500),
+    ("DeltaPruningMargin", 200, 0, 1000),
+];
+
+// Search techniques, each of which can be compiled out with a cargo feature
+// (see Cargo.toml), so that A/B tests can measure what each contributes.
+// Each technique is a single block of the search guarded by its flag.
+const USE_ASPIRATION_WINDOWS: bool = !cfg!(feature = "no-aspiration-windows");
+const USE_LMR: bool = !cfg!(feature = "no-lmr");
+const USE_SEE_PRUNING: bool = !cfg!(feature = "no-see-pruning");
+const USE_QSEARCH_SEE_PRUNING: bool = !cfg!(feature = "no-qsearch-see-pruning");
+const USE_DELTA_PRUNING: bool = !cfg!(feature = "no-delta-pruning");
+const USE_QSEARCH_CHECKS: bool = !cfg!(feature = "no-qsearch-checks");
+
+// When the best move fails low, the time for the move may be extended up to
+// this multiple of it, but not beyond this fraction of the time left
+const FAIL_LOW_TIME_FACTOR: i32 = 3;
+const FAIL_LOW_MAX_SHARE: i32 = 5;
 
 // When prioritizing moves, a bonus may be assigned to a move.
 // Principal variation (PV) moves are the most valuable, and are
 // usually discovered on the previous iterative deepening loop.
 // Moves that lead to a beta cutoff are also very valuable as they
 // can signficantly decrease the search space.  Promotions and
-// captures are valuable, followed by killer moves.
-const PV_MOVE_PRIORITY_BONUS: i32 = 600;
-const CUTOFF_PRIORITY_BONUS: i32 = 500;
-const PROMOTION_PRIORITY_BONUS: i32 = 400;
-const CAPTURE_PRIORITY_BONUS: i32 = 300;
-const KILLER_MOVE_BONUS: i32 = 200;
-const PAWN_PUSH_BONUS: i32 = 100;
+// captures are valuable, followed by killer moves.  Captures that static
+// exchange evaluation expects to lose material are searched after all
+// quiet moves.
+const PV_MOVE_PRIORITY_BONUS: i32 = 6000;
+const CUTOFF_PRIORITY_BONUS: i32 = 5000;
+const PROMOTION_PRIORITY_BONUS: i32 = 4000;
+const CAPTURE_PRIORITY_BONUS: i32 = 3000;
+const KILLER_MOVE_BONUS: i32 = 2000;
+const PAWN_PUSH_BONUS: i32 = 1000;
+const LOSING_CAPTURE_PRIORITY: i32 = -2000;
+
+// Captures are ordered by their SEE value, in pawns, and then by MVV-LVA,
+// so each pawn of SEE value is worth more than any MVV-LVA score
+const SEE_ORDER_SCALE: i32 = 50;
 
 // Piece values in centipawns used in static exchange evaluation (SEE)
 // Indexed by PNBRQK position.
 const SEE_PIECE_VALUES: [i32; 6] = [100, 300, 300, 500, 900, 20000];
 
-// How frequently (in number of function calls of negamax) to check for
-// a halt condition.  A halt can happen if we run out of time or a
-// stop command was issued.
+// How frequently (in number of function calls of negamax) to check the
+// clock for running out of time.  The stop flag is cheap to read, so it is
+// checked on every call.
 const CHECK_HALT_CONDITION_INTERVAL: u64 = 5000;
 
+// Number of transposition table entries sampled to estimate how full the
+// table is, for the UCI "hashfull" metric (in permill)
+const HASHFULL_SAMPLE_ENTRIES: usize = 1000;
+
+// Minimum time in milliseconds to keep searching after "ponderhit", even if
+// the time spent pondering already used up the time for the move
+const MIN_PONDERHIT_TIME_MS: u128 = 50;
+
+// Maximum number of plies from the root tracked by ply-indexed search state
+// (the principal variation table, killer moves, and evaluation stack).  The
+// requested search depth is clamped below this, and negamax falls back to the
+// static evaluation if a line ever reaches it.
+const MAX_PLY: usize = 128;
+
+// Scores at or beyond this (in absolute value) are mate scores, which
+// depend on the number of plies to the mate
+const MATE_BOUND: i32 = CHECKMATE_VALUE - MAX_PLY as i32;
+
+// A move represented as (start square, end square, promotion piece)
+pub type SearchMove = (u8, u8, Option<usize>);
+
+// Limits for an analysis search.  A depth of 0 means no depth limit, and a
+// movetime of 0 means no time limit.  If neither is set, the search runs
+// until the stop flag is set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SearchLimits {
+    pub depth: u8,
+    pub movetime: i32,
+}
+
+// Phase material (see pieces::PIECE_PHASE) at or below which delta pruning
+// in quiescence search is disabled (see PARAM_DELTA_PRUNING_MARGIN for its
+// margin)
+const DELTA_PRUNING_MIN_PHASE_MATERIAL: i32 = 4;
+
+// Number of plies at the start of quiescence search in which quiet checks
+// are searched along with captures, and the most plies quiescence search
+// may go before it stands pat
+const QSEARCH_CHECK_PLIES: usize = 1;
+const QSEARCH_MAX_PLIES: usize = 16;
+
+// Stack size in bytes that a thread running a search should have.  The
+// search recurses once per ply, for up to MAX_PLY plies of negamax and then
+// QSEARCH_MAX_PLIES plies of quiescence search.  Each negamax frame holds a
+// move list of movegen::MAX_MOVES moves (about 33 KiB per ply in a release
+// build) and each quiescence frame two (about 49 KiB per ply), so the
+// deepest line takes about 5 MiB, or twice that with the extra frames of
+// null move and verification searches.  This is more than the default
+// stack of a spawned thread (2 MiB), so the size allowed leaves ample room.
+pub const SEARCH_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+// Priority of moves that quiescence search skips, which are sorted last
+const QSEARCH_SKIP_PRIORITY: i32 = i32::MIN;
+
+// Number of entries per color in the correction history table, which must
+// be a power of two, and the scale of its entries (entries are stored in
+// 1/CORRECTION_HISTORY_GRAIN centipawns so small updates are not lost).
+// Corrections are limited to CORRECTION_HISTORY_MAX centipawns.
+const CORRECTION_HISTORY_SIZE: usize = 16384;
+const CORRECTION_HISTORY_GRAIN: i32 = 256;
+const CORRECTION_HISTORY_MAX: i32 = 64;
+
+// Maximum magnitude of a history heuristic score.  Scores are updated with
+// "gravity", which scales each bonus or penalty by how far the score is
+// from this limit, so scores approach it without ever exceeding it.
+const HISTORY_MAX: i32 = 1 << 14;
+
+// Whether history scores are indexed by [piece][end square] rather than
+// [start square][end square] (the "butterfly" board).  Piece-to indexing
+// shares scores between moves of the same piece to the same square from
+// different squares, and learns faster, while butterfly indexing tells apart
+// moves of different pieces of the same type.
+const HISTORY_PIECE_TO: bool = true;
+
+
 // TT Flag corresponding to a value
+#[derive(Clone, Copy)]
 enum TTFlag {
 
     // An exact value is one that falls between alpha and beta and
@@ -71,8 +214,38 @@ enum TTFlag {
     Upperbound,
 }
 
+// The expected type of a node, predicted from its parent before it is
+// searched.  PV nodes are expected to have a score within the window, cut
+// nodes to fail high, and all nodes to fail low (so every move must be
+// searched).  The prediction is used to decide how selective to be.
+// See https://www.chessprogramming.org/Node_Types
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum NodeType {
+    Pv,
+    Cut,
+    All,
+}
+
+impl NodeType {
+
+    // Get the expected type of a child node.  The first child of a PV node
+    // is a PV node and the rest are expected to be cut nodes, since they
+    // are searched with a null window to prove they are worse.  The first
+    // child of a cut node is expected to be an all node, and the children
+    // of an all node are expected to be cut nodes.
+    fn child(self, is_first_move: bool) -> NodeType {
+        match self {
+            NodeType::Pv if is_first_move => NodeType::Pv,
+            NodeType::Cut if is_first_move => NodeType::All,
+            _ => NodeType::Cut,
+        }
+    }
+
+}
+
 // An entry within a transposition table.  The total size of an
-// entry is 24B.
+// entry (as an Option<TTEntry>) is 24B.
+#[derive(Clone, Copy)]
 struct TTEntry {
 
     // Zobrist hash of the board state at this node, used to
@@ -99,25 +272,14 @@ struct TTEntry {
     // beta-cutoff move -- a move that is too good.  If this is a
     // "upper bound", then there is no best move -- this field
     // should be ignored.
-    // This represents (start square, end square).
-    best_move: Option<(u8, u8)>,
+    // This represents (start square, end square, promotion piece), with
+    // the promotion piece stored as a u8 to keep the entry small.
+    best_move: Option<(u8, u8, Option<u8>)>,
 
-    // Whether or not this TT entry is still valid
-    valid: bool,
-
-}
-
-// An entry into a static exchange evaluation (SEE) attack vector.
-struct SEEAttacker {
-
-    // The value of the piece attacking
-    value: i32,
-
-    // The location of the piece attacking
-    square: usize,
-
-    // A list of square locations of any blockers
-    blockers: Vec<usize>,
+    // The generation of the search that stored this entry (see
+    // tt_generation).  Entries from earlier searches are still used, but
+    // are always replaced.
+    generation: u8,
 
 }
 
@@ -125,8 +287,8 @@ struct SEEAttacker {
 #[derive(Debug)]
 pub struct BestMoveInformation {
 
-    // The best move represented as (start square, end square)
-    pub best_move_from_last_iteration: Option<(u8, u8)>,
+    // The best move represented as (start square, end square, promotion piece)
+    pub best_move_from_last_iteration: Option<(u8, u8, Option<usize>)>,
 
     // The value / score from the engine's (current player's)
     // perspective assuming the best move is played
@@ -147,6 +309,284 @@ pub struct BestMoveInformation {
 
 }
 
+// A score from the perspective of the player to move: either centipawns,
+// or the number of moves to a mate, which is negative if the player is
+// getting mated
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Score {
+    Centipawns(i32),
+    Mate(i32),
+}
+
+impl Score {
+
+    // Get the score of a search value
+    fn from_value(value: i32) -> Score {
+        if value >= MATE_BOUND {
+            Score::Mate((CHECKMATE_VALUE - value + 1) / 2)
+        } else if value <= -MATE_BOUND {
+            Score::Mate(-(CHECKMATE_VALUE + value) / 2)
+        } else {
+            Score::Centipawns(value)
+        }
+    }
+
+}
+
+// Format a score as in the UCI "info" message: "cp <centipawns>" or
+// "mate <moves>"
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Score::Centipawns(cp) => write!(f, "cp {}", cp),
+            Score::Mate(moves) => write!(f, "mate {}", moves),
+        }
+    }
+}
+
+// Format a search value from the perspective of the player to move for
+// the UCI "info" message
+fn format_score(value: i32) -> String {
+    Score::from_value(value).to_string()
+}
+
+// The result of one completed depth of a search
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SearchIteration {
+
+    // The depth searched
+    pub depth: u8,
+
+    // The score of the best move
+    pub score: Score,
+
+    // The number of nodes searched at this depth
+    pub nodes: u64,
+
+    // Time in milliseconds taken to search this depth
+    pub time_ms: u128,
+
+    // The principal variation, in long algebraic notation
+    pub pv: Vec<String>,
+
+}
+
+// The result of a search, returned by find_best_move for the caller to
+// report (e.g., as the UCI "bestmove" message).  Moves are in long
+// algebraic notation.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SearchResult {
+
+    // The move to play, or None if there are no legal moves
+    pub best_move: Option<String>,
+
+    // The expected reply to the best move, for the GUI to ponder on
+    pub ponder_move: Option<String>,
+
+    // The score of the best move
+    pub score: Score,
+
+    // The deepest depth completed
+    pub depth: u8,
+
+    // The result of each completed depth
+    pub iterations: Vec<SearchIteration>,
+
+    // The total number of nodes searched
+    pub nodes: u64,
+
+    // Time in milliseconds taken by the search
+    pub time_ms: u128,
+
+}
+
+// Convert a score of a node at the given ply from the root into a score
+// for storing in the transposition table.  Mate scores in the table count
+// plies to the mate from the node itself rather than from the root, so they
+// stay correct when the same position is reached at a different ply.
+fn score_to_tt(value: i32, ply: usize) -> i32 {
+    if value >= MATE_BOUND {
+        value + ply as i32
+    } else if value <= -MATE_BOUND {
+        value - ply as i32
+    } else {
+        value
+    }
+}
+
+// Convert a score from the transposition table back into a score of a node
+// at the given ply from the root
+fn score_from_tt(value: i32, ply: usize) -> i32 {
+    if value >= MATE_BOUND {
+        value - ply as i32
+    } else if value <= -MATE_BOUND {
+        value + ply as i32
+    } else {
+        value
+    }
+}
+
+// Get the number of transposition table entries that fit in the given size
+// in MiB, rounded down to a power of two
+fn get_num_tt_entries(size_mb: u64) -> usize {
+    let entries = (size_mb * 1024 * 1024 / mem::size_of::<Option<TTEntry>>() as u64).max(1);
+    1 << (63 - entries.leading_zeros())
+}
+
+// Apply a bonus (or a penalty, if negative) to a history score with
+// gravity: the change is scaled down as the score nears the maximum in the
+// direction of the change, so a move's score saturates smoothly, and a move
+// that stops causing cut-offs quickly loses its score
+// See https://www.chessprogramming.org/History_Heuristic
+fn apply_history_bonus(score: &mut i32, bonus: i32) {
+    *score += bonus - *score * bonus.abs() / HISTORY_MAX;
+}
+
+// Get the index of a move into the history table, depending on
+// HISTORY_PIECE_TO
+fn get_history_index(start_square: usize, end_square: usize, piece: usize) -> (usize, usize) {
+    if HISTORY_PIECE_TO {
+        (piece, end_square)
+    } else {
+        (start_square, end_square)
+    }
+}
+
+// Get the order of a capture among captures (higher is searched first),
+// given its SEE value: by the SEE value, and then by MVV-LVA
+fn get_capture_order(see: i32, captured_piece: usize, piece: usize) -> i32 {
+    see / SEE_PIECE_VALUES[pieces::PAWN] * SEE_ORDER_SCALE + pieces::MVV_LVA[captured_piece][piece]
+}
+
+// Get the time limits for a move in milliseconds: the time we aim to spend,
+// and the hard limit the search may be extended to if the best move fails
+// low.  Without a time limit (time_available of 0), both are unlimited.
+fn get_time_limits(time_available: i32, time_inc: i32, moves_to_go: u16) -> (i32, i32) {
+    if time_available <= 0 {
+        return (INF, INF);
+    }
+
+    // For time management purposes, we allocate time into
+    // equal chunks based on the number of moves to go, and
+    // add this chunk to half of our time increment.  If we're
+    // running low on time, we try to ensure we have at least
+    // 100ms for a move.
+    // Note that this follows the approach outlined here:
+    // http://mediocrechess.blogspot.com/2007/01/guide-time-management.html
+    let mut time_for_move = time_available / (moves_to_go as i32) + time_inc / 2;
+    if time_for_move > time_available {
+        time_for_move = time_available - 500;
+    }
+    if time_for_move < 0 {
+        time_for_move = 100;
+    }
+
+    // The hard limit is a multiple of the time for the move, but never more
+    // than a share of the time left, so a single move can't use up the clock
+    let time_hard_limit = cmp::max(time_for_move, cmp::min(FAIL_LOW_TIME_FACTOR * time_for_move, time_available / FAIL_LOW_MAX_SHARE));
+    (time_for_move, time_hard_limit)
+}
+
+// Get the effective branching factor of an iterative deepening search,
+// given the node count of each depth: the average factor by which the node
+// count grew from one depth to the next (the geometric mean of the ratios).
+// This needs at least two depths.
+fn get_effective_branching_factor(depth_nodes: &[u64]) -> Option<f64> {
+    let (first, last) = (*depth_nodes.first()?, *depth_nodes.last()?);
+    if depth_nodes.len() < 2 || first == 0 {
+        return None;
+    }
+    Some((last as f64 / first as f64).powf(1.0 / (depth_nodes.len() - 1) as f64))
+}
+
+// The state of a single search thread: the heuristics learned while
+// searching (killers, history, and correction history), the stacks indexed
+// by ply, and the node and halt counters.  Everything shared between threads
+// (the board template, transposition table, time limits, and options) stays
+// in SearchEngine.
+struct SearchThread {
+
+    // Killer moves, indexed by ply from the root.  These are stored as full
+    // moves, including the promotion piece, like the other move tables.
+    primary_killers: [Option<SearchMove>; MAX_PLY],
+    secondary_killers: [Option<SearchMove>; MAX_PLY],
+
+    // History heuristic scores of quiet moves that caused beta cut-offs,
+    // indexed by [color] and the index of get_history_index: either [start
+    // square][end square], or [piece][end square] (where only the first
+    // rows are used)
+    // See https://www.chessprogramming.org/History_Heuristic
+    history: [[[i32; 64]; 64]; 2],
+
+    // Countermove history scores of quiet moves that caused beta cut-offs,
+    // in reply to the previous move.  This is indexed by the previous move
+    // (see get_countermove_index), then by [piece][end square] of the
+    // quiet move.  It is kept on the heap since it is large.
+    // See https://www.chessprogramming.org/Countermove_Heuristic
+    countermove_history: Vec<[[i32; 64]; 6]>,
+
+    // Correction history, which learns the difference between the static
+    // evaluation and the search score of positions, indexed by [color][pawn
+    // structure key].  This is applied to future static evaluations of
+    // positions with the same pawn structure.
+    // See https://www.chessprogramming.org/Static_Evaluation_Correction_History
+    correction_history: Vec<[i32; CORRECTION_HISTORY_SIZE]>,
+
+    // Static evaluation of the nodes on the current search path, indexed by
+    // ply from the root (None if the side to move was in check)
+    eval_stack: [Option<i32>; MAX_PLY],
+
+    // Triangular principal variation table, indexed by ply from the root.
+    // Row ply holds the PV from that ply, in entries ply..pv_length[ply].
+    // See https://www.chessprogramming.org/Triangular_PV-Table
+    pv_table: Vec<[Option<SearchMove>; MAX_PLY]>,
+    pv_length: [usize; MAX_PLY],
+
+    // Scores of the moves at the root in the current iteration, which are
+    // exact for the best move and bounds for the rest
+    root_move_scores: Vec<(SearchMove, i32)>,
+
+    // Total moves analyzed in current search
+    moves_analyzed: i32,
+
+    // Total moves analyzed across all iterations of the last search
+    search_nodes: u64,
+
+    // Whether the current iteration was halted due to running out of time
+    // or receiving a stop command
+    halt_search: bool,
+
+    // Count down until checking the clock
+    halt_check_countdown: u64,
+
+}
+
+impl SearchThread {
+
+    // Construct a new SearchThread with empty heuristics and counters
+    fn new() -> SearchThread {
+        SearchThread {
+            primary_killers: [None; MAX_PLY],
+            secondary_killers: [None; MAX_PLY],
+            history: [[[0; 64]; 64]; 2],
+            countermove_history: vec![[[0; 64]; 6]; 2 * 6 * 64],
+            correction_history: vec![[0; CORRECTION_HISTORY_SIZE]; 2],
+            eval_stack: [None; MAX_PLY],
+            pv_table: vec![[None; MAX_PLY]; MAX_PLY],
+            pv_length: [0; MAX_PLY],
+            root_move_scores: Vec::new(),
+            moves_analyzed: 0,
+            search_nodes: 0,
+            halt_search: false,
+            halt_check_countdown: CHECK_HALT_CONDITION_INTERVAL,
+        }
+    }
+
+}
+
 // The main engine
 pub struct SearchEngine {
 
@@ -154,67 +594,178 @@ pub struct SearchEngine {
     // to the engine thread.
     pub rx_channel: Receiver<String>,
 
-    // The game board
+    // The game board, which is the template for the board each search
+    // thread works on
     board: chess_board::ChessBoard,
 
-    // The transposition table size in entries.  Each entry
-    // is 24B so the total size of the TT is: 24B * num_tt_entries.
+    // State owned by the thread doing the search
+    thread: SearchThread,
+
+    // The transposition table size in entries, which is a power of two so
+    // the table can be indexed with a mask rather than a modulo
     num_tt_entries: usize,
 
-    // The transposition table
-    transposition_table: Vec<Option<TTEntry>>,
+    // The transposition table, which is kept from one search to the next
+    // and only cleared for a new game or a change to the scoring options
+    transposition_table: AlignedTable<Option<TTEntry>>,
 
-    // Killer moves, indexed by ply.  We assume that we will
-    // never reach greater than 100 ply.
-    primary_killers: [Option<(u8, u8)>; 100],
-    secondary_killers: [Option<(u8, u8)>; 100],
+    // The generation of the current search, which is incremented (and
+    // wraps around) at the start of each search to tell entries stored by
+    // this search apart from ones left over from earlier searches
+    tt_generation: u8,
 
-    // Max depth we were instructed to search to
-    max_depth_for_search: u8,
-    
     // The stored best move from the last iteration
-    // represented by (start square, end square)
-    best_move_from_last_iteration: Option<(u8, u8)>,
+    // represented by (start square, end square, promotion piece)
+    best_move_from_last_iteration: Option<(u8, u8, Option<usize>)>,
 
-    // Total moves analyzed in current search
-    moves_analyzed: i32,
+    // Score of the last search from the perspective of the player to move,
+    // taken from the last completed iteration
+    search_score: i32,
 
     // The maximum time we can spend on this move in milliseconds
     time_max_for_move: u128,
 
-    // The time we started the move
-    move_start_time: time::Instant,
+    // The time we can extend the search to, in milliseconds, if the best
+    // move fails low
+    time_hard_limit_for_move: u128,
 
-    // Whether the current iteration was halted due to running out of time
-    // or receiving a stop command
-    halt_search: bool,
+    // The time we started the move, in milliseconds according to the clock
+    move_start_time: u128,
 
-    // Count down until checking for a halt condition
-    halt_check_countdown: u64,
+    // The time by which the search must halt, in milliseconds according to
+    // the clock
+    deadline: u128,
+
+    // Flag set by the UCI thread to stop the search
+    stop_flag: Arc<AtomicBool>,
+
+    // Flag set by the UCI thread while the engine is pondering (searching
+    // on the opponent's time), and cleared on "ponderhit"
+    ponder_flag: Arc<AtomicBool>,
+
+    // Whether the current search is pondering.  While pondering, the
+    // search ignores its time limits and holds its best move.
+    pondering: bool,
+
+    // The clock used for time management and the sink for output, which
+    // are provided by the platform
+    clock: Box<dyn platform::Clock>,
+    output: Box<dyn platform::Output>,
+
+    // Length of the board's history at the root of the search, used to
+    // compute the ply from the root
+    root_history_len: usize,
+
+    // Learned results of earlier searches, if a learning file is in use
+    learning: Option<learning::LearningTable>,
+
+    // Contempt for draws in centipawns (see DEFAULT_CONTEMPT)
+    contempt: i32,
+
+    // Maximum search speed in nodes per second, or 0 for no limit
+    nps_limit: u64,
+
+    // Maximum number of nodes for the next search, or 0 for no limit
+    node_limit: u64,
+
+    // Which evaluation terms are enabled
+    eval_terms: evaluate::EvalTerms,
+
+    // Values of the search parameters, indexed as in SEARCH_PARAMS
+    params: [i32; NUM_SEARCH_PARAMS],
+
+    // Whether to print the PV in SAN rather than long algebraic notation
+    pv_san: bool,
+
+    // Whether the GUI turned on debug mode, in which the node counts and
+    // times of each depth are reported at the end of a search
+    debug: bool,
+
+    // Root moves to leave out of the next search, for analysis of the
+    // alternatives to a move
+    excluded_root_moves: Vec<SearchMove>,
+
+    // Whether to play at a limited strength given by an Elo rating, and
+    // the handicaps for the current search if so
+    limit_strength: bool,
+    elo: i32,
+    strength: Option<strength::StrengthLimit>,
+
+    // Random numbers for the strength handicaps
+    random: strength::Random,
 
 }
 
 impl SearchEngine {
 
-    // Construct a new SearchEngine
+    // Construct a new SearchEngine using the standard clock and printing
+    // output to standard out
     pub fn new(rx: Receiver<String>) -> SearchEngine {
+        SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(platform::StdOutput))
+    }
+
+    // Construct a new SearchEngine with the given clock and output, for
+    // platforms where std::time or standard output are not available
+    pub fn with_platform(rx: Receiver<String>, clock: Box<dyn platform::Clock>, output: Box<dyn platform::Output>) -> SearchEngine {
         SearchEngine {
             rx_channel: rx,
             board: chess_board::ChessBoard::new(),
-            num_tt_entries: (DEFAULT_TT_SIZE_MB * 1000000 / mem::size_of::<TTEntry>() as u64) as usize,
-            transposition_table: Vec::new(),
-            primary_killers: [None; 100],
-            secondary_killers: [None; 100],
-            max_depth_for_search: 0,
+            num_tt_entries: get_num_tt_entries(DEFAULT_TT_SIZE_MB),
+            transposition_table: AlignedTable::empty(),
+            tt_generation: 0,
+            thread: SearchThread::new(),
             best_move_from_last_iteration: None,
-            moves_analyzed: 0,
+            search_score: 0,
             time_max_for_move: 0,
-            move_start_time: time::Instant::now(),
-            halt_search: false,
-            halt_check_countdown: CHECK_HALT_CONDITION_INTERVAL,
+            time_hard_limit_for_move: 0,
+            move_start_time: 0,
+            deadline: 0,
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            ponder_flag: Arc::new(AtomicBool::new(false)),
+            pondering: false,
+            clock,
+            output,
+            root_history_len: 0,
+            learning: None,
+            contempt: DEFAULT_CONTEMPT,
+            nps_limit: 0,
+            node_limit: 0,
+            eval_terms: evaluate::ALL_EVAL_TERMS,
+            params: SEARCH_PARAMS.map(|(_, default, _, _)| default),
+            pv_san: false,
+            debug: false,
+            excluded_root_moves: Vec::new(),
+            limit_strength: false,
+            elo: strength::DEFAULT_ELO,
+            strength: None,
+            random: strength::Random::new(),
         }
     }
 
+    // Share a stop flag with the thread sending commands to the engine.
+    // Setting the flag halts the current search, and the flag must be
+    // cleared before starting a new one.
+    pub fn set_stop_flag(&mut self, stop_flag: Arc<AtomicBool>) {
+        self.stop_flag = stop_flag;
+    }
+
+    // Share a ponder flag with the thread sending commands to the engine.
+    // The flag is set before a "go ponder" command is sent, and cleared on
+    // "ponderhit" to turn the search into a normal timed search.
+    pub fn set_ponder_flag(&mut self, ponder_flag: Arc<AtomicBool>) {
+        self.ponder_flag = ponder_flag;
+    }
+
+    // Sets whether the next search is pondering
+    pub fn set_pondering(&mut self, pondering: bool) {
+        self.pondering = pondering;
+    }
+
+    // Write a line to the engine's output
+    pub fn write_line(&self, line: &str) {
+        self.output.write_line(line);
+    }
+
     // Start a new game, resetting everything
     pub fn new_game(&mut self) {
 
@@ -222,12 +773,11 @@ impl SearchEngine {
         self.board.new_game();
 
         // Reset the transposition table
-        self.transposition_table.clear();
-        self.transposition_table.resize_with(self.num_tt_entries, ||-> Option<TTEntry> {None});
+        self.clear_transposition_table();
 
         // Reset other state
         self.best_move_from_last_iteration = None;
-        self.moves_analyzed = 0;
+        self.thread = SearchThread::new();
     
     }
 
@@ -238,29 +788,222 @@ impl SearchEngine {
     // The move string is in long algebraic notation without piece names,
     // with spaces between each move, as dictated by the UCI protocol.
     // move.  For instance, "e2e4 b8c6".  Promotion looks like "f7f8q".
-    pub fn set_board_state(&mut self, fen_str: &str, move_str: &str) {
+    // An error is returned if the FEN string or any move is invalid.  If
+    // an illegal move is found, the board is left at the position before
+    // that move.
+    pub fn set_board_state(&mut self, fen_str: &str, move_str: &str) -> Result<(), String> {
 
         // Get the list of moves passed in
-        let moves = movegen::convert_moves_str_into_list(move_str);
+        let moves = movegen::convert_moves_str_into_list(move_str)?;
 
         // Start the board at a given starting position
-        self.board.new_game_from_fen(fen_str);
+        self.board.new_game_from_fen(fen_str)?;
 
-        // Play out the provided moves
-        for (start_square, end_square, promotion) in moves {
+        // Reset other state
+        self.best_move_from_last_iteration = None;
+        self.thread.moves_analyzed = 0;
+
+        // Play out the provided moves, ensuring each is legal
+        for (m_str, (start_square, end_square, promotion)) in move_str.split_whitespace().zip(moves) {
+            let my_color = self.color_turn();
+            let mut psuedo_legal_moves = movegen::MoveList::new();
+            movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut psuedo_legal_moves);
+            // If a promotion piece was not specified, assume a queen
+            let is_match = |m: &&movegen::ChessMove| {
+                m.start_square == start_square && m.end_square == end_square &&
+                (m.promotion_piece.is_none() || m.promotion_piece == promotion.or(Some(pieces::QUEEN)))
+            };
+            match psuedo_legal_moves.iter().find(is_match) {
+                Some(m) if movegen::is_legal_move(&mut self.board, m) => {},
+                _ => return Err(format!("Illegal move in move list: {}", m_str)),
+            }
             self.board.make_move(start_square, end_square, promotion);
         }
 
-        // Reset other state
-        self.best_move_from_last_iteration = None;
-        self.moves_analyzed = 0;
+        Ok(())
 
     }
 
-    // Sets the transposition table size in MB
+    // Sets the transposition table size in MB (MiB).  The board (the
+    // current game position and its history) is kept, and so are the
+    // entries of the table: they are rehashed into the new table, so
+    // resizing during analysis does not throw away the search so far.  If
+    // the new table is smaller, entries that land on the same index compete
+    // as in the search, with entries from the current generation and then
+    // deeper entries kept.  Both tables are allocated while the entries are
+    // copied.  This is only called from the engine thread, so a "setoption"
+    // sent during a search is queued until the search is over rather than
+    // resizing the table under it.
     pub fn set_tt_size_mb(&mut self, size_mb: u64) {
-        self.num_tt_entries = (size_mb * 1000000 / mem::size_of::<TTEntry>() as u64) as usize;
-        self.transposition_table.resize_with(self.num_tt_entries, ||-> Option<TTEntry> {None});
+        let num_tt_entries = get_num_tt_entries(size_mb);
+        if num_tt_entries == self.num_tt_entries && self.transposition_table.len() == num_tt_entries {
+            return;
+        }
+        self.num_tt_entries = num_tt_entries;
+        let old_table = mem::replace(&mut self.transposition_table, AlignedTable::empty());
+        self.clear_transposition_table();
+        for entry in old_table.iter().flatten() {
+            let tt_key = (entry.zobrist_hash & (num_tt_entries as u64 - 1)) as usize;
+            let priority = |e: &TTEntry| (e.generation == self.tt_generation, e.depth);
+            match &self.transposition_table[tt_key] {
+                Some(existing) if priority(existing) >= priority(entry) => {},
+                _ => self.transposition_table[tt_key] = Some(*entry),
+            }
+        }
+    }
+
+    // Sets the learning file, loading the positions learned so far.  An
+    // empty path turns learning off.
+    pub fn set_learning_file(&mut self, path: &str) -> Result<(), String> {
+        self.learning = None;
+        if !path.is_empty() {
+            self.learning = Some(learning::LearningTable::load(path)?);
+        }
+        Ok(())
+    }
+
+    // Sets the contempt for draws in centipawns
+    pub fn set_contempt(&mut self, contempt: i32) {
+        if contempt != self.contempt {
+            self.contempt = contempt;
+            self.invalidate_transposition_table();
+        }
+    }
+
+    // Sets the maximum search speed in nodes per second, or 0 for no limit
+    pub fn set_nps_limit(&mut self, nps_limit: u64) {
+        self.nps_limit = nps_limit;
+    }
+
+    // Sets whether an evaluation term (indexed as in evaluate::EvalTrace)
+    // is included in the static evaluation
+    pub fn set_eval_term(&mut self, term: usize, enabled: bool) {
+        if enabled != self.eval_terms[term] {
+            self.eval_terms[term] = enabled;
+            self.invalidate_transposition_table();
+        }
+    }
+
+    // Sets a search parameter (indexed as in SEARCH_PARAMS) for tuning.
+    // The value is assumed to be within the parameter's range.
+    #[cfg(feature = "tune")]
+    pub fn set_search_param(&mut self, param: usize, value: i32) {
+        if value != self.params[param] {
+            self.params[param] = value;
+            self.invalidate_transposition_table();
+        }
+    }
+
+    // Sets the maximum number of nodes of the next search, or 0 for no
+    // limit.  This is cleared once the search is done.  Since the search
+    // stops at the same node regardless of speed, a search limited only by
+    // nodes is reproducible across machines.
+    pub fn set_node_limit(&mut self, node_limit: u64) {
+        self.node_limit = node_limit;
+    }
+
+    // Sets root moves to leave out of the next search.  These are cleared
+    // once the search is done.  If every legal move is excluded, the
+    // exclusions are ignored.
+    pub fn set_excluded_root_moves(&mut self, moves: Vec<SearchMove>) {
+        self.excluded_root_moves = moves;
+    }
+
+    // Sets the root moves of the next search from moves to include and
+    // moves to exclude.  If the include list is not empty, only the legal
+    // moves in it are searched.  Moves in the exclude list are never
+    // searched.  As with set_excluded_root_moves, if this leaves no legal
+    // move, every legal move is searched.
+    pub fn set_root_moves(&mut self, include: &[SearchMove], exclude: &[SearchMove]) {
+        let mut moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&self.board, self.color_turn(), false, &mut moves);
+        let excluded_moves = moves.iter()
+            .filter(|m| movegen::is_legal_move(&mut self.board, m))
+            .map(|m| (m.start_square as u8, m.end_square as u8, m.promotion_piece))
+            .filter(|m| (!include.is_empty() && !include.contains(m)) || exclude.contains(m))
+            .collect();
+        self.set_excluded_root_moves(excluded_moves);
+    }
+
+    // Analyze a position, given as a FEN string and moves played from it
+    // (as in set_board_state), within the given limits.  Only the moves in
+    // the include list (if it is not empty) and not in the exclude list are
+    // searched at the root.  This is the entry point for driving analysis
+    // without the UCI loop, such as from a GUI or web back end; output goes
+    // to the engine's output, and the result is returned to the caller.
+    // Like every search, this should run on a thread with a stack of at
+    // least SEARCH_STACK_SIZE bytes.
+    pub fn analyze(&mut self, fen_str: &str, move_str: &str, limits: &SearchLimits, include: &[SearchMove], exclude: &[SearchMove]) -> Result<SearchResult, String> {
+        self.set_board_state(fen_str, move_str)?;
+        self.set_root_moves(include, exclude);
+        let time_available = if limits.movetime > 0 {limits.movetime} else {-1};
+        Ok(self.find_best_move(limits.depth, time_available, 0, 1))
+    }
+
+    // Sets whether to print the PV in SAN rather than long algebraic
+    // notation
+    pub fn set_pv_san(&mut self, pv_san: bool) {
+        self.pv_san = pv_san;
+    }
+
+    // Sets whether debug mode is on
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    // Sets whether to play at a limited strength, and the Elo rating of
+    // that strength
+    pub fn set_limit_strength(&mut self, limit_strength: bool) {
+        if limit_strength != self.limit_strength {
+            self.limit_strength = limit_strength;
+            self.invalidate_transposition_table();
+        }
+    }
+    pub fn set_elo(&mut self, elo: i32) {
+        if elo != self.elo {
+            self.elo = elo;
+            self.invalidate_transposition_table();
+        }
+    }
+
+    // Returns the size of the transposition table in bytes and in entries
+    pub fn get_tt_size(&self) -> (usize, usize) {
+        (self.num_tt_entries * mem::size_of::<Option<TTEntry>>(), self.num_tt_entries)
+    }
+
+    // Returns the transposition table index of the current board
+    fn get_tt_index(&self) -> usize {
+        (self.board.zobrist_hash & (self.num_tt_entries as u64 - 1)) as usize
+    }
+
+    // Returns how full the transposition table is, in permill, estimated
+    // from the first entries of the table.  Only entries stored by the
+    // current search are counted, since older entries are free to replace.
+    fn get_hashfull(&self) -> usize {
+        let sample = &self.transposition_table[..cmp::min(HASHFULL_SAMPLE_ENTRIES, self.transposition_table.len())];
+        if sample.is_empty() {
+            return 0;
+        }
+        sample.iter().filter(|e| matches!(e, Some(entry) if entry.generation == self.tt_generation)).count() * 1000 / sample.len()
+    }
+
+    // Clears the transposition table, allocating it at the configured size
+    // if needed
+    fn clear_transposition_table(&mut self) {
+        if self.transposition_table.len() == self.num_tt_entries {
+            self.transposition_table.fill(None);
+        } else {
+            self.transposition_table = AlignedTable::new(self.num_tt_entries, None);
+        }
+    }
+
+    // Clears the transposition table after a setting that changes the
+    // scores of positions, as the scores kept from earlier searches no
+    // longer hold.  A table not yet allocated is left to the next search.
+    fn invalidate_transposition_table(&mut self) {
+        if !self.transposition_table.is_empty() {
+            self.clear_transposition_table();
+        }
     }
 
     // Returns the color of the player to move
@@ -268,271 +1011,453 @@ impl SearchEngine {
         if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK}
     }
 
+    // Returns the number of nodes searched by the last search
+    pub fn last_search_nodes(&self) -> u64 {
+        self.thread.search_nodes
+    }
+
+    // Returns the score of the last search from the perspective of the
+    // player to move
+    pub fn last_search_score(&self) -> i32 {
+        self.search_score
+    }
+
+    // Prints the static evaluation of the board broken down by term
+    pub fn print_eval(&self) {
+        evaluate::print_eval_trace(&self.board);
+    }
+
+    // Checks that the static evaluation of the board matches that of its
+    // color-flipped mirror, returning the evaluation
+    pub fn check_eval_symmetry(&self) -> Result<i32, String> {
+        evaluate::check_eval_symmetry(&self.board)
+    }
+
+    // Prints the board along with its FEN, key, and checkers
+    pub fn display_board(&self) {
+        self.board.print_display();
+    }
+
     // Prints the board, for debugging purposes
     pub fn print_board(&self) {
         self.board.print_debug();
     }
 
-    // This returns the engine's top move given a maximum search depth.
+    // Runs perft on the current board to the given depth, printing the
+    // number of leaf nodes under each root move ("divide"), followed by
+    // the total and the nodes per second.  If a hash size is given, a
+    // perft table of that size is used to count transpositions once.
+    pub fn perft_divide(&mut self, depth: usize, hash_size_mb: Option<u64>) {
+        let start_time = self.clock.now_ms();
+        let mut table = hash_size_mb.map(movegen::PerftTable::new);
+        let my_color = self.color_turn();
+        let mut moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut moves);
+        let mut total = 0;
+        for m in moves.iter() {
+            if !movegen::is_legal_move(&mut self.board, m) {
+                continue;
+            }
+            self.board.make_move(m.start_square, m.end_square, m.promotion_piece);
+            let count = movegen::perft(&mut self.board, depth - 1, table.as_mut());
+            self.board.unmake_move();
+            let lan = movegen::convert_move_list_to_lan(&vec![(m.start_square as u8, m.end_square as u8, m.promotion_piece)]);
+            self.output.write_line(&format!("{}: {}", lan.trim(), count));
+            total += count;
+        }
+        let duration = self.clock.now_ms() - start_time;
+        let nps = (total as u128 * 1000).checked_div(duration).unwrap_or(0);
+        self.output.write_line("");
+        self.output.write_line(&format!("Nodes searched: {}", total));
+        self.output.write_line(&format!("Time: {} ms, nps: {}", duration, nps));
+    }
+
+    // This returns the engine's top move given a maximum search depth, along
+    // with the score, PV of each depth, and statistics of the search.
     // This uses self.board as the current state of the board to search from.
     // This uses an iterative deepening search.  The PV move found in the
     // previous iteration is the first searched node in the next iteration.
-    // This will print information to standard out in UCI format in compliance
-    // with the UCI protocol.
-    pub fn find_best_move(&mut self, mut max_depth: u8, time_available: i32, time_inc: i32, moves_to_go: u16) -> String {
+    // This will print "info" messages in UCI format while searching; the
+    // caller reports the result (e.g., as the "bestmove" message).
+    pub fn find_best_move(&mut self, mut max_depth: u8, time_available: i32, time_inc: i32, moves_to_go: u16) -> SearchResult {
 
         // Sanity check on transposition tables.  Note that the user should
         // have sent a ucinewgame command first to reset the transposition
         // tables.  But, if they did not, we'll reset them here so we don't
         // crash.
         if self.transposition_table.len() == 0 {
-            self.transposition_table.clear();
-            self.transposition_table.resize_with(self.num_tt_entries, ||-> Option<TTEntry> {None});
-        }
-
-        // If depth is 0, then we're not using depth as a limiter
-        if max_depth == 0 {
-            max_depth = 99;
-        }
-
-        // If time_available is greater than 0, then we're using
-        // time as a limiter
-        let mut time_for_move = INF;
-        if time_available > 0 {
-
-            // For time management purposes, we allocate time into
-            // equal chunks based on the number of moves to go, and
-            // add this chunk to half of our time increment.  If we're
-            // running low on time, we try to ensure we have at least
-            // 100ms for a move.
-            // Note that this follows the approach outlined here:
-            // http://mediocrechess.blogspot.com/2007/01/guide-time-management.html
-            time_for_move = time_available / (moves_to_go as i32) + time_inc / 2;
-            if time_for_move > time_available {
-                time_for_move = time_available - 500;
-            }
-            if time_for_move < 0 {
-                time_for_move = 100;
-            }
+            self.clear_transposition_table();
+        }
+        self.tt_generation = self.tt_generation.wrapping_add(1);
+
+        // Get the handicaps for this search if playing at limited strength
+        self.strength = if self.limit_strength {
+            Some(strength::StrengthLimit::from_elo(self.elo, self.random.next_u64()))
+        } else {
+            None
+        };
+
+        // Find the first legal move, which is played if the search is
+        // stopped before it finds a best move, so that we never send a null
+        // move.  If there are no legal moves, the game is already over, so
+        // report checkmate or stalemate without searching.
+        let my_color = self.color_turn();
+        let mut root_moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut root_moves);
+        let root_legal_moves: Vec<SearchMove> = root_moves.iter()
+            .filter(|m| movegen::is_legal_move(&mut self.board, m))
+            .map(|m| (m.start_square as u8, m.end_square as u8, m.promotion_piece))
+            .collect();
+        if !root_legal_moves.is_empty() && root_legal_moves.iter().all(|m| self.excluded_root_moves.contains(m)) {
+            self.output.write_line("info string all legal moves are excluded; searching all of them");
+            self.excluded_root_moves.clear();
+        }
+        let first_legal_move = root_legal_moves.into_iter().find(|m| !self.excluded_root_moves.contains(m));
+        if first_legal_move.is_none() {
+            let is_checkmate = movegen::is_king_in_check(&self.board, my_color);
+            self.thread.search_nodes = 0;
+            self.excluded_root_moves.clear();
+            self.node_limit = 0;
+            self.search_score = if is_checkmate {-CHECKMATE_VALUE} else {0};
+            let score = Score::from_value(self.search_score);
+            self.output.write_line(&format!("info depth 0 score {}", score));
+            return SearchResult {
+                best_move: None,
+                ponder_move: None,
+                score,
+                depth: 0,
+                iterations: Vec::new(),
+                nodes: 0,
+                time_ms: 0,
+            };
+        }
+
+        // If depth is 0, then we're not using depth as a limiter.  Either
+        // way, the depth cannot exceed the ply-indexed search state.
+        if max_depth == 0 || max_depth as usize >= MAX_PLY {
+            max_depth = (MAX_PLY - 1) as u8;
         }
 
+        // Get the time we aim to spend on this move, and the most we may
+        // spend if the best move fails low
+        let (time_for_move, time_hard_limit) = get_time_limits(time_available, time_inc, moves_to_go);
+
         // Update start time and move time
-        self.move_start_time =  time::Instant::now();
+        self.thread.search_nodes = 0;
+        self.move_start_time = self.clock.now_ms();
         self.time_max_for_move = time_for_move as u128;
-        self.halt_check_countdown = CHECK_HALT_CONDITION_INTERVAL;
+        self.time_hard_limit_for_move = time_hard_limit as u128;
+        self.deadline = self.move_start_time + self.time_max_for_move;
+        self.thread.halt_check_countdown = self.get_halt_check_interval();
+        self.age_move_ordering_data();
 
         // Information about the last iteration
         let mut last_iteration_info: Option<BestMoveInformation> = None;
 
         // Start the clock for the first iteration
-        let mut start_time_iteration = time::Instant::now();
+        let mut start_time_iteration = self.clock.now_ms();
         
         // Start of iterative deepening loop
         let mut value: i32;
         let mut depth = 1;
         let mut alpha = -INF;
         let mut beta = INF;
-        let mut left_aspiration_window = ASPIRATION_WINDOW_HALF_SIZE;
-        let mut right_aspiration_window = ASPIRATION_WINDOW_HALF_SIZE;
+        let mut left_aspiration_window = self.params[PARAM_ASPIRATION_WINDOW];
+        let mut right_aspiration_window = self.params[PARAM_ASPIRATION_WINDOW];
+        let mut best_root_move: Option<(SearchMove, i32)> = None;
+        let mut root_move_scores = Vec::new();
+        let mut iterations = Vec::new();
         while depth <= max_depth {
 
-            // Store the max depth for this search
-            self.max_depth_for_search = depth;
-
             // Find the best move using negamax
-            value = self.negamax(depth, alpha, beta, true);
+            value = self.negamax(depth, alpha, beta, true, NodeType::Pv);
 
             // Check if this search was halted due to time or a stop command
             // and if so then ignore the results
-            if self.halt_search {
+            if self.thread.halt_search {
                 break;
             }
 
             // If our evaluation fell outside of the window, we have
-            // to redo the search at the same depth with a wider window
-            if value <= alpha {
-                alpha -= left_aspiration_window;
-                left_aspiration_window *= 2;
-                self.best_move_from_last_iteration = None;
-                continue;
-            } else if value >= beta {
-                beta += right_aspiration_window;
-                right_aspiration_window *= 2;
-                self.best_move_from_last_iteration = None;
+            // to redo the search at the same depth with a wider window.
+            // Only the side of the window that failed is widened, and on a
+            // fail low the upper bound is also brought down, since the
+            // score is likely to be lower than expected.  The bound found
+            // is reported while re-searching.
+            if value <= alpha || value >= beta {
+                let bound = if value <= alpha {"upperbound"} else {"lowerbound"};
+                let pv: Vec<SearchMove> = self.thread.pv_table[0][..self.thread.pv_length[0]].iter().flatten().copied().collect();
+                let mut line = format!("info depth {} score {} {} nodes {} time {}",
+                    depth,
+                    format_score(value),
+                    bound,
+                    self.thread.moves_analyzed,
+                    self.clock.now_ms() - start_time_iteration);
+                if !pv.is_empty() {
+                    line.push_str(&format!(" pv {}", self.format_pv(&pv)));
+                }
+                self.output.write_line(&line);
+                if value <= alpha {
+
+                    // The best move of the last iteration has failed low,
+                    // so allow extra time to resolve it rather than switch
+                    // to a move that was searched less deeply
+                    self.deadline = cmp::max(self.deadline, self.move_start_time + self.time_hard_limit_for_move);
+                    beta = (alpha + beta) / 2;
+                    alpha = cmp::max(value - left_aspiration_window, -INF);
+                    left_aspiration_window *= 2;
+                } else {
+
+                    // A move that failed high is better than the best move
+                    // of the last iteration, so keep it (with its score as
+                    // a lower bound) in case the search is halted before the
+                    // re-search completes
+                    if let Some(fail_high_move) = self.best_move_from_last_iteration {
+                        best_root_move = Some((fail_high_move, value));
+                    }
+                    beta = cmp::min(value + right_aspiration_window, INF);
+                    right_aspiration_window *= 2;
+                }
                 continue;
             }
 
             // End the clock for this iteration
-            let duration_iteration = start_time_iteration.elapsed();
+            let duration_iteration = self.clock.now_ms() - start_time_iteration;
 
             // Create a record for the iteration
             let info = BestMoveInformation {
                 best_move_from_last_iteration: self.best_move_from_last_iteration,
                 value,
-                moves_analyzed: self.moves_analyzed,
+                moves_analyzed: self.thread.moves_analyzed,
                 depth_searched: depth,
-                duration_of_search: duration_iteration.as_millis(),
-                pv_line: self.extract_pv_line(),
+                duration_of_search: duration_iteration,
+                pv_line: self.thread.pv_table[0][..self.thread.pv_length[0]].iter().flatten().copied().collect(),
             };
 
             // Per the UCI protocol, print "info" messages to standard out
-            println!("info depth {} score cp {} nodes {} time {} pv {}",
+            self.output.write_line(&format!("info depth {} score {} nodes {} time {} hashfull {} pv {}",
                 info.depth_searched,
-                info.value,
+                format_score(info.value),
                 info.moves_analyzed,
                 info.duration_of_search,
-                movegen::convert_move_list_to_lan(&info.pv_line));
+                self.get_hashfull(),
+                self.format_pv(&info.pv_line)));
 
-            // Store the record
-            last_iteration_info = Some(info);
+            // Track whether the best move changed in this iteration
+            let best_move_changed = match &last_iteration_info {
+                Some(last_info) => last_info.best_move_from_last_iteration != info.best_move_from_last_iteration,
+                None => false,
+            };
 
-            // Reset the aspiration window for the next depth
-            left_aspiration_window = ASPIRATION_WINDOW_HALF_SIZE;
-            right_aspiration_window = ASPIRATION_WINDOW_HALF_SIZE;
-            alpha = value - left_aspiration_window;
-            beta = value + right_aspiration_window;
+            // Store the record, and the best root move and its score, which
+            // are kept through the aspiration re-searches of the next depth
+            // (a fail low keeps the best move, as no other move beat it)
+            best_root_move = info.best_move_from_last_iteration.map(|m| (m, info.value));
+            iterations.push(SearchIteration {
+                depth: info.depth_searched,
+                score: Score::from_value(info.value),
+                nodes: info.moves_analyzed as u64,
+                time_ms: info.duration_of_search,
+                pv: info.pv_line.iter().map(|m| movegen::convert_move_list_to_lan(&vec!(*m)).trim().to_string()).collect(),
+            });
+            last_iteration_info = Some(info);
+            root_move_scores = self.thread.root_move_scores.clone();
+
+            // Reset the aspiration window for the next depth.  Mate scores
+            // change by whole plies as the mate gets closer or further, so
+            // a full window is used once a mate is found.
+            left_aspiration_window = self.params[PARAM_ASPIRATION_WINDOW];
+            right_aspiration_window = self.params[PARAM_ASPIRATION_WINDOW];
+            if !USE_ASPIRATION_WINDOWS || value.abs() >= MATE_BOUND {
+                alpha = -INF;
+                beta = INF;
+            } else {
+                alpha = value - left_aspiration_window;
+                beta = value + right_aspiration_window;
+            }
 
-            // Reset some state for next iteration
+            // Reset some state for next iteration, and decay the history
+            // scores so the next iteration's cut-offs count for more
             self.best_move_from_last_iteration = None;
-            self.moves_analyzed = 0;
+            self.decay_history();
+            self.thread.search_nodes += self.thread.moves_analyzed as u64;
+            self.thread.moves_analyzed = 0;
 
             // Don't start the next iteration if we don't have sufficient time.
             // We assume it will take at least 2x longer to search the next depth
             // compare to the depth just searched.
-            if self.move_start_time.elapsed().as_millis() + 2 * duration_iteration.as_millis() > self.time_max_for_move {
-                break;
+            // If the best move just changed, though, the new move has only
+            // been searched to this depth, so search another iteration to
+            // validate it, extending the time up to the hard limit.
+            let elapsed = self.clock.now_ms() - self.move_start_time;
+            if !self.pondering && elapsed + 2 * duration_iteration > self.time_max_for_move {
+                if !best_move_changed || elapsed >= self.time_hard_limit_for_move {
+                    break;
+                }
+                self.deadline = cmp::max(self.deadline, self.move_start_time + self.time_hard_limit_for_move);
             }
 
             // Increase depth
             depth += 1;
 
             // Start the clock for the next iteration
-            start_time_iteration = time::Instant::now();
+            start_time_iteration = self.clock.now_ms();
 
         }
 
-        // Clear out the transposition tables and search-specific state
-        self.transposition_table.clear();
-        self.transposition_table.resize_with(self.num_tt_entries, ||-> Option<TTEntry> {None});
-        self.halt_search = false;
-        self.time_max_for_move = 0;
-        self.halt_check_countdown = CHECK_HALT_CONDITION_INTERVAL;
-        self.primary_killers = [None; 100];
-        self.secondary_killers = [None; 100];
-        self.max_depth_for_search = 0;
-
-        // Get the best move
-        let mut bm = String::from("0000");
-        if let Some(info) = &last_iteration_info {
-            if let Some((move_start, move_end)) = info.best_move_from_last_iteration {
-                
-                // If pawn promotion, add the promotion piece
-                // TODO: Allow promotions to pieces other than queens.
-                let mut promotion = None;
-                if move_end >= 56 || move_end <= 7 {
-                    if let Some((_,p)) = self.board.get_color_and_piece_on_square(move_start as usize) {
-                        if p == pieces::PAWN {
-                            promotion = Some(pieces::QUEEN);
-                        }
-                    }
-                }
-            
-                let move_vec = vec!((move_start, move_end, promotion));
-                bm = movegen::convert_move_list_to_lan(&move_vec);
-
-            }
+        // The best move cannot be sent while pondering, so if the search
+        // finished early (e.g., it reached the maximum depth), wait for
+        // "ponderhit" or "stop"
+        while self.pondering && !self.stop_flag.load(Ordering::Relaxed) && self.ponder_flag.load(Ordering::Relaxed) {
+            self.clock.sleep_ms(1);
         }
+        self.pondering = false;
 
-        // Per the UCI protocol, print the best move to standard out
-        println!("bestmove {}", bm);
-        bm.trim().to_string()
-    }
+        // In debug mode, report the nodes and time of each depth, and the
+        // effective branching factor
+        if self.debug {
+            self.report_depth_stats(&iterations);
+        }
 
-    // This returns a priority bonus for move ordering if the move is
-    // a PV move or causes a beta cutoff (in other words, is a hash move).
-    // This is determined via lookup in the transposition table.
-    fn get_move_priority_bonus(&self, start_square: usize, end_square: usize) -> i32 {
-        let tt_key = (self.board.zobrist_hash % self.num_tt_entries as u64) as usize;
-        if let Some(tt_entry) = &self.transposition_table[tt_key] {
-            if tt_entry.valid && tt_entry.zobrist_hash == self.board.zobrist_hash {
-                if let Some((bm_start_square, bm_end_square)) = tt_entry.best_move {
-                    if bm_start_square == start_square as u8 && bm_end_square == end_square as u8 {
-                        match tt_entry.flag {
-                            TTFlag::Exact => return PV_MOVE_PRIORITY_BONUS,
-                            TTFlag::Lowerbound => return CUTOFF_PRIORITY_BONUS,
-                            TTFlag::Upperbound => return 0,
-                        }
-                    }
-                }
-            }
+        // Clear out search-specific state.  The transposition table is
+        // kept, so the next search (such as after the opponent's reply, or
+        // of the same position to a greater depth) starts from this one.
+        let has_exclusions = !self.excluded_root_moves.is_empty();
+        self.excluded_root_moves.clear();
+        self.node_limit = 0;
+        self.thread.halt_search = false;
+        self.thread.search_nodes += self.thread.moves_analyzed as u64;
+        self.thread.moves_analyzed = 0;
+        self.time_max_for_move = 0;
+        self.time_hard_limit_for_move = 0;
+        self.deadline = 0;
+        self.thread.halt_check_countdown = CHECK_HALT_CONDITION_INTERVAL;
+
+        // Get the best move, using the learning file if there is one (unless
+        // root moves were excluded, as the learned move may be one of them)
+        let ponder_move = match &last_iteration_info {
+            Some(info) if info.pv_line.len() >= 2 => Some((info.pv_line[0], info.pv_line[1])),
+            _ => None,
+        };
+        let depth_searched = last_iteration_info.map_or(0, |info| info.depth_searched);
+        let (mut best_move, mut value) = match best_root_move {
+            Some((m, root_value)) => (Some(m), root_value),
+            None => (None, 0),
+        };
+        if self.learning.is_some() && !has_exclusions {
+            (best_move, value) = self.apply_learning(best_move, value, depth_searched);
         }
-        0
-    }
+        self.search_score = value;
+        if let Some(limit) = self.strength {
+            best_move = self.choose_weaker_move(best_move, value, &root_move_scores, &limit);
+        }
+        let best_move = best_move.or(first_legal_move).expect("No legal move at the root");
 
-    // Add any "blockers" on the specified ray, except for the target.
-    // Used for SEE attacker computation.
-    fn add_blocks_to_see_attacker(&self, capture_square: usize, ray: u64, blockers: &mut Vec<usize>) {
-        for s in bitboard::occupied_squares(ray & self.board.bb_occupied_squares) {
-            if s != capture_square {
-                blockers.push(s);
-            }
+        // The expected reply from the PV is given for the GUI to ponder on,
+        // if the PV starts with the best move
+        let ponder_move = match ponder_move {
+            Some((pv_move, reply)) if pv_move == best_move => Some(movegen::convert_move_list_to_lan(&vec!(reply)).trim().to_string()),
+            _ => None,
+        };
+        SearchResult {
+            best_move: Some(movegen::convert_move_list_to_lan(&vec!(best_move)).trim().to_string()),
+            ponder_move,
+            score: Score::from_value(value),
+            depth: depth_searched,
+            iterations,
+            nodes: self.thread.search_nodes,
+            time_ms: self.clock.now_ms() - self.move_start_time,
         }
     }
 
-    // Check all four directional "rays" starting from our position to see
-    // if we hit the target, assuming no blocking pieces exist.  Return true
-    // if we did, false if not.  Along the way, collect any blockers for the
-    // ray (if there is one) that hits the target.
-    fn check_bishop_for_see_attack(&self, square: usize, capture_square: usize, capture_square_bb: u64, blockers: &mut Vec<usize>) -> bool {
-        let mut test_ray = movegen::get_diagonal_attacks_bb(capture_square_bb, square, 1);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+    // Report the node count and time of each completed depth of a search,
+    // and the effective branching factor over them
+    fn report_depth_stats(&self, iterations: &[SearchIteration]) {
+        for iteration in iterations {
+            self.output.write_line(&format!("info string depth {} nodes {} time {}", iteration.depth, iteration.nodes, iteration.time_ms));
         }
-        test_ray = movegen::get_diagonal_attacks_bb(capture_square_bb, square, 2);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
-        }
-        test_ray = movegen::get_antidiagonal_attacks_bb(capture_square_bb, square, 1);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+        let depth_nodes: Vec<u64> = iterations.iter().map(|iteration| iteration.nodes).collect();
+        if let Some(ebf) = get_effective_branching_factor(&depth_nodes) {
+            self.output.write_line(&format!("info string ebf {:.2}", ebf));
         }
-        test_ray = movegen::get_antidiagonal_attacks_bb(capture_square_bb, square, 2);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+    }
+
+    // Format a PV from the root for an "info" message, in the notation
+    // chosen by the PV Notation option
+    fn format_pv(&self, pv: &Vec<SearchMove>) -> String {
+        if self.pv_san {
+            pgn::convert_move_list_to_san(&self.board, pv)
+        } else {
+            movegen::convert_move_list_to_lan(pv)
         }
-        return false;
     }
 
-    // Check all four directional "rays" starting from our position to see
-    // if we hit the target, assuming no blocking pieces exist.  Return true
-    // if we did, false if not.  Along the way, collect any blockers for the
-    // ray (if there is one) that hits the target.
-    fn check_rook_for_see_attack(&self, square: usize, capture_square: usize, capture_square_bb: u64, blockers: &mut Vec<usize>) -> bool {
-        let mut test_ray = movegen::get_file_attacks_bb(capture_square_bb, square, 1);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+    // When playing at limited strength, sometimes replace the best move with
+    // a random other move whose score at the root was within the margin of
+    // the best move's score
+    fn choose_weaker_move(&mut self, best_move: Option<SearchMove>, value: i32, root_move_scores: &[(SearchMove, i32)], limit: &strength::StrengthLimit) -> Option<SearchMove> {
+        if self.random.next_f64() >= limit.suboptimal_chance {
+            return best_move;
         }
-        test_ray = movegen::get_file_attacks_bb(capture_square_bb, square, 2);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+        let candidates: Vec<SearchMove> = root_move_scores.iter()
+            .filter(|(m, score)| Some(*m) != best_move && *score >= value - limit.suboptimal_margin)
+            .map(|(m, _)| *m)
+            .collect();
+        if candidates.is_empty() {
+            return best_move;
         }
-        test_ray = movegen::get_rank_attacks_bb(capture_square_bb, square, 1);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+        Some(candidates[(self.random.next_u64() % candidates.len() as u64) as usize])
+    }
+
+    // Blend the learning file into the result of a search of the root
+    // position.  If the position was searched deeper in an earlier game,
+    // the learned move and score are used instead of the search result.
+    // Otherwise, the search result is learned.
+    fn apply_learning(&mut self, best_move: Option<SearchMove>, value: i32, depth_searched: u8) -> (Option<SearchMove>, i32) {
+        let key = self.board.zobrist_hash;
+        let learning = self.learning.as_mut().expect("Learning is not enabled");
+        if let Some(learned) = learning.probe(key) {
+            if learned.depth > depth_searched {
+                let (start_square, end_square, promotion_piece) = learned.best_move;
+                if let Some(m) = movegen::get_pseudo_legal_move(&self.board, start_square as usize, end_square as usize, promotion_piece) {
+                    if movegen::is_legal_move(&mut self.board, &m) {
+                        self.output.write_line(&format!("info string using learned depth {} score {}", learned.depth, format_score(learned.value)));
+                        return (Some(learned.best_move), learned.value);
+                    }
+                }
+            }
         }
-        test_ray = movegen::get_rank_attacks_bb(capture_square_bb, square, 2);
-        if test_ray & capture_square_bb != 0 {
-            self.add_blocks_to_see_attacker(capture_square, test_ray, blockers);
-            return true;
+        if let Some(m) = best_move {
+            if let Err(e) = learning.record(key, learning::LearnedPosition {depth: depth_searched, value, best_move: m}) {
+                self.output.write_line(&format!("info string error: {}", e));
+            }
         }
-        return false;
+        (best_move, value)
+    }
+
+    // This returns the hash move (a PV move or a move that caused a beta
+    // cutoff) for the current board from the transposition table, along
+    // with its priority bonus for move ordering.  Since entries can collide,
+    // the move is only returned if the piece on its start square belongs to
+    // the side to move and it is pseudo-legal in the current position (the
+    // search checks legality before making any move), so a foreign move can
+    // never pick up the bonus or reach SEE with a bogus capture.
+    fn get_hash_move(&self) -> Option<(SearchMove, i32)> {
+        let tt_key = self.get_tt_index();
+        let (bm_start_square, bm_end_square, bm_promotion_piece, bonus) = match &self.transposition_table[tt_key] {
+            Some(tt_entry) if tt_entry.zobrist_hash == self.board.zobrist_hash => {
+                let bonus = match tt_entry.flag {
+                    TTFlag::Exact => PV_MOVE_PRIORITY_BONUS,
+                    TTFlag::Lowerbound => CUTOFF_PRIORITY_BONUS,
+                    TTFlag::Upperbound => return None,
+                };
+                let (start_square, end_square, promotion_piece) = tt_entry.best_move?;
+                (start_square, end_square, promotion_piece.map(|p| p as usize), bonus)
+            },
+            _ => return None,
+        };
+        movegen::get_pseudo_legal_move(&self.board, bm_start_square as usize, bm_end_square as usize, bm_promotion_piece)?;
+        Some(((bm_start_square, bm_end_square, bm_promotion_piece), bonus))
     }
 
     // Perform static exchange evaluation (SEE) for a particular capture move.
@@ -542,196 +1467,329 @@ impl SearchEngine {
     // Scores greater than or equal to 0 are worth searching further because they
     // could be winning captures.  Scores less than 0 are likely loosing captures
     // and hence less worthy of further search.
+    // A quiet move may also be evaluated, as a capture of nothing, in which
+    // case a score less than 0 means the moved piece is likely to be lost.
     // Note that only a simulation is performed here; we do not actually "make_move".
+    // This uses the swap algorithm: starting with a bitboard of all attackers
+    // of the capture square, the least valuable attacker of the side to move
+    // captures next, and any sliding pieces behind it (x-rays) are added.
+    // See https://www.chessprogramming.org/SEE_-_The_Swap_Algorithm
     fn see_capture_eval(&self, capture_move: &movegen::ChessMove) -> i32 {
 
-        // Extract captured piece
-        let cap_piece = if let Some(c) = capture_move.captured_piece {
-            c
-        } else {
-            panic!("Attempting SEE on non-capture move")
-        };
+        // Extract the value of the captured piece
+        let cap_value = capture_move.captured_piece.map_or(0, |c| SEE_PIECE_VALUES[c]);
+
+        // Sliding pieces that can be uncovered as x-ray attackers
+        let bb = &self.board.bb_pieces;
+        let bishops_queens = bb[pieces::COLOR_WHITE][pieces::BISHOP] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::BISHOP] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
+        let rooks_queens = bb[pieces::COLOR_WHITE][pieces::ROOK] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::ROOK] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
 
-        // TODO - factor in en passant movement.  For now, we're going to just
-        // be safe and assume all en passant captures are worth searching.
+        // For en passant, the captured pawn is not on the target square,
+        // so remove it from the occupancy before finding attackers, as it
+        // may have been blocking a slider on the same file
+        let target = capture_move.end_square;
+        let mut occ = self.board.bb_occupied_squares;
         if capture_move.is_en_passant {
-            return 1;
-        }
-
-        // Create a bitboard representing the capture square
-        let capture_square_bb = bitboard::to_bb(capture_move.end_square);
-
-        // Make a list of all attackers of the target square,
-        // even if there are nodes blocking the way (for sliding piece
-        // attackers).   We will note those blockers.  We will then simulate
-        // the attack sequence using the least valuable piece remaining for
-        // each side, removing potential blockers as we go, until there are
-        // no more attackers left.
-        // The following vector contains two other vectors -- one for white
-        // and one for black.  Each of these color vectors is a list of
-        // SEE_Attacker entries which indicate the piece value, square of
-        // the piece, and any blockers.
-        let mut attackers = vec![Vec::new(), Vec::new()];
-        for color in 0..2 {
-            for (piece, bb) in self.board.bb_pieces[color].iter().enumerate() {
-                for square in bitboard::occupied_squares(*bb) {
+            let captured_square = if capture_move.start_square < target {target - 8} else {target + 8};
+            occ ^= bitboard::to_bb(captured_square);
+        }
 
-                    // Skip the initial capture; we'll simulate that
-                    // seperately to kick things off.
-                    if square == capture_move.start_square {
-                        continue;
-                    }
+        // The gain list holds the speculative material gain after each
+        // capture in the sequence, from the perspective of the capturing side
+        let mut gain: [i32; 32] = [0; 32];
+        let mut depth = 0;
+        let mut attackers = movegen::attackers_to(&self.board, target, occ);
+        let mut from_square = capture_move.start_square;
+        let mut attacking_piece = capture_move.piece;
+        let mut current_turn_color = if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        gain[0] = cap_value;
+
+        // A promotion capture gains the difference between the promoted
+        // piece and the pawn, and leaves the promoted piece on the square
+        if let Some(promotion_piece) = capture_move.promotion_piece {
+            gain[0] += SEE_PIECE_VALUES[promotion_piece] - SEE_PIECE_VALUES[pieces::PAWN];
+            attacking_piece = promotion_piece;
+        }
+        let is_promotion_square = !(8..56).contains(&target);
 
-                    // Store blockers
-                    let mut blockers = Vec::new();
-
-                    // Get the attack bitboard of the appropriate piece
-                    let mut capture_attacker_bb = 0;
-                    if piece == pieces::PAWN {
-                        capture_attacker_bb = bitboard::BB_PAWN_ATTACKS[color][square];
-                    } else if piece == pieces::KNIGHT {
-                        capture_attacker_bb = bitboard::BB_KNIGHT_ATTACKS[square]
-                    } else if piece == pieces::BISHOP {
-                        if self.check_bishop_for_see_attack(square, capture_move.end_square, capture_square_bb, &mut blockers) {
-                            // We only have to indicate that we've attacked the square
-                            capture_attacker_bb = capture_square_bb;
-                        }
-                    } else if piece == pieces::ROOK {
-                        if self.check_rook_for_see_attack(square, capture_move.end_square, capture_square_bb, &mut blockers) {
-                            // We only have to indicate that we've attacked the square
-                            capture_attacker_bb = capture_square_bb;
-                        }
-                    } else if piece == pieces::QUEEN {
-                        if self.check_bishop_for_see_attack(square, capture_move.end_square, capture_square_bb, &mut blockers) {
-                            // We only have to indicate that we've attacked the square
-                            capture_attacker_bb = capture_square_bb;
-
-                        } else if self.check_rook_for_see_attack(square, capture_move.end_square, capture_square_bb, &mut blockers) {
-                            // We only have to indicate that we've attacked the square
-                            capture_attacker_bb = capture_square_bb;
-                        }
-                    } else if piece == pieces::KING {
-                        capture_attacker_bb = bitboard::BB_KING_ATTACKS[square]
-                    }
+        // Simulate captures one by one until there are none left
+        loop {
 
-                    // Determine if the piece is attacking the captured square.
-                    // Note that bitbord is 0 otherwise, which will bypass
-                    // this if statement.
-                    if capture_attacker_bb & capture_square_bb != 0 {
-                        attackers[color].push(SEEAttacker {
-                            value: SEE_PIECE_VALUES[piece],
-                            square,
-                            blockers,
-                        });
-                    }
+            // The attacking piece is now on the target square, and may be
+            // captured by the other side
+            depth += 1;
+            gain[depth] = SEE_PIECE_VALUES[attacking_piece] - gain[depth - 1];
+
+            // Remove the attacker, and add any x-ray attacker along the
+            // line it was on
+            let from_bb = bitboard::to_bb(from_square);
+            occ ^= from_bb;
+            attackers &= !from_bb;
+            let line = bitboard::BB_LINE[from_square][target];
+            if line != 0 {
+                if line == bitboard::BB_RANK_MASK[target] || line == bitboard::BB_FILES[target % 8] {
+                    attackers |= movegen::get_rook_attacks_bb(occ, target) & line & rooks_queens & occ;
+                } else {
+                    attackers |= movegen::get_bishop_attacks_bb(occ, target) & line & bishops_queens & occ;
+                }
+            }
 
+            // Get the least valuable attacker of the other side
+            current_turn_color = 1 - current_turn_color;
+            let mut next_attacker = None;
+            for (piece, piece_bb) in bb[current_turn_color].iter().enumerate() {
+                if let Some(s) = bitboard::bit_scan_forward(attackers & piece_bb) {
+                    next_attacker = Some((s, piece));
+                    break;
                 }
             }
+
+            // If we couldn't find a suitable attacker, we're done
+            match next_attacker {
+                Some((s, piece)) => {
+                    from_square = s;
+                    attacking_piece = piece;
+
+                    // A pawn recapturing on the last rank promotes (assume
+                    // to a queen)
+                    if piece == pieces::PAWN && is_promotion_square {
+                        gain[depth] += SEE_PIECE_VALUES[pieces::QUEEN] - SEE_PIECE_VALUES[pieces::PAWN];
+                        attacking_piece = pieces::QUEEN;
+                    }
+                },
+                None => break,
+            }
+
+            // Guard against overflowing the gain list
+            if depth >= gain.len() - 1 {
+                break;
+            }
+
+        }
+
+        // Finally, evaluate the scores, taking into account the option for a
+        // player to refuse to continue the capture line, and return in
+        // centipawns
+        while depth > 1 {
+            depth -= 1;
+            gain[depth - 1] = -cmp::max(-gain[depth - 1], gain[depth]);
         }
+        gain[0]
 
-        // Sort the attackers from least to most valuable (we're always going
-        // to attack with the least valuable piece first.
-        attackers[pieces::COLOR_WHITE].sort_unstable_by(|a, b| a.value.cmp(&b.value));
-        attackers[pieces::COLOR_BLACK].sort_unstable_by(|a, b| a.value.cmp(&b.value));
+    }
 
-        // Simulate the initial capture
-        let my_color = if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
-        let mut current_turn_color = my_color;
-        let mut scores = Vec::new();
-        let mut score = SEE_PIECE_VALUES[cap_piece];
-        let mut attacked_piece_value = SEE_PIECE_VALUES[capture_move.piece];
-        scores.push(score);
-        let mut selected_attacker_square = Some(capture_move.start_square);
-        let mut selected_attacker_value = SEE_PIECE_VALUES[capture_move.piece];
+    // Check whether the static exchange evaluation of a move (a capture, or
+    // a quiet move as a capture of nothing) is at least the threshold.  This
+    // gives the same answer as comparing see_capture_eval to the threshold,
+    // but is cheaper, as it stops simulating captures as soon as one side
+    // cannot change the answer.  The balance is the material won relative
+    // to the threshold, from the perspective of the side making the move.
+    // See https://www.chessprogramming.org/SEE_-_The_Swap_Algorithm
+    fn see_ge(&self, m: &movegen::ChessMove, threshold: i32) -> bool {
+
+        // The balance after the move, and the value of the piece that can
+        // be captured in return
+        let mut balance = m.captured_piece.map_or(0, |c| SEE_PIECE_VALUES[c]) - threshold;
+        let mut at_risk = SEE_PIECE_VALUES[m.piece];
+        if let Some(promotion_piece) = m.promotion_piece {
+            balance += SEE_PIECE_VALUES[promotion_piece] - SEE_PIECE_VALUES[pieces::PAWN];
+            at_risk = SEE_PIECE_VALUES[promotion_piece];
+        }
+        if balance < 0 {
+            return false;
+        }
 
-        // Switch turns in the simulation
-        current_turn_color = 1 - current_turn_color;
+        // If the balance holds even after losing the moved piece, the answer
+        // is known, unless a pawn can recapture and promote
+        let target = m.end_square;
+        let is_promotion_square = !(8..56).contains(&target);
+        if balance - at_risk >= 0 && !is_promotion_square {
+            return true;
+        }
+
+        // Set up the attackers as in see_capture_eval
+        let bb = &self.board.bb_pieces;
+        let bishops_queens = bb[pieces::COLOR_WHITE][pieces::BISHOP] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::BISHOP] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
+        let rooks_queens = bb[pieces::COLOR_WHITE][pieces::ROOK] | bb[pieces::COLOR_WHITE][pieces::QUEEN] | bb[pieces::COLOR_BLACK][pieces::ROOK] | bb[pieces::COLOR_BLACK][pieces::QUEEN];
+        let mut occ = self.board.bb_occupied_squares;
+        if m.is_en_passant {
+            let captured_square = if m.start_square < target {target - 8} else {target + 8};
+            occ ^= bitboard::to_bb(captured_square);
+        }
+        let mut attackers = movegen::attackers_to(&self.board, target, occ);
+        let mut from_square = m.start_square;
+        let my_color = self.color_turn();
+        let mut current_turn_color = my_color;
 
-        // Simulate captures one by one until there are none left
         loop {
 
-            // Remove the attacker as a blocker
-            if let Some(sa) = selected_attacker_square {
-                for color in 0..2 {
-                    for i in attackers[color].iter_mut() {
-                        if let Some(pos) = i.blockers.iter().position(|x| *x == sa) {
-                            i.blockers.remove(pos);
-                        }
-                    }
+            // Remove the last attacker, and add any x-ray attacker along the
+            // line it was on
+            let from_bb = bitboard::to_bb(from_square);
+            occ ^= from_bb;
+            attackers &= !from_bb;
+            let line = bitboard::BB_LINE[from_square][target];
+            if line != 0 {
+                if line == bitboard::BB_RANK_MASK[target] || line == bitboard::BB_FILES[target % 8] {
+                    attackers |= movegen::get_rook_attacks_bb(occ, target) & line & rooks_queens & occ;
+                } else {
+                    attackers |= movegen::get_bishop_attacks_bb(occ, target) & line & bishops_queens & occ;
                 }
             }
 
-            // Get the next non-blocked attacker
-            selected_attacker_square = None;
-            let mut attacker_pos = 0;
-            for i in attackers[current_turn_color].iter() {
-                if i.blockers.is_empty() {
-                    selected_attacker_square = Some(i.square);
-                    selected_attacker_value = i.value;
+            // Get the least valuable attacker of the other side.  If there
+            // is none, the balance stands.
+            current_turn_color = 1 - current_turn_color;
+            let mut next_attacker = None;
+            for (piece, piece_bb) in bb[current_turn_color].iter().enumerate() {
+                if let Some(s) = bitboard::bit_scan_forward(attackers & piece_bb) {
+                    next_attacker = Some((s, piece));
                     break;
                 }
-                attacker_pos += 1;
             }
-
-            // If we couldn't find a suitable attacker, we're done
-            if selected_attacker_square.is_none() {
-                break;
+            let (s, mut piece) = match next_attacker {
+                Some(a) => a,
+                None => return balance >= 0,
+            };
+            let mut promotion_gain = 0;
+            if piece == pieces::PAWN && is_promotion_square {
+                promotion_gain = SEE_PIECE_VALUES[pieces::QUEEN] - SEE_PIECE_VALUES[pieces::PAWN];
+                piece = pieces::QUEEN;
             }
 
-            // Update the score with this attack and remove the attacker
-            if let Some(l) = scores.last() {
-                score = attacked_piece_value - l;
+            // The side to move either stands on the balance or captures.
+            // Stop if the balance is already good enough for it, or if even
+            // capturing (without losing the capturing piece) is not enough
+            // to change the answer.
+            if current_turn_color == my_color {
+                if balance >= 0 {
+                    return true;
+                }
+                balance += at_risk + promotion_gain;
+                if balance < 0 {
+                    return false;
+                }
             } else {
-                panic!("Cannot find last score");
+                if balance < 0 {
+                    return false;
+                }
+                balance -= at_risk + promotion_gain;
+                if balance >= 0 {
+                    return true;
+                }
             }
-            attacked_piece_value = selected_attacker_value;
-            scores.push(score);
-            attackers[current_turn_color].remove(attacker_pos);
-            current_turn_color = 1 - current_turn_color;
+            from_square = s;
+            at_risk = SEE_PIECE_VALUES[piece];
 
         }
 
-        // Finally, evaluate the scores, taking into account the option for a
-        // player to refuse to continue the capture line, and return in
-        // centipawns
-        for i in (1..scores.len()).rev() {
-            if scores[i-1] > -scores[i] {
-                scores[i-1] = -scores[i];
-            }
+    }
+
+    // Age the move ordering data from previous searches, so it stays useful
+    // across consecutive "go" commands.  Killer moves are indexed by ply
+    // from the root, so they are shifted by the number of plies the game has
+    // advanced since the last search (or cleared if the position is not a
+    // continuation of it).  History scores are decayed.
+    fn age_move_ordering_data(&mut self) {
+        let root_history_len = self.board.zobrist_history.len();
+        if root_history_len >= self.root_history_len && root_history_len - self.root_history_len < MAX_PLY {
+            let plies = root_history_len - self.root_history_len;
+            self.thread.primary_killers.copy_within(plies.., 0);
+            self.thread.secondary_killers.copy_within(plies.., 0);
+            self.thread.primary_killers[MAX_PLY - plies..].fill(None);
+            self.thread.secondary_killers[MAX_PLY - plies..].fill(None);
+        } else {
+            self.thread.primary_killers = [None; MAX_PLY];
+            self.thread.secondary_killers = [None; MAX_PLY];
+        }
+        self.root_history_len = root_history_len;
+        self.decay_history();
+    }
+
+    // Halve all history scores (including countermove history), so that
+    // recent cut-offs count for more than older ones
+    fn decay_history(&mut self) {
+        for score in self.thread.history.iter_mut().flatten().flatten() {
+            *score /= 2;
+        }
+        for score in self.thread.countermove_history.iter_mut().flatten().flatten() {
+            *score /= 2;
+        }
+    }
+
+    // Get the index of the previous move into the countermove history, from
+    // the color that made it and its piece and end square, or None if there
+    // is no previous move
+    fn get_countermove_index(&self) -> Option<usize> {
+        let (piece, end_square) = self.board.get_last_move()?;
+        let color = 1 - self.color_turn();
+        Some((color * 6 + piece) * 64 + end_square)
+    }
+
+    // Apply a bonus (or penalty) to the history and countermove history
+    // scores of a quiet move, given as (start square, end square, piece)
+    fn update_quiet_history(&mut self, color: usize, countermove_index: Option<usize>, quiet_move: (usize, usize, usize), bonus: i32) {
+        let (start_square, end_square, piece) = quiet_move;
+        let (from, to) = get_history_index(start_square, end_square, piece);
+        apply_history_bonus(&mut self.thread.history[color][from][to], bonus);
+        if let Some(index) = countermove_index {
+            apply_history_bonus(&mut self.thread.countermove_history[index][piece][end_square], bonus);
         }
-        scores[0]
-        
     }
 
     // This scores moves, assigning a priority (higher is better)
     // Priority from high to low is: (1) PV moves, (2) moves that cause
-    // a beta cut-off, (3) captures, sorted by MVV-LVA, (4) killer moves,
-    // and (5) all other moves.
-    fn score_moves(&self, moves: &mut Vec<movegen::ChessMove>, ply: u8) {
+    // a beta cut-off, (3) captures that don't lose material, sorted by SEE
+    // value and then MVV-LVA, (4) killer moves, (5) all other quiet moves,
+    // sorted by history and countermove history scores (with pawn pushes
+    // first), and (6) captures that lose material.
+    fn score_moves(&self, moves: &mut movegen::MoveList, ply: usize) {
+
+        // Check the transposition table for PV and cut-off moves
+        let hash_move = self.get_hash_move();
+        let color = self.color_turn();
+        let countermove_index = self.get_countermove_index();
 
         // Assign a priority to all moves
         for m in moves.iter_mut() {
 
-            // Check the transposition table for PV and cut-off moves
-            let mut priority = self.get_move_priority_bonus(m.start_square, m.end_square);
+            let mut priority = match hash_move {
+                Some((hm, bonus)) if hm == (m.start_square as u8, m.end_square as u8, m.promotion_piece) => bonus,
+                _ => 0,
+            };
 
-            // Check for promotions, captures, and killer moves
+            // Check for promotions, captures, and killer moves.  Note that
+            // underpromotions are rarely good, so they get no promotion bonus.
             if priority == 0 {
-                if m.piece == pieces::PAWN && (m.end_square / 8 == 0 || m.end_square / 8 == 7) {
+                if m.promotion_piece == Some(pieces::QUEEN) {
                     priority = PROMOTION_PRIORITY_BONUS;
                 } else if let Some(cap) = m.captured_piece {
-                    priority = CAPTURE_PRIORITY_BONUS + pieces::MVV_LVA[cap][m.piece];
-                } else {
-                    let cur_move = Some((m.start_square as u8, m.end_square as u8));
-                    if cur_move == self.primary_killers[ply as usize] || cur_move == self.secondary_killers[ply as usize] {
+                    let see = self.see_capture_eval(m);
+                    let bonus = if see >= 0 {CAPTURE_PRIORITY_BONUS} else {LOSING_CAPTURE_PRIORITY};
+                    priority = bonus + get_capture_order(see, cap, m.piece);
+                } else if m.promotion_piece.is_none() {
+                    let cur_move = Some((m.start_square as u8, m.end_square as u8, m.promotion_piece));
+                    if cur_move == self.thread.primary_killers[ply] || cur_move == self.thread.secondary_killers[ply] {
                         priority = KILLER_MOVE_BONUS;
                     }
                 }
             }
 
-            // Give a slight edge to pawn pushes
-            if priority == 0 && m.piece == pieces::PAWN {
-                priority = PAWN_PUSH_BONUS;
+            // Give a slight edge to pawn pushes, and order the remaining
+            // quiet moves by the sum of their history and countermove
+            // history scores, limited to the range of a single score
+            if priority == 0 {
+                if m.piece == pieces::PAWN {
+                    priority = PAWN_PUSH_BONUS;
+                }
+                if m.captured_piece.is_none() && m.promotion_piece.is_none() {
+                    let (from, to) = get_history_index(m.start_square, m.end_square, m.piece);
+                    let mut score = self.thread.history[color][from][to];
+                    if let Some(index) = countermove_index {
+                        score += self.thread.countermove_history[index][m.piece][m.end_square];
+                    }
+                    priority += (score * (PAWN_PUSH_BONUS - 1) / HISTORY_MAX).clamp(1 - PAWN_PUSH_BONUS, PAWN_PUSH_BONUS - 1);
+                }
             }
 
             // Set priority
@@ -744,7 +1802,7 @@ impl SearchEngine {
     // Place the ith highest priority move in the ith slot.
     // Values in positions less than i have already been sorted / used so
     // we only have to check moves at and to the right of i.
-    fn sort_move_with_priority(&self, moves: &mut Vec<movegen::ChessMove>, i: usize) {
+    fn sort_move_with_priority(&self, moves: &mut movegen::MoveList, i: usize) {
         let mut highest_value = moves[i].priority;
         let mut highest_index = i;
         for index in (i+1)..moves.len() {
@@ -759,80 +1817,197 @@ impl SearchEngine {
         }
     }
 
+    // Check if the search should halt, due to a stop command or running out
+    // of time.  For performance reasons, the clock is not read on every call.
+    fn check_halt(&mut self) -> bool {
+        if self.thread.halt_search {
+            return true;
+        }
+        if self.stop_flag.load(Ordering::Relaxed) {
+            self.thread.halt_search = true;
+            return true;
+        }
+        if self.pondering {
+            if self.ponder_flag.load(Ordering::Relaxed) {
+                return false;
+            }
+            self.ponderhit();
+        }
+        let nodes = self.thread.search_nodes + self.thread.moves_analyzed as u64;
+        if self.node_limit > 0 && nodes >= self.node_limit {
+            self.thread.halt_search = true;
+            return true;
+        }
+        if let Some(limit) = &self.strength {
+            if nodes >= limit.node_limit {
+                self.thread.halt_search = true;
+                return true;
+            }
+        }
+        self.thread.halt_check_countdown -= 1;
+        if self.thread.halt_check_countdown == 0 {
+            self.thread.halt_check_countdown = self.get_halt_check_interval();
+            if self.nps_limit > 0 {
+                self.throttle();
+            }
+            if self.clock.now_ms() > self.deadline {
+                self.thread.halt_search = true;
+                return true;
+            }
+        }
+        false
+    }
+
+    // Turn a pondering search into a normal timed search when the opponent
+    // plays the expected move.  The time spent pondering is credited to this
+    // move, so the search continues to the deadline counted from the start
+    // of pondering (but at least a short while, for the current iteration).
+    fn ponderhit(&mut self) {
+        self.pondering = false;
+        let now = self.clock.now_ms();
+        self.deadline = cmp::max(self.deadline, now + MIN_PONDERHIT_TIME_MS);
+        self.time_max_for_move = cmp::max(self.time_max_for_move, now + MIN_PONDERHIT_TIME_MS - self.move_start_time);
+    }
+
+    // Get the number of nodes between checks of the clock.  When the speed
+    // is limited, the clock is checked about 100 times per second, so that
+    // the pauses to throttle the search are short and spread out.
+    fn get_halt_check_interval(&self) -> u64 {
+        if self.nps_limit > 0 {
+            (self.nps_limit / 100).clamp(1, CHECK_HALT_CONDITION_INTERVAL)
+        } else {
+            CHECK_HALT_CONDITION_INTERVAL
+        }
+    }
+
+    // Pause the search if it is ahead of the node rate allowed by the
+    // nodes per second limit, without sleeping past the deadline
+    fn throttle(&self) {
+        let nodes = self.thread.search_nodes + self.thread.moves_analyzed as u64;
+        let target_ms = (nodes as u128) * 1000 / (self.nps_limit as u128);
+        let now = self.clock.now_ms();
+        let elapsed = now - self.move_start_time;
+        if target_ms > elapsed {
+            let pause = cmp::min(target_ms - elapsed, self.deadline.saturating_sub(now));
+            self.clock.sleep_ms(pause as u64);
+        }
+    }
+
     // This is an implementation of the quiescence search, which allows
     // the engine to keep searching "non-quiet" (i.e, capture) moves
     // beyond the search horizon.  This is done to mitigate the horizon
     // effect, which may cause a bad decision to be made right at the edge
-    // of the search horizon.
+    // of the search horizon.  In the first plies (qply is the number of
+    // plies into the quiescence search), quiet checks are also searched, so
+    // that short mating attacks at the horizon are found.  A side in check
+    // cannot stand pat, so all of its evasions are searched instead.
     // See https://www.chessprogramming.org/Quiescence_Search
-    fn quiesce(&mut self, mut alpha: i32, beta: i32) -> i32 {
+    fn quiesce(&mut self, mut alpha: i32, beta: i32, qply: usize) -> i32 {
         
         // Before doing any searching, check to make sure we're not
-        // halting.  For performance reasons, we won't check this
-        // condition on every quiesce call.
-        if self.halt_search {
+        // halting
+        if self.check_halt() {
             return 0;
         }
-        self.halt_check_countdown -= 1;
-        if self.halt_check_countdown <= 0 {
-            self.halt_check_countdown = CHECK_HALT_CONDITION_INTERVAL;
-
-            // Check if we should halt due to time
-            if self.move_start_time.elapsed().as_millis() > self.time_max_for_move {
-                self.halt_search = true;
-                return 0;
-            }
-
-            // Check if we should halt due to a stop command
-            for cmd in self.rx_channel.try_iter() {
-                if cmd.trim() == "stop" {
-                    self.halt_search = true;
-                    return 0;
-                }
-            }
 
+        // This is our stand pat score, which is the current score
+        // of the board without additional moves.  This is the same
+        // evaluation used everywhere else in the search: it is from the
+        // point of view of the side to move and includes the tempo bonus
+        // for that side.
+        let stand_pat = self.get_corrected_evaluation();
+
+        // Keep the quiescence search from going on indefinitely
+        if qply >= QSEARCH_MAX_PLIES {
+            return stand_pat;
         }
 
-        // This is our stand pat score, which is the current score
-        // of the board without additional moves.
-        let stand_pat = evaluate::static_evaluation(&self.board);
+        // When in check, the stand pat score is meaningless, since the
+        // position may be lost, so there is no beta cut-off on it or delta
+        // pruning, and the best score starts as being checkmated.  This is
+        // the score returned if there are no legal evasions.
+        let my_color = if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let in_check = movegen::is_king_in_check(&self.board, my_color);
+        let mut best_value = if in_check {
+            let ply = self.board.zobrist_history.len() - self.root_history_len;
+            -CHECKMATE_VALUE + ply as i32
+        } else {
+            stand_pat
+        };
 
         // Check for a beta cut-off
-        if stand_pat >= beta {
-            return beta;
+        if best_value >= beta {
+            return best_value;
         }
 
-        // Delta pruning
+        // Delta pruning skips captures that cannot raise alpha even if the
+        // captured piece is won for free, with some margin for positional
+        // gains.  The stand pat score includes our tempo bonus, which is lost
+        // once we make a capture, so it is removed from the margin.  In late
+        // endgames, where a single capture is more likely to change the
+        // outcome, delta pruning is disabled.
         // See https://www.chessprogramming.org/Delta_Pruning
-        if stand_pat < alpha - pieces::PIECE_VALUES_MG[pieces::QUEEN] {
-            return alpha;
-        }
+        let use_delta_pruning = USE_DELTA_PRUNING && !in_check && self.board.phase_material > DELTA_PRUNING_MIN_PHASE_MATERIAL;
+        let delta_margin = self.params[PARAM_DELTA_PRUNING_MARGIN] - evaluate::get_tapered_value(&self.board, evaluate::TEMPO_BONUS[0], evaluate::TEMPO_BONUS[1]);
 
         // Increase alpha if our stand pat score is high enough
-        if alpha < stand_pat {
-            alpha = stand_pat;
+        if alpha < best_value {
+            alpha = best_value;
         }
 
-        // Generate all moves.  Note that we will only search
-        // capture moves.
-        let my_color = if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
-        let mut moves = movegen::generate_all_psuedo_legal_moves(&self.board, my_color, true);
+        // Generate all evasions if in check.  Otherwise, generate the
+        // capture moves, and in the first plies the quiet moves that give
+        // check.
+        let mut moves = movegen::MoveList::new();
+        if in_check {
+            movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut moves);
+        } else if USE_QSEARCH_CHECKS && qply < QSEARCH_CHECK_PLIES {
+            let mut all_moves = movegen::MoveList::new();
+            movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut all_moves);
+            for m in all_moves.iter() {
+                if m.captured_piece.is_some() || movegen::gives_check(&self.board, m) {
+                    moves.push(*m);
+                }
+            }
+        } else {
+            movegen::generate_all_psuedo_legal_moves(&self.board, my_color, true, &mut moves);
+        }
 
-        // Assign priorities according to MVV-LVA
+        // Perform static exchange evaluation on each move to order the
+        // captures by SEE value and then MVV-LVA (with captures that promote
+        // to a queen first, as in the main search), and to determine if it's
+        // worth searching further (for a quiet check, whether the checking
+        // piece is likely to be lost).  Quiet moves (checks or evasions) are
+        // searched after the captures that don't lose material.  Evasions
+        // are all searched, but other moves that lose material are skipped.
+        // Quiet moves are not ordered by SEE, so only whether their SEE is
+        // at least 0 is checked, which is cheaper.
         for m in moves.iter_mut() {
-            if m.captured_piece.is_none() {
-                println!("ERROR: Non-capture selected in q search");
-                continue;
-            }
-            m.priority = pieces::MVV_LVA[m.captured_piece.unwrap()][m.piece];
+            m.priority = match m.captured_piece {
+                Some(captured_piece) => {
+                    let see = self.see_capture_eval(m);
+                    if USE_QSEARCH_SEE_PRUNING && !in_check && see < 0 {
+                        QSEARCH_SKIP_PRIORITY
+                    } else if m.promotion_piece == Some(pieces::QUEEN) {
+                        PROMOTION_PRIORITY_BONUS + get_capture_order(see, captured_piece, m.piece)
+                    } else {
+                        get_capture_order(see, captured_piece, m.piece)
+                    }
+                },
+                None => if in_check || !USE_QSEARCH_SEE_PRUNING || self.see_ge(m, 0) {-1} else {QSEARCH_SKIP_PRIORITY},
+            };
         }
 
-        // Recursively search the capture moves
+        // Recursively search the moves
         for i in 0..moves.len() {
 
-            // Grab the next highest priority move
+            // Grab the next highest priority move, stopping at the moves
+            // to skip
             self.sort_move_with_priority(&mut moves, i);
             let m = &moves[i];
+            if m.priority == QSEARCH_SKIP_PRIORITY {
+                break;
+            }
 
             // Check to make sure it's legal
             if !movegen::is_legal_move(&mut self.board, m) {
@@ -840,129 +2015,189 @@ impl SearchEngine {
             }
 
             // Update analyzed moves
-            self.moves_analyzed += 1;
-
-            // Perform static exchange evaluation on this capture
-            // move to determine if it's worth searching further.
-            if self.see_capture_eval(m) < 0 {
-                continue;
+            self.thread.moves_analyzed += 1;
+
+            // Delta pruning (promotions and quiet checks are always
+            // searched)
+            if use_delta_pruning && m.promotion_piece.is_none() && m.captured_piece.is_some() {
+                let captured_piece = m.captured_piece.unwrap_or(pieces::PAWN);
+                let gain = evaluate::get_tapered_value(&self.board, pieces::PIECE_VALUES_MG[captured_piece], pieces::PIECE_VALUES_EG[captured_piece]);
+                if stand_pat + gain + delta_margin < alpha {
+                    best_value = cmp::max(best_value, stand_pat + gain + delta_margin);
+                    continue;
+                }
             }
 
             // Make the move
-            self.board.make_move(m.start_square, m.end_square, None);
+            self.board.make_move(m.start_square, m.end_square, m.promotion_piece);
 
             // Recursively search on the new board state
-            let score_for_move = -self.quiesce(-beta, -alpha);
+            let score_for_move = -self.quiesce(-beta, -alpha, qply + 1);
 
             // Unmake the move
             self.board.unmake_move();
 
             // Check for a beta cut-off
             if score_for_move >= beta {
-                return beta;
+                return score_for_move;
             }
 
-            // Check to see if we can raise alpha
-            if score_for_move > alpha {
-                alpha = score_for_move;
+            // Check to see if we can raise the best score and alpha
+            if score_for_move > best_value {
+                best_value = score_for_move;
+                if score_for_move > alpha {
+                    alpha = score_for_move;
+                }
             }
 
         }
 
-        // Return alpha, the minimum score we know we can get
-        alpha
+        // Return the best score found.  This search is fail-soft, so the
+        // score may be outside of the alpha-beta window, which gives a
+        // tighter bound to the caller.
+        best_value
+
+    }
 
+    // Get the score of a draw at the given ply from the root, from the
+    // perspective of the player to move.  The contempt is scaled by the
+    // remaining (non-pawn) material, so the engine avoids draws while there
+    // is still play left in the position, but accepts them in bare endings.
+    // A draw is worse than equal for the engine (the player to move at the
+    // root) and better than equal for its opponent.
+    fn get_draw_value(&self, ply: usize) -> i32 {
+        let scaled_contempt = self.contempt * cmp::min(self.board.phase_material, pieces::TOTAL_PHASE) / pieces::TOTAL_PHASE;
+        if ply.is_multiple_of(2) {-scaled_contempt} else {scaled_contempt}
     }
 
     // This is an implementation of the minimax algorithm with alpha-beta
     // pruning and is the core of the engine's search routine.  This uses
-    // transposition table lookups to enhance performance.
+    // transposition table lookups to enhance performance.  The search is
+    // fail-soft: the score returned may be outside of the alpha-beta window,
+    // in which case it is a bound on the true score.  The node type is the
+    // type this node is expected to be (see NodeType).
     // See https://en.wikipedia.org/wiki/Negamax
-    fn negamax(&mut self, depth: u8, mut alpha: i32, beta: i32, root: bool) -> i32 {
+    fn negamax(&mut self, depth: u8, mut alpha: i32, beta: i32, root: bool, node_type: NodeType) -> i32 {
         
         // Before doing any searching, check to make sure we're not
-        // halting.  For performance reasons, we won't check this
-        // condition on every negamax call.
-        if self.halt_search {
+        // halting
+        if self.check_halt() {
             return 0;
         }
-        self.halt_check_countdown -= 1;
-        if self.halt_check_countdown <= 0 {
-            self.halt_check_countdown = CHECK_HALT_CONDITION_INTERVAL;
 
-            // Check if we should halt due to time
-            if self.move_start_time.elapsed().as_millis() > self.time_max_for_move {
-                self.halt_search = true;
-                return 0;
-            }
-
-            // Check if we should halt due to a stop command
-            for cmd in self.rx_channel.try_iter() {
-                if cmd.trim() == "stop" {
-                    self.halt_search = true;
-                    return 0;
-                }
-            }
+        // Update moves analyzed count
+        self.thread.moves_analyzed += 1;
 
+        // The PV from this node is empty until a move raises alpha
+        let ply = self.board.zobrist_history.len() - self.root_history_len;
+        if ply >= MAX_PLY - 1 {
+            return self.get_corrected_evaluation();
+        }
+        self.thread.pv_length[ply] = ply;
+        if root {
+            self.thread.root_move_scores.clear();
         }
 
-        // Update moves analyzed count
-        self.moves_analyzed += 1;
+        // Check for draw types that don't involve move checking.  A single
+        // repetition of any earlier position, whether it was reached in the
+        // game or along the search path, is scored as a draw.  This is
+        // checked before the transposition tables, since whether a position
+        // is a repetition depends on the path to it rather than just the
+        // position.  Note that we don't check this at the root because we
+        // still have to get and return a valid move.
+        if !root {
+            if evaluate::is_draw_by_insufficient_material(&self.board) || evaluate::is_repetition(&self.board) {
+                return self.get_draw_value(ply);
+            }
+        }
 
-        // Check transposition tables for any cached values
+        // Check transposition tables for any cached values.  This is not
+        // done at PV nodes, so that the PV is always searched out in full
+        // rather than cut short by an entry (which would lose the ponder
+        // move), and an entry from an earlier search can never end the
+        // search at the root without a best move (or with one of the
+        // excluded root moves).
         let alpha_orig = alpha;
-        let tt_key = (self.board.zobrist_hash % self.num_tt_entries as u64) as usize;
+        let tt_key = self.get_tt_index();
         if let Some(tt_entry) = &self.transposition_table[tt_key] {
-            if tt_entry.valid && tt_entry.zobrist_hash == self.board.zobrist_hash && tt_entry.depth >= depth {
+            if node_type != NodeType::Pv && tt_entry.zobrist_hash == self.board.zobrist_hash && tt_entry.depth >= depth {
+                let tt_value = score_from_tt(tt_entry.value, ply);
                 match tt_entry.flag {
-                    TTFlag::Exact => return tt_entry.value,
-                    TTFlag::Lowerbound => if tt_entry.value >= beta {return tt_entry.value;},
-                    TTFlag::Upperbound => if tt_entry.value <= alpha {return tt_entry.value;},
+                    TTFlag::Exact => return tt_value,
+                    TTFlag::Lowerbound => if tt_value >= beta {return tt_value;},
+                    TTFlag::Upperbound => if tt_value <= alpha {return tt_value;},
                 }
             }
         }
 
-        // Check for draw types that don't involve move checking
-        // Note that we don't check this at the root because we still have
-        // to get and return a valid move.
-        if !root {
-            if evaluate::is_draw_by_insufficient_material(&self.board) || evaluate::is_draw_by_threefold_repitition(&self.board) {
-                return DRAW_VALUE;
-            }
-        }
-
         // Check if we're at our search horizon
         if depth == 0 {
-            return self.quiesce(alpha, beta);
+            return self.quiesce(alpha, beta, 0);
         }
 
-        // Compute ply, which will be used to store killer moves
-        let ply = self.max_depth_for_search - depth + 1;
-
         // Generate all moves to search
         let my_color = if self.board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
-        let mut moves = movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false);
+        let mut moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&self.board, my_color, false, &mut moves);
 
         // Score the moves
         self.score_moves(&mut moves, ply);
 
+        // Get the static evaluation, which is used to update the correction
+        // history.  This is not meaningful when in check.
+        let in_check = movegen::is_king_in_check(&self.board, my_color);
+        let static_eval = if in_check {None} else {Some(self.get_corrected_evaluation())};
+
+        // Determine whether our position is "improving", meaning the static
+        // evaluation is better than it was at our previous turn (two plies
+        // ago).  If that is unknown, such as when either side was in check,
+        // assume it is improving, which is the conservative choice for
+        // pruning.  Moves in positions that are not improving are reduced
+        // more in late move reductions.
+        // See https://www.chessprogramming.org/Improving
+        self.thread.eval_stack[ply] = static_eval;
+        let improving = match (static_eval, ply.checked_sub(2).and_then(|p| self.thread.eval_stack[p])) {
+            (Some(eval), Some(previous_eval)) => eval > previous_eval,
+            _ => true,
+        };
+
         // Recursively search the moves
+        let mut best_move_is_quiet = true;
         let mut best_move = None;
         let mut value = -INF;
         let mut legal_moves_searched = 0;
+        let mut quiets_searched = [(0, 0, 0); movegen::MAX_MOVES];
+        let countermove_index = self.get_countermove_index();
+        let mut num_quiets_searched = 0;
         for i in 0..moves.len() {
 
             // Grab the next highest priority move
             self.sort_move_with_priority(&mut moves, i);
             let m = &moves[i];
 
-            // Check to make sure it's legal
+            // Check to make sure it's legal, and not excluded at the root
             if !movegen::is_legal_move(&mut self.board, m) {
                 continue;
             }
+            if root && self.excluded_root_moves.contains(&(m.start_square as u8, m.end_square as u8, m.promotion_piece)) {
+                continue;
+            }
+
+            // Determine whether the move gives check before making it
+            let gives_check = movegen::gives_check(&self.board, m);
+
+            // SEE pruning - near the horizon, at nodes not expected to be in
+            // the PV, skip captures that are expected to lose more material
+            // than a margin that grows with depth.  At least one move is
+            // searched first, and checks are not pruned.
+            if USE_SEE_PRUNING && !root && node_type != NodeType::Pv && depth as i32 <= self.params[PARAM_SEE_PRUNING_MAX_DEPTH] && legal_moves_searched > 0 && static_eval.is_some()
+                && m.captured_piece.is_some() && m.promotion_piece.is_none() && !gives_check && value > -MATE_BOUND
+                && !self.see_ge(m, -self.params[PARAM_SEE_PRUNING_MARGIN] * depth as i32) {
+                continue;
+            }
 
             // Make the move
-            self.board.make_move(m.start_square, m.end_square, None);
+            self.board.make_move(m.start_square, m.end_square, m.promotion_piece);
 
              // Recursively search on the new board state.
             let mut score_for_move;
@@ -970,14 +2205,24 @@ impl SearchEngine {
 
                 // This is not the first (and probably best) move, so search
                 // with reduced depth and/or window
-                let promotion = m.piece == pieces::PAWN && (m.end_square / 8 == 0 || m.end_square / 8 == 7);
-                if legal_moves_searched > 3 && depth >= 3 && !promotion && m.captured_piece.is_none() && !movegen::is_king_in_check(&self.board, my_color) && !movegen::is_king_in_check(&self.board, 1 - my_color) {
+                if USE_LMR && legal_moves_searched >= self.params[PARAM_LMR_MIN_MOVES] && depth as i32 >= self.params[PARAM_LMR_MIN_DEPTH] && m.promotion_piece.is_none() && m.captured_piece.is_none() && !in_check && !gives_check {
                     
                     // Late move reductions (LMR) - this is a late move (and
                     // so probably not great) and the move is not particularly
                     // tactical in nature, so search with a null window and
-                    // decreased depth.
-                    score_for_move = -self.negamax(depth - 2, -alpha - 1, -alpha, false);
+                    // decreased depth.  Very late moves are reduced further
+                    // if our position is not improving, or if this node is
+                    // expected to fail high anyway.  Moves at PV nodes are
+                    // never reduced further, since their scores matter most.
+                    // The reduction never takes the search below depth 0,
+                    // whatever the tuned parameters.
+                    let is_very_late = legal_moves_searched >= self.params[PARAM_LMR_LATE_MOVES] && depth as i32 >= self.params[PARAM_LMR_LATE_DEPTH];
+                    let reduction = match node_type {
+                        NodeType::Pv => 1,
+                        NodeType::Cut if is_very_late => 2,
+                        _ => if !improving && is_very_late {2} else {1},
+                    };
+                    score_for_move = -self.negamax((depth - 1).saturating_sub(reduction), -alpha - 1, -alpha, false, node_type.child(false));
 
                 } else {
 
@@ -986,24 +2231,35 @@ impl SearchEngine {
                     // deepening framework) and so assume that this is not
                     // the best.  Verify this assumption by performing a
                     // null window search.
-                    score_for_move = -self.negamax(depth - 1, -alpha - 1, -alpha, false);
+                    score_for_move = -self.negamax(depth - 1, -alpha - 1, -alpha, false, node_type.child(false));
 
                 }
 
                 // Check if our assumption that we wouldn't get a better move
-                // held.  If not, re-search at full depth and window.
+                // held.  If not, re-search at full depth and window, which
+                // makes the move a new PV candidate.
                 if alpha < score_for_move && score_for_move < beta {
-                    score_for_move = -self.negamax(depth - 1, -beta, -alpha, false);
+                    score_for_move = -self.negamax(depth - 1, -beta, -alpha, false, NodeType::Pv);
                 }
 
             } else {
-                score_for_move = -self.negamax(depth - 1, -beta, -alpha, false);
+                score_for_move = -self.negamax(depth - 1, -beta, -alpha, false, node_type.child(true));
+            }
+            if root {
+                self.thread.root_move_scores.push(((m.start_square as u8, m.end_square as u8, m.promotion_piece), score_for_move));
             }
 
             // Update best move
             if score_for_move > value {
                 value = score_for_move;
-                best_move = Some((m.start_square as u8, m.end_square as u8));
+                best_move = Some((m.start_square as u8, m.end_square as u8, m.promotion_piece));
+                best_move_is_quiet = m.captured_piece.is_none() && m.promotion_piece.is_none();
+
+                // If this move raises alpha, the PV from this node is this
+                // move followed by the PV from the child node
+                if score_for_move > alpha {
+                    self.update_pv(ply, best_move);
+                }
             }
 
             // Unmake the move
@@ -1020,27 +2276,47 @@ impl SearchEngine {
                 // move to try during future move ordering calls.  If there
                 // is already a killer move, shift it over so that we store
                 // at most two.  Note that we don't store capture moves as
-                // killer moves because they are sorted seperately.
+                // killer moves because they are sorted seperately.  Quiet
+                // moves also get a history bonus, which grows quickly with
+                // depth since cut-offs near the root are most valuable, and
+                // the quiet moves searched before it, which failed to cause
+                // a cut-off, get the same amount as a penalty.
                 // See https://www.chessprogramming.org/Killer_Move
-                let cur_move = Some((m.start_square as u8, m.end_square as u8));
-                if m.captured_piece.is_none() && cur_move != self.primary_killers[ply as usize] {
-                    self.secondary_killers[ply as usize] = self.primary_killers[ply as usize];
-                    self.primary_killers[ply as usize] = cur_move;
+                let cur_move = Some((m.start_square as u8, m.end_square as u8, m.promotion_piece));
+                if m.captured_piece.is_none() && m.promotion_piece.is_none() {
+                    if cur_move != self.thread.primary_killers[ply] {
+                        self.thread.secondary_killers[ply] = self.thread.primary_killers[ply];
+                        self.thread.primary_killers[ply] = cur_move;
+                    }
+                    let bonus = depth as i32 * depth as i32;
+                    self.update_quiet_history(my_color, countermove_index, (m.start_square, m.end_square, m.piece), bonus);
+                    for &quiet in &quiets_searched[..num_quiets_searched] {
+                        self.update_quiet_history(my_color, countermove_index, quiet, -bonus);
+                    }
                 }
 
                 break;
             }
 
+            // Keep track of the quiet moves searched that did not cause a
+            // cut-off
+            if m.captured_piece.is_none() && m.promotion_piece.is_none() {
+                quiets_searched[num_quiets_searched] = (m.start_square, m.end_square, m.piece);
+                num_quiets_searched += 1;
+            }
+
         }
 
         // Check for checkmate and stalemate
         if legal_moves_searched == 0 {
-            if movegen::is_king_in_check(&self.board, my_color) {
-                // The other player wins by checkmate
-                return -CHECKMATE_VALUE;
+            if in_check {
+                // The other player wins by checkmate.  The score is
+                // adjusted by the ply, so that faster mates score higher
+                // for the winner and slower mates for the loser.
+                return -CHECKMATE_VALUE + ply as i32;
             } else {
                 // Stalemate
-                return DRAW_VALUE;
+                return self.get_draw_value(ply);
             }
         }
 
@@ -1049,29 +2325,51 @@ impl SearchEngine {
             panic!("No best move found");
         }
 
-        // Information about what already exists in the TT entry
+        // A halted search scores the moves it did not finish as 0, so its
+        // result is not stored, since the table is kept for later searches
+        if self.thread.halt_search {
+            return value;
+        }
+
+        // Update the correction history with the difference between the
+        // search score and the static evaluation.  This is only done when
+        // the score is trustworthy: the best move is quiet (captures are
+        // resolved by quiescence search instead) and the score is not a
+        // bound on the wrong side of the static evaluation.
+        if let Some(eval) = static_eval {
+            let is_bound_ok = (value > alpha_orig || value < eval) && (value < beta || value > eval);
+            if best_move_is_quiet && is_bound_ok && value.abs() < MATE_BOUND {
+                self.update_correction_history(value - eval, depth);
+            }
+        }
+
+        // Information about what already exists in the TT entry.  Entries
+        // from earlier searches are replaced as if the slot were empty.
         let mut existing_tt_entry_flag = &TTFlag::Upperbound;
         let mut existing_tt_entry_depth = 0;
         if let Some(tt_entry) = &self.transposition_table[tt_key] {
-            if tt_entry.valid {
+            if tt_entry.generation == self.tt_generation {
                 existing_tt_entry_depth = tt_entry.depth;
                 existing_tt_entry_flag = &tt_entry.flag;
             }
         }
 
-        // Store the best move in the transposition table
+        // Store the best move in the transposition table.  Results from PV
+        // nodes always replace the existing entry, since there are few of
+        // them and they are the most valuable to the next iteration.
+        let is_pv = node_type == NodeType::Pv;
         if value <= alpha_orig {
 
             // The best move in this subtree failed low, meaning that
             // it was not as good as an existing acceptable move.
-            if matches!(existing_tt_entry_flag, TTFlag::Upperbound) && depth >= existing_tt_entry_depth {
+            if is_pv || (matches!(existing_tt_entry_flag, TTFlag::Upperbound) && depth >= existing_tt_entry_depth) {
                 self.transposition_table[tt_key] = Some(TTEntry {
                     zobrist_hash: self.board.zobrist_hash,
                     depth,
-                    value,
+                    value: score_to_tt(value, ply),
                     flag: TTFlag::Upperbound,
                     best_move: None,
-                    valid: true,
+                    generation: self.tt_generation,
                 });
             }
 
@@ -1079,14 +2377,14 @@ impl SearchEngine {
 
             // The best move in this subtree failed high, meaning that
             // it caused a beta cut-off.
-            if matches!(existing_tt_entry_flag, TTFlag::Upperbound) || (matches!(existing_tt_entry_flag, TTFlag::Lowerbound) && depth >= existing_tt_entry_depth) {
+            if is_pv || matches!(existing_tt_entry_flag, TTFlag::Upperbound) || (matches!(existing_tt_entry_flag, TTFlag::Lowerbound) && depth >= existing_tt_entry_depth) {
                 self.transposition_table[tt_key] = Some(TTEntry {
                     zobrist_hash: self.board.zobrist_hash,
                     depth,
-                    value,
+                    value: score_to_tt(value, ply),
                     flag: TTFlag::Lowerbound,
-                    best_move,
-                    valid: true,
+                    best_move: best_move.map(|(start, end, promotion)| (start, end, promotion.map(|p| p as u8))),
+                    generation: self.tt_generation,
                 });
             }
 
@@ -1094,14 +2392,14 @@ impl SearchEngine {
 
             // The best move in this subtree is between alpha and beta,
             // meaning it is an exact value
-            if matches!(existing_tt_entry_flag, TTFlag::Upperbound) || matches!(existing_tt_entry_flag, TTFlag::Lowerbound) || (matches!(existing_tt_entry_flag, TTFlag::Exact) && depth >= existing_tt_entry_depth) {
+            if is_pv || matches!(existing_tt_entry_flag, TTFlag::Upperbound) || matches!(existing_tt_entry_flag, TTFlag::Lowerbound) || (matches!(existing_tt_entry_flag, TTFlag::Exact) && depth >= existing_tt_entry_depth) {
                 self.transposition_table[tt_key] = Some(TTEntry {
                     zobrist_hash: self.board.zobrist_hash,
                     depth,
-                    value,
+                    value: score_to_tt(value, ply),
                     flag: TTFlag::Exact,
-                    best_move,
-                    valid: true,
+                    best_move: best_move.map(|(start, end, promotion)| (start, end, promotion.map(|p| p as u8))),
+                    generation: self.tt_generation,
                 });
             }
 
@@ -1117,56 +2415,42 @@ impl SearchEngine {
 
     }
 
-    // Extract the PV line from the transposition table
-    fn extract_pv_line(&mut self) -> Vec<(u8, u8, Option<usize>)> {
+    // Get the index of the current board's pawn structure into the
+    // correction history, by hashing the pawn bitboards
+    fn get_correction_history_index(&self) -> usize {
+        let pawns = self.board.bb_pieces[pieces::COLOR_WHITE][pieces::PAWN] ^ self.board.bb_pieces[pieces::COLOR_BLACK][pieces::PAWN].rotate_left(32);
+        (pawns.wrapping_mul(0x9e3779b97f4a7c15) >> 32) as usize & (CORRECTION_HISTORY_SIZE - 1)
+    }
 
-        let mut pv_line = Vec::new();
-        let mut moves_made = 0;
-        let mut zobrist_loop_detect = Vec::new();
+    // Get the static evaluation of the current board, adjusted by the
+    // correction history, and by noise when playing at limited strength
+    fn get_corrected_evaluation(&self) -> i32 {
+        let correction = self.thread.correction_history[self.color_turn()][self.get_correction_history_index()];
+        let noise = self.strength.map_or(0, |limit| limit.get_eval_noise(self.board.zobrist_hash));
+        evaluate::static_evaluation(&self.board, &self.eval_terms) + correction / CORRECTION_HISTORY_GRAIN + noise
+    }
 
-        // Follow the PV moves in the transposition table until there are none
-        // remaining at that depth.  Note that because the transposition table
-        // is a hash table with limited size, it is possible that PV moves
-        // were overwritten later in search.  In this case, the PV line discovered
-        // may be shorter than it should be according to the depth searched.
-        loop {
-            let tt_key = (self.board.zobrist_hash % self.num_tt_entries as u64) as usize;
-            if let Some(tt_entry) = &self.transposition_table[tt_key] {
-                if tt_entry.valid && tt_entry.zobrist_hash == self.board.zobrist_hash && !zobrist_loop_detect.contains(&tt_entry.zobrist_hash) {
-                    if let TTFlag::Exact = tt_entry.flag {
-                        zobrist_loop_detect.push(tt_entry.zobrist_hash);
-                        // TODO do we have to check to make sure this best move is legal?
-                        if let Some((move_start, move_end)) = tt_entry.best_move {
-
-                            // If pawn promotion, add the promotion piece
-                            // TODO: Allow promotions to pieces other than queens.
-                            let mut promotion = None;
-                            if move_end >= 56 || move_end <= 7 {
-                                if let Some((_,p)) = self.board.get_color_and_piece_on_square(move_start as usize) {
-                                    if p == pieces::PAWN {
-                                        promotion = Some(pieces::QUEEN);
-                                    }
-                                }
-                            }
-                            
-                            pv_line.push((move_start, move_end, promotion));
-                            self.board.make_move(move_start as usize, move_end as usize, None);
-                            moves_made += 1;
-                        }
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-        for _i in 0..moves_made {
-            self.board.unmake_move();
-        }
-        pv_line
+    // Move the correction history entry of the current board towards the
+    // given difference between the search score and the static evaluation,
+    // weighted by the depth of the search
+    fn update_correction_history(&mut self, diff: i32, depth: u8) {
+        let weight = cmp::min(depth as i32 + 1, 16);
+        let color = self.color_turn();
+        let index = self.get_correction_history_index();
+        let entry = &mut self.thread.correction_history[color][index];
+        let updated = (*entry * (256 - weight) + diff * CORRECTION_HISTORY_GRAIN * weight) / 256;
+        *entry = updated.clamp(-CORRECTION_HISTORY_MAX * CORRECTION_HISTORY_GRAIN, CORRECTION_HISTORY_MAX * CORRECTION_HISTORY_GRAIN);
+    }
+
+    // Set the PV from the given ply to the given move followed by the PV
+    // from the next ply
+    fn update_pv(&mut self, ply: usize, m: Option<SearchMove>) {
+        let child_length = self.thread.pv_length[ply + 1].max(ply + 1);
+        let (parent_rows, child_rows) = self.thread.pv_table.split_at_mut(ply + 1);
+        let pv = &mut parent_rows[ply];
+        pv[ply] = m;
+        pv[ply + 1..child_length].copy_from_slice(&child_rows[0][ply + 1..child_length]);
+        self.thread.pv_length[ply] = child_length;
     }
 
 }
@@ -1180,6 +2464,7 @@ mod tests {
     
     use std::sync::mpsc::{Sender, Receiver};
     use std::sync::mpsc;
+    use std::thread;
     use crate::chess_board::ChessBoard;
     use super::*;
 
@@ -1215,22 +2500,10 @@ mod tests {
             captured_piece: Some(pieces::PAWN),
             priority: 0,
             is_en_passant: false,
+            promotion_piece: None,
         };
-        let searcher = SearchEngine {
-            rx_channel: rx,
-            board,
-            num_tt_entries: 0,
-            transposition_table: Vec::new(),
-            primary_killers: [None; 100],
-            secondary_killers: [None; 100],
-            max_depth_for_search: 0,
-            best_move_from_last_iteration: None,
-            moves_analyzed: 0,
-            time_max_for_move: 0,
-            move_start_time: time::Instant::now(),
-            halt_search: false,
-            halt_check_countdown: CHECK_HALT_CONDITION_INTERVAL,
-        };
+        let mut searcher = SearchEngine::new(rx);
+        searcher.board = board;
         let see_value = searcher.see_capture_eval(&m);
         assert_eq!(see_value, -600);
 
@@ -1263,24 +2536,660 @@ mod tests {
             captured_piece: Some(pieces::PAWN),
             priority: 0,
             is_en_passant: false,
+            promotion_piece: None,
         };
-        let searcher = SearchEngine {
-            rx_channel: rx,
-            board,
-            num_tt_entries: 0,
-            transposition_table: Vec::new(),
-            primary_killers: [None; 100],
-            secondary_killers: [None; 100],
-            max_depth_for_search: 0,
-            best_move_from_last_iteration: None,
-            moves_analyzed: 0,
-            time_max_for_move: 0,
-            move_start_time: time::Instant::now(),
-            halt_search: false,
-            halt_check_countdown: CHECK_HALT_CONDITION_INTERVAL,
-        };
+        let mut searcher = SearchEngine::new(rx);
+        searcher.board = board;
         let see_value = searcher.see_capture_eval(&m);
         assert_eq!(see_value, 100);
     }
 
-}
\ No newline at end of file
+    // Test SEE with promotion and en passant captures
+    #[test]
+    fn test_see_promotion_and_en_passant() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+
+        // Pawn captures a rook and promotes to a queen, which is then
+        // captured by a knight
+        searcher.board.new_game_from_fen("1r2k3/P2n4/8/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let m = movegen::ChessMove {
+            start_square: 48,
+            end_square: 57,
+            piece: pieces::PAWN,
+            captured_piece: Some(pieces::ROOK),
+            priority: 0,
+            is_en_passant: false,
+            promotion_piece: Some(pieces::QUEEN),
+        };
+        assert_eq!(searcher.see_capture_eval(&m), 400);
+
+        // En passant capture, where removing the captured pawn uncovers
+        // a rook that can recapture
+        searcher.board.new_game_from_fen("4k3/8/8/3pP3/8/8/8/3rK3 w - d6 0 1").unwrap();
+        let m = movegen::ChessMove {
+            start_square: 36,
+            end_square: 43,
+            piece: pieces::PAWN,
+            captured_piece: Some(pieces::PAWN),
+            priority: 0,
+            is_en_passant: true,
+            promotion_piece: None,
+        };
+        assert_eq!(searcher.see_capture_eval(&m), 0);
+    }
+
+    // Test that resizing the transposition table keeps the game position
+    #[test]
+    fn test_set_tt_size_keeps_position() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "e2e4 e7e5").unwrap();
+        let history = searcher.board.zobrist_history.clone();
+        searcher.set_tt_size_mb(1);
+        assert_eq!(searcher.transposition_table.len(), searcher.num_tt_entries);
+        assert!(searcher.num_tt_entries.is_power_of_two() && searcher.get_tt_size().0 <= 1024 * 1024);
+        assert_eq!(searcher.board.zobrist_history, history);
+        assert!(searcher.find_best_move(3, 0, 0, 1).best_move.is_some());
+    }
+
+    // Test that the transposition table is kept from one search to the
+    // next, and that its entries are rehashed when it is resized
+    #[test]
+    fn test_tt_kept_across_searches_and_resizes() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.set_tt_size_mb(1);
+        searcher.new_game();
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        searcher.set_board_state(fen, "").unwrap();
+        let first = searcher.find_best_move(6, 0, 0, 1);
+        let entries: Vec<TTEntry> = searcher.transposition_table.iter().flatten().copied().collect();
+        assert!(!entries.is_empty());
+
+        // Every entry is found at its index in a larger table, and again
+        // after shrinking back to the size they came from
+        for size_mb in [4, 1] {
+            searcher.set_tt_size_mb(size_mb);
+            assert_eq!(searcher.transposition_table.iter().flatten().count(), entries.len());
+            for entry in entries.iter() {
+                let tt_key = (entry.zobrist_hash & (searcher.num_tt_entries as u64 - 1)) as usize;
+                assert!(matches!(&searcher.transposition_table[tt_key], Some(e) if e.zobrist_hash == entry.zobrist_hash && e.depth == entry.depth));
+            }
+        }
+
+        // Searching the same position again reuses the kept entries
+        searcher.set_board_state(fen, "").unwrap();
+        let second = searcher.find_best_move(6, 0, 0, 1);
+        assert!(second.best_move.is_some() && second.depth == 6);
+        assert!(second.nodes < first.nodes);
+
+        // Setting an option to its current value keeps the table, and
+        // changing an option that affects scores clears it
+        searcher.set_contempt(searcher.contempt);
+        assert!(searcher.transposition_table.iter().any(|e| e.is_some()));
+        searcher.set_contempt(searcher.contempt + 10);
+        assert!(searcher.transposition_table.iter().all(|e| e.is_none()));
+
+        // A new game clears the table
+        searcher.new_game();
+        assert!(searcher.transposition_table.iter().all(|e| e.is_none()));
+    }
+
+    // Test that the result of a halted search is not stored in the
+    // transposition table
+    #[test]
+    fn test_halted_search_not_stored() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.set_tt_size_mb(1);
+        searcher.new_game();
+        searcher.set_board_state("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", "").unwrap();
+        searcher.set_node_limit(2000);
+        searcher.negamax(8, -INF, INF, true, NodeType::Pv);
+        assert!(searcher.thread.halt_search);
+        let tt_key = (searcher.board.zobrist_hash & (searcher.num_tt_entries as u64 - 1)) as usize;
+        assert!(searcher.transposition_table[tt_key].is_none());
+    }
+
+    // Test that hash moves are only used when they are pseudo-legal moves
+    // of the side to move, as a colliding entry may hold any move
+    #[test]
+    fn test_get_hash_move() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.set_tt_size_mb(1);
+        searcher.set_board_state(chess_board::STARTFEN, "").unwrap();
+        let tt_key = searcher.get_tt_index();
+        let store = |searcher: &mut SearchEngine, flag: TTFlag, best_move: (u8, u8, Option<u8>)| {
+            searcher.transposition_table[tt_key] = Some(TTEntry {
+                zobrist_hash: searcher.board.zobrist_hash,
+                depth: 5,
+                value: 0,
+                flag,
+                best_move: Some(best_move),
+                generation: searcher.tt_generation,
+            });
+        };
+
+        // A move of the side to move is used with the bonus for its flag
+        store(&mut searcher, TTFlag::Lowerbound, (12, 28, None));
+        assert_eq!(searcher.get_hash_move(), Some(((12, 28, None), CUTOFF_PRIORITY_BONUS)));
+        store(&mut searcher, TTFlag::Exact, (6, 21, None));
+        assert_eq!(searcher.get_hash_move(), Some(((6, 21, None), PV_MOVE_PRIORITY_BONUS)));
+
+        // Moves of the opponent's pieces, from empty squares, to illegal
+        // squares, or with a bad promotion piece are rejected, as are moves
+        // from upper bound entries
+        for best_move in [(52, 36, None), (28, 36, None), (12, 36, None), (12, 28, Some(pieces::QUEEN as u8)), (1, 11, None)] {
+            store(&mut searcher, TTFlag::Lowerbound, best_move);
+            assert_eq!(searcher.get_hash_move(), None, "Accepted {:?}", best_move);
+        }
+        store(&mut searcher, TTFlag::Upperbound, (12, 28, None));
+        assert_eq!(searcher.get_hash_move(), None);
+    }
+
+    // Test the estimate of how full the transposition table is
+    #[test]
+    fn test_get_hashfull() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.set_tt_size_mb(1);
+        assert_eq!(searcher.get_hashfull(), 0);
+        for i in 0..HASHFULL_SAMPLE_ENTRIES {
+            if i % 4 == 0 {
+                searcher.transposition_table[i] = Some(TTEntry {
+                    zobrist_hash: i as u64,
+                    depth: 1,
+                    value: 0,
+                    flag: TTFlag::Exact,
+                    best_move: None,
+                    generation: searcher.tt_generation,
+                });
+            }
+        }
+        assert_eq!(searcher.get_hashfull(), 250);
+
+        // Entries past the sample are not counted
+        let last = searcher.transposition_table.len() - 1;
+        searcher.transposition_table[last] = searcher.transposition_table[0];
+        assert_eq!(searcher.get_hashfull(), 250);
+
+        // Entries from earlier searches are not counted
+        searcher.tt_generation += 1;
+        assert_eq!(searcher.get_hashfull(), 0);
+    }
+
+    // Test that the PV collected during search is legal
+    #[test]
+    fn test_pv_table() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        searcher.set_board_state(fen, "").unwrap();
+        searcher.find_best_move(5, 0, 0, 1);
+        let pv: Vec<SearchMove> = searcher.thread.pv_table[0][..searcher.thread.pv_length[0]].iter().flatten().copied().collect();
+        assert!(!pv.is_empty());
+        assert!(searcher.set_board_state(fen, &movegen::convert_move_list_to_lan(&pv)).is_ok());
+    }
+
+    // Test that killer moves are shifted and history is decayed as the
+    // game advances between searches
+    #[test]
+    fn test_age_move_ordering_data() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "").unwrap();
+        searcher.age_move_ordering_data();
+        searcher.thread.primary_killers[2] = Some((12, 28, None));
+        searcher.thread.history[0][12][28] = 100;
+        searcher.set_board_state("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", "e2e4 e7e5").unwrap();
+        searcher.age_move_ordering_data();
+        assert_eq!(searcher.thread.primary_killers[0], Some((12, 28, None)));
+        assert_eq!(searcher.thread.primary_killers[2], None);
+        assert_eq!(searcher.thread.history[0][12][28], 50);
+    }
+
+    // Test that mate scores in the transposition table are relative to the
+    // node, and that other scores are stored as is
+    #[test]
+    fn test_tt_mate_scores() {
+        let mate_in_3_at_ply_4 = CHECKMATE_VALUE - 7;
+        assert_eq!(score_to_tt(mate_in_3_at_ply_4, 4), CHECKMATE_VALUE - 3);
+        assert_eq!(score_from_tt(score_to_tt(mate_in_3_at_ply_4, 4), 2), CHECKMATE_VALUE - 5);
+        assert_eq!(score_from_tt(score_to_tt(-mate_in_3_at_ply_4, 4), 6), -(CHECKMATE_VALUE - 9));
+        assert_eq!(score_from_tt(score_to_tt(150, 4), 9), 150);
+    }
+
+    // Test the expected types of child nodes
+    #[test]
+    fn test_node_type_child() {
+        assert_eq!(NodeType::Pv.child(true), NodeType::Pv);
+        assert_eq!(NodeType::Pv.child(false), NodeType::Cut);
+        assert_eq!(NodeType::Cut.child(true), NodeType::All);
+        assert_eq!(NodeType::Cut.child(false), NodeType::Cut);
+        assert_eq!(NodeType::All.child(true), NodeType::Cut);
+        assert_eq!(NodeType::All.child(false), NodeType::Cut);
+    }
+
+    // Test that contempt is applied against the engine and scaled down as
+    // material comes off the board
+    #[test]
+    fn test_draw_value() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_contempt(24);
+        searcher.set_board_state(chess_board::STARTFEN, "").unwrap();
+        assert_eq!(searcher.get_draw_value(0), -24);
+        assert_eq!(searcher.get_draw_value(1), 24);
+        searcher.set_board_state("4k3/8/8/8/8/8/8/R3K3 w - - 0 1", "").unwrap();
+        assert_eq!(searcher.get_draw_value(0), -2);
+        searcher.set_board_state("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", "").unwrap();
+        assert_eq!(searcher.get_draw_value(0), 0);
+    }
+
+    // Test that the shortest mate is found and reported in moves
+    #[test]
+    fn test_mate_scores() {
+        assert_eq!(format_score(CHECKMATE_VALUE - 1), "mate 1");
+        assert_eq!(format_score(CHECKMATE_VALUE - 5), "mate 3");
+        assert_eq!(format_score(-CHECKMATE_VALUE + 4), "mate -2");
+        assert_eq!(format_score(-35), "cp -35");
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1", "").unwrap();
+        let result = searcher.find_best_move(4, 0, 0, 1);
+        assert_eq!(result.best_move.as_deref(), Some("a1a8"));
+        assert_eq!(result.score, Score::Mate(1));
+        assert_eq!(result.depth, 4);
+        assert_eq!(result.iterations.len(), 4);
+        assert_eq!(result.iterations[3].pv, vec!["a1a8"]);
+        assert_eq!(searcher.last_search_score(), CHECKMATE_VALUE - 1);
+    }
+
+    // Test that a null move is never returned: terminal positions report
+    // no move, and a search stopped before it starts plays a legal move
+    #[test]
+    fn test_no_null_bestmove() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("6Rk/5Q2/8/8/8/8/8/6K1 b - - 0 1", "").unwrap();
+        assert_eq!(searcher.find_best_move(4, 0, 0, 1).best_move, None);
+        assert_eq!(searcher.last_search_score(), -CHECKMATE_VALUE);
+        searcher.set_board_state("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", "").unwrap();
+        assert_eq!(searcher.find_best_move(4, 0, 0, 1).best_move, None);
+        assert_eq!(searcher.last_search_score(), 0);
+        searcher.set_board_state(chess_board::STARTFEN, "").unwrap();
+        searcher.set_stop_flag(Arc::new(AtomicBool::new(true)));
+        let bm = searcher.find_best_move(4, 0, 0, 1).best_move.unwrap();
+        let (start_square, end_square, promotion_piece) = movegen::convert_moves_str_into_list(&bm).unwrap()[0];
+        let m = movegen::get_pseudo_legal_move(&searcher.board, start_square, end_square, promotion_piece).unwrap();
+        assert!(movegen::is_legal_move(&mut searcher.board, &m));
+    }
+
+    // Test that an iteration that falls outside the aspiration window
+    // reports the bound before re-searching
+    #[test]
+    #[cfg_attr(any(feature = "no-aspiration-windows", feature = "no-qsearch-checks"), ignore)]
+    fn test_aspiration_bound_report() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let lines = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let output_lines = Arc::clone(&lines);
+        let output = move |line: &str| output_lines.lock().unwrap().push(line.to_string());
+        let mut searcher = SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(output));
+        searcher.new_game();
+        searcher.set_board_state("7k/8/8/8/8/8/R7/1R4K1 w - - 0 1", "").unwrap();
+        searcher.find_best_move(3, 0, 0, 1);
+        let lines = lines.lock().unwrap();
+        let bound_index = lines.iter().position(|l| l.starts_with("info depth 2 score mate 2 lowerbound")).expect("No bound reported");
+        assert!(lines[bound_index + 1].starts_with("info depth 2 score mate 2 nodes"));
+    }
+
+    // Test that a search halted while re-searching after a fail high keeps
+    // the move that failed high, and its score
+    #[test]
+    #[cfg_attr(any(feature = "no-aspiration-windows", feature = "no-qsearch-checks"), ignore)]
+    fn test_halt_during_aspiration_research() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let output_stop_flag = Arc::clone(&stop_flag);
+        let output = move |line: &str| {
+            if line.contains("lowerbound") {
+                output_stop_flag.store(true, Ordering::Relaxed);
+            }
+        };
+        let mut searcher = SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(output));
+        searcher.set_stop_flag(stop_flag);
+        searcher.new_game();
+        searcher.set_board_state("7k/8/8/8/8/8/R7/1R4K1 w - - 0 1", "").unwrap();
+        assert_eq!(searcher.find_best_move(3, 0, 0, 1).best_move.as_deref(), Some("a2a7"));
+        assert_eq!(searcher.last_search_score(), CHECKMATE_VALUE - 3);
+    }
+
+    // Test that excluded root moves are not played, that the exclusions
+    // only apply to one search, and that excluding every move is ignored
+    #[test]
+    fn test_excluded_root_moves() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1", "").unwrap();
+        searcher.set_excluded_root_moves(vec![(0, 56, None)]);
+        assert_ne!(searcher.find_best_move(4, 0, 0, 1).best_move.as_deref(), Some("a1a8"));
+        assert_eq!(searcher.find_best_move(4, 0, 0, 1).best_move.as_deref(), Some("a1a8"));
+        searcher.set_board_state("7k/8/8/8/8/8/8/K7 w - - 0 1", "").unwrap();
+        searcher.set_excluded_root_moves(vec![(0, 1, None), (0, 8, None), (0, 9, None)]);
+        assert_ne!(searcher.find_best_move(2, 0, 0, 1).best_move, None);
+    }
+
+    // Test that a node limited search stops at the limit, and is
+    // reproducible
+    #[test]
+    fn test_node_limit() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(|_: &str| {}));
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            searcher.new_game();
+            searcher.set_board_state("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", "").unwrap();
+            searcher.set_node_limit(20000);
+            let result = searcher.find_best_move(0, -1, -1, 1);
+            assert!(result.nodes >= 20000 && result.nodes < 20100);
+            results.push((result.nodes, result.best_move, result.depth));
+        }
+        assert_eq!(results[0], results[1]);
+
+        // The limit only applies to one search
+        searcher.set_board_state("7k/8/6K1/8/8/8/8/R7 w - - 0 1", "").unwrap();
+        assert_eq!(searcher.find_best_move(2, -1, -1, 1).depth, 2);
+        assert_eq!(searcher.node_limit, 0);
+    }
+
+    // Test that quiescence search finds a mate by a quiet check in its
+    // first ply, but not in later plies
+    #[test]
+    #[cfg_attr(feature = "no-qsearch-checks", ignore)]
+    fn test_quiesce_quiet_checks() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1", "").unwrap();
+        searcher.root_history_len = searcher.board.zobrist_history.len();
+        assert_eq!(searcher.quiesce(-INF, INF, 0), CHECKMATE_VALUE - 1);
+        assert!(searcher.quiesce(-INF, INF, QSEARCH_CHECK_PLIES) < MATE_BOUND);
+    }
+
+    // Test that quiescence search does not stand pat when in check: here
+    // black is a queen up, but must move the king and lose the queen
+    #[test]
+    fn test_quiesce_in_check() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("k7/3q4/1N6/8/8/8/8/7K b - - 0 1", "").unwrap();
+        searcher.root_history_len = searcher.board.zobrist_history.len();
+        assert!(searcher.get_corrected_evaluation() > 0);
+        assert!(searcher.quiesce(-INF, INF, 0) < 0);
+        assert!(searcher.quiesce(-INF, INF, QSEARCH_CHECK_PLIES) < 0);
+        searcher.set_board_state("6Rk/5Q2/8/8/8/8/8/6K1 b - - 0 1", "").unwrap();
+        searcher.root_history_len = searcher.board.zobrist_history.len();
+        assert_eq!(searcher.quiesce(-INF, INF, QSEARCH_CHECK_PLIES), -CHECKMATE_VALUE);
+    }
+
+    // Test that captures are ordered by SEE value, then MVV-LVA, and that
+    // a capture losing material is ordered below quiet moves
+    #[test]
+    fn test_capture_ordering() {
+        assert!(get_capture_order(100, pieces::PAWN, pieces::KING) > get_capture_order(0, pieces::QUEEN, pieces::QUEEN));
+        assert!(get_capture_order(0, pieces::QUEEN, pieces::QUEEN) > get_capture_order(0, pieces::PAWN, pieces::QUEEN));
+        assert!(get_capture_order(-100, pieces::QUEEN, pieces::PAWN) < get_capture_order(0, pieces::PAWN, pieces::KING));
+        assert!(get_capture_order(0, pieces::KING, pieces::KING) > get_capture_order(0, pieces::QUEEN, pieces::PAWN));
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("4k3/8/4p3/3p4/8/8/8/1N1QK3 w - - 0 1", "").unwrap();
+        let mut moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&searcher.board, pieces::COLOR_WHITE, false, &mut moves);
+        searcher.score_moves(&mut moves, 0);
+        let losing_capture = moves.iter().find(|m| m.captured_piece.is_some()).unwrap();
+        assert!(losing_capture.priority < LOSING_CAPTURE_PRIORITY + SEE_ORDER_SCALE);
+        assert!(moves.iter().filter(|m| m.captured_piece.is_none()).all(|m| m.priority > losing_capture.priority));
+    }
+
+    // Test that history scores saturate at the maximum in either direction
+    #[test]
+    fn test_apply_history_bonus() {
+        let mut score = 0;
+        apply_history_bonus(&mut score, 100);
+        assert_eq!(score, 100);
+        for _ in 0..10000 {
+            apply_history_bonus(&mut score, 4096);
+            assert!(score <= HISTORY_MAX);
+        }
+        assert!(score > HISTORY_MAX * 9 / 10);
+        let saturated = score;
+        apply_history_bonus(&mut score, -4096);
+        assert!(saturated - score > 4096);
+        for _ in 0..10000 {
+            apply_history_bonus(&mut score, -4096);
+            assert!(score >= -HISTORY_MAX);
+        }
+        assert!(score < -HISTORY_MAX * 9 / 10);
+    }
+
+    // Test that countermove history orders a quiet move first only in
+    // reply to the previous move it was learned for
+    #[test]
+    fn test_countermove_history() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state(chess_board::STARTFEN, "e2e4").unwrap();
+        let index = searcher.get_countermove_index();
+        assert_eq!(index, Some((pieces::COLOR_WHITE * 6 + pieces::PAWN) * 64 + 28));
+        searcher.update_quiet_history(pieces::COLOR_BLACK, index, (57, 42, pieces::KNIGHT), 1000);
+        let get_best_knight_move = |searcher: &SearchEngine| {
+            let mut moves = movegen::MoveList::new();
+            movegen::generate_all_psuedo_legal_moves(&searcher.board, pieces::COLOR_BLACK, false, &mut moves);
+            searcher.score_moves(&mut moves, 0);
+            let best = moves.iter().filter(|m| m.piece == pieces::KNIGHT).max_by_key(|m| m.priority).unwrap();
+            (best.end_square, best.priority)
+        };
+        let (end_square, priority_after_e4) = get_best_knight_move(&searcher);
+        assert_eq!(end_square, 42);
+        searcher.set_board_state(chess_board::STARTFEN, "d2d4").unwrap();
+        let (_, priority_after_d4) = get_best_knight_move(&searcher);
+        assert!(priority_after_e4 > priority_after_d4);
+    }
+
+    // Test SEE of quiet moves, where the moved piece is not one of the
+    // attackers of its target square
+    #[test]
+    fn test_see_quiet_move() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+
+        // A pawn push defended by nothing, that the king can capture
+        searcher.board.new_game_from_fen("4k3/8/3P4/8/8/8/8/4K3 w - - 0 1").unwrap();
+        let m = movegen::ChessMove {
+            start_square: 43,
+            end_square: 51,
+            piece: pieces::PAWN,
+            captured_piece: None,
+            priority: 0,
+            is_en_passant: false,
+            promotion_piece: None,
+        };
+        assert!(searcher.see_capture_eval(&m) < 0);
+        assert!(!searcher.see_ge(&m, 0));
+
+        // A pawn push that a pawn can capture
+        searcher.board.new_game_from_fen("4k3/8/8/2p5/8/8/3P4/4K3 w - - 0 1").unwrap();
+        let m = movegen::ChessMove {
+            start_square: 11,
+            end_square: 27,
+            piece: pieces::PAWN,
+            captured_piece: None,
+            priority: 0,
+            is_en_passant: false,
+            promotion_piece: None,
+        };
+        assert_eq!(searcher.see_capture_eval(&m), -100);
+        assert!(!searcher.see_ge(&m, 0));
+    }
+
+    // Test that see_ge agrees with the full static exchange evaluation, for
+    // every move of positions along games from the bench positions
+    #[test]
+    fn test_see_ge() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        for fen in ["r1bqk2r/pp1n1ppp/2pbpn2/3p4/2PP4/2NBPN2/PP3PPP/R1BQK2R w KQkq - 0 1",
+                    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                    "1k1r3r/1pp2pp1/p1n1b3/4Pq1p/1P1pQ3/P1P2N2/5PPP/R1B1R1K1 b - - 0 1",
+                    "3r2k1/P4ppp/8/8/8/8/5PPP/1R4K1 w - - 0 1",
+                    "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1"] {
+            searcher.set_board_state(fen, "").unwrap();
+            for ply in 0..20 {
+                let color = searcher.color_turn();
+                let mut moves = movegen::MoveList::new();
+                movegen::generate_all_psuedo_legal_moves(&searcher.board, color, false, &mut moves);
+                let legal_moves: Vec<movegen::ChessMove> = moves.iter().filter(|m| movegen::is_legal_move(&mut searcher.board, m)).copied().collect();
+                if legal_moves.is_empty() {
+                    break;
+                }
+                for m in legal_moves.iter() {
+                    let see = searcher.see_capture_eval(m);
+                    for threshold in [-1000, -300, -101, -100, -1, 0, 1, 100, 200, 299, 300, 800, 1100] {
+                        assert_eq!(searcher.see_ge(m, threshold), see >= threshold, "{} {:?} {}", fen, m, threshold);
+                    }
+                }
+                let m = legal_moves[(ply * 7) % legal_moves.len()];
+                searcher.board.make_move(m.start_square, m.end_square, m.promotion_piece);
+            }
+        }
+    }
+
+    // Test analysis with moves to include and exclude at the root
+    #[test]
+    fn test_analyze() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(|_: &str| {}));
+        searcher.new_game();
+        let fen = "7k/8/6K1/8/8/8/8/R7 w - - 0 1";
+        let limits = SearchLimits {depth: 3, movetime: 0};
+
+        // Ra8 mates, so it is found without any restrictions
+        let result = searcher.analyze(fen, "", &limits, &[], &[]).unwrap();
+        assert_eq!(result.best_move.as_deref(), Some("a1a8"));
+
+        // Only the included moves are searched, less any excluded ones
+        let result = searcher.analyze(fen, "", &limits, &[(0, 8, None), (0, 56, None)], &[(0, 56, None)]).unwrap();
+        assert_eq!(result.best_move.as_deref(), Some("a1a2"));
+
+        // Without a legal included move, every legal move is searched
+        let result = searcher.analyze(fen, "", &limits, &[(0, 63, None)], &[]).unwrap();
+        assert_eq!(result.best_move.as_deref(), Some("a1a8"));
+
+        // Moves are played out from the FEN, and invalid positions are errors
+        let result = searcher.analyze(fen, "g6f6 h8g8", &limits, &[], &[]).unwrap();
+        assert!(result.best_move.unwrap().starts_with(['a', 'f']));
+        assert!(searcher.analyze("invalid", "", &limits, &[], &[]).is_err());
+        assert!(searcher.analyze(fen, "a1a9", &limits, &[], &[]).is_err());
+    }
+
+    // Test that the defaults of the search parameters are within their
+    // ranges, and that the parameter names are unique
+    #[test]
+    fn test_search_params() {
+        for (i, (name, default, min, max)) in SEARCH_PARAMS.iter().enumerate() {
+            assert!(min <= default && default <= max, "{}", name);
+            assert!(SEARCH_PARAMS[i+1..].iter().all(|p| p.0 != *name), "{}", name);
+        }
+    }
+
+    // Test that searching with every search parameter at its minimum, and
+    // at its maximum, works (as a tuner may try any value in the ranges)
+    #[test]
+    fn test_search_param_extremes() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        for extreme in [|p: &(&str, i32, i32, i32)| p.2, |p: &(&str, i32, i32, i32)| p.3] {
+            searcher.new_game();
+            for (i, p) in SEARCH_PARAMS.iter().enumerate() {
+                searcher.params[i] = extreme(p);
+            }
+            searcher.set_board_state("r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3", "").unwrap();
+            let result = searcher.find_best_move(6, 0, 0, 1);
+            assert!(result.best_move.is_some() && result.depth == 6);
+        }
+    }
+
+    // Test the effective branching factor
+    #[test]
+    fn test_get_effective_branching_factor() {
+        assert_eq!(get_effective_branching_factor(&[]), None);
+        assert_eq!(get_effective_branching_factor(&[20]), None);
+        assert_eq!(get_effective_branching_factor(&[0, 100]), None);
+        assert_eq!(get_effective_branching_factor(&[10, 40, 160]), Some(4.0));
+        let ebf = get_effective_branching_factor(&[20, 50, 300, 900]).unwrap();
+        assert!((ebf - 45f64.powf(1.0 / 3.0)).abs() < 1e-9);
+    }
+
+    // Test the time limits for a move, and that the hard limit for a fail
+    // low leaves most of the clock and doesn't extend a fixed move time
+    #[test]
+    fn test_get_time_limits() {
+        assert_eq!(get_time_limits(0, 0, 1), (INF, INF));
+        assert_eq!(get_time_limits(60000, 0, 30), (2000, 6000));
+        assert_eq!(get_time_limits(60000, 2000, 30), (3000, 9000));
+        assert_eq!(get_time_limits(20000, 0, 5), (4000, 4000));
+        assert_eq!(get_time_limits(1000, 0, 1), (1000, 1000));
+        for time_available in [100, 1000, 10000, 100000] {
+            let (time_for_move, time_hard_limit) = get_time_limits(time_available, 0, 40);
+            assert!(time_for_move <= time_hard_limit);
+            assert!(time_hard_limit <= time_available);
+        }
+    }
+
+    // Test that a pondering search holds its best move until "ponderhit",
+    // and then finishes as a timed search
+    #[test]
+    fn test_ponderhit() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        let ponder_flag = Arc::new(AtomicBool::new(true));
+        searcher.set_ponder_flag(Arc::clone(&ponder_flag));
+        searcher.new_game();
+        searcher.set_board_state(chess_board::STARTFEN, "e2e4 e7e5").unwrap();
+        searcher.set_pondering(true);
+        let ponderhit = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(200));
+            ponder_flag.store(false, Ordering::Relaxed);
+        });
+        let start = std::time::Instant::now();
+        assert_ne!(searcher.find_best_move(2, 0, 0, 1).best_move, None);
+        assert!(start.elapsed().as_millis() >= 200);
+        assert!(!searcher.pondering);
+        ponderhit.join().unwrap();
+    }
+
+    // Test that an underpromotion found by the search is reported exactly,
+    // here a knight promotion forking the king and queen
+    #[test]
+    fn test_underpromotion_bestmove() {
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let mut searcher = SearchEngine::new(rx);
+        searcher.new_game();
+        searcher.set_board_state("8/4P1k1/3q4/8/8/8/P7/K7 w - - 0 1", "").unwrap();
+        assert_eq!(searcher.find_best_move(5, 0, 0, 1).best_move.as_deref(), Some("e7e8n"));
+    }
+
+}
@@ -0,0 +1,194 @@
+// This module implements self-play, where the engine plays games against
+// itself from a set of varied openings.  Games are adjudicated by the
+// rules of chess (and a maximum game length) and written to a PGN file,
+// so users can generate test games without an external GUI.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use topas::search;
+use topas::chess_board;
+use topas::movegen;
+use topas::evaluate;
+use topas::pgn;
+use topas::pieces;
+
+// Default file self-play games are appended to
+pub const DEFAULT_SELFPLAY_PGN: &str = "selfplay.pgn";
+
+// Games that reach this many plies are adjudicated as a draw
+const MAX_GAME_PLIES: usize = 400;
+
+// Moves remaining until the next time control that is assumed for sudden
+// death time controls, matching the "go" command
+pub const SELFPLAY_MOVES_TO_GO: u16 = 25;
+
+// Openings that games are started from, cycled through in order
+pub const SELFPLAY_OPENINGS: [&str; 12] = [
+    "e2e4 e7e5 g1f3 b8c6 f1b5",
+    "e2e4 e7e5 g1f3 b8c6 f1c4",
+    "e2e4 c7c5 g1f3 d7d6 d2d4 c5d4 f3d4 g8f6 b1c3",
+    "e2e4 c7c5 b1c3 b8c6 g2g3",
+    "e2e4 e7e6 d2d4 d7d5",
+    "e2e4 c7c6 d2d4 d7d5",
+    "d2d4 d7d5 c2c4 e7e6 b1c3 g8f6",
+    "d2d4 d7d5 c2c4 c7c6",
+    "d2d4 g8f6 c2c4 g7g6 b1c3 f8g7",
+    "d2d4 g8f6 c2c4 e7e6 b1c3 f8b4",
+    "c2c4 e7e5 b1c3",
+    "g1f3 d7d5 g2g3",
+];
+
+// A time control of a base time and an increment, in milliseconds
+pub struct TimeControl {
+    pub base: i64,
+    pub increment: i64,
+}
+
+// Parse a time control of the form "<seconds>+<increment seconds>"
+// (e.g., "10+0.1"), where the increment is optional
+pub fn parse_time_control(tc: &str) -> Result<TimeControl, String> {
+    let (base, increment) = tc.split_once('+').unwrap_or((tc, "0"));
+    let base = base.parse::<f64>().map_err(|_| format!("Invalid time control: {}", tc))?;
+    let increment = increment.parse::<f64>().map_err(|_| format!("Invalid time control: {}", tc))?;
+    if base <= 0.0 || increment < 0.0 {
+        return Err(format!("Invalid time control: {}", tc));
+    }
+    Ok(TimeControl {
+        base: (base * 1000.0) as i64,
+        increment: (increment * 1000.0) as i64,
+    })
+}
+
+// Determine if the game is over, returning the result and the reason
+pub fn get_game_result(board: &mut chess_board::ChessBoard, plies: usize) -> Option<(&'static str, &'static str)> {
+    let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+    let mut moves = movegen::MoveList::new();
+    movegen::generate_all_psuedo_legal_moves(board, my_color, false, &mut moves);
+    if !moves.iter().any(|m| movegen::is_legal_move(board, m)) {
+        if movegen::is_king_in_check(board, my_color) {
+            return Some((if my_color == pieces::COLOR_WHITE {"0-1"} else {"1-0"}, "checkmate"));
+        }
+        return Some(("1/2-1/2", "stalemate"));
+    }
+    if evaluate::is_draw_by_insufficient_material(board) {
+        return Some(("1/2-1/2", "insufficient material"));
+    }
+    if evaluate::is_draw_by_threefold_repitition(board) {
+        return Some(("1/2-1/2", "threefold repetition"));
+    }
+    if board.halfmove_clock >= 100 {
+        return Some(("1/2-1/2", "fifty move rule"));
+    }
+    if plies >= MAX_GAME_PLIES {
+        return Some(("1/2-1/2", "maximum game length"));
+    }
+    None
+}
+
+// Play a single game from an opening, returning the moves played (as a
+// UCI-style move list), the result, and the reason for the result.  The
+// first engine plays white and the second plays black.
+pub fn play_game(engines: &mut [search::SearchEngine; 2], opening: &str, tc: &TimeControl) -> (String, &'static str, &'static str) {
+    let mut board = chess_board::ChessBoard::new();
+    board.new_game();
+    let mut move_string = String::new();
+    let mut plies = 0;
+    for engine in engines.iter_mut() {
+        engine.new_game();
+    }
+
+    // Play the opening
+    for lan in opening.split_whitespace() {
+        let m = pgn::lan_to_move(&mut board, lan).expect("Invalid self-play opening");
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        move_string.push_str(lan);
+        move_string.push(' ');
+        plies += 1;
+    }
+
+    // Let the engines play out the game
+    let mut clocks = [tc.base, tc.base];
+    loop {
+        if let Some((result, reason)) = get_game_result(&mut board, plies) {
+            return (move_string, result, reason);
+        }
+        let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let engine = &mut engines[my_color];
+        engine.set_board_state(chess_board::STARTFEN, &move_string).expect("Invalid self-play game state");
+        let start_time = time::Instant::now();
+        let lan = engine.find_best_move(0, clocks[my_color] as i32, tc.increment as i32, SELFPLAY_MOVES_TO_GO).best_move.expect("No legal move in self-play game");
+        clocks[my_color] -= start_time.elapsed().as_millis() as i64;
+        if clocks[my_color] < 0 {
+            return (move_string, if my_color == pieces::COLOR_WHITE {"0-1"} else {"1-0"}, "time forfeit");
+        }
+        clocks[my_color] += tc.increment;
+        let m = pgn::lan_to_move(&mut board, &lan).expect("Engine made an illegal move");
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        move_string.push_str(&lan);
+        move_string.push(' ');
+        plies += 1;
+    }
+}
+
+// Play a number of self-play games at the given time control, appending
+// each game to a PGN file, and print the results
+pub fn run_selfplay(num_games: u32, tc_str: &str, pgn_path: &str) {
+    let tc = match parse_time_control(tc_str) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+    let mut file = match OpenOptions::new().create(true).append(true).open(pgn_path) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Cannot open PGN file {}: {}", pgn_path, e);
+            return;
+        },
+    };
+    let (_, rx_white): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let (_, rx_black): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let mut engines = [search::SearchEngine::new(rx_white), search::SearchEngine::new(rx_black)];
+
+    // Scores are (white wins, black wins, draws)
+    let mut scores = (0, 0, 0);
+    for game in 1..=num_games {
+        let opening = SELFPLAY_OPENINGS[(game as usize - 1) % SELFPLAY_OPENINGS.len()];
+        let (move_string, result, reason) = play_game(&mut engines, opening, &tc);
+        match result {
+            "1-0" => scores.0 += 1,
+            "0-1" => scores.1 += 1,
+            _ => scores.2 += 1,
+        }
+        println!("Game {} of {}: {} ({})", game, num_games, result, reason);
+
+        // Write the game
+        let termination = match reason {
+            "time forfeit" => "time forfeit",
+            "maximum game length" => "adjudication",
+            _ => "normal",
+        };
+        let headers = vec![
+            (String::from("Event"), String::from("Topas self-play")),
+            (String::from("Site"), String::from("?")),
+            (String::from("Date"), String::from("????.??.??")),
+            (String::from("Round"), game.to_string()),
+            (String::from("White"), String::from("Topas")),
+            (String::from("Black"), String::from("Topas")),
+            (String::from("TimeControl"), format!("{}+{}", tc.base as f64 / 1000.0, tc.increment as f64 / 1000.0)),
+            (String::from("Termination"), String::from(termination)),
+        ];
+        let pgn_str = pgn::export_pgn(&headers, chess_board::STARTFEN, &move_string, result).expect("Invalid self-play game");
+        if let Err(e) = writeln!(file, "{}", pgn_str) {
+            println!("Cannot write to PGN file {}: {}", pgn_path, e);
+            return;
+        }
+    }
+    println!();
+    println!("Self-play finished: white wins {}, black wins {}, draws {}", scores.0, scores.1, scores.2);
+    println!("Games written to {}", pgn_path);
+}
@@ -0,0 +1,269 @@
+// This module implements an analysis server, so Topas can be run behind a
+// web front end.  Clients connect over TCP and send one command per line:
+//
+//   analyze <FEN> [moves <move> ...] [depth <depth>] [movetime <ms>]
+//           [searchmoves <move> ...] [excludemoves <move> ...]
+//   stop
+//   quit
+//
+// Analysis of each request is streamed back as it is computed, using the
+// same "info" and "bestmove" lines as the UCI protocol.  Each connection
+// gets its own engine in its own thread, driven through the library's
+// search API (SearchEngine::analyze), and the engine's output is sent to the
+// connection instead of standard out.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use topas::search;
+use topas::platform;
+use crate::uci;
+
+// Default port the server listens on
+pub const DEFAULT_SERVER_PORT: u16 = 7878;
+
+// Time to analyze a position for if neither a depth nor a time is given
+const DEFAULT_ANALYSIS_TIME_MS: i32 = 1000;
+
+// Parameters of the analyze command, which end its move lists
+const ANALYZE_PARAMETERS: [&str; 5] = ["moves", "depth", "movetime", "searchmoves", "excludemoves"];
+
+// An analysis request
+struct AnalysisRequest {
+    fen: String,
+    moves: String,
+    limits: search::SearchLimits,
+    include: Vec<search::SearchMove>,
+    exclude: Vec<search::SearchMove>,
+}
+
+// Parse an analyze command of the form "analyze <FEN> [moves <move> ...]
+// [depth <depth>] [movetime <ms>] [searchmoves <move> ...]
+// [excludemoves <move> ...]"
+fn parse_analysis_request(tokens: &[&str]) -> Result<AnalysisRequest, String> {
+    if tokens.len() < 7 {
+        return Err(String::from("Invalid analyze command (expected a FEN string)"));
+    }
+    let fen = tokens[1..7].join(" ");
+    let mut moves = String::new();
+    let mut depth = 0;
+    let mut movetime = 0;
+    let include = uci::parse_move_list(tokens, "searchmoves", &ANALYZE_PARAMETERS).map_err(|e| format!("Invalid searchmoves: {}", e))?;
+    let exclude = uci::parse_move_list(tokens, "excludemoves", &ANALYZE_PARAMETERS).map_err(|e| format!("Invalid excludemoves: {}", e))?;
+    let mut i = 7;
+    while i < tokens.len() {
+        match tokens[i] {
+            "searchmoves" | "excludemoves" => {
+                while i + 1 < tokens.len() && !ANALYZE_PARAMETERS.contains(&tokens[i+1]) {
+                    i += 1;
+                }
+            },
+            "moves" => {
+                while i + 1 < tokens.len() && !ANALYZE_PARAMETERS.contains(&tokens[i+1]) {
+                    moves.push_str(tokens[i+1]);
+                    moves.push(' ');
+                    i += 1;
+                }
+            },
+            "depth" => {
+                depth = match tokens.get(i+1).map(|x| x.parse::<u8>()) {
+                    Some(Ok(d)) if d >= 1 => d,
+                    _ => return Err(String::from("Invalid analyze depth")),
+                };
+                i += 1;
+            },
+            "movetime" => {
+                movetime = match tokens.get(i+1).map(|x| x.parse::<i32>()) {
+                    Some(Ok(d)) if d >= 1 => d,
+                    _ => return Err(String::from("Invalid analyze movetime")),
+                };
+                i += 1;
+            },
+            _ => return Err(format!("Invalid analyze parameter: {}", tokens[i])),
+        }
+        i += 1;
+    }
+    if depth == 0 && movetime == 0 {
+        movetime = DEFAULT_ANALYSIS_TIME_MS;
+    }
+    Ok(AnalysisRequest {fen, moves, limits: search::SearchLimits {depth, movetime}, include, exclude})
+}
+
+// Handle a single client connection.  Commands are read from the
+// connection and passed to an engine thread, so that a "stop" command can
+// interrupt an analysis in progress by setting the engine's stop flag.
+//
+// A "stop" applies to every analyze command sent before it, including ones
+// still queued for the engine thread.  The reader counts the analyze
+// commands it has passed on, and on a "stop" records that count as the
+// number of requests stopped.  The engine thread clears the stop flag as it
+// starts each request, and sets it again if that request was already
+// stopped.  Clearing the flag before checking the count means a "stop" that
+// arrives in between is never lost.
+fn handle_connection(stream: TcpStream, hash_mb: Option<u64>) {
+    let output_stream = match stream.try_clone() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let engine_stop_flag = Arc::clone(&stop_flag);
+    let requests_stopped = Arc::new(AtomicUsize::new(0));
+    let engine_requests_stopped = Arc::clone(&requests_stopped);
+    let engine_thread = thread::Builder::new().stack_size(search::SEARCH_STACK_SIZE).spawn(move || {
+        let output = move |line: &str| {
+            let _ = writeln!(&output_stream, "{}", line);
+        };
+        let mut engine = search::SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(output));
+        engine.set_stop_flag(Arc::clone(&engine_stop_flag));
+        if let Some(hash) = hash_mb {
+            let _ = uci::set_option(&mut engine, "Hash", &hash.to_string());
+        }
+        engine.new_game();
+        let mut requests_started = 0;
+        while let Ok(command) = engine.rx_channel.recv() {
+            let tokens: Vec<&str> = command.split_whitespace().collect();
+            match tokens.first() {
+                Some(&"analyze") => {
+                    requests_started += 1;
+                    engine_stop_flag.store(false, Ordering::SeqCst);
+                    if engine_requests_stopped.load(Ordering::SeqCst) >= requests_started {
+                        engine_stop_flag.store(true, Ordering::SeqCst);
+                    }
+                    let result = parse_analysis_request(&tokens).and_then(|r| {
+                        engine.analyze(&r.fen, &r.moves, &r.limits, &r.include, &r.exclude)
+                    });
+                    match result {
+                        Ok(result) => engine.write_line(&uci::format_bestmove(&result)),
+                        Err(e) => engine.write_line(&format!("error {}", e)),
+                    }
+                },
+                None => {},
+                Some(&"quit") => break,
+                _ => engine.write_line("error Unknown command"),
+            }
+        }
+    });
+    let engine_thread = match engine_thread {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    let mut requests_sent = 0;
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(l) if l.trim() == "quit" => break,
+            Ok(l) if l.trim() == "stop" => {
+                requests_stopped.store(requests_sent, Ordering::SeqCst);
+                stop_flag.store(true, Ordering::SeqCst);
+            },
+            Ok(l) => {
+                if l.split_whitespace().next() == Some("analyze") {
+                    requests_sent += 1;
+                }
+                if tx.send(l).is_err() {
+                    break;
+                }
+            },
+            Err(_) => break,
+        }
+    }
+    stop_flag.store(true, Ordering::Relaxed);
+    let _ = tx.send(String::from("quit"));
+    let _ = engine_thread.join();
+}
+
+// Run the analysis server on the given port, handling each connection in
+// its own thread.  Each connection's engine gets a hash table of the given
+// size in MB, or the default size if none is given.
+pub fn run_server(port: u16, hash_mb: Option<u64>) {
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Cannot listen on port {}: {}", port, e);
+            return;
+        },
+    };
+    println!("Analysis server listening on 127.0.0.1:{}", port);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                thread::spawn(move || handle_connection(s, hash_mb));
+            },
+            Err(e) => println!("Connection failed: {}", e),
+        }
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test parsing of analysis requests
+    #[test]
+    fn test_parse_analysis_request() {
+        let command = "analyze rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 moves e2e4 e7e5 depth 8";
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        let request = parse_analysis_request(&tokens).unwrap();
+        assert_eq!(request.fen, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert_eq!(request.moves, "e2e4 e7e5 ");
+        assert_eq!((request.limits.depth, request.limits.movetime), (8, 0));
+        assert!(request.include.is_empty() && request.exclude.is_empty());
+
+        let command = "analyze rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1 searchmoves e2e4 d2d4 moves g1f3 excludemoves d2d4 movetime 50";
+        let tokens: Vec<&str> = command.split_whitespace().collect();
+        let request = parse_analysis_request(&tokens).unwrap();
+        assert_eq!(request.moves, "g1f3 ");
+        assert_eq!(request.include, vec![(12, 28, None), (11, 27, None)]);
+        assert_eq!(request.exclude, vec![(11, 27, None)]);
+        assert_eq!((request.limits.depth, request.limits.movetime), (0, 50));
+
+        let tokens: Vec<&str> = "analyze 8/8/4k3/8/8/8/3P4/4K3 w - - 0 1".split_whitespace().collect();
+        let request = parse_analysis_request(&tokens).unwrap();
+        assert_eq!((request.limits.depth, request.limits.movetime), (0, DEFAULT_ANALYSIS_TIME_MS));
+
+        let tokens: Vec<&str> = "analyze 8/8/4k3/8/8/8/3P4/4K3 w - - 0 1 depth x".split_whitespace().collect();
+        assert!(parse_analysis_request(&tokens).is_err());
+    }
+
+    // Test that a "stop" sent right after an analyze command stops it, even
+    // if the engine has not started it yet, and not the analysis after it.
+    // The first command is indented, which the engine thread accepts, so
+    // it must also be counted as a request.
+    #[test]
+    fn test_stop_queued_analysis() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || handle_connection(listener.accept().unwrap().0, Some(1)));
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(std::time::Duration::from_secs(30))).unwrap();
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R w KQkq - 2 3";
+        write!(client, "  analyze {} depth 60\nstop\nanalyze {} depth 2\n", fen, fen).unwrap();
+
+        // Both analyses finish, the second at its full depth
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut bestmoves = 0;
+        let mut last_depth = String::new();
+        while bestmoves < 2 {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).unwrap() > 0);
+            if line.starts_with("bestmove") {
+                bestmoves += 1;
+            } else if line.starts_with("info depth") {
+                last_depth = line;
+            }
+        }
+        assert!(last_depth.starts_with("info depth 2 "));
+        writeln!(client, "quit").unwrap();
+        server.join().unwrap();
+    }
+
+}
@@ -0,0 +1,164 @@
+// This module implements a sequential probability ratio test (SPRT) harness
+// that plays matches between two engine configurations (differing in their
+// option settings).  Games are played in pairs from the same opening with
+// colors reversed, and the pair results are tracked with a pentanomial
+// model, which is how mainstream engines validate changes.
+// See https://www.chessprogramming.org/Sequential_Probability_Ratio_Test
+
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use topas::search;
+use crate::selfplay;
+use crate::uci;
+
+// Default maximum number of game pairs before the test is inconclusive
+pub const DEFAULT_SPRT_MAX_PAIRS: u32 = 5000;
+
+// False positive (alpha) and false negative (beta) rates of the test
+const SPRT_ALPHA: f64 = 0.05;
+const SPRT_BETA: f64 = 0.05;
+
+// Convert an Elo difference to an expected score
+fn elo_to_score(elo: f64) -> f64 {
+    1.0 / (1.0 + 10.0_f64.powf(-elo / 400.0))
+}
+
+// Convert an expected score to an Elo difference
+pub fn score_to_elo(score: f64) -> f64 {
+    let score = score.clamp(1e-6, 1.0 - 1e-6);
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+// Get the mean and variance of the pair scores (normalized to [0, 1]) of
+// a pentanomial distribution, where counts[i] is the number of pairs with
+// a total score of i/2
+fn pentanomial_mean_and_variance(counts: &[u32; 5]) -> (f64, f64) {
+    let n: u32 = counts.iter().sum();
+    if n == 0 {
+        return (0.5, 0.0);
+    }
+    let scores = [0.0, 0.25, 0.5, 0.75, 1.0];
+    let mean = counts.iter().zip(scores.iter()).map(|(c, s)| *c as f64 * s).sum::<f64>() / n as f64;
+    let variance = counts.iter().zip(scores.iter()).map(|(c, s)| *c as f64 * (s - mean).powi(2)).sum::<f64>() / n as f64;
+    (mean, variance)
+}
+
+// Compute the log-likelihood ratio (LLR) of H1 (the Elo difference is
+// elo1) versus H0 (the Elo difference is elo0) from the pentanomial counts,
+// using the normal approximation of the generalized SPRT.
+// See http://hardy.uhasselt.be/Fishtest/support_MLE_multinomial.pdf
+fn compute_llr(counts: &[u32; 5], elo0: f64, elo1: f64) -> f64 {
+    let n: u32 = counts.iter().sum();
+    let (mean, variance) = pentanomial_mean_and_variance(counts);
+    if variance <= 0.0 {
+        return 0.0;
+    }
+    let score0 = elo_to_score(elo0);
+    let score1 = elo_to_score(elo1);
+    n as f64 * (score1 - score0) * (2.0 * mean - score0 - score1) / (2.0 * variance)
+}
+
+// Parse an engine configuration of the form "name=value,name=value" (or
+// "default" for no options), and apply it to an engine
+fn configure_engine(engine: &mut search::SearchEngine, config: &str) -> Result<(), String> {
+    if config == "default" {
+        return Ok(());
+    }
+    for option in config.split(',') {
+        match option.split_once('=') {
+            Some((name, value)) => uci::set_option(engine, name, value).map_err(|e| format!("{} ({})", e, option))?,
+            None => return Err(format!("Invalid option setting: {}", option)),
+        }
+    }
+    Ok(())
+}
+
+// Get the score of the first engine from a game result
+fn get_score(result: &str, first_engine_is_white: bool) -> u32 {
+    // Scores are in half points
+    match (result, first_engine_is_white) {
+        ("1-0", true) | ("0-1", false) => 2,
+        ("1/2-1/2", _) => 1,
+        _ => 0,
+    }
+}
+
+// Run an SPRT match between configuration A and configuration B at the
+// given time control, testing H0: elo(A - B) = elo0 against
+// H1: elo(A - B) = elo1.  The match stops when either hypothesis is
+// accepted or the maximum number of game pairs is reached.
+pub fn run_sprt(tc_str: &str, elo0: f64, elo1: f64, config_a: &str, config_b: &str, max_pairs: u32) {
+    let tc = match selfplay::parse_time_control(tc_str) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+    let (_, rx_a): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let (_, rx_b): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let mut engines = [search::SearchEngine::new(rx_a), search::SearchEngine::new(rx_b)];
+    for (engine, config) in engines.iter_mut().zip([config_a, config_b]) {
+        if let Err(e) = configure_engine(engine, config) {
+            println!("{}", e);
+            return;
+        }
+    }
+    let lower_bound = (SPRT_BETA / (1.0 - SPRT_ALPHA)).ln();
+    let upper_bound = ((1.0 - SPRT_BETA) / SPRT_ALPHA).ln();
+
+    // Play game pairs, with engine A as white in the first game of the
+    // pair and as black in the second
+    let mut counts: [u32; 5] = [0; 5];
+    let mut llr = 0.0;
+    for pair in 0..max_pairs {
+        let opening = selfplay::SELFPLAY_OPENINGS[pair as usize % selfplay::SELFPLAY_OPENINGS.len()];
+        let (_, result_first, _) = selfplay::play_game(&mut engines, opening, &tc);
+        engines.swap(0, 1);
+        let (_, result_second, _) = selfplay::play_game(&mut engines, opening, &tc);
+        engines.swap(0, 1);
+        counts[(get_score(result_first, true) + get_score(result_second, false)) as usize] += 1;
+
+        // Report the status of the test
+        llr = compute_llr(&counts, elo0, elo1);
+        let (mean, _) = pentanomial_mean_and_variance(&counts);
+        println!("Pair {}: {} {} | Pentanomial [{}, {}, {}, {}, {}] | Elo {:.1} | LLR {:.2} ({:.2}, {:.2})",
+            pair + 1, result_first, result_second, counts[0], counts[1], counts[2], counts[3], counts[4],
+            score_to_elo(mean), llr, lower_bound, upper_bound);
+        if llr <= lower_bound || llr >= upper_bound {
+            break;
+        }
+    }
+    println!();
+    if llr >= upper_bound {
+        println!("SPRT finished: H1 accepted (A is likely stronger than B by {} Elo or more)", elo1);
+    } else if llr <= lower_bound {
+        println!("SPRT finished: H0 accepted (A is likely stronger than B by {} Elo or less)", elo0);
+    } else {
+        println!("SPRT finished: inconclusive after {} game pairs", max_pairs);
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test the SPRT statistics
+    #[test]
+    fn test_sprt_statistics() {
+        assert!((elo_to_score(0.0) - 0.5).abs() < 1e-9);
+        assert!((score_to_elo(elo_to_score(35.0)) - 35.0).abs() < 1e-6);
+        let (mean, variance) = pentanomial_mean_and_variance(&[0, 0, 10, 0, 0]);
+        assert!((mean - 0.5).abs() < 1e-9 && variance == 0.0);
+
+        // A strongly winning result favors H1, and a losing result favors H0
+        assert!(compute_llr(&[5, 20, 50, 60, 15], 0.0, 5.0) > 0.0);
+        assert!(compute_llr(&[15, 60, 50, 20, 5], 0.0, 5.0) < 0.0);
+    }
+
+}
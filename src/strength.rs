@@ -0,0 +1,166 @@
+// This module implements the UCI_LimitStrength and UCI_Elo options, which
+// let a GUI present the engine as an opponent of adjustable strength.  An
+// Elo rating is mapped to three handicaps, which all get milder as the
+// rating goes up:
+//
+//   1. A node limit, which keeps the search shallow
+//   2. Noise added to the static evaluation, so that positions are misjudged
+//   3. A chance of playing a move other than the best one, if its score is
+//      within a margin of the best
+//
+// The mapping is not calibrated against rated opponents, so the ratings
+// are only a rough guide.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// Range of the UCI_Elo option, and its default
+pub const MIN_ELO: i32 = 800;
+pub const MAX_ELO: i32 = 2400;
+pub const DEFAULT_ELO: i32 = 1500;
+
+// Node limits at the minimum and maximum Elo.  The limit grows
+// exponentially in between, since each extra ply costs a multiple of the
+// nodes of the last.
+const MIN_ELO_NODES: f64 = 250.0;
+const MAX_ELO_NODES: f64 = 1000000.0;
+
+// Evaluation noise in centipawns at the minimum Elo, falling to zero at
+// the maximum
+const MAX_EVAL_NOISE: i32 = 150;
+
+// Chance of playing a move other than the best at the minimum Elo, falling
+// to zero at the maximum, and the range of the score margin (in
+// centipawns) within which the other move must be
+const MAX_SUBOPTIMAL_CHANCE: f64 = 0.35;
+const MIN_SUBOPTIMAL_MARGIN: i32 = 50;
+const MAX_SUBOPTIMAL_MARGIN: i32 = 200;
+
+// The handicaps for a search at limited strength
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StrengthLimit {
+
+    // Maximum number of nodes to search
+    pub node_limit: u64,
+
+    // Maximum evaluation noise in centipawns, in either direction
+    pub eval_noise: i32,
+
+    // Chance of playing a move other than the best
+    pub suboptimal_chance: f64,
+
+    // How much worse than the best move, in centipawns, another move may be
+    // and still be played
+    pub suboptimal_margin: i32,
+
+    // Seed for the evaluation noise, chosen for each search so the noise
+    // is the same for a position within a search but varies between them
+    noise_seed: u64,
+
+}
+
+impl StrengthLimit {
+
+    // Get the handicaps for an Elo rating, which is clamped to the range
+    // of the UCI_Elo option
+    pub fn from_elo(elo: i32, noise_seed: u64) -> StrengthLimit {
+        let weakness = (MAX_ELO - elo.clamp(MIN_ELO, MAX_ELO)) as f64 / (MAX_ELO - MIN_ELO) as f64;
+        StrengthLimit {
+            node_limit: (MAX_ELO_NODES * (MIN_ELO_NODES / MAX_ELO_NODES).powf(weakness)) as u64,
+            eval_noise: (MAX_EVAL_NOISE as f64 * weakness).round() as i32,
+            suboptimal_chance: MAX_SUBOPTIMAL_CHANCE * weakness,
+            suboptimal_margin: MIN_SUBOPTIMAL_MARGIN + ((MAX_SUBOPTIMAL_MARGIN - MIN_SUBOPTIMAL_MARGIN) as f64 * weakness).round() as i32,
+            noise_seed,
+        }
+    }
+
+    // Get the evaluation noise for a position, given its Zobrist key
+    pub fn get_eval_noise(&self, zobrist_hash: u64) -> i32 {
+        if self.eval_noise == 0 {
+            return 0;
+        }
+        let range = 2 * self.eval_noise as u64 + 1;
+        (mix(zobrist_hash ^ self.noise_seed) % range) as i32 - self.eval_noise
+    }
+
+}
+
+// Mix the bits of a number (the SplitMix64 finalizer), giving a well
+// distributed hash of it
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// A small pseudo-random number generator for the strength handicaps,
+// seeded from the standard library's random hash keys so that no external
+// crate is needed
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+
+    // Construct a new generator with a random seed
+    pub fn new() -> Random {
+        Random {
+            state: RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    // Get the next random number
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.state)
+    }
+
+    // Get a random number in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+}
+
+impl Default for Random {
+    fn default() -> Random {
+        Random::new()
+    }
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test that the handicaps get milder as the Elo goes up, and that the
+    // evaluation noise stays in range and is fixed for a position
+    #[test]
+    fn test_strength_limit() {
+        let weakest = StrengthLimit::from_elo(0, 1);
+        let middle = StrengthLimit::from_elo(DEFAULT_ELO, 1);
+        let strongest = StrengthLimit::from_elo(MAX_ELO, 1);
+        assert_eq!(weakest, StrengthLimit::from_elo(MIN_ELO, 1));
+        assert_eq!(weakest.node_limit, MIN_ELO_NODES as u64);
+        assert_eq!(strongest.node_limit, MAX_ELO_NODES as u64);
+        assert!(weakest.node_limit < middle.node_limit && middle.node_limit < strongest.node_limit);
+        assert!(weakest.eval_noise > middle.eval_noise && middle.eval_noise > strongest.eval_noise);
+        assert_eq!(strongest.eval_noise, 0);
+        assert_eq!(strongest.suboptimal_chance, 0.0);
+        for key in 0..1000 {
+            let noise = weakest.get_eval_noise(key);
+            assert!(noise.abs() <= MAX_EVAL_NOISE);
+            assert_eq!(noise, weakest.get_eval_noise(key));
+            assert_eq!(strongest.get_eval_noise(key), 0);
+        }
+        let mut random = Random::new();
+        for _ in 0..1000 {
+            assert!((0.0..1.0).contains(&random.next_f64()));
+        }
+    }
+
+}
@@ -0,0 +1,178 @@
+// This module implements built-in tactical test suites, which are a quick
+// sanity check of the engine's strength after making changes.  Each
+// position is stored in Extended Position Description (EPD) format with a
+// best move ("bm") operation, and is scored as solved if the engine finds
+// one of the best moves within the allotted time.
+// See https://www.chessprogramming.org/Extended_Position_Description
+
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use topas::search;
+use topas::chess_board;
+use topas::pgn;
+
+// Default time per position in milliseconds
+pub const DEFAULT_TACTICS_TIME_MS: u32 = 1000;
+
+// The Bratko-Kopec test
+// See https://www.chessprogramming.org/Bratko-Kopec_Test
+const BRATKO_KOPEC: [&str; 24] = [
+    "1k1r4/pp1b1R2/3q2pp/4p3/2B5/4Q3/PPP2B2/2K5 b - - bm Qd1+; id \"BK.01\";",
+    "3r1k2/4npp1/1ppr3p/p6P/P2PPPP1/1NR5/5K2/2R5 w - - bm d5; id \"BK.02\";",
+    "2q1rr1k/3bbnnp/p2p1pp1/2pPp3/PpP1P1P1/1P2BNNP/2BQ1PRK/7R b - - bm f5; id \"BK.03\";",
+    "rnbqkb1r/p3pppp/1p6/2ppP3/3N4/2P5/PPP1QPPP/R1B1KB1R w KQkq - bm e6; id \"BK.04\";",
+    "r1b2rk1/2q1b1pp/p2ppn2/1p6/3QP3/1BN1B3/PPP3PP/R4RK1 w - - bm Nd5 a4; id \"BK.05\";",
+    "2r3k1/pppR1pp1/4p3/4P1P1/5P2/1P4K1/P1P5/8 w - - bm g6; id \"BK.06\";",
+    "1nk1r1r1/pp2n1pp/4p3/q2pPp1N/b1pP1P2/B1P2R2/2P1B1PP/R2Q2K1 w - - bm Nf6; id \"BK.07\";",
+    "4b3/p3kp2/6p1/3pP2p/2pP1P2/4K1P1/P3N2P/8 w - - bm f5; id \"BK.08\";",
+    "2kr1bnr/pbpq4/2n1pp2/3p3p/3P1P1B/2N2N1Q/PPP3PP/2KR1B1R w - - bm f5; id \"BK.09\";",
+    "3rr1k1/pp3pp1/1qn2np1/8/3p4/PP1R1P2/2P1NQPP/R1B3K1 b - - bm Ne5; id \"BK.10\";",
+    "2r1nrk1/p2q1ppp/bp1p4/n1pPp3/P1P1P3/2PBB1N1/4QPPP/R4RK1 w - - bm f4; id \"BK.11\";",
+    "r3r1k1/ppqb1ppp/8/4p1NQ/8/2P5/PP3PPP/R3R1K1 b - - bm Bf5; id \"BK.12\";",
+    "r2q1rk1/4bppp/p2p4/2pP4/3pP3/3Q4/PP1B1PPP/R3R1K1 w - - bm b4; id \"BK.13\";",
+    "rnb2r1k/pp2p2p/2pp2p1/q2P1p2/8/1Pb2NP1/PB2PPBP/R2Q1RK1 w - - bm Qd2 Qe1; id \"BK.14\";",
+    "2r3k1/1p2q1pp/2b1pr2/p1pp4/6Q1/1P1PP1R1/P1PN2PP/5RK1 w - - bm Qxg7+; id \"BK.15\";",
+    "r1bqkb1r/4npp1/p1p4p/1p1pP1B1/8/1B6/PPPN1PPP/R2Q1RK1 w kq - bm Ne4; id \"BK.16\";",
+    "r2q1rk1/1ppnbppp/p2p1nb1/3Pp3/2P1P1P1/2N2N1P/PPB1QP2/R1B2RK1 b - - bm h5; id \"BK.17\";",
+    "r1bq1rk1/pp2ppbp/2np2p1/2n5/P3PP2/N1P2N2/1PB3PP/R1B1QRK1 b - - bm Nb3; id \"BK.18\";",
+    "3rr3/2pq2pk/p2p1pnp/8/2QBPP2/1P6/P5PP/4RRK1 b - - bm Rxe4; id \"BK.19\";",
+    "r4k2/pb2bp1r/1p1qp2p/3pNp2/3P1P2/2N3P1/PPP1Q2P/2KRR3 w - - bm g4; id \"BK.20\";",
+    "3rn2k/ppb2rpp/2ppqp2/5N2/2P1P3/1P5Q/PB3PPP/3RR1K1 w - - bm Nh6; id \"BK.21\";",
+    "2r2rk1/1bqnbpp1/1p1ppn1p/pP6/N1P1P3/P2B1N1P/1B2QPP1/R2R2K1 b - - bm Bxe4; id \"BK.22\";",
+    "r1bqk2r/pp2bppp/2p5/3pP3/P2Q1P2/2N1B3/1PP3PP/R4RK1 b kq - bm f6; id \"BK.23\";",
+    "r2qnrnk/p2b2b1/1p1p2pp/2pPpp2/1PP1P3/PRNBB3/3QNPPP/5RK1 w - - bm f4; id \"BK.24\";",
+];
+
+// The first positions of the Win at Chess (WAC) test
+// See https://www.chessprogramming.org/Win_at_Chess
+const WIN_AT_CHESS: [&str; 16] = [
+    "2rr3k/pp3pp1/1nnqbN1p/3pN3/2pP4/2P3Q1/PPB4P/R4RK1 w - - bm Qg6; id \"WAC.001\";",
+    "8/7p/5k2/5p2/p1p2P2/Pr1pPK2/1P1R3P/8 b - - bm Rxb2; id \"WAC.002\";",
+    "5rk1/1ppb3p/p1pb4/6q1/3P1p1r/2P1R2P/PP1BQ1P1/5RKN w - - bm Rg3; id \"WAC.003\";",
+    "r1bq2rk/pp3pbp/2p1p1pQ/7P/3P4/2PB1N2/PP3PPR/2KR4 w - - bm Qxh7+; id \"WAC.004\";",
+    "5k2/6pp/p1qN4/1p1p4/3P4/2PKP2Q/PP3r2/3R4 b - - bm Qc4+; id \"WAC.005\";",
+    "7k/p7/1R5K/6r1/6p1/6P1/8/8 w - - bm Rb7; id \"WAC.006\";",
+    "rnbqkb1r/pppp1ppp/8/4P3/6n1/7P/PPPNPPP1/R1BQKBNR b KQkq - bm Ne3; id \"WAC.007\";",
+    "r4q1k/p2bR1rp/2p2Q1N/5p2/5p2/2P5/PP3PPP/R5K1 w - - bm Rf7; id \"WAC.008\";",
+    "3q1rk1/p4pp1/2pb3p/3p4/6Pr/1PNQ4/P1PB1PP1/4RRK1 b - - bm Bh2+; id \"WAC.009\";",
+    "2br2k1/2q3rn/p2NppQ1/2p1P3/Pp5R/4P3/1P3PPP/3R2K1 w - - bm Rxh7; id \"WAC.010\";",
+    "r1b1kb1r/3q1ppp/pBp1pn2/8/Np3P2/5B2/PPP3PP/R2Q1RK1 w kq - bm Bxc6; id \"WAC.011\";",
+    "4k1r1/2p3r1/1pR1p3/3pP2p/3P2qP/P4N2/1PQ4P/5R1K b - - bm Qxf3+; id \"WAC.012\";",
+    "5rk1/pp4p1/2n1p2p/2Npq3/2p5/6P1/P3P1BP/R4Q1K w - - bm Qxf8+; id \"WAC.013\";",
+    "r2rb1k1/pp1q1p1p/2n1p1p1/2bp4/5P2/PP1BPR1Q/1BPN2PP/R5K1 w - - bm Qxh7+; id \"WAC.014\";",
+    "1R6/1brk2p1/4p2p/p1P1Pp2/P7/6P1/1P4P1/2R3K1 w - - bm Rxb7; id \"WAC.015\";",
+    "r4rk1/ppp2ppp/2n5/2bqp3/8/P2PB3/1PP1NPPP/R2Q1RK1 w - - bm Nc3; id \"WAC.016\";",
+];
+
+// A position from a test suite
+struct EpdPosition {
+    fen: String,
+    best_moves: Vec<String>,
+    id: String,
+}
+
+// Parse an EPD line with "bm" and "id" operations.  EPD does not include
+// the halfmove clock and fullmove number, so these are added to make a
+// complete FEN string.
+fn parse_epd(epd: &str) -> Result<EpdPosition, String> {
+    let fields: Vec<&str> = epd.splitn(5, ' ').collect();
+    if fields.len() != 5 {
+        return Err(format!("Invalid EPD: {}", epd));
+    }
+    let fen = format!("{} {} {} {} 0 1", fields[0], fields[1], fields[2], fields[3]);
+    let mut best_moves = Vec::new();
+    let mut id = String::new();
+    for operation in fields[4].split(';') {
+        let operation = operation.trim();
+        if let Some(e) = operation.strip_prefix("bm ") {
+            best_moves = e.split_whitespace().map(|x| x.to_string()).collect();
+        } else if let Some(e) = operation.strip_prefix("id ") {
+            id = e.trim_matches('"').to_string();
+        }
+    }
+    if best_moves.is_empty() {
+        return Err(format!("EPD has no best move: {}", epd));
+    }
+    Ok(EpdPosition {fen, best_moves, id})
+}
+
+// Get the positions of a test suite by name ("bk" or "wac"), or of all
+// suites if no name is given
+fn get_suite(name: Option<&str>) -> Option<Vec<&'static str>> {
+    match name {
+        Some("bk") => Some(BRATKO_KOPEC.to_vec()),
+        Some("wac") => Some(WIN_AT_CHESS.to_vec()),
+        None => Some(BRATKO_KOPEC.iter().chain(WIN_AT_CHESS.iter()).copied().collect()),
+        _ => None,
+    }
+}
+
+// Run a test suite, searching each position for the given number of
+// milliseconds, and print the number of positions solved
+pub fn run_tactics(suite_name: Option<&str>, time_per_position: u32) {
+    let suite = match get_suite(suite_name) {
+        Some(e) => e,
+        None => {
+            println!("Unknown test suite; valid suites are bk and wac");
+            return;
+        },
+    };
+    let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let mut engine = search::SearchEngine::new(rx);
+    let mut solved = 0;
+    for epd in suite.iter() {
+        let position = parse_epd(epd).expect("Invalid built-in EPD");
+
+        // Convert the best moves from SAN into moves on the board
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen(&position.fen).expect("Invalid built-in EPD");
+        let mut best_moves = Vec::new();
+        for san in position.best_moves.iter() {
+            best_moves.push(pgn::san_to_move(&mut board, san).expect("Invalid built-in EPD"));
+        }
+
+        // Search the position, and convert the engine's move to SAN
+        engine.new_game();
+        engine.set_board_state(&position.fen, "").expect("Invalid built-in EPD");
+        let engine_move = engine.find_best_move(0, time_per_position as i32, 0, 1).best_move.expect("No legal move in built-in EPD");
+        let engine_move = match pgn::lan_to_move(&mut board, &engine_move) {
+            Ok(m) => m,
+            Err(_) => {
+                println!("{}: not solved (expected {}, found {})", position.id, position.best_moves.join(" "), engine_move);
+                continue;
+            },
+        };
+        let is_solved = best_moves.contains(&engine_move);
+        if is_solved {
+            solved += 1;
+        }
+        println!("{}: {} (expected {}, found {})", position.id, if is_solved {"solved"} else {"not solved"}, position.best_moves.join(" "), pgn::move_to_san(&mut board, &engine_move));
+    }
+    println!();
+    println!("Solved {} of {} positions", solved, suite.len());
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test that all built-in positions are valid and their best moves are
+    // legal
+    #[test]
+    fn test_suites_are_valid() {
+        for epd in get_suite(None).unwrap() {
+            let position = parse_epd(epd).unwrap();
+            let mut board = chess_board::ChessBoard::new();
+            board.new_game_from_fen(&position.fen).unwrap();
+            for san in position.best_moves.iter() {
+                assert!(pgn::san_to_move(&mut board, san).is_ok(), "{} {}", position.id, san);
+            }
+        }
+    }
+
+}
@@ -0,0 +1,369 @@
+// This module implements a round-robin match manager, where Topas and
+// external UCI engines play each other at a time control.  Every pairing
+// plays games in pairs from the self-play openings with colors reversed.
+// Games are adjudicated the same way as self-play and written to a PGN
+// file, and an Elo estimate is printed for each participant, so users can
+// test Topas without an external tool such as cutechess.
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+use topas::search;
+use topas::chess_board;
+use topas::pgn;
+use topas::pieces;
+use topas::platform;
+use crate::selfplay;
+use crate::sprt;
+
+// File match games are appended to
+pub const DEFAULT_MATCH_PGN: &str = "match.pgn";
+
+// Participant name that refers to Topas itself, rather than an external
+// engine executable
+const TOPAS_PARTICIPANT: &str = "topas";
+
+// Time in milliseconds an external engine has to respond to "uci" and
+// "isready"
+const HANDSHAKE_TIMEOUT_MS: u64 = 10000;
+
+// Time in milliseconds a player may overstep its clock before losing on
+// time, to allow for the latency of talking to an external engine
+const TIME_MARGIN_MS: i64 = 100;
+
+// An external UCI engine running as a child process
+struct ExternalEngine {
+
+    // The engine process and its standard input
+    process: Child,
+    stdin: ChildStdin,
+
+    // Lines written by the engine to its standard output.  These are read
+    // by a separate thread so that waiting for the engine can time out.
+    lines: Receiver<String>,
+
+}
+
+impl ExternalEngine {
+
+    // Launch an engine and perform the UCI handshake, returning the engine
+    // along with the name it reports
+    fn launch(path: &str) -> Result<(ExternalEngine, String), String> {
+        let mut process = Command::new(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Cannot launch engine {}: {}", path, e))?;
+        let stdin = process.stdin.take().expect("Engine standard input is not piped");
+        let stdout = process.stdout.take().expect("Engine standard output is not piped");
+        let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut engine = ExternalEngine {process, stdin, lines: rx};
+        let mut name = path.to_string();
+        engine.send("uci").map_err(|_| format!("Cannot communicate with engine {}", path))?;
+        loop {
+            match engine.lines.recv_timeout(time::Duration::from_millis(HANDSHAKE_TIMEOUT_MS)) {
+                Ok(line) if line.trim() == "uciok" => break,
+                Ok(line) => if let Some(n) = line.strip_prefix("id name ") {
+                    name = n.trim().to_string();
+                },
+                Err(_) => return Err(format!("Engine {} did not respond to the uci command", path)),
+            }
+        }
+        Ok((engine, name))
+    }
+
+    // Send a command to the engine
+    fn send(&mut self, command: &str) -> Result<(), &'static str> {
+        writeln!(self.stdin, "{}", command).and_then(|_| self.stdin.flush()).map_err(|_| "engine failure")
+    }
+
+    // Wait for a line from the engine for which the condition holds,
+    // returning the reason the game is lost if the engine does not respond
+    // in time
+    fn wait_for(&mut self, timeout_ms: u64, condition: impl Fn(&str) -> bool) -> Result<String, &'static str> {
+        let deadline = time::Instant::now() + time::Duration::from_millis(timeout_ms);
+        loop {
+            match self.lines.recv_timeout(deadline.saturating_duration_since(time::Instant::now())) {
+                Ok(line) if condition(&line) => return Ok(line),
+                Ok(_) => {},
+                Err(RecvTimeoutError::Timeout) => return Err("time forfeit"),
+                Err(RecvTimeoutError::Disconnected) => return Err("engine failure"),
+            }
+        }
+    }
+
+    // Start a new game
+    fn new_game(&mut self) -> Result<(), &'static str> {
+        self.send("ucinewgame")?;
+        self.send("isready")?;
+        self.wait_for(HANDSHAKE_TIMEOUT_MS, |line| line.trim() == "readyok")?;
+        Ok(())
+    }
+
+    // Get the engine's move for a position reached from the starting
+    // position by a UCI-style move list
+    fn get_move(&mut self, move_string: &str, clocks: &[i64; 2], increment: i64, timeout_ms: u64) -> Result<String, &'static str> {
+        if move_string.trim().is_empty() {
+            self.send("position startpos")?;
+        } else {
+            self.send(&format!("position startpos moves {}", move_string.trim()))?;
+        }
+        self.send(&format!("go wtime {} btime {} winc {} binc {}",
+            clocks[pieces::COLOR_WHITE].max(1), clocks[pieces::COLOR_BLACK].max(1), increment, increment))?;
+        let line = self.wait_for(timeout_ms, |line| line.starts_with("bestmove"))?;
+        Ok(line.split_whitespace().nth(1).unwrap_or("").to_string())
+    }
+
+}
+
+impl Drop for ExternalEngine {
+
+    // Ask the engine to quit, and make sure the process is gone
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        thread::sleep(time::Duration::from_millis(100));
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+
+}
+
+// A participant in a match
+enum Player {
+    Topas(Box<search::SearchEngine>),
+    External(ExternalEngine),
+}
+
+impl Player {
+
+    // Start a new game
+    fn new_game(&mut self) -> Result<(), &'static str> {
+        match self {
+            Player::Topas(engine) => {
+                engine.new_game();
+                Ok(())
+            },
+            Player::External(engine) => engine.new_game(),
+        }
+    }
+
+    // Get the player's move, returning the reason the game is lost if the
+    // player fails to move
+    fn get_move(&mut self, move_string: &str, clocks: &[i64; 2], my_color: usize, tc: &selfplay::TimeControl) -> Result<String, &'static str> {
+        match self {
+            Player::Topas(engine) => {
+                engine.set_board_state(chess_board::STARTFEN, move_string).expect("Invalid match game state");
+                Ok(engine.find_best_move(0, clocks[my_color] as i32, tc.increment as i32, selfplay::SELFPLAY_MOVES_TO_GO).best_move.expect("No legal move in match game"))
+            },
+            Player::External(engine) => engine.get_move(move_string, clocks, tc.increment, (clocks[my_color] + TIME_MARGIN_MS).max(0) as u64),
+        }
+    }
+
+}
+
+// Create a participant, returning the player and its name
+fn create_player(participant: &str) -> Result<(Player, String), String> {
+    if participant == TOPAS_PARTICIPANT {
+
+        // Topas' search output is not shown during a match
+        let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        let engine = search::SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(|_: &str| {}));
+        return Ok((Player::Topas(Box::new(engine)), String::from("Topas")));
+    }
+    let (engine, name) = ExternalEngine::launch(participant)?;
+    Ok((Player::External(engine), name))
+}
+
+// Play a single game from an opening between the players at the given
+// indices, returning the moves played (as a UCI-style move list), the
+// result, and the reason for the result
+fn play_game(players: &mut [Player], white: usize, black: usize, opening: &str, tc: &selfplay::TimeControl) -> (String, &'static str, &'static str) {
+    let mut board = chess_board::ChessBoard::new();
+    board.new_game();
+    let mut move_string = String::new();
+    let mut plies = 0;
+    for (color, index) in [white, black].into_iter().enumerate() {
+        if let Err(reason) = players[index].new_game() {
+            return (move_string, if color == pieces::COLOR_WHITE {"0-1"} else {"1-0"}, reason);
+        }
+    }
+
+    // Play the opening
+    for lan in opening.split_whitespace() {
+        let m = pgn::lan_to_move(&mut board, lan).expect("Invalid match opening");
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        move_string.push_str(lan);
+        move_string.push(' ');
+        plies += 1;
+    }
+
+    // Let the players play out the game
+    let mut clocks = [tc.base, tc.base];
+    loop {
+        if let Some((result, reason)) = selfplay::get_game_result(&mut board, plies) {
+            return (move_string, result, reason);
+        }
+        let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let loss = if my_color == pieces::COLOR_WHITE {"0-1"} else {"1-0"};
+        let player = &mut players[if my_color == pieces::COLOR_WHITE {white} else {black}];
+        let start_time = time::Instant::now();
+        let lan = match player.get_move(&move_string, &clocks, my_color, tc) {
+            Ok(e) => e,
+            Err(reason) => return (move_string, loss, reason),
+        };
+        clocks[my_color] -= start_time.elapsed().as_millis() as i64;
+        if clocks[my_color] < -TIME_MARGIN_MS {
+            return (move_string, loss, "time forfeit");
+        }
+        clocks[my_color] = clocks[my_color].max(0) + tc.increment;
+        let m = match pgn::lan_to_move(&mut board, &lan) {
+            Ok(e) => e,
+            Err(_) => return (move_string, loss, "illegal move"),
+        };
+        board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        move_string.push_str(&lan);
+        move_string.push(' ');
+        plies += 1;
+    }
+}
+
+// Get the Elo estimate of a player, along with the margin of its 95%
+// confidence interval, from its wins, draws, and losses
+fn get_elo_estimate(wins: u32, draws: u32, losses: u32) -> (f64, f64) {
+    let n = (wins + draws + losses) as f64;
+    if n == 0.0 {
+        return (0.0, 0.0);
+    }
+    let score = (wins as f64 + 0.5 * draws as f64) / n;
+    let variance = (wins as f64 * (1.0 - score).powi(2) + draws as f64 * (0.5 - score).powi(2) + losses as f64 * score.powi(2)) / n;
+    let margin = 1.96 * (variance / n).sqrt();
+    let elo = sprt::score_to_elo(score);
+    (elo, (sprt::score_to_elo(score + margin) - sprt::score_to_elo(score - margin)) / 2.0)
+}
+
+// Run a round-robin match between the participants (each "topas" or the
+// path to a UCI engine executable) at the given time control.  Every
+// pairing plays the given number of game pairs.  Games are appended to a
+// PGN file, and the standings are printed at the end.
+pub fn run_match(rounds: u32, tc_str: &str, participants: &[&str], pgn_path: &str) {
+    let tc = match selfplay::parse_time_control(tc_str) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+    let mut file = match OpenOptions::new().create(true).append(true).open(pgn_path) {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Cannot open PGN file {}: {}", pgn_path, e);
+            return;
+        },
+    };
+    let mut players = Vec::new();
+    let mut names = Vec::new();
+    for participant in participants.iter() {
+        match create_player(participant) {
+            Ok((player, name)) => {
+                players.push(player);
+                names.push(name);
+            },
+            Err(e) => {
+                println!("{}", e);
+                return;
+            },
+        }
+    }
+
+    // Play every pairing, with the players swapping colors after each game
+    // from the same opening.  Scores are (wins, draws, losses).
+    let mut scores = vec![(0, 0, 0); players.len()];
+    let num_games = rounds as usize * players.len() * (players.len() - 1);
+    let mut game = 0;
+    for round in 0..rounds as usize {
+        let opening = selfplay::SELFPLAY_OPENINGS[round % selfplay::SELFPLAY_OPENINGS.len()];
+        for first in 0..players.len() {
+            for second in first + 1..players.len() {
+                for (white, black) in [(first, second), (second, first)] {
+                    let (move_string, result, reason) = play_game(&mut players, white, black, opening, &tc);
+                    game += 1;
+                    match result {
+                        "1-0" => {scores[white].0 += 1; scores[black].2 += 1},
+                        "0-1" => {scores[black].0 += 1; scores[white].2 += 1},
+                        _ => {scores[white].1 += 1; scores[black].1 += 1},
+                    }
+                    println!("Game {} of {}: {} - {} {} ({})", game, num_games, names[white], names[black], result, reason);
+
+                    // Write the game
+                    let termination = match reason {
+                        "time forfeit" => "time forfeit",
+                        "illegal move" | "engine failure" => "rules infraction",
+                        "maximum game length" => "adjudication",
+                        _ => "normal",
+                    };
+                    let headers = vec![
+                        (String::from("Event"), String::from("Topas match")),
+                        (String::from("Site"), String::from("?")),
+                        (String::from("Date"), String::from("????.??.??")),
+                        (String::from("Round"), (round + 1).to_string()),
+                        (String::from("White"), names[white].clone()),
+                        (String::from("Black"), names[black].clone()),
+                        (String::from("TimeControl"), format!("{}+{}", tc.base as f64 / 1000.0, tc.increment as f64 / 1000.0)),
+                        (String::from("Termination"), String::from(termination)),
+                    ];
+                    let pgn_str = pgn::export_pgn(&headers, chess_board::STARTFEN, &move_string, result).expect("Invalid match game");
+                    if let Err(e) = writeln!(file, "{}", pgn_str) {
+                        println!("Cannot write to PGN file {}: {}", pgn_path, e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    // Print the standings, with each player's Elo relative to the field
+    let mut standings: Vec<usize> = (0..players.len()).collect();
+    standings.sort_by_key(|&i| std::cmp::Reverse(2 * scores[i].0 + scores[i].1));
+    println!();
+    println!("Match finished:");
+    println!("{:>4}  {:<30} {:>7} {:>6} {:>16}", "Rank", "Name", "Score", "Games", "Elo");
+    for (rank, &i) in standings.iter().enumerate() {
+        let (wins, draws, losses) = scores[i];
+        let (elo, margin) = get_elo_estimate(wins, draws, losses);
+        println!("{:>4}  {:<30} {:>7.1} {:>6} {:>8.1} +/- {:<5.1}", rank + 1, names[i], wins as f64 + 0.5 * draws as f64, wins + draws + losses, elo, margin);
+    }
+    println!("Games written to {}", pgn_path);
+}
+
+// =====================================
+//             UNIT TESTS
+// =====================================
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Test the Elo estimate of a player
+    #[test]
+    fn test_get_elo_estimate() {
+        let (elo, margin) = get_elo_estimate(10, 0, 10);
+        assert!(elo.abs() < 1e-6 && margin > 0.0);
+        let (elo, _) = get_elo_estimate(15, 0, 5);
+        assert!((elo - sprt::score_to_elo(0.75)).abs() < 1e-6);
+        assert_eq!(get_elo_estimate(0, 0, 0), (0.0, 0.0));
+    }
+
+}
@@ -3,20 +3,45 @@
 // with the chess engine.  There is a main processing loop
 // "main_loop" which will handle all input.  Output is printed
 // to standard out by the module that has relavant UCU information
-// (for instance, the search module).
+// (for instance, the search module), through platform::write_stdout,
+// which flushes each reply immediately.
 // See https://en.wikipedia.org/wiki/Universal_Chess_Interface
 
+use std::fs;
 use std::io;
 use std::io::Write;
 use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
-use crate::search;
-use crate::pieces;
-use crate::chess_board;
+use topas::search;
+use topas::pieces;
+use topas::chess_board;
 use crate::uci;
-use crate::movegen;
-use crate::evaluate;
+use topas::movegen;
+use topas::evaluate;
+use topas::pgn;
+use crate::bench;
+use crate::tactics;
+use crate::selfplay;
+use crate::sprt;
+use crate::tournament;
+use topas::platform;
+use topas::strength;
+
+// Time in milliseconds spent evaluating each position when analyzing a
+// finished terminal game
+const TERMINAL_ANALYSIS_TIME_MS: i32 = 100;
+
+// Loss in centipawns for which a move in a terminal game is flagged as a
+// mistake or a blunder
+const TERMINAL_MISTAKE_THRESHOLD: i32 = 100;
+const TERMINAL_BLUNDER_THRESHOLD: i32 = 300;
+
+// Evaluations are capped when analyzing a terminal game, so that checkmate
+// scores don't dwarf every other swing
+const TERMINAL_ANALYSIS_MAX_SCORE: i32 = 2000;
 
 pub struct UCI {
     
@@ -26,6 +51,16 @@ pub struct UCI {
     // Transmission channel to send commands to the engine thread
     tx: Sender<String>,
 
+    // Flag shared with the engine thread to stop a search in progress
+    stop_flag: Arc<AtomicBool>,
+
+    // Flag shared with the engine thread indicating the search in progress
+    // is pondering
+    ponder_flag: Arc<AtomicBool>,
+
+    // Flag shared with the engine thread indicating a search is in progress
+    searching: Arc<AtomicBool>,
+
 }
 
 impl UCI {
@@ -39,40 +74,76 @@ impl UCI {
         // engine thread (rx).
         let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
 
+        // Flag set by the main thread to stop a search in progress, which
+        // the engine checks on every node
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let engine_stop_flag = Arc::clone(&stop_flag);
+
+        // Flag set by the main thread when it sends a "go ponder" command,
+        // and cleared on "ponderhit" to start the clock on the search
+        let ponder_flag = Arc::new(AtomicBool::new(false));
+        let engine_ponder_flag = Arc::clone(&ponder_flag);
+
+        // Flag set by the main thread when it sends a "go" command, and
+        // cleared by the engine thread when the search is over
+        let searching = Arc::new(AtomicBool::new(false));
+        let engine_searching = Arc::clone(&searching);
+
         // Spawn a long lived thread that will handle engine execution.
-        let t = Some(thread::spawn(move || {
+        // Move lists are allocated on the stack at every node of the
+        // search, so this thread gets a larger stack than the default.
+        let t = Some(thread::Builder::new().stack_size(search::SEARCH_STACK_SIZE).spawn(move || {
 
             let mut engine = search::SearchEngine::new(rx);
+            engine.set_stop_flag(engine_stop_flag);
+            engine.set_ponder_flag(engine_ponder_flag);
             engine.new_game();
-            loop {
 
-                // Wait on a command (note this is a blocking call)
-                let uci_command = engine.rx_channel.recv().unwrap();
+            // Wait on a command (note this is a blocking call).  The loop
+            // also ends if the main thread drops its sender.
+            while let Ok(uci_command) = engine.rx_channel.recv() {
 
                 // Parse and act on the UCI command.  Note that the stop
-                // command is handled by the engine, which will periodically
-                // check the receiver for that command.
+                // command is handled by the main thread, which sets the
+                // stop flag.
                 let tokens: Vec<&str> = uci_command.split_whitespace().collect();
                 if !tokens.is_empty() {
                     match tokens[0] {
                         "setoption" => uci::setoption_command(&mut engine, &tokens),
                         "ucinewgame" => uci::ucinewgame_command(&mut engine),
+                        "debug" => uci::debug_command(&mut engine, &tokens),
                         "position" => uci::position_command(&mut engine, &tokens),
-                        "go" => uci::go_command(&mut engine, &tokens),
-                        "stop" => {},
+                        "go" => {
+                            uci::go_command(&mut engine, &tokens);
+                            engine_searching.store(false, Ordering::Relaxed);
+                        },
+                        "isready" => uci::isready_command(),
+                        "d" => uci::display_board(&mut engine),
                         "print" => uci::print_board(&mut engine),
+                        "eval" => uci::print_eval(&mut engine),
+                        "evalcheck" => uci::evalcheck_command(&mut engine),
+                        "loadpgn" => uci::loadpgn_command(&mut engine, &uci_command),
+                        "perft" => uci::perft_command(&mut engine, &tokens),
+                        "bench" => uci::bench_command(&tokens),
+                        "tactics" => uci::tactics_command(&tokens),
+                        "selfplay" => uci::selfplay_command(&tokens),
+                        "sprt" => uci::sprt_command(&tokens),
+                        "match" => uci::match_command(&tokens),
                         "quit" => break,
-                        _ => println!("Unknown command"),
+                        _ => platform::write_stdout(&["Unknown command"]),
                     }
                 }
 
             }
-        }));
+        }).expect("Failed to spawn engine thread"));
 
         // return UCI state
         UCI {
             engine_thread: t,
             tx,
+            stop_flag,
+            ponder_flag,
+            searching,
         }
 
     }
@@ -91,52 +162,229 @@ impl UCI {
             if !tokens.is_empty() {
                 match tokens[0] {
                     "uci" => uci::uci_command(),
-                    "isready" => uci::isready_command(),
+                    "isready" => self.isready(uci_command),
                     "terminal" => uci::play_terminal(),
+                    "stop" => self.stop_flag.store(true, Ordering::Relaxed),
+                    "ponderhit" => self.ponder_flag.store(false, Ordering::Relaxed),
                     "quit" => break,
+                    "go" => {
+                        self.stop_flag.store(false, Ordering::Relaxed);
+                        self.ponder_flag.store(tokens.contains(&"ponder"), Ordering::Relaxed);
+                        self.searching.store(true, Ordering::Relaxed);
+                        self.tx.send(uci_command).unwrap();
+                    },
                     _ => self.tx.send(uci_command).unwrap(),
                 }
             }
 
         }
 
-        // Send a "stop command" to interupt any current search, and then
-        // send a "quit" command and wait for the engine thread to exit.
-        self.tx.send(String::from("stop")).unwrap();
+        self.quit();
+
+    }
+
+    // Respond to "isready".  During a search, GUIs use this as a heartbeat,
+    // so reply immediately.  Otherwise, pass it to the engine thread so that
+    // the reply is sent once any earlier commands (such as resizing the hash
+    // table) have been processed.
+    fn isready(&self, uci_command: String) {
+        if self.searching.load(Ordering::Relaxed) {
+            uci::isready_command();
+        } else {
+            self.tx.send(uci_command).unwrap();
+        }
+    }
+
+    // Send a command to the engine thread, as if it was received over UCI
+    pub fn send_command(&self, command: &str) {
+        self.tx.send(String::from(command)).unwrap();
+    }
+
+    // Wait for the engine thread to finish all commands sent so far (including
+    // any search in progress) and exit.  This is used when Topas is run with
+    // a command line subcommand, such as "topas bench".
+    pub fn finish(self) {
+        drop(self.tx);
+        self.engine_thread.map(thread::JoinHandle::join);
+    }
+
+    // Set the stop flag to interupt any current search, and then
+    // send a "quit" command and wait for the engine thread to exit.
+    fn quit(&mut self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
         self.tx.send(String::from("quit")).unwrap();
         self.engine_thread.take().map(thread::JoinHandle::join);
-
     }
 
 }
 
+// Prefix of the options that turn evaluation terms on and off, which are
+// meant for testing the impact of each term
+const EVAL_TERM_OPTION_PREFIX: &str = "Experimental Eval ";
+
 // Process the "uci" command within the main thread.
 pub fn uci_command() {
-    println!("id name Topas {}", env!("CARGO_PKG_VERSION"));
-    println!("id author Sam Nelson");
-    println!("option name Hash type spin default {} min 1 max 131072", search::DEFAULT_TT_SIZE_MB);
-    println!("uciok");
+    let mut lines = vec![
+        format!("id name Topas {}", env!("CARGO_PKG_VERSION")),
+        String::from("id author Sam Nelson"),
+        format!("option name Hash type spin default {} min 1 max 131072", search::DEFAULT_TT_SIZE_MB),
+        format!("option name Contempt type spin default {} min -100 max 100", search::DEFAULT_CONTEMPT),
+        String::from("option name LearningFile type string default <empty>"),
+        String::from("option name Ponder type check default false"),
+        String::from("option name Nodes Per Second type spin default 0 min 0 max 100000000"),
+        String::from("option name PV Notation type combo default LAN var LAN var SAN"),
+        String::from("option name UCI_LimitStrength type check default false"),
+        format!("option name UCI_Elo type spin default {} min {} max {}", strength::DEFAULT_ELO, strength::MIN_ELO, strength::MAX_ELO),
+    ];
+    for name in evaluate::EVAL_TERM_NAMES.iter() {
+        lines.push(format!("option name {}{} type check default true", EVAL_TERM_OPTION_PREFIX, name));
+    }
+    #[cfg(feature = "tune")]
+    for (name, default, min, max) in search::SEARCH_PARAMS.iter() {
+        lines.push(format!("option name {} type spin default {} min {} max {}", name, default, min, max));
+    }
+    lines.push(String::from("uciok"));
+    platform::write_stdout(&lines.iter().map(|l| l.as_str()).collect::<Vec<&str>>());
 }
 
-// Process the "isready" command within the main thread.
+// Process the "isready" command, within the main thread during a search
+// and within the engine thread otherwise.
 pub fn isready_command() {
-    println!("readyok");
+    platform::write_stdout(&["readyok"]);
 }
 
-// Process the "setoption" command within the engine thread.
+// Process the "setoption" command within the engine thread.  Option names
+// may contain spaces, so the name is everything between "name" and "value".
 pub fn setoption_command(engine: &mut search::SearchEngine, tokens: &Vec<&str>) {
-    if tokens.len() == 5 && tokens[1] == "name" && tokens[2] == "Hash" && tokens[3] == "value" {
-        if let Ok(d) = tokens[4].parse::<u64>() {
-            if d >= 1 && d <= 131072 {
-                engine.set_tt_size_mb(d);
-            } else {
-                println!("Hash value out of range");
-            }
-        } else {
-            println!("Invalid value for Hash");
+    let value_index = tokens.iter().position(|t| *t == "value").unwrap_or(0);
+    if value_index >= 3 && tokens[1] == "name" {
+        let name = tokens[2..value_index].join(" ");
+        match set_option(engine, &name, &tokens[value_index + 1..].join(" ")) {
+            Ok(()) if name == "Hash" => {
+                let (bytes, entries) = engine.get_tt_size();
+                platform::write_stdout(&[&format!("info string Hash table uses {} MiB ({} entries)", bytes / (1024 * 1024), entries)]);
+            },
+            Ok(()) => {},
+            Err(e) => platform::write_stdout(&[&e]),
         }
     } else {
-        println!("Invalid option");
+        platform::write_stdout(&["Invalid option"]);
+    }
+}
+
+// Set an engine option by name.  This is shared by the "setoption"
+// command and by engine-vs-engine matches with different configurations.
+pub fn set_option(engine: &mut search::SearchEngine, name: &str, value: &str) -> Result<(), String> {
+    #[cfg(feature = "tune")]
+    if let Some(param) = search::SEARCH_PARAMS.iter().position(|(param_name, _, _, _)| *param_name == name) {
+        return set_search_param(engine, param, value);
+    }
+    match name {
+        "Hash" => {
+            if let Ok(d) = value.parse::<u64>() {
+                if (1..=131072).contains(&d) {
+                    engine.set_tt_size_mb(d);
+                    Ok(())
+                } else {
+                    Err(String::from("Hash value out of range"))
+                }
+            } else {
+                Err(String::from("Invalid value for Hash"))
+            }
+        },
+        "Contempt" => {
+            if let Ok(d) = value.parse::<i32>() {
+                if (-100..=100).contains(&d) {
+                    engine.set_contempt(d);
+                    Ok(())
+                } else {
+                    Err(String::from("Contempt value out of range"))
+                }
+            } else {
+                Err(String::from("Invalid value for Contempt"))
+            }
+        },
+        "Nodes Per Second" => {
+            if let Ok(d) = value.parse::<u64>() {
+                if d <= 100000000 {
+                    engine.set_nps_limit(d);
+                    Ok(())
+                } else {
+                    Err(String::from("Nodes Per Second value out of range"))
+                }
+            } else {
+                Err(String::from("Invalid value for Nodes Per Second"))
+            }
+        },
+        "Ponder" => {
+            // Pondering is controlled by the GUI with "go ponder", so this
+            // only tells the GUI that it is supported
+            match value {
+                "true" | "false" => Ok(()),
+                _ => Err(String::from("Invalid value for Ponder")),
+            }
+        },
+        "PV Notation" => {
+            match value {
+                "LAN" => engine.set_pv_san(false),
+                "SAN" => engine.set_pv_san(true),
+                _ => return Err(String::from("Invalid value for PV Notation")),
+            }
+            Ok(())
+        },
+        "UCI_LimitStrength" => {
+            match value {
+                "true" => engine.set_limit_strength(true),
+                "false" => engine.set_limit_strength(false),
+                _ => return Err(String::from("Invalid value for UCI_LimitStrength")),
+            }
+            Ok(())
+        },
+        "UCI_Elo" => {
+            if let Ok(d) = value.parse::<i32>() {
+                if (strength::MIN_ELO..=strength::MAX_ELO).contains(&d) {
+                    engine.set_elo(d);
+                    Ok(())
+                } else {
+                    Err(String::from("UCI_Elo value out of range"))
+                }
+            } else {
+                Err(String::from("Invalid value for UCI_Elo"))
+            }
+        },
+        "LearningFile" => {
+            if value == "<empty>" {
+                engine.set_learning_file("")
+            } else {
+                engine.set_learning_file(value)
+            }
+        },
+        _ if name.starts_with(EVAL_TERM_OPTION_PREFIX) => {
+            let term_name = &name[EVAL_TERM_OPTION_PREFIX.len()..];
+            let term = evaluate::EVAL_TERM_NAMES.iter().position(|&t| t == term_name).ok_or("Invalid option")?;
+            match value {
+                "true" => engine.set_eval_term(term, true),
+                "false" => engine.set_eval_term(term, false),
+                _ => return Err(format!("Invalid value for {}", name)),
+            }
+            Ok(())
+        },
+        _ => Err(String::from("Invalid option")),
+    }
+}
+
+// Set a search parameter (with the "tune" feature), checking that the value
+// is within the parameter's range
+#[cfg(feature = "tune")]
+fn set_search_param(engine: &mut search::SearchEngine, param: usize, value: &str) -> Result<(), String> {
+    let (name, _, min, max) = search::SEARCH_PARAMS[param];
+    match value.parse::<i32>() {
+        Ok(d) if (min..=max).contains(&d) => {
+            engine.set_search_param(param, d);
+            Ok(())
+        },
+        Ok(_) => Err(format!("{} value out of range", name)),
+        Err(_) => Err(format!("Invalid value for {}", name)),
     }
 }
 
@@ -158,6 +406,7 @@ pub fn position_command(engine: &mut search::SearchEngine, tokens: &Vec<&str>) {
             fen_str = format!("{} {} {} {} {} {}", tokens[2], tokens[3], tokens[4], tokens[5], tokens[6], tokens[7]);
             move_start = 8;
         } else {
+            platform::write_stdout(&["info string error: Invalid position command"]);
             return;
         }
 
@@ -169,8 +418,11 @@ pub fn position_command(engine: &mut search::SearchEngine, tokens: &Vec<&str>) {
             }
         }
 
-        // Set the board state
-        engine.set_board_state(&fen_str, &move_str);
+        // Set the board state, reporting any errors to the GUI rather
+        // than exiting
+        if let Err(e) = engine.set_board_state(&fen_str, &move_str) {
+            platform::write_stdout(&[&format!("info string error: {}", e)]);
+        }
     }
 }
 
@@ -179,6 +431,45 @@ pub fn ucinewgame_command(engine: &mut search::SearchEngine) {
     engine.new_game();
 }
 
+// Process the "debug" command within the engine thread.  Usage is
+// "debug [on | off]".
+pub fn debug_command(engine: &mut search::SearchEngine, tokens: &[&str]) {
+    match tokens.get(1) {
+        Some(&"on") => engine.set_debug(true),
+        Some(&"off") => engine.set_debug(false),
+        _ => platform::write_stdout(&["info string error: Usage is debug [on | off]"]),
+    }
+}
+
+// Format the result of a search as the "bestmove" message, along with the
+// expected reply to ponder on if there is one.  If there are no legal
+// moves, the best move is "(none)".
+pub fn format_bestmove(result: &search::SearchResult) -> String {
+    let best_move = result.best_move.as_deref().unwrap_or("(none)");
+    match &result.ponder_move {
+        Some(ponder_move) => format!("bestmove {} ponder {}", best_move, ponder_move),
+        None => format!("bestmove {}", best_move),
+    }
+}
+
+// Parameters of the "go" command, which end the move lists of
+// "searchmoves" and "excludemoves"
+const GO_PARAMETERS: [&str; 13] = ["searchmoves", "excludemoves", "ponder", "wtime", "btime", "winc", "binc", "movestogo", "depth", "nodes", "mate", "movetime", "infinite"];
+
+// Extract the list of moves following the given parameter, which runs until
+// the next parameter in the given list.  If the parameter is not present,
+// the list is empty.
+pub fn parse_move_list(tokens: &[&str], name: &str, parameters: &[&str]) -> Result<Vec<search::SearchMove>, String> {
+    match tokens.iter().position(|&x| x == name) {
+        Some(e) => {
+            let moves: Vec<&str> = tokens[e+1..].iter().take_while(|x| !parameters.contains(x)).copied().collect();
+            let moves = movegen::convert_moves_str_into_list(&moves.join(" "))?;
+            Ok(moves.iter().map(|(s, e, p)| (*s as u8, *e as u8, *p)).collect())
+        },
+        None => Ok(Vec::new()),
+    }
+}
+
 // Process the "go" command within the engine thread.
 // This is the main request to search.
 pub fn go_command(engine: &mut search::SearchEngine, tokens: &Vec<&str>) {
@@ -250,16 +541,217 @@ pub fn go_command(engine: &mut search::SearchEngine, tokens: &Vec<&str>) {
         }
     }
 
+    // Extract the node limit, if provided
+    let mut nodes = 0;
+    if let Some(e) = tokens.iter().position(|&x| x == "nodes") {
+        if tokens.len() > e+1 {
+            if let Ok(d) = tokens[e+1].parse::<u64>() {
+                nodes = d;
+            }
+        }
+    }
+
+    // Extract the root moves to restrict the search to, and the root moves
+    // to exclude from the search (a non-UCI extension)
+    let included_moves = match parse_move_list(tokens, "searchmoves", &GO_PARAMETERS) {
+        Ok(e) => e,
+        Err(e) => {
+            platform::write_stdout(&[&format!("Invalid searchmoves: {}", e)]);
+            return;
+        },
+    };
+    let excluded_moves = match parse_move_list(tokens, "excludemoves", &GO_PARAMETERS) {
+        Ok(e) => e,
+        Err(e) => {
+            platform::write_stdout(&[&format!("Invalid excludemoves: {}", e)]);
+            return;
+        },
+    };
+
     // Perform the search with either depth or time as a limiter.
     // If neither of these is present, check for a "infinite" command.
-    if depth > 0 || my_time > 0  || tokens.iter().any(|&x| x == "infinite") {
-        engine.find_best_move(depth, my_time, my_inc, movestogo);
+    if depth > 0 || my_time > 0 || nodes > 0 || tokens.iter().any(|&x| x == "infinite") {
+        engine.set_root_moves(&included_moves, &excluded_moves);
+        engine.set_node_limit(nodes);
+        engine.set_pondering(tokens.contains(&"ponder"));
+        let result = engine.find_best_move(depth, my_time, my_inc, movestogo);
+        engine.write_line(&format_bestmove(&result));
     } else {
-        println!("Invalid go parameters; ignoring");
+        platform::write_stdout(&["Invalid go parameters; ignoring"]);
     }
 
 }
 
+// Extra (non-UCI) eval command for debugging the static evaluation,
+// handled within the engine thread.
+pub fn print_eval(engine: &mut search::SearchEngine) {
+    engine.print_eval();
+}
+
+// Extra (non-UCI) command to check that the static evaluation of the
+// current position matches that of its color-flipped mirror, handled
+// within the engine thread.
+pub fn evalcheck_command(engine: &mut search::SearchEngine) {
+    match engine.check_eval_symmetry() {
+        Ok(eval) => platform::write_stdout(&[&format!("Evaluation is symmetric: {} (side to move)", eval)]),
+        Err(e) => platform::write_stdout(&[&e]),
+    }
+}
+
+// Extra (non-UCI) bench command, handled within the engine thread.
+// Usage is "bench [depth]" or "bench nodes <nodes>".
+pub fn bench_command(tokens: &[&str]) {
+    if tokens.get(1) == Some(&"nodes") {
+        match tokens.get(2).map(|x| x.parse::<u64>()) {
+            Some(Ok(n)) if n >= 1 => bench::run_bench(0, n),
+            _ => platform::write_stdout(&["Invalid bench nodes"]),
+        }
+        return;
+    }
+    match tokens.get(1).map(|x| x.parse::<u8>()) {
+        None => bench::run_bench(bench::BENCH_DEPTH, 0),
+        Some(Ok(d)) if d >= 1 => bench::run_bench(d, 0),
+        _ => platform::write_stdout(&["Invalid bench depth"]),
+    }
+}
+
+// Extra (non-UCI) command to run the built-in tactical test suites,
+// handled within the engine thread.
+// Usage is "tactics [bk | wac] [movetime <ms>]".
+pub fn tactics_command(tokens: &[&str]) {
+    let mut suite_name = None;
+    let mut time_per_position = tactics::DEFAULT_TACTICS_TIME_MS;
+    let mut i = 1;
+    while i < tokens.len() {
+        if tokens[i] == "movetime" {
+            match tokens.get(i+1).map(|x| x.parse::<u32>()) {
+                Some(Ok(d)) if d >= 1 => time_per_position = d,
+                _ => {
+                    platform::write_stdout(&["Invalid tactics movetime"]);
+                    return;
+                },
+            }
+            i += 2;
+        } else {
+            suite_name = Some(tokens[i]);
+            i += 1;
+        }
+    }
+    tactics::run_tactics(suite_name, time_per_position);
+}
+
+// Extra (non-UCI) command to play games of the engine against itself,
+// handled within the engine thread.
+// Usage is "selfplay <number of games> <time control> [PGN file]".
+pub fn selfplay_command(tokens: &[&str]) {
+    if tokens.len() < 3 || tokens.len() > 4 {
+        platform::write_stdout(&["Invalid selfplay command"]);
+        return;
+    }
+    match tokens[1].parse::<u32>() {
+        Ok(n) if n >= 1 => selfplay::run_selfplay(n, tokens[2], tokens.get(3).unwrap_or(&selfplay::DEFAULT_SELFPLAY_PGN)),
+        _ => platform::write_stdout(&["Invalid number of selfplay games"]),
+    }
+}
+
+// Extra (non-UCI) command to run an SPRT match between two engine
+// configurations, handled within the engine thread.
+// Usage is "sprt <time control> <elo0> <elo1> <config A> <config B> [max pairs]"
+// where a configuration is "default" or "name=value,name=value".
+pub fn sprt_command(tokens: &[&str]) {
+    if tokens.len() < 6 || tokens.len() > 7 {
+        platform::write_stdout(&["Invalid sprt command"]);
+        return;
+    }
+    let (elo0, elo1) = match (tokens[2].parse::<f64>(), tokens[3].parse::<f64>()) {
+        (Ok(e0), Ok(e1)) if e0 < e1 => (e0, e1),
+        _ => {
+            platform::write_stdout(&["Invalid sprt Elo bounds"]);
+            return;
+        },
+    };
+    let max_pairs = match tokens.get(6).map(|x| x.parse::<u32>()) {
+        None => sprt::DEFAULT_SPRT_MAX_PAIRS,
+        Some(Ok(d)) if d >= 1 => d,
+        _ => {
+            platform::write_stdout(&["Invalid sprt maximum number of game pairs"]);
+            return;
+        },
+    };
+    sprt::run_sprt(tokens[1], elo0, elo1, tokens[4], tokens[5], max_pairs);
+}
+
+// Extra (non-UCI) command to run a round-robin match between Topas and
+// external UCI engines, handled within the engine thread.
+// Usage is "match <rounds> <time control> <engine> <engine> [engine ...]"
+// where an engine is "topas" or the path to a UCI engine executable.
+pub fn match_command(tokens: &[&str]) {
+    if tokens.len() < 5 {
+        platform::write_stdout(&["Invalid match command"]);
+        return;
+    }
+    match tokens[1].parse::<u32>() {
+        Ok(n) if n >= 1 => tournament::run_match(n, tokens[2], &tokens[3..], tournament::DEFAULT_MATCH_PGN),
+        _ => platform::write_stdout(&["Invalid number of match rounds"]),
+    }
+}
+
+// Extra (non-UCI) perft command for debugging move generation, handled
+// within the engine thread.
+// Usage is "perft <depth> [hash <MB>]".
+pub fn perft_command(engine: &mut search::SearchEngine, tokens: &[&str]) {
+    let depth = match tokens.get(1).map(|x| x.parse::<usize>()) {
+        Some(Ok(d)) if d >= 1 => d,
+        _ => {
+            platform::write_stdout(&["Invalid perft depth"]);
+            return;
+        },
+    };
+    let mut hash_size_mb = None;
+    if let Some(e) = tokens.iter().position(|&x| x == "hash") {
+        match tokens.get(e+1).map(|x| x.parse::<u64>()) {
+            Some(Ok(d)) if (1..=131072).contains(&d) => hash_size_mb = Some(d),
+            _ => {
+                platform::write_stdout(&["Invalid perft hash size"]);
+                return;
+            },
+        }
+    }
+    engine.perft_divide(depth, hash_size_mb);
+}
+
+// Extra (non-UCI) command to set the board to the final position of
+// the game in a PGN file, handled within the engine thread.
+pub fn loadpgn_command(engine: &mut search::SearchEngine, uci_command: &str) {
+    let path = uci_command.trim().trim_start_matches("loadpgn").trim();
+    let pgn_str = match fs::read_to_string(path) {
+        Ok(e) => e,
+        Err(e) => {
+            platform::write_stdout(&[&format!("info string error: Cannot read PGN file {}: {}", path, e)]);
+            return;
+        },
+    };
+    let game = match pgn::parse_pgn(&pgn_str) {
+        Ok(e) => e,
+        Err(e) => {
+            platform::write_stdout(&[&format!("info string error: {}", e)]);
+            return;
+        },
+    };
+    if let Err(e) = engine.set_board_state(&game.fen, &game.moves) {
+        platform::write_stdout(&[&format!("info string error: {}", e)]);
+        return;
+    }
+    let get_header = |name: &str| game.headers.iter().find(|(n, _)| n == name).map_or("?", |(_, v)| v.as_str());
+    platform::write_stdout(&[&format!("info string loaded PGN game {} - {} {}", get_header("White"), get_header("Black"), game.result)]);
+}
+
+// Extra (non-UCI) display command, showing the board, FEN, key, and
+// checkers, handled within the engine thread.
+pub fn display_board(engine: &mut search::SearchEngine) {
+    engine.display_board();
+}
+
 // Extra (non-UCI) print command for debuging, handled within the
 // engine thread.
 pub fn print_board(engine: &mut search::SearchEngine) {
@@ -279,6 +771,7 @@ pub fn play_terminal() {
     let mut use_unicode = false;
     let human_color;
     let mut time_per_move = 5000;
+    let mut max_depth = 0;
     println!();
     println!("===================================");
     println!("Welcome to the Topas Chess Terminal");
@@ -290,6 +783,7 @@ pub fn play_terminal() {
     println!("   - Unicode support: no");
     println!("   - Topas hash table size: 2GB");
     println!("   - Topas time per move: 5 seconds");
+    println!("   - Topas strength: 10 (full strength)");
     let use_defaults;
     loop {
         print!("Do you want to continue with these defaults ('yes' to continue, 'no' to edit): ");
@@ -334,6 +828,20 @@ pub fn play_terminal() {
             }
             println!(" -> Invalid input, please enter an integer between 1 and 1000.");
         }
+        loop {
+
+            // Strength levels below 10 limit the search depth to the level
+            print!("Enter the strength of Topas from 1 (beginner) to 10 (full strength) (enter 10 if unsure): ");
+            io::stdout().flush().unwrap();
+            let input = get_user_input();
+            if let Ok(i) = input.parse::<u8>() {
+                if (1..=10).contains(&i) {
+                    max_depth = if i == 10 {0} else {i};
+                    break;
+                }
+            }
+            println!(" -> Invalid input, please enter an integer between 1 and 10.");
+        }
     }
     loop {
         print!("Would you like to play as white or black? ");
@@ -360,33 +868,63 @@ pub fn play_terminal() {
     }
 
     // Play the game
+    let mut start_fen = String::from(chess_board::STARTFEN);
     let mut move_string = String::new();
     board.new_game();
     let mut turn = pieces::COLOR_WHITE;
-    loop {
+    'game: loop {
         let mut cur_move;
         let mut move_raw;
         println!();
-        if human_color == pieces::COLOR_BLACK {
-            println!("Black: You");
-        } else {
-            println!("Black: Topas");
-        }
-        board.print(use_unicode);
+
+        // The human's pieces are always shown at the bottom
         if human_color == pieces::COLOR_WHITE {
+            println!("Black: Topas");
+            board.print(use_unicode, false);
             println!("White: You");
         } else {
             println!("White: Topas");
+            board.print(use_unicode, true);
+            println!("Black: You");
         }
         println!();
         if turn == human_color {
             loop {
 
                 // Get move from user
-                print!("Your turn - enter move in long algebraic notation (type quit to quit): ");
+                print!("Your turn - enter move in long algebraic notation (type moves to list legal moves, save <file> or load <file> to save or resume a game, quit to quit): ");
                 io::stdout().flush().unwrap();
-                move_raw = get_user_input();
+                let input = get_user_input_raw();
+                move_raw = input.to_lowercase();
+                if move_raw == "moves" {
+                    print_legal_moves(&mut board);
+                    continue;
+                }
+                if let Some(path) = input.strip_prefix("save ") {
+                    match save_terminal_game(path.trim(), human_color, &start_fen, &move_string) {
+                        Ok(()) => println!(" -> Game saved to {}", path.trim()),
+                        Err(e) => println!(" -> Unable to save game: {}", e),
+                    }
+                    continue;
+                }
+                if let Some(path) = input.strip_prefix("load ") {
+                    match load_terminal_game(path.trim()) {
+                        Ok((fen, moves, loaded_board)) => {
+                            println!(" -> Game loaded from {}", path.trim());
+                            start_fen = fen;
+                            move_string = moves;
+                            board = loaded_board;
+                            turn = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+                            continue 'game;
+                        },
+                        Err(e) => {
+                            println!(" -> Unable to load game: {}", e);
+                            continue;
+                        },
+                    }
+                }
                 if move_raw == "quit" {
+                    print_terminal_pgn(human_color, &start_fen, &move_string, "*");
                     println!("You are leaving the Topas Chess Terminal and switching back into UCI mode.");
                     println!("Enter quit again to exit the program; else enter any other UCI command.");
                     return;
@@ -395,35 +933,32 @@ pub fn play_terminal() {
                     println!(" -> Invalid move input");
                     continue;
                 }
-                cur_move = movegen::convert_moves_str_into_list(&move_raw);
-                let cur_piece = board.get_color_and_piece_on_square(cur_move[0].0);
-                if cur_piece.is_none() {
+                cur_move = match movegen::convert_moves_str_into_list(&move_raw) {
+                    Ok(m) => m,
+                    Err(_) => {
+                        println!(" -> Invalid move input");
+                        continue;
+                    },
+                };
+                if board.get_color_and_piece_on_square(cur_move[0].0).is_none() {
                     println!(" -> Invalid move, no piece on selected square");
                     continue;
                 }
-                let mut cap_piece = None;
-                if let Some(e) = board.get_color_and_piece_on_square(cur_move[0].1) {
-                    cap_piece = Some(e.1);
-                }
-                let start_file = cur_move[0].0 % 8;
-                let end_file = cur_move[0].1 % 8;
-                let mut is_en_passant = false;
-                if cur_piece.unwrap().1 == pieces::PAWN && (start_file != end_file) {
-                    is_en_passant = true;
-                }
-                let cur_move_struct = movegen::ChessMove {
-                    start_square: cur_move[0].0,
-                    end_square: cur_move[0].1,
-                    piece: cur_piece.unwrap().1,
-                    captured_piece: cap_piece,
-                    priority: 0,
-                    is_en_passant,
-                };
 
-                // Validate move
-                if !movegen::generate_all_psuedo_legal_moves(&board, turn, false).contains(&cur_move_struct) || !movegen::is_legal_move(&mut board, &cur_move_struct) {
-                    println!(" -> Illegal move");
-                    continue;
+                // Validate move.  If a promotion piece was not entered, then
+                // assume a queen.
+                let mut all_moves = movegen::MoveList::new();
+                movegen::generate_all_psuedo_legal_moves(&board, turn, false, &mut all_moves);
+                let cur_move_struct = all_moves.iter().find(|m| {
+                    m.start_square == cur_move[0].0 && m.end_square == cur_move[0].1 &&
+                    (m.promotion_piece.is_none() || m.promotion_piece == cur_move[0].2.or(Some(pieces::QUEEN)))
+                });
+                match cur_move_struct {
+                    Some(m) if movegen::is_legal_move(&mut board, m) => cur_move[0].2 = m.promotion_piece,
+                    _ => {
+                        println!(" -> Illegal move");
+                        continue;
+                    },
                 }
                 break;
             }
@@ -431,39 +966,54 @@ pub fn play_terminal() {
 
             // Get best move from engine
             println!("Topas is now thinking...");
-            engine.set_board_state(chess_board::STARTFEN, &move_string);
-            move_raw = engine.find_best_move(99, time_per_move as i32, 0, 1);
-            cur_move = movegen::convert_moves_str_into_list(&move_raw);
+            engine.set_board_state(&start_fen, &move_string).expect("Invalid terminal game state");
+            move_raw = engine.find_best_move(max_depth, time_per_move as i32, 0, 1).best_move.expect("No legal move in terminal game");
+            cur_move = movegen::convert_moves_str_into_list(&move_raw).expect("Invalid engine move");
 
         }
         
         // Make the move and switch turns
-        board.make_move(cur_move[0].0, cur_move[0].1, Some(pieces::QUEEN));
-        move_string.push_str(&move_raw);
-        move_string.push_str(" ");
+        board.make_move(cur_move[0].0, cur_move[0].1, cur_move[0].2);
+        move_string.push_str(&movegen::convert_move_list_to_lan(&vec![(cur_move[0].0 as u8, cur_move[0].1 as u8, cur_move[0].2)]));
         turn = 1 - turn;
 
         // Check for game end state
-        let mut all_moves = movegen::generate_all_psuedo_legal_moves(&board, turn, false);
-        all_moves.retain(|x| movegen::is_legal_move(&mut board, &x));
-        if all_moves.len() == 0 {
+        let mut all_moves = movegen::MoveList::new();
+        movegen::generate_all_psuedo_legal_moves(&board, turn, false, &mut all_moves);
+        if !all_moves.iter().any(|x| movegen::is_legal_move(&mut board, x)) {
             if movegen::is_king_in_check(&board, turn) {
                 println!("Game over: {} wins by checkmate", if turn == pieces::COLOR_WHITE {"Black"} else {"White"});
+                print_terminal_pgn(human_color, &start_fen, &move_string, if turn == pieces::COLOR_WHITE {"0-1"} else {"1-0"});
             } else {
                 println!("Game over: Draw by stalemate");
+                print_terminal_pgn(human_color, &start_fen, &move_string, "1/2-1/2");
             }
             break;
         }
         if evaluate::is_draw_by_insufficient_material(&board) {
             println!("Game over: Draw by insufficient material");
+            print_terminal_pgn(human_color, &start_fen, &move_string, "1/2-1/2");
             break;
         }
         if evaluate::is_draw_by_threefold_repitition(&board) {
             println!("Game over: Draw by threefold repitition");
+            print_terminal_pgn(human_color, &start_fen, &move_string, "1/2-1/2");
             break;
         }
     }
 
+    // Offer to analyze the finished game
+    loop {
+        println!();
+        print!("Would you like Topas to analyze the game (yes/no)? ");
+        io::stdout().flush().unwrap();
+        match get_user_input().as_str() {
+            "yes" | "y" => {analyze_terminal_game(&start_fen, &move_string); break},
+            "no" | "n" => break,
+            _ => println!(" -> Invalid input, please enter 'yes' or 'no'."),
+        }
+    }
+
     // Exit terminal
     println!();
     println!("You are leaving the Topas Chess Terminal and switching back into UCI mode.");
@@ -471,6 +1021,158 @@ pub fn play_terminal() {
 
 }
 
+// Get the PGN of a terminal game
+fn get_terminal_pgn(human_color: usize, start_fen: &str, move_string: &str, result: &str) -> Result<String, String> {
+    let (white, black) = if human_color == pieces::COLOR_WHITE {("You", "Topas")} else {("Topas", "You")};
+    let headers = vec![
+        (String::from("Event"), String::from("Topas Chess Terminal game")),
+        (String::from("Site"), String::from("?")),
+        (String::from("Date"), String::from("????.??.??")),
+        (String::from("Round"), String::from("-")),
+        (String::from("White"), String::from(white)),
+        (String::from("Black"), String::from(black)),
+    ];
+    pgn::export_pgn(&headers, start_fen, move_string, result)
+}
+
+// Print the PGN of a terminal game so it can be reviewed in other tools
+fn print_terminal_pgn(human_color: usize, start_fen: &str, move_string: &str, result: &str) {
+    match get_terminal_pgn(human_color, start_fen, move_string, result) {
+        Ok(e) => {
+            println!();
+            println!("PGN of the game:");
+            println!();
+            print!("{}", e);
+        },
+        Err(e) => println!("Unable to create PGN of the game: {}", e),
+    }
+}
+
+// Save a terminal game in progress.  A file ending in ".fen" gets the FEN
+// of the current position; anything else gets the PGN of the game so far.
+fn save_terminal_game(path: &str, human_color: usize, start_fen: &str, move_string: &str) -> Result<(), String> {
+    let contents = if path.to_lowercase().ends_with(".fen") {
+        let mut board = chess_board::ChessBoard::new();
+        board.new_game_from_fen(start_fen)?;
+        for lan in move_string.split_whitespace() {
+            let m = pgn::lan_to_move(&mut board, lan)?;
+            board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        }
+        format!("{}\n", board.to_fen())
+    } else {
+        get_terminal_pgn(human_color, start_fen, move_string, "*")?
+    };
+    fs::write(path, contents).map_err(|e| e.to_string())
+}
+
+// Load a terminal game to resume, from either a FEN or a PGN file.
+// Returns the starting FEN, the moves played since, and the board.
+fn load_terminal_game(path: &str) -> Result<(String, String, chess_board::ChessBoard), String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut board = chess_board::ChessBoard::new();
+    let (fen, moves) = if board.new_game_from_fen(contents.trim()).is_ok() {
+        (contents.trim().to_string(), String::new())
+    } else {
+        let game = pgn::parse_pgn(&contents)?;
+        board.new_game_from_fen(&game.fen)?;
+        for lan in game.moves.split_whitespace() {
+            let m = pgn::lan_to_move(&mut board, lan)?;
+            board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        }
+        (game.fen, game.moves)
+    };
+    if pgn::get_legal_moves(&mut board).is_empty() {
+        return Err(String::from("The game is already over"));
+    }
+    Ok((fen, moves, board))
+}
+
+// Replay a finished terminal game with a quick engine evaluation of each
+// position, flagging the moves where the evaluation swung against the
+// player who made them
+fn analyze_terminal_game(start_fen: &str, move_string: &str) {
+
+    // Use a separate engine whose search output is discarded.  Draws are
+    // scored as equal, since the evaluations are not from either player's
+    // point of view.
+    let (_, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let mut engine = search::SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(|_: &str| {}));
+    engine.set_contempt(0);
+    let mut board = chess_board::ChessBoard::new();
+    board.new_game_from_fen(start_fen).expect("Invalid terminal game state");
+    println!();
+    println!("Analyzing the game...");
+
+    // Evaluate every position of the game from white's perspective, and
+    // get each move in SAN along with its move number
+    let moves: Vec<&str> = move_string.split_whitespace().collect();
+    let mut evals = Vec::new();
+    let mut annotated_moves = Vec::new();
+    for i in 0..=moves.len() {
+        let my_color = if board.whites_turn {pieces::COLOR_WHITE} else {pieces::COLOR_BLACK};
+        let score = if !pgn::get_legal_moves(&mut board).is_empty() {
+            // Each position has the other side to move, so the scores of
+            // the last search are not kept
+            engine.new_game();
+            engine.set_board_state(start_fen, &moves[..i].join(" ")).expect("Invalid terminal game state");
+            engine.find_best_move(0, TERMINAL_ANALYSIS_TIME_MS, 0, 1);
+            engine.last_search_score().clamp(-TERMINAL_ANALYSIS_MAX_SCORE, TERMINAL_ANALYSIS_MAX_SCORE)
+        } else if movegen::is_king_in_check(&board, my_color) {
+            -TERMINAL_ANALYSIS_MAX_SCORE
+        } else {
+            0
+        };
+        evals.push(if board.whites_turn {score} else {-score});
+        if let Some(lan) = moves.get(i) {
+            let m = pgn::lan_to_move(&mut board, lan).expect("Invalid terminal game move");
+            let move_number = format!("{}{}", board.fullmove_number, if board.whites_turn {"."} else {"..."});
+            annotated_moves.push((move_number, pgn::move_to_san(&mut board, &m), my_color));
+            board.make_move(m.start_square, m.end_square, m.promotion_piece);
+        }
+    }
+
+    // Print each move with the evaluation before and after it, and count
+    // the mistakes and blunders of each player
+    let mut mistakes = [0; 2];
+    let mut blunders = [0; 2];
+    println!();
+    println!("Post-game analysis (evaluations in pawns from white's perspective):");
+    println!();
+    for (i, (move_number, san, color)) in annotated_moves.iter().enumerate() {
+        let loss = if *color == pieces::COLOR_WHITE {evals[i] - evals[i + 1]} else {evals[i + 1] - evals[i]};
+        let (annotation, comment) = if loss >= TERMINAL_BLUNDER_THRESHOLD {
+            blunders[*color] += 1;
+            ("??", " (blunder)")
+        } else if loss >= TERMINAL_MISTAKE_THRESHOLD {
+            mistakes[*color] += 1;
+            ("?", " (mistake)")
+        } else {
+            ("", "")
+        };
+        println!("{:>8} {:<10} {:+6.2} -> {:+6.2}{}", move_number, format!("{}{}", san, annotation),
+            evals[i] as f64 / 100.0, evals[i + 1] as f64 / 100.0, comment);
+    }
+    println!();
+    println!("White: {} mistakes, {} blunders", mistakes[pieces::COLOR_WHITE], blunders[pieces::COLOR_WHITE]);
+    println!("Black: {} mistakes, {} blunders", mistakes[pieces::COLOR_BLACK], blunders[pieces::COLOR_BLACK]);
+
+}
+
+// Print the legal moves for the side to move in SAN, grouped by piece,
+// along with the long algebraic notation expected by the terminal
+fn print_legal_moves(board: &mut chess_board::ChessBoard) {
+    let legal_moves = pgn::get_legal_moves(board);
+    for piece in [pieces::PAWN, pieces::KNIGHT, pieces::BISHOP, pieces::ROOK, pieces::QUEEN, pieces::KING] {
+        let moves: Vec<String> = legal_moves.iter().filter(|m| m.piece == piece).map(|m| {
+            let lan = movegen::convert_move_list_to_lan(&vec![(m.start_square as u8, m.end_square as u8, m.promotion_piece)]);
+            format!("{} ({})", pgn::move_to_san(board, m), lan.trim())
+        }).collect();
+        if !moves.is_empty() {
+            println!(" -> {}: {}", pieces::PIECE_ID_TO_NAME[piece], moves.join(", "));
+        }
+    }
+}
+
 // Validate move string
 fn valid_move_entry(m: &str) -> bool {
     if m.len() < 4 || m.len() > 5 {
@@ -490,7 +1192,12 @@ fn valid_move_entry(m: &str) -> bool {
 
 // Get user input
 fn get_user_input() -> String {
+    get_user_input_raw().to_lowercase()
+}
+
+// Get user input, preserving case (e.g., for file names)
+fn get_user_input_raw() -> String {
     let mut input = String::new();
     io::stdin().read_line(&mut input).expect("Failed to read line");
-    input.trim().to_lowercase().to_string()
+    input.trim().to_string()
 }
\ No newline at end of file
@@ -0,0 +1,48 @@
+// Integration tests of the library API, which drive the engine through the
+// public crate path as an integrator would, without the UCI loop
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use topas::platform;
+use topas::chess_board;
+use topas::pieces;
+use topas::search::{SearchEngine, SearchLimits};
+
+// Construct an engine whose output is collected into the returned lines
+fn new_engine() -> (SearchEngine, Arc<Mutex<Vec<String>>>) {
+    let (_, rx) = mpsc::channel();
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&lines);
+    let output = move |line: &str| sink.lock().unwrap().push(line.to_string());
+    let mut engine = SearchEngine::with_platform(rx, Box::new(platform::StdClock::new()), Box::new(output));
+    engine.new_game();
+    (engine, lines)
+}
+
+// Test analyzing positions with include and exclude lists of root moves
+#[test]
+fn test_analyze_root_moves() {
+    let (mut engine, lines) = new_engine();
+    let limits = SearchLimits {depth: 4, movetime: 0};
+
+    // Of the included moves e2e4 and d2d4, e2e4 is excluded
+    let result = engine.analyze(chess_board::STARTFEN, "", &limits, &[(12, 28, None), (11, 27, None)], &[(12, 28, None)]).unwrap();
+    assert_eq!(result.best_move.as_deref(), Some("d2d4"));
+    assert_eq!(result.depth, 4);
+    assert!(lines.lock().unwrap().iter().any(|l| l.starts_with("info depth 4 ") && l.contains(" pv d2d4")));
+
+    // Underpromotion is searched alone if it is the only included move
+    let fen = "8/P6k/8/8/8/8/8/K7 w - - 0 1";
+    let result = engine.analyze(fen, "", &limits, &[(48, 56, Some(pieces::KNIGHT))], &[]).unwrap();
+    assert_eq!(result.best_move.as_deref(), Some("a7a8n"));
+
+    // Excluding the mating move finds another
+    let fen = "7k/8/6K1/8/8/8/8/R7 w - - 0 1";
+    let result = engine.analyze(fen, "", &limits, &[], &[]).unwrap();
+    assert_eq!(result.best_move.as_deref(), Some("a1a8"));
+    let result = engine.analyze(fen, "", &limits, &[], &[(0, 56, None)]).unwrap();
+    assert_ne!(result.best_move.as_deref(), Some("a1a8"));
+
+    // Invalid positions are errors
+    assert!(engine.analyze("invalid", "", &limits, &[], &[]).is_err());
+}